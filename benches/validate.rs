@@ -0,0 +1,109 @@
+/// validation throughput, from one thread and from several at once with and without a writer churning the store. the
+/// threads benchmarks report the validations per second of all threads together; the target is at least a million
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use otp_session_lib::db::{DataStore, SessionItem};
+use otp_session_lib::hasher::Hashing;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USERS: usize = 10_000;
+
+// a store holding an otp and a session for each user, and the code and user pairs to validate
+fn populate(hashing: Hashing) -> (DataStore, Vec<(String, String)>) {
+    let mut store = DataStore::create().with_hashing(hashing);
+    let mut keys = Vec::with_capacity(USERS * 2);
+    for n in 0..USERS {
+        let user = format!("user-{n}");
+        let otp = format!("{:06}", fastrand::u32(..1_000_000));
+        let session = format!("{:032x}", fastrand::u128(..));
+        for code in [otp, session] {
+            store.put(SessionItem::new(&code, &user, 3_600)).unwrap();
+            keys.push((code, user.clone()));
+        }
+    }
+
+    (store, keys)
+}
+
+// validate iters pairs from each thread at once, starting at different pairs, and return the time until all finish
+fn validate_from(
+    store: &DataStore,
+    keys: &[(String, String)],
+    threads: usize,
+    iters: u64,
+) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..threads {
+            scope.spawn(move || {
+                let pairs = keys.iter().cycle().skip(t * keys.len() / threads);
+                for (code, user) in pairs.take(iters as usize) {
+                    black_box(store.validate(code, user));
+                }
+            });
+        }
+    });
+
+    start.elapsed()
+}
+
+// put and remove items for other users until stopped, as logins and logouts would
+fn churn(store: &DataStore, stop: &AtomicBool) {
+    let mut store = store.clone();
+    let mut n = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let (code, user) = (
+            format!("{:06}", n % 1_000_000),
+            format!("churn-{}", n % 1_000),
+        );
+        store.put(SessionItem::new(&code, &user, 60)).unwrap();
+        store.remove(&code, &user);
+        n += 1;
+    }
+}
+
+fn validate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("validate");
+
+    group.throughput(Throughput::Elements(1));
+    for hashing in [Hashing::AHash, Hashing::Sip, Hashing::Fx] {
+        let (store, keys) = populate(hashing);
+        group.bench_function(BenchmarkId::new("one_thread", hashing), |b| {
+            let mut n = 0;
+            b.iter(|| {
+                let (code, user) = &keys[n % keys.len()];
+                n += 1;
+                store.validate(black_box(code), black_box(user))
+            })
+        });
+    }
+
+    let (store, keys) = populate(Hashing::default());
+
+    for threads in [2, 4, 8] {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_with_input(
+            BenchmarkId::new("threads", threads),
+            &threads,
+            |b, &threads| b.iter_custom(|iters| validate_from(&store, &keys, threads, iters)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("threads_with_writer", threads),
+            &threads,
+            |b, &threads| {
+                let stop = AtomicBool::new(false);
+                thread::scope(|scope| {
+                    scope.spawn(|| churn(&store, &stop));
+                    b.iter_custom(|iters| validate_from(&store, &keys, threads, iters));
+                    stop.store(true, Ordering::Relaxed);
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, validate);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/otp_session.proto");
+
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/otp_session.proto")?;
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+/// random otp and session codes, and uuid, ulid and nanoid session ids, drawn from the caller's generator, so firmware
+/// can seed it from a hardware rng, and comparing codes
+#[cfg(feature = "alloc")]
+use {
+    alloc::{format, string::String},
+    fastrand::Rng,
+};
+
+/// return a random otp of length digits, without a leading zero; length is 1 to 19
+#[cfg(feature = "alloc")]
+pub fn otp_code(rng: &mut Rng, length: u32) -> String {
+    let range = 10_u64.pow(length - 1)..10_u64.pow(length);
+    format!("{}", rng.u64(range))
+}
+
+/// return a random hex session code
+#[cfg(feature = "alloc")]
+pub fn session_code(rng: &mut Rng) -> String {
+    let range = 1_000_000_000_000..10_000_000_000_000;
+    format!("{:x}{:x}", rng.u64(range.clone()), rng.u64(range))
+}
+
+/// return a random version 4 uuid, e.g. 0f8fad5b-d9cb-469f-a165-70867728950e
+#[cfg(feature = "alloc")]
+pub fn uuid_v4(rng: &mut Rng) -> String {
+    let mut bytes = [0; 16];
+    rng.fill(&mut bytes);
+    uuid_v4_from(bytes)
+}
+
+/// return the version 4 uuid made from these random bytes, e.g. from a secure generator the caller has
+#[cfg(feature = "alloc")]
+pub fn uuid_v4_from(bytes: [u8; 16]) -> String {
+    uuid(bytes, 4)
+}
+
+/// return a version 7 uuid for the unix time in milliseconds: the time comes first, so later ids sort after earlier
+/// ones, and the rest is random
+#[cfg(feature = "alloc")]
+pub fn uuid_v7(rng: &mut Rng, unix_millis: u64) -> String {
+    let mut bytes = [0; 16];
+    rng.fill(&mut bytes);
+    uuid_v7_from(bytes, unix_millis)
+}
+
+/// return the version 7 uuid for the unix time in milliseconds made from these random bytes; the first six are
+/// replaced by the time
+#[cfg(feature = "alloc")]
+pub fn uuid_v7_from(mut bytes: [u8; 16], unix_millis: u64) -> String {
+    bytes[..6].copy_from_slice(&unix_millis.to_be_bytes()[2..]);
+    uuid(bytes, 7)
+}
+
+// set the version and the rfc 9562 variant, then format as lowercase 8-4-4-4-12 hex
+#[cfg(feature = "alloc")]
+fn uuid(mut bytes: [u8; 16], version: u8) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | (version << 4);
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = format!("{:032x}", u128::from_be_bytes(bytes));
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// crockford's base32, which ulids are written in
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// return a ulid for the unix time in milliseconds: 26 characters that sort by the time, then random ones, e.g.
+/// 01HGW2N7EHJ4Y9KQ3T5V8XZB6C
+#[cfg(feature = "alloc")]
+pub fn ulid(rng: &mut Rng, unix_millis: u64) -> String {
+    let mut bytes = [0; 16];
+    rng.fill(&mut bytes);
+    ulid_from(bytes, unix_millis)
+}
+
+/// return the ulid for the unix time in milliseconds made from these random bytes; the first six are replaced by the
+/// time
+#[cfg(feature = "alloc")]
+pub fn ulid_from(mut bytes: [u8; 16], unix_millis: u64) -> String {
+    bytes[..6].copy_from_slice(&unix_millis.to_be_bytes()[2..]);
+    encode_ulid(u128::from_be_bytes(bytes))
+}
+
+/// return the 128 bits as a ulid
+#[cfg(feature = "alloc")]
+pub fn encode_ulid(value: u128) -> String {
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD[(value >> (i * 5)) as usize & 31] as char)
+        .collect()
+}
+
+/// return the 128 bits of a ulid in upper case, or None if it isn't one
+pub fn decode_ulid(text: &str) -> Option<u128> {
+    if text.len() != 26 || text.as_bytes()[0] > b'7' {
+        return None;
+    }
+    text.bytes().try_fold(0, |value, b| {
+        let digit = CROCKFORD.iter().position(|&c| c == b)?;
+        Some(value << 5 | digit as u128)
+    })
+}
+
+/// the default nanoid alphabet: 64 characters that need no escaping in urls
+pub const NANOID_ALPHABET: &str =
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_-";
+
+/// the default nanoid length, 126 random bits with the default alphabet
+pub const NANOID_LENGTH: usize = 21;
+
+/// return a nanoid-style code of length characters drawn uniformly from the ascii alphabet, e.g. V1StGXR8_Z5jdHi6B-myT
+#[cfg(feature = "alloc")]
+pub fn nanoid(rng: &mut Rng, alphabet: &str, length: usize) -> String {
+    let alphabet = alphabet.as_bytes();
+    (0..length)
+        .map(|_| alphabet[rng.usize(..alphabet.len())] as char)
+        .collect()
+}
+
+/// return true if the given code is the expected one, comparing every byte so the time taken doesn't reveal how much
+/// of a guess was right
+pub fn codes_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn codes() {
+        let mut rng = Rng::with_seed(7);
+        for length in [4, 6, 10] {
+            let code = otp_code(&mut rng, length);
+            assert_eq!(code.len(), length as usize);
+            assert!(code.bytes().all(|b| b.is_ascii_digit()) && !code.starts_with('0'));
+        }
+
+        let code = session_code(&mut rng);
+        assert!((20..=22).contains(&code.len()));
+        assert!(u128::from_str_radix(&code, 16).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn uuids() {
+        let mut rng = Rng::with_seed(7);
+        let id = uuid_v4(&mut rng);
+        assert_eq!(id.len(), 36);
+        assert_eq!([&id[8..9], &id[13..14], &id[18..19], &id[23..24]], ["-"; 4]);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+
+        let id = uuid_v7(&mut rng, 0x0189_2c7a_1f00);
+        assert!(id.starts_with("01892c7a-1f00-7"), "{}", id);
+        assert!(uuid_v7(&mut rng, 1_700_000_000_001) > uuid_v7(&mut rng, 1_700_000_000_000));
+
+        assert_eq!(
+            uuid_v4_from([0xff; 16]),
+            "ffffffff-ffff-4fff-bfff-ffffffffffff"
+        );
+        assert_eq!(
+            uuid_v7_from([0; 16], 1),
+            "00000000-0001-7000-8000-000000000000"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn ulids() {
+        let mut rng = Rng::with_seed(7);
+        let id = ulid(&mut rng, 1_469_918_176_385);
+        assert!(id.starts_with("01ARYZ6S41"), "{}", id);
+        assert_eq!(encode_ulid(decode_ulid(&id).unwrap()), id);
+        assert!(ulid(&mut rng, 1_700_000_000_001) > ulid(&mut rng, 1_700_000_000_000));
+        assert_eq!(ulid_from([0xff; 16], 1), "0000000001ZZZZZZZZZZZZZZZZ");
+
+        assert_eq!(decode_ulid("7ZZZZZZZZZZZZZZZZZZZZZZZZZ"), Some(u128::MAX));
+        assert_eq!(decode_ulid("80000000000000000000000000"), None);
+        assert_eq!(decode_ulid("01arYZ6S41TSV4RRFFQ69G5FAV"), None);
+        assert_eq!(decode_ulid("01ARYZ6S41"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn nanoids() {
+        let mut rng = Rng::with_seed(7);
+        let id = nanoid(&mut rng, NANOID_ALPHABET, NANOID_LENGTH);
+        assert_eq!(id.len(), NANOID_LENGTH);
+        assert!(id.chars().all(|c| NANOID_ALPHABET.contains(c)), "{}", id);
+
+        let id = nanoid(&mut rng, "ab", 64);
+        assert_eq!(id.len(), 64);
+        assert!(id.contains('a') && id.contains('b'), "{}", id);
+    }
+
+    #[test]
+    fn matching() {
+        assert!(codes_match("123456", "123456"));
+        assert!(!codes_match("123456", "123457"));
+        assert!(!codes_match("123456", "12345"));
+    }
+}
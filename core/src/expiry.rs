@@ -0,0 +1,38 @@
+// expiry math on unix seconds, saturating at the end of time rather than overflowing
+
+/// return when something good for ttl seconds from now expires
+pub fn expires_at(now: u64, ttl: u64) -> u64 {
+    now.saturating_add(ttl)
+}
+
+/// return true once now has reached the expiry
+pub fn is_expired(expires: u64, now: u64) -> bool {
+    expires <= now
+}
+
+/// return the seconds left until the expiry, or zero once it has passed
+pub fn remaining(expires: u64, now: u64) -> u64 {
+    expires.saturating_sub(now)
+}
+
+/// return the number of the period holding now, e.g. a totp counter; a zero period counts as one second
+pub fn step(now: u64, period: u64) -> u64 {
+    now / period.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        assert_eq!(expires_at(1_000, 60), 1_060);
+        assert_eq!(expires_at(u64::MAX - 1, 60), u64::MAX);
+        assert!(!is_expired(1_060, 1_059));
+        assert!(is_expired(1_060, 1_060));
+        assert_eq!(remaining(1_060, 1_000), 60);
+        assert_eq!(remaining(1_060, 2_000), 0);
+        assert_eq!(step(59, 30), 1);
+        assert_eq!(step(59, 0), 59);
+    }
+}
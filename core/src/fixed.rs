@@ -0,0 +1,216 @@
+/// a store of a fixed number of codes in an array, for microcontrollers that track a handful of pairing codes without
+/// an allocator
+use crate::code::codes_match;
+use crate::expiry::is_expired;
+use core::fmt;
+
+/// the longest code or user a fixed store holds, in bytes
+pub const MAX_LEN: usize = 32;
+
+/// why a fixed store could not take a code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixedError {
+    /// every slot holds an unexpired code
+    Full,
+    /// the code or user is longer than MAX_LEN bytes
+    TooLong,
+}
+
+impl fmt::Display for FixedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixedError::Full => f.write_str("the store is full"),
+            FixedError::TooLong => write!(f, "codes and users are at most {} bytes", MAX_LEN),
+        }
+    }
+}
+
+// a short string kept in place
+#[derive(Debug, Clone, Copy)]
+struct Text {
+    bytes: [u8; MAX_LEN],
+    len: u8,
+}
+
+impl Text {
+    fn new(text: &str) -> Result<Text, FixedError> {
+        if text.len() > MAX_LEN {
+            return Err(FixedError::TooLong);
+        }
+        let mut bytes = [0; MAX_LEN];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+
+        Ok(Text {
+            bytes,
+            len: text.len() as u8,
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        // only ever filled from a str
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    code: Text,
+    user: Text,
+    expires: u64,
+}
+
+impl Entry {
+    // compare the code in constant time, as it is the secret
+    fn is(&self, code: &str, user: &str) -> bool {
+        self.user.as_str() == user && codes_match(self.code.as_str(), code)
+    }
+}
+
+/// up to N codes with their users and unix expiry times, e.g. `FixedStore::<8>::new()`; expired codes give up their
+/// slots to new ones
+#[derive(Debug, Clone)]
+pub struct FixedStore<const N: usize> {
+    entries: [Option<Entry>; N],
+}
+
+impl<const N: usize> Default for FixedStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FixedStore<N> {
+    /// create an empty store; const, so it can be a static
+    pub const fn new() -> FixedStore<N> {
+        FixedStore { entries: [None; N] }
+    }
+
+    /// return the most codes the store holds
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// return the number of codes held, expired or not
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// return true if no codes are held
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// keep the code for the user until the unix time expires, replacing the same pair; a full store reuses the
+    /// slot of a code that has expired at now
+    pub fn put(
+        &mut self,
+        code: &str,
+        user: &str,
+        expires: u64,
+        now: u64,
+    ) -> Result<(), FixedError> {
+        let entry = Entry {
+            code: Text::new(code)?,
+            user: Text::new(user)?,
+            expires,
+        };
+        let slot = match self.position(code, user) {
+            Some(index) => index,
+            None => self
+                .entries
+                .iter()
+                .position(|slot| match slot {
+                    Some(held) => is_expired(held.expires, now),
+                    None => true,
+                })
+                .ok_or(FixedError::Full)?,
+        };
+        self.entries[slot] = Some(entry);
+
+        Ok(())
+    }
+
+    /// return true if the code is held for the user and has not expired at now
+    pub fn is_valid(&self, code: &str, user: &str, now: u64) -> bool {
+        self.position(code, user)
+            .and_then(|index| self.entries[index])
+            .is_some_and(|entry| !is_expired(entry.expires, now))
+    }
+
+    /// remove the code if it is valid at now, so it can only be used once; return true if it was valid
+    pub fn consume(&mut self, code: &str, user: &str, now: u64) -> bool {
+        let valid = self.is_valid(code, user, now);
+        if valid {
+            self.remove(code, user);
+        }
+
+        valid
+    }
+
+    /// remove the user's code; return true if it was held
+    pub fn remove(&mut self, code: &str, user: &str) -> bool {
+        match self.position(code, user) {
+            Some(index) => self.entries[index].take().is_some(),
+            None => false,
+        }
+    }
+
+    /// free the slots of the codes that have expired at now; return the number freed
+    pub fn purge_expired(&mut self, now: u64) -> usize {
+        let mut count = 0;
+        for slot in self.entries.iter_mut() {
+            if slot.is_some_and(|entry| is_expired(entry.expires, now)) {
+                *slot = None;
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    fn position(&self, code: &str, user: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|slot| slot.is_some_and(|entry| entry.is(code, user)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_store() {
+        static EMPTY: FixedStore<2> = FixedStore::new();
+        let mut store = EMPTY.clone();
+        assert_eq!(store.capacity(), 2);
+
+        store.put("123456", "phone", 1_060, 1_000).unwrap();
+        store.put("654321", "watch", 1_030, 1_000).unwrap();
+        assert!(store.is_valid("123456", "phone", 1_059));
+        assert!(!store.is_valid("123456", "watch", 1_000));
+        assert!(!store.is_valid("123456", "phone", 1_060));
+        assert_eq!(
+            store.put("111111", "tablet", 1_060, 1_000),
+            Err(FixedError::Full)
+        );
+        assert_eq!(
+            store.put("123456789012345678901234567890123", "phone", 1_060, 1_000),
+            Err(FixedError::TooLong)
+        );
+
+        // the same pair replaces its slot, and an expired code gives its slot up
+        store.put("123456", "phone", 1_120, 1_000).unwrap();
+        store.put("111111", "tablet", 1_120, 1_030).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.is_valid("654321", "watch", 1_000));
+
+        assert!(store.consume("111111", "tablet", 1_030));
+        assert!(!store.consume("111111", "tablet", 1_030));
+        assert!(store.remove("123456", "phone"));
+        assert!(store.is_empty());
+
+        store.put("222222", "phone", 1_060, 1_000).unwrap();
+        assert_eq!(store.purge_expired(1_060), 1);
+    }
+}
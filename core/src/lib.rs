@@ -0,0 +1,12 @@
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod code;
+pub mod expiry;
+pub mod fixed;
+#[cfg(feature = "alloc")]
+pub mod totp;
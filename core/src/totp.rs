@@ -0,0 +1,223 @@
+/// time based one time passwords (rfc 6238) for enrolling authenticator apps, and verifying their codes on devices
+use crate::code::codes_match;
+use crate::expiry::step;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use anyhow::{bail, Result};
+use fastrand::Rng;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// the default number of digits in a totp code
+pub const TOTP_DIGITS: u32 = 6;
+
+/// the default seconds each totp code is good for
+pub const TOTP_PERIOD: u64 = 30;
+
+/// the number of random bytes in a generated secret, as recommended by rfc 4226
+pub const SECRET_BYTES: usize = 20;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// return the unpadded rfc 4648 base32 encoding of the bytes, as used for otpauth secrets
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    encoded
+}
+
+/// decode base32, ignoring case, padding and spaces
+pub fn base32_decode(encoded: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.chars().filter(|c| *c != '=' && *c != ' ') {
+        let upper = c.to_ascii_uppercase() as u8;
+        let Some(value) = BASE32.iter().position(|b| *b == upper) else {
+            bail!("invalid base32 character: {:?}", c);
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// generate a random base32 secret from the generator; fastrand isn't cryptographic, so only for firmware that seeds
+/// it from a hardware rng for each secret
+pub fn generate_secret_with(rng: &mut Rng) -> String {
+    let bytes: Vec<u8> = (0..SECRET_BYTES).map(|_| rng.u8(..)).collect();
+    base32_encode(&bytes)
+}
+
+/// generate a random base32 secret from the operating system's secure generator, or on no_std targets the one
+/// registered as getrandom's custom backend
+#[cfg(feature = "getrandom")]
+pub fn generate_secret() -> String {
+    let mut bytes = [0; SECRET_BYTES];
+    getrandom::getrandom(&mut bytes).expect("the secure random generator failed");
+    base32_encode(&bytes)
+}
+
+/// return the rfc 4226 hotp code for the key and counter
+pub fn hotp(key: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let word = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    let code = word as u64 % 10u64.pow(digits);
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+// percent encode everything but the rfc 3986 unreserved characters
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// an authenticator app enrollment: the shared secret and how codes are derived from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Totp {
+    /// the service name shown in the app
+    pub issuer: String,
+    /// the user's account name shown in the app
+    pub account: String,
+    /// the base32 shared secret
+    pub secret: String,
+    pub digits: u32,
+    pub period: u64,
+}
+
+impl Totp {
+    /// create an enrollment with a new random secret and the default digits and period
+    #[cfg(feature = "getrandom")]
+    pub fn new(issuer: &str, account: &str) -> Totp {
+        Totp::with_secret(issuer, account, &generate_secret())
+    }
+
+    /// create an enrollment for an existing base32 secret
+    pub fn with_secret(issuer: &str, account: &str, secret: &str) -> Totp {
+        Totp {
+            issuer: issuer.to_string(),
+            account: account.to_string(),
+            secret: secret.to_string(),
+            digits: TOTP_DIGITS,
+            period: TOTP_PERIOD,
+        }
+    }
+
+    /// return the code for the unix time
+    pub fn code_at(&self, now: u64) -> Result<String> {
+        let key = base32_decode(&self.secret)?;
+        Ok(hotp(&key, step(now, self.period), self.digits))
+    }
+
+    /// return true if the code matches the unix time's step or the one either side, allowing for clock drift
+    pub fn verify_at(&self, code: &str, now: u64) -> bool {
+        let Ok(key) = base32_decode(&self.secret) else {
+            return false;
+        };
+
+        let current = step(now, self.period);
+        [
+            current.saturating_sub(1),
+            current,
+            current.saturating_add(1),
+        ]
+        .iter()
+        .any(|counter| codes_match(&hotp(&key, *counter, self.digits), code))
+    }
+
+    /// return the otpauth uri that authenticator apps scan to enroll
+    pub fn uri(&self) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            url_encode(&self.issuer),
+            url_encode(&self.account),
+            self.secret,
+            url_encode(&self.issuer),
+            self.digits,
+            self.period
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("mzxw6ytboi======").unwrap(), b"foobar");
+        assert!(base32_decode("MZ1W").is_err());
+
+        let secret = generate_secret_with(&mut Rng::with_seed(7));
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).unwrap().len(), SECRET_BYTES);
+    }
+
+    #[test]
+    #[cfg(feature = "getrandom")]
+    fn secure_secret() {
+        let secret = generate_secret();
+        assert_eq!(base32_decode(&secret).unwrap().len(), SECRET_BYTES);
+        assert_ne!(secret, generate_secret());
+    }
+
+    #[test]
+    fn rfc_vectors() {
+        // rfc 4226 appendix d and rfc 6238 appendix b (sha1)
+        let key = b"12345678901234567890";
+        assert_eq!(hotp(key, 0, 6), "755224");
+        assert_eq!(hotp(key, 9, 6), "520489");
+
+        let totp = Totp {
+            digits: 8,
+            ..Totp::with_secret("acme", "sally", &base32_encode(key))
+        };
+        assert_eq!(totp.code_at(59).unwrap(), "94287082");
+        assert_eq!(totp.code_at(1_111_111_109).unwrap(), "07081804");
+        assert!(totp.verify_at("94287082", 89));
+        assert!(!totp.verify_at("94287082", 120));
+    }
+
+    #[test]
+    fn uri() {
+        let totp = Totp::with_secret("Acme Corp", "sally@example.com", "JBSWY3DPEHPK3PXP");
+        assert_eq!(
+            totp.uri(),
+            "otpauth://totp/Acme%20Corp:sally%40example.com?secret=JBSWY3DPEHPK3PXP&issuer=Acme%20Corp&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+/// kotlin and swift bindings, generated by uniffi, for the totp/hotp and code validation primitives of the core, so
+/// mobile apps verify enrollment codes offline exactly as the services do
+use otp_session_core::{code, expiry, totp};
+use std::fmt;
+use std::sync::Arc;
+
+uniffi::setup_scaffolding!();
+
+/// why a secret could not be used
+#[derive(Debug, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum CodeError {
+    /// the secret is not base32
+    InvalidSecret(String),
+}
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodeError::InvalidSecret(reason) => write!(f, "invalid secret: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CodeError {}
+
+impl From<anyhow::Error> for CodeError {
+    fn from(e: anyhow::Error) -> CodeError {
+        CodeError::InvalidSecret(e.to_string())
+    }
+}
+
+/// return the rfc 4226 hotp code for the key and counter
+#[uniffi::export]
+pub fn hotp(key: Vec<u8>, counter: u64, digits: u32) -> String {
+    totp::hotp(&key, counter, digits)
+}
+
+/// return the unpadded base32 encoding of the bytes, as used for otpauth secrets
+#[uniffi::export]
+pub fn base32_encode(bytes: Vec<u8>) -> String {
+    totp::base32_encode(&bytes)
+}
+
+/// decode base32, ignoring case, padding and spaces
+#[uniffi::export]
+pub fn base32_decode(encoded: String) -> Result<Vec<u8>, CodeError> {
+    Ok(totp::base32_decode(&encoded)?)
+}
+
+/// return true if the given code is the expected one, in time that doesn't depend on where they differ
+#[uniffi::export]
+pub fn codes_match(expected: String, given: String) -> bool {
+    code::codes_match(&expected, &given)
+}
+
+/// return when something good for ttl seconds from the unix time now expires
+#[uniffi::export]
+pub fn expires_at(now: u64, ttl: u64) -> u64 {
+    expiry::expires_at(now, ttl)
+}
+
+/// return true once the unix time now has reached the expiry
+#[uniffi::export]
+pub fn is_expired(expires: u64, now: u64) -> bool {
+    expiry::is_expired(expires, now)
+}
+
+/// return the seconds left until the expiry, or zero once it has passed
+#[uniffi::export]
+pub fn remaining(expires: u64, now: u64) -> u64 {
+    expiry::remaining(expires, now)
+}
+
+/// an authenticator enrollment: the shared secret and how codes are derived from it
+#[derive(Debug, uniffi::Object)]
+pub struct Totp {
+    totp: totp::Totp,
+}
+
+#[uniffi::export]
+impl Totp {
+    /// create an enrollment with a new random secret and the default 6 digits and 30 second period
+    #[uniffi::constructor]
+    pub fn new(issuer: String, account: String) -> Arc<Totp> {
+        Arc::new(Totp {
+            totp: totp::Totp::new(&issuer, &account),
+        })
+    }
+
+    /// create an enrollment for an existing base32 secret, e.g. one scanned from a provisioning uri
+    #[uniffi::constructor]
+    pub fn with_secret(
+        issuer: String,
+        account: String,
+        secret: String,
+        digits: u32,
+        period: u64,
+    ) -> Arc<Totp> {
+        Arc::new(Totp {
+            totp: totp::Totp {
+                digits,
+                period,
+                ..totp::Totp::with_secret(&issuer, &account, &secret)
+            },
+        })
+    }
+
+    /// return the base32 shared secret
+    pub fn secret(&self) -> String {
+        self.totp.secret.clone()
+    }
+
+    /// return the code for the unix time
+    pub fn code_at(&self, now: u64) -> Result<String, CodeError> {
+        Ok(self.totp.code_at(now)?)
+    }
+
+    /// return true if the code matches the unix time's step or the one either side
+    pub fn verify_at(&self, code: String, now: u64) -> bool {
+        self.totp.verify_at(&code, now)
+    }
+
+    /// return the otpauth uri that authenticator apps scan to enroll
+    pub fn uri(&self) -> String {
+        self.totp.uri()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp() {
+        // rfc 6238 appendix b (sha1)
+        let secret = base32_encode(b"12345678901234567890".to_vec());
+        let totp = Totp::with_secret("acme".into(), "sally".into(), secret, 8, 30);
+        assert_eq!(totp.code_at(59).unwrap(), "94287082");
+        assert!(totp.verify_at("94287082".into(), 89));
+        assert!(!totp.verify_at("94287082".into(), 120));
+
+        let bad = Totp::with_secret("acme".into(), "sally".into(), "MZ1W".into(), 6, 30);
+        assert!(matches!(bad.code_at(59), Err(CodeError::InvalidSecret(_))));
+        assert_eq!(base32_decode("mzxw6ytboi".into()).unwrap(), b"foobar");
+    }
+}
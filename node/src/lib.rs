@@ -0,0 +1,146 @@
+/// node.js bindings: the otp and session stores with promise based create, validate and remove. each call runs on the
+/// libuv thread pool, so express handlers never block the event loop on a store lock
+use napi::bindgen_prelude::{AsyncTask, ToNapiValue, TypeName};
+use napi::{Env, Error, Result, Task};
+use napi_derive::napi;
+use otp_session_lib::db::Validation;
+use otp_session_lib::otp::Otp;
+use otp_session_lib::session::Session;
+
+/// the outcome of a validation, as the json-rpc api reports it
+#[napi(object)]
+pub struct Validated {
+    pub valid: bool,
+    /// why, e.g. valid, expired or not_found
+    pub result: String,
+}
+
+impl From<Validation> for Validated {
+    fn from(validation: Validation) -> Validated {
+        Validated {
+            valid: validation.is_valid(),
+            result: validation.as_str().to_string(),
+        }
+    }
+}
+
+/// a store call run off the event loop; its promise resolves with the value or rejects with the error
+pub struct Call<T> {
+    call: Option<Box<dyn FnOnce() -> anyhow::Result<T> + Send>>,
+}
+
+impl<T> Call<T> {
+    fn spawn(call: impl FnOnce() -> anyhow::Result<T> + Send + 'static) -> AsyncTask<Call<T>> {
+        AsyncTask::new(Call {
+            call: Some(Box::new(call)),
+        })
+    }
+}
+
+impl<T: ToNapiValue + TypeName + Send + 'static> Task for Call<T> {
+    type Output = T;
+    type JsValue = T;
+
+    fn compute(&mut self) -> Result<T> {
+        let call = self
+            .call
+            .take()
+            .ok_or_else(|| Error::from_reason("the call already ran"))?;
+        call().map_err(reason)
+    }
+
+    fn resolve(&mut self, _env: Env, output: T) -> Result<T> {
+        Ok(output)
+    }
+}
+
+// reject with the error's message
+fn reason(e: anyhow::Error) -> Error {
+    Error::from_reason(e.to_string())
+}
+
+/// the otp store
+#[napi]
+pub struct OtpStore {
+    otp: Otp,
+}
+
+#[napi]
+impl OtpStore {
+    /// create a store whose codes last timeout seconds, 300 by default
+    #[napi(constructor)]
+    pub fn new(timeout: Option<u32>) -> Result<OtpStore> {
+        let mut builder = Otp::builder();
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout as u64);
+        }
+
+        Ok(OtpStore {
+            otp: builder.build().map_err(reason)?,
+        })
+    }
+
+    /// create an otp for the user and resolve with the code
+    #[napi]
+    pub fn create(&self, user: String) -> AsyncTask<Call<String>> {
+        let mut otp = self.otp.clone();
+        Call::spawn(move || otp.create_user_otp(&user))
+    }
+
+    /// resolve with whether the code is valid for the user, and why
+    #[napi]
+    pub fn validate(&self, code: String, user: String) -> AsyncTask<Call<Validated>> {
+        let otp = self.otp.clone();
+        Call::spawn(move || Ok(otp.validate(&code, &user).into()))
+    }
+
+    /// remove the user's code; resolve with the code if it was removed, else null
+    #[napi]
+    pub fn remove(&self, code: String, user: String) -> AsyncTask<Call<Option<String>>> {
+        let mut otp = self.otp.clone();
+        Call::spawn(move || Ok(otp.remove(&code, &user)))
+    }
+}
+
+/// the session store
+#[napi]
+pub struct SessionStore {
+    session: Session,
+}
+
+#[napi]
+impl SessionStore {
+    /// create a store whose sessions last timeout seconds, 14000 by default
+    #[napi(constructor)]
+    pub fn new(timeout: Option<u32>) -> Result<SessionStore> {
+        let mut builder = Session::builder();
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout as u64);
+        }
+
+        Ok(SessionStore {
+            session: builder.build().map_err(reason)?,
+        })
+    }
+
+    /// create a session for the user and resolve with the code
+    #[napi]
+    pub fn create(&self, user: String) -> AsyncTask<Call<String>> {
+        let mut session = self.session.clone();
+        Call::spawn(move || session.create_user_session(&user))
+    }
+
+    /// resolve with whether the session is valid for the user, and why
+    #[napi]
+    pub fn validate(&self, code: String, user: String) -> AsyncTask<Call<Validated>> {
+        let session = self.session.clone();
+        Call::spawn(move || Ok(session.validate(&code, &user).into()))
+    }
+
+    /// remove the user's session; resolve with the code if it was removed, else null
+    #[napi]
+    pub fn remove(&self, code: String, user: String) -> AsyncTask<Call<Option<String>>> {
+        let mut session = self.session.clone();
+        Call::spawn(move || Ok(session.remove(&code, &user)))
+    }
+}
@@ -0,0 +1,18 @@
+import { test } from 'node:test'
+import assert from 'node:assert/strict'
+import { createRequire } from 'node:module'
+
+const { OtpStore, SessionStore } = createRequire(import.meta.url)('../index.js')
+
+for (const Store of [OtpStore, SessionStore]) {
+  test(`${Store.name} creates, validates and removes`, async () => {
+    const store = new Store(60)
+    const code = await store.create('sally')
+    assert.deepEqual(await store.validate(code, 'sally'), { valid: true, result: 'valid' })
+    assert.equal((await store.validate(code, 'jack')).valid, false)
+
+    assert.equal(await store.remove(code, 'sally'), code)
+    assert.equal(await store.remove(code, 'sally'), null)
+    assert.equal((await store.validate(code, 'sally')).valid, false)
+  })
+}
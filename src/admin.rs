@@ -0,0 +1,204 @@
+/// scoped api tokens that guard the administrative operations (list, revoke-all, purge, export, import)
+use crate::hash::{random_hex, sha256_hex};
+use anyhow::{anyhow, bail, Result};
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// minimum length for pre-shared admin tokens
+pub const MIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    List,
+    Revoke,
+    Purge,
+    Export,
+    Import,
+}
+
+impl Scope {
+    /// every scope, for tokens with full admin access
+    pub const ALL: [Scope; 5] = [
+        Scope::List,
+        Scope::Revoke,
+        Scope::Purge,
+        Scope::Export,
+        Scope::Import,
+    ];
+
+    /// return the scope name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::List => "list",
+            Scope::Revoke => "revoke",
+            Scope::Purge => "purge",
+            Scope::Export => "export",
+            Scope::Import => "import",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| anyhow!("unknown admin scope: {}", s))
+    }
+}
+
+/// a registered admin token; the secret itself is never stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl AdminToken {
+    /// return true if the token grants this scope
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// the admin token registry; tokens are keyed by their sha-256 hash and clones share the same registry
+#[derive(Debug, Clone, Default)]
+pub struct AdminTokens {
+    tokens: Arc<RwLock<HashMap<String, AdminToken>>>,
+}
+
+impl AdminTokens {
+    /// create an empty registry
+    pub fn new() -> AdminTokens {
+        AdminTokens::default()
+    }
+
+    /// create a random token with these scopes and return the secret; it can't be recovered later
+    pub fn create(&self, name: &str, scopes: &[Scope]) -> Result<String> {
+        let token = format!("adm_{}", random_hex(24));
+        self.insert(name, &token, scopes)?;
+
+        Ok(token)
+    }
+
+    /// register a pre-shared token, e.g. from configuration
+    pub fn insert(&self, name: &str, token: &str, scopes: &[Scope]) -> Result<()> {
+        if name.is_empty() {
+            bail!("admin token name is required");
+        }
+
+        if token.len() < MIN_TOKEN_LENGTH {
+            bail!(
+                "admin token {} must be at least {} characters",
+                name,
+                MIN_TOKEN_LENGTH
+            );
+        }
+
+        let mut map = self.tokens.write().unwrap();
+        if map.values().any(|t| t.name == name) {
+            bail!("admin token {} already exists", name);
+        }
+
+        let admin = AdminToken {
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+        };
+        map.insert(sha256_hex(token.as_bytes()), admin);
+
+        Ok(())
+    }
+
+    /// revoke the named token; return true if it existed
+    pub fn revoke(&self, name: &str) -> bool {
+        let mut map = self.tokens.write().unwrap();
+        let before = map.len();
+        map.retain(|_, t| t.name != name);
+        map.len() < before
+    }
+
+    /// return the registered tokens without their secrets, sorted by name
+    pub fn list(&self) -> Vec<AdminToken> {
+        let map = self.tokens.read().unwrap();
+        let mut list: Vec<AdminToken> = map.values().cloned().collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// return the token registered for this secret
+    pub fn lookup(&self, token: &str) -> Option<AdminToken> {
+        let map = self.tokens.read().unwrap();
+        map.get(&sha256_hex(token.as_bytes())).cloned()
+    }
+
+    /// return true if the secret is a registered token that grants the scope
+    pub fn authorize(&self, token: &str, scope: Scope) -> bool {
+        self.lookup(token).is_some_and(|t| t.has_scope(scope))
+    }
+
+    /// return true if no tokens are registered
+    pub fn is_empty(&self) -> bool {
+        self.tokens.read().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes() {
+        for scope in Scope::ALL {
+            assert_eq!(scope.to_string().parse::<Scope>().unwrap(), scope);
+        }
+        assert!("delete".parse::<Scope>().is_err());
+    }
+
+    #[test]
+    fn create_authorize() {
+        let tokens = AdminTokens::new();
+        assert!(tokens.is_empty());
+
+        let token = tokens.create("ops", &[Scope::List, Scope::Export]).unwrap();
+        assert!(token.starts_with("adm_"));
+        assert!(tokens.authorize(&token, Scope::List));
+        assert!(tokens.authorize(&token, Scope::Export));
+        assert!(!tokens.authorize(&token, Scope::Purge));
+        assert!(!tokens.authorize("adm_wrong", Scope::List));
+
+        let admin = tokens.lookup(&token).unwrap();
+        assert_eq!(admin.name, "ops");
+    }
+
+    #[test]
+    fn insert_revoke() {
+        let tokens = AdminTokens::new();
+        let token = "0123456789abcdef";
+        tokens.insert("backup", token, &[Scope::Export]).unwrap();
+
+        assert!(tokens.insert("backup", "fedcba9876543210", &[]).is_err());
+        assert!(tokens.insert("short", "abc", &[]).is_err());
+        assert!(tokens.insert("", token, &[]).is_err());
+
+        // clones share the registry
+        let list = tokens.clone().list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].scopes, vec![Scope::Export]);
+
+        assert!(tokens.revoke("backup"));
+        assert!(!tokens.revoke("backup"));
+        assert!(tokens.lookup(token).is_none());
+    }
+}
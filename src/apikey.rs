@@ -0,0 +1,247 @@
+/// long lived api keys for service to service callers: a public id and a secret, with only the secret's hash kept
+use crate::clock::Clock;
+use crate::db::{DataStore, SessionItem, Validation};
+use crate::hash::{random_hex, sha256_hex};
+use crate::logging;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the prefix of every api key, so leaked keys are easy to spot in logs and scanners
+pub const API_KEY_PREFIX: &str = "osk";
+
+/// the number of random bytes in a key's public id
+pub const KEY_ID_BYTES: usize = 6;
+
+/// the number of random bytes in a key's secret
+pub const KEY_SECRET_BYTES: usize = 24;
+
+/// an api key's details; the secret is only returned by create
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    /// the public part of the key, safe to log
+    pub id: String,
+    pub user: String,
+    pub name: String,
+    /// unix time the key was created
+    pub created: u64,
+    /// unix time the key last authenticated
+    pub last_used: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    key: ApiKey,
+    // the sha-256 of the secret, stored as the item code
+    hash: String,
+}
+
+/// the api keys; each is an item in a DataStore that never expires, so user locks work as they do for sessions.
+/// clones share the keys
+#[derive(Debug, Clone)]
+pub struct ApiKeys {
+    store: DataStore,
+    keys: Arc<RwLock<HashMap<String, Entry>>>,
+}
+
+impl Default for ApiKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// split a key into its id and secret
+fn parse(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix(API_KEY_PREFIX)?.strip_prefix('_')?;
+    rest.split_once('_')
+}
+
+impl ApiKeys {
+    /// create an empty key store
+    pub fn new() -> ApiKeys {
+        ApiKeys {
+            store: DataStore::create(),
+            keys: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// use this clock for the created and last used times
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ApiKeys {
+        self.store = self.store.with_clock(clock);
+        self
+    }
+
+    /// create a key for the user; return the full key, e.g. `osk_<id>_<secret>`, which can't be shown again
+    pub fn create(&mut self, user: &str, name: &str) -> Result<(String, ApiKey)> {
+        let (id, secret) = (random_hex(KEY_ID_BYTES), random_hex(KEY_SECRET_BYTES));
+        let hash = sha256_hex(secret.as_bytes());
+        let key = ApiKey {
+            id: id.clone(),
+            user: user.to_string(),
+            name: name.to_string(),
+            created: self.store.now(),
+            last_used: None,
+        };
+
+        self.store.put(SessionItem {
+            code: hash.clone(),
+            user: user.to_string(),
+            expires: u64::MAX,
+            ..Default::default()
+        })?;
+        let entry = Entry {
+            key: key.clone(),
+            hash,
+        };
+        self.keys.write().unwrap().insert(id.clone(), entry);
+        logging::event("apikey.create", &[("user", user), ("id", &id)]);
+
+        Ok((format!("{}_{}_{}", API_KEY_PREFIX, id, secret), key))
+    }
+
+    /// return why the key is or is not valid, recording the use if it is
+    pub fn validate(&self, key: &str) -> Validation {
+        let Some((id, secret)) = parse(key) else {
+            return Validation::NotFound;
+        };
+
+        let mut keys = self.keys.write().unwrap();
+        let Some(entry) = keys.get_mut(id) else {
+            return Validation::NotFound;
+        };
+
+        let hash = sha256_hex(secret.as_bytes());
+        if hash != entry.hash {
+            return Validation::NotFound;
+        }
+
+        let result = self.store.validate(&hash, &entry.key.user);
+        if result.is_valid() {
+            entry.key.last_used = Some(self.store.now());
+        }
+
+        result
+    }
+
+    /// return the key's details if it is valid, recording the use
+    pub fn authenticate(&self, key: &str) -> Result<ApiKey> {
+        let result = self.validate(key);
+        if !result.is_valid() {
+            bail!("api key not valid: {}", result.as_str());
+        }
+
+        let id = parse(key).map(|(id, _)| id).unwrap_or_default();
+        match self.get(id) {
+            Some(key) => Ok(key),
+            None => bail!("api key not valid: {}", Validation::Revoked.as_str()),
+        }
+    }
+
+    /// return the key's details by id
+    pub fn get(&self, id: &str) -> Option<ApiKey> {
+        self.keys
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|entry| entry.key.clone())
+    }
+
+    /// return the keys, optionally only the user's, oldest first
+    pub fn list(&self, user: Option<&str>) -> Vec<ApiKey> {
+        let mut keys: Vec<ApiKey> = self
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .filter(|entry| user.map_or(true, |user| entry.key.user == user))
+            .map(|entry| entry.key.clone())
+            .collect();
+        keys.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+
+        keys
+    }
+
+    /// revoke the key by id; return false if there was no such key
+    pub fn revoke(&mut self, id: &str) -> bool {
+        let Some(entry) = self.keys.write().unwrap().remove(id) else {
+            return false;
+        };
+
+        self.store.remove(&entry.hash, &entry.key.user);
+        logging::event("apikey.revoke", &[("user", &entry.key.user), ("id", id)]);
+        true
+    }
+
+    /// revoke all of the user's keys; return the number revoked
+    pub fn revoke_user(&mut self, user: &str) -> usize {
+        let ids: Vec<String> = self
+            .list(Some(user))
+            .into_iter()
+            .map(|key| key.id)
+            .collect();
+        ids.iter().filter(|id| self.revoke(id)).count()
+    }
+
+    /// stop every key of the user working until unlock_user, without revoking them
+    pub fn lock_user(&self, user: &str) -> bool {
+        self.store.lock_user(user)
+    }
+
+    /// let the user's keys work again; return false if they were not locked
+    pub fn unlock_user(&self, user: &str) -> bool {
+        self.store.unlock_user(user)
+    }
+
+    /// return the number of keys
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    /// return true if there are no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn create_validate_revoke() {
+        let clock = MockClock::at(1_000);
+        let mut keys = ApiKeys::new().with_clock(Arc::new(clock.clone()));
+        let (key, info) = keys.create("billing", "nightly export").unwrap();
+        assert!(key.starts_with(&format!("osk_{}_", info.id)));
+        assert_eq!(key.len(), 4 + KEY_ID_BYTES * 2 + 1 + KEY_SECRET_BYTES * 2);
+        assert_eq!(info.last_used, None);
+
+        // keys never expire; uses are recorded
+        clock.set(1_000_000_000);
+        assert_eq!(keys.validate(&key), Validation::Valid);
+        let used = keys.authenticate(&key).unwrap();
+        assert_eq!(
+            (used.user.as_str(), used.last_used),
+            ("billing", Some(1_000_000_000))
+        );
+
+        let wrong = format!("{}x", &key[..key.len() - 1]);
+        assert_eq!(keys.validate(&wrong), Validation::NotFound);
+        assert_eq!(keys.validate("not a key"), Validation::NotFound);
+
+        assert!(keys.lock_user("billing"));
+        assert_eq!(keys.validate(&key), Validation::Locked);
+        keys.unlock_user("billing");
+
+        keys.create("billing", "backup").unwrap();
+        keys.create("search", "indexer").unwrap();
+        assert_eq!(keys.list(Some("billing")).len(), 2);
+        assert!(keys.revoke(&info.id));
+        assert!(!keys.revoke(&info.id));
+        assert_eq!(keys.validate(&key), Validation::NotFound);
+        assert!(keys.authenticate(&key).is_err());
+        assert_eq!(keys.revoke_user("billing"), 1);
+        assert_eq!(keys.len(), 1);
+    }
+}
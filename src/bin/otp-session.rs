@@ -0,0 +1,17 @@
+/// the otp-session command line
+use clap::Parser;
+use otp_session_lib::cli::{self, Cli};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut stdout = std::io::stdout();
+    match cli::run(&cli, &mut stdout) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            cli::print_error(cli.output, &mut stdout, &e);
+            ExitCode::from(2)
+        }
+    }
+}
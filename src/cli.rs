@@ -0,0 +1,1081 @@
+/// the otp-session command line, run against a local snapshot file or a running daemon's json-rpc server
+use crate::client::{RemoteError, SessionClient};
+use crate::clock::unix_now;
+use crate::config::Config;
+use crate::daemon::{Daemon, DaemonConfig};
+use crate::hasher::Hashing;
+use crate::jsonrpc::{WatchEvent, FORBIDDEN, INVALID_PARAMS, METHOD_NOT_FOUND, UNAUTHORIZED};
+use crate::logging;
+use crate::otp::Otp;
+use crate::session::Session;
+use crate::snapshot::{Format, Snapshot, Strategy};
+use crate::stats::{StatsReport, StoreStats};
+use crate::totp::Totp;
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// the snapshot file used when neither --file nor --connect is given
+pub const DEFAULT_FILE: &str = "otp-session.json";
+
+/// how results and errors are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Output {
+    #[default]
+    Text,
+    /// one json object per command; errors are `{"error": {"code": ..., "message": ...}}`
+    Json,
+}
+
+impl Output {
+    /// return the output name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Output::Text => "text",
+            Output::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(anyhow!("unknown output: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "otp-session",
+    version,
+    about = "create, validate and revoke otps and sessions"
+)]
+pub struct Cli {
+    /// snapshot file to read and update
+    #[arg(long, default_value = DEFAULT_FILE, conflicts_with = "connect")]
+    pub file: PathBuf,
+    /// address of a running daemon's json-rpc server, e.g. 127.0.0.1:7400
+    #[arg(long)]
+    pub connect: Option<String>,
+    /// token for the daemon's json-rpc server; with serve, the token json-rpc and resp clients must send
+    #[arg(long)]
+    pub token: Option<String>,
+    /// text or json
+    #[arg(long, global = true, default_value = "text")]
+    pub output: Output,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// one time passwords
+    #[command(subcommand)]
+    Otp(OtpCommand),
+    /// user sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// generate a totp secret for the user and print the provisioning qr code and otpauth uri
+    Enroll {
+        user: String,
+        /// the service name shown in the authenticator app
+        #[arg(long, default_value = "otp-session")]
+        issuer: String,
+    },
+    /// print the item counts, validation failure rates and the users with the most sessions
+    Stats {
+        /// print the stats as json, the same as --output json
+        #[arg(long)]
+        json: bool,
+        /// the number of users to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// run the daemon in the foreground until SIGTERM or SIGINT, restoring from and flushing to the --file snapshot
+    Serve {
+        /// json-rpc listen address
+        #[arg(long, default_value = "127.0.0.1:7400")]
+        listen: String,
+        /// healthz/readyz listen address
+        #[arg(long, default_value = "127.0.0.1:7401")]
+        probe: String,
+        /// redis protocol listen address
+        #[arg(long)]
+        resp: Option<String>,
+        /// settings file (toml, yaml or key=value), reloaded on SIGHUP or when it changes
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// the storage backend: memory, or with the plugins feature the path of a backend plugin's shared library
+        #[arg(long, default_value = "memory")]
+        backend: String,
+        /// the config string a plugin backend is opened with, e.g. a connection url
+        #[cfg(feature = "plugins")]
+        #[arg(long, default_value = "")]
+        backend_config: String,
+        /// how the stores hash codes and user names: ahash, sip or fx (unkeyed; trusted deployments only)
+        #[arg(long, default_value = "ahash")]
+        hashing: Hashing,
+    },
+    /// run newline-delimited commands from stdin, e.g. `session revoke sally` or json lines like
+    /// `{"method": "session.revoke_all", "params": {"user": "sally"}}`; every line is parsed before any runs, and
+    /// against a snapshot file nothing is saved unless every command succeeds
+    Batch,
+    /// remove expired items, and with --older-than stale ones, from the snapshot file; a daemon only purges expired
+    /// items
+    Prune {
+        /// also remove items older than this, e.g. 7d, estimated from the configured timeouts
+        #[arg(long, value_parser = crate::config::parse_duration)]
+        older_than: Option<Duration>,
+        /// print what would be removed without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// settings file with the timeouts; otherwise the environment and defaults
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// stream the daemon's creations, validations and expirations as a live table until interrupted, like
+    /// `redis-cli monitor`; needs --connect and an admin token with the list scope
+    Watch {
+        /// exit after this many events
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// write a snapshot of the active otps and sessions to stdout or a file
+    Export {
+        /// json or bincode
+        #[arg(long, default_value = "json")]
+        format: Format,
+        /// write to this file and print the item count
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// load a snapshot file into the stores and print the number of items imported
+    Import {
+        path: PathBuf,
+        /// json or bincode
+        #[arg(long, default_value = "json")]
+        format: Format,
+        /// merge keeps existing items, overwrite removes them first
+        #[arg(long, default_value = "merge")]
+        strategy: Strategy,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum OtpCommand {
+    /// create an otp for the user and print the code
+    Create { user: String },
+    /// print why the code is or is not valid; exits 1 if not valid
+    Validate { user: String, code: String },
+    /// remove the user's code
+    Remove { user: String, code: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum SessionCommand {
+    /// create a session for the user and print the code
+    Create { user: String },
+    /// print why the code is or is not valid; exits 1 if not valid
+    Validate { user: String, code: String },
+    /// remove the user's session, or all of their sessions when no code is given
+    Revoke { user: String, code: Option<String> },
+}
+
+impl Command {
+    // the json-rpc method and params for the command
+    fn request(&self) -> Result<(&'static str, Value)> {
+        let request = match self {
+            Command::Enroll { .. }
+            | Command::Serve { .. }
+            | Command::Batch
+            | Command::Prune { .. }
+            | Command::Watch { .. } => unreachable!("runs separately"),
+            Command::Stats { top, .. } => ("admin.stats", json!({ "top": top })),
+            Command::Export { .. } => ("admin.export", Value::Null),
+            Command::Import {
+                path,
+                format,
+                strategy,
+            } => {
+                let snapshot = read_snapshot(path, *format)?;
+                let params = json!({ "snapshot": snapshot, "strategy": strategy.as_str() });
+                ("admin.import", params)
+            }
+            Command::Otp(OtpCommand::Create { user }) => ("otp.create", json!({ "user": user })),
+            Command::Otp(OtpCommand::Validate { user, code }) => {
+                ("otp.validate", json!({ "user": user, "code": code }))
+            }
+            Command::Otp(OtpCommand::Remove { user, code }) => {
+                ("otp.remove", json!({ "user": user, "code": code }))
+            }
+            Command::Session(SessionCommand::Create { user }) => {
+                ("session.create", json!({ "user": user }))
+            }
+            Command::Session(SessionCommand::Validate { user, code }) => {
+                ("session.validate", json!({ "user": user, "code": code }))
+            }
+            Command::Session(SessionCommand::Revoke { user, code: None }) => {
+                ("session.revoke_all", json!({ "user": user }))
+            }
+            Command::Session(SessionCommand::Revoke {
+                user,
+                code: Some(code),
+            }) => ("session.remove", json!({ "user": user, "code": code })),
+        };
+
+        Ok(request)
+    }
+
+    // run the command against the stores, returning the same result the json-rpc server would
+    fn apply(&self, otp: &mut Otp, session: &mut Session) -> Result<Value> {
+        let result = match self {
+            Command::Enroll { .. }
+            | Command::Serve { .. }
+            | Command::Batch
+            | Command::Prune { .. }
+            | Command::Watch { .. } => unreachable!("runs separately"),
+            Command::Stats { top, .. } => json!(StatsReport::collect(otp, session, *top)),
+            Command::Export { .. } => json!(Snapshot::capture(otp, session)),
+            Command::Import {
+                path,
+                format,
+                strategy,
+            } => {
+                let count = read_snapshot(path, *format)?.import(otp, session, *strategy)?;
+                json!({ "imported": count })
+            }
+            Command::Otp(OtpCommand::Create { user }) => {
+                json!({ "code": otp.create_user_otp(user)? })
+            }
+            Command::Otp(OtpCommand::Validate { user, code }) => {
+                let result = otp.validate(code, user);
+                json!({ "valid": result.is_valid(), "result": result.as_str() })
+            }
+            Command::Otp(OtpCommand::Remove { user, code }) => {
+                json!({ "removed": otp.remove(code, user).is_some() })
+            }
+            Command::Session(SessionCommand::Create { user }) => {
+                json!({ "code": session.create_user_session(user)? })
+            }
+            Command::Session(SessionCommand::Validate { user, code }) => {
+                let result = session.validate(code, user);
+                json!({ "valid": result.is_valid(), "result": result.as_str() })
+            }
+            Command::Session(SessionCommand::Revoke { user, code: None }) => {
+                json!({ "removed": session.remove_user(user) })
+            }
+            Command::Session(SessionCommand::Revoke {
+                user,
+                code: Some(code),
+            }) => json!({ "removed": session.remove(code, user).is_some() }),
+        };
+
+        Ok(result)
+    }
+
+    // return true if the command changes the stores
+    fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Command::Stats { .. }
+                | Command::Export { .. }
+                | Command::Otp(OtpCommand::Validate { .. })
+                | Command::Session(SessionCommand::Validate { .. })
+        )
+    }
+}
+
+// read a snapshot file in the format
+fn read_snapshot(path: &Path, format: Format) -> Result<Snapshot> {
+    Snapshot::read(BufReader::new(File::open(path)?), format)
+}
+
+// write the exported snapshot to the file, printing the item count, or to out
+fn export<W: Write>(
+    out: &mut W,
+    result: Value,
+    format: Format,
+    path: Option<&Path>,
+    output: Output,
+) -> Result<bool> {
+    let snapshot: Snapshot = serde_json::from_value(result)?;
+    match path {
+        Some(path) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            snapshot.write(&mut writer, format)?;
+            writer.flush()?;
+            let count = snapshot.otp.len() + snapshot.session.len();
+            match output {
+                Output::Text => writeln!(out, "{}", count)?,
+                Output::Json => writeln!(out, "{}", json!({ "exported": count }))?,
+            }
+        }
+        None => {
+            snapshot.write(&mut *out, format)?;
+            if format == Format::Json {
+                writeln!(out)?;
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+// restore the stores from the snapshot file, if it exists
+fn open(path: &Path) -> Result<(Otp, Session)> {
+    let mut otp = Otp::new();
+    let mut session = Session::new();
+    if path.exists() {
+        Snapshot::load(path)?.restore(&mut otp, &mut session)?;
+    }
+
+    Ok((otp, session))
+}
+
+// connect to the daemon, authenticating when there is a token
+fn connect(addr: &str, token: Option<&str>) -> Result<SessionClient> {
+    match token {
+        Some(token) => SessionClient::connect_with_token(addr, token),
+        None => SessionClient::connect(addr),
+    }
+}
+
+// run the command against the snapshot file, saving it when the command changes the stores
+fn run_local(path: &Path, command: &Command) -> Result<Value> {
+    let (mut otp, mut session) = open(path)?;
+    let result = command.apply(&mut otp, &mut session)?;
+    if command.writes() {
+        Snapshot::capture(&otp, &session).save(path)?;
+    }
+
+    Ok(result)
+}
+
+// run the command on the daemon
+fn run_remote(addr: &str, token: Option<&str>, command: &Command) -> Result<Value> {
+    let (method, params) = command.request()?;
+    connect(addr, token)?.call(method, params)
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchParams {
+    user: String,
+    #[serde(default)]
+    code: Option<String>,
+}
+
+// parse a batch line: a json-rpc style request or the words of a command
+fn parse_line(line: &str) -> Result<Command> {
+    if !line.starts_with('{') {
+        let words = std::iter::once("otp-session").chain(line.split_whitespace());
+        let command = Cli::try_parse_from(words)?.command;
+        return match command {
+            Command::Batch
+            | Command::Serve { .. }
+            | Command::Enroll { .. }
+            | Command::Prune { .. }
+            | Command::Watch { .. } => {
+                bail!("batch, serve, enroll, prune and watch can't run in a batch")
+            }
+            command => Ok(command),
+        };
+    }
+
+    let request: BatchRequest = serde_json::from_str(line)?;
+    let method = request.method.as_str();
+    let BatchParams { user, code } = serde_json::from_value(request.params)?;
+    let code = || {
+        code.clone()
+            .ok_or_else(|| anyhow!("{} needs a code", method))
+    };
+    let command = match method {
+        "otp.create" => Command::Otp(OtpCommand::Create { user }),
+        "otp.validate" => Command::Otp(OtpCommand::Validate {
+            code: code()?,
+            user,
+        }),
+        "otp.remove" => Command::Otp(OtpCommand::Remove {
+            code: code()?,
+            user,
+        }),
+        "session.create" => Command::Session(SessionCommand::Create { user }),
+        "session.validate" => Command::Session(SessionCommand::Validate {
+            code: code()?,
+            user,
+        }),
+        "session.remove" => Command::Session(SessionCommand::Revoke {
+            code: Some(code()?),
+            user,
+        }),
+        "session.revoke_all" => Command::Session(SessionCommand::Revoke { code: None, user }),
+        _ => bail!("unsupported batch method: {}", method),
+    };
+
+    Ok(command)
+}
+
+/// run the batch commands read from input, printing each result; stop at the first error. against a snapshot file the
+/// commands share one load and save, so a failed batch leaves the file unchanged; a daemon applies each command as it
+/// arrives. blank lines and lines starting with # are skipped
+pub fn run_batch<R: BufRead, W: Write>(cli: &Cli, input: R, out: &mut W) -> Result<bool> {
+    let mut commands = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let command = parse_line(line).map_err(|e| e.context(format!("line {}", n + 1)))?;
+        commands.push((n + 1, command));
+    }
+
+    let mut ok = true;
+    match &cli.connect {
+        Some(addr) => {
+            let client = connect(addr, cli.token.as_deref())?;
+            for (n, command) in &commands {
+                let (method, params) = command.request()?;
+                let result = client
+                    .call(method, params)
+                    .map_err(|e| e.context(format!("line {}", n)))?;
+                ok &= print_result(cli.output, out, command, result)?;
+            }
+        }
+        None => {
+            let (mut otp, mut session) = open(&cli.file)?;
+            let mut results = Vec::with_capacity(commands.len());
+            for (n, command) in &commands {
+                let result = command
+                    .apply(&mut otp, &mut session)
+                    .map_err(|e| e.context(format!("line {}", n)))?;
+                results.push(result);
+            }
+            if commands.iter().any(|(_, command)| command.writes()) {
+                Snapshot::capture(&otp, &session).save(&cli.file)?;
+            }
+            for ((_, command), result) in commands.iter().zip(results) {
+                ok &= print_result(cli.output, out, command, result)?;
+            }
+        }
+    }
+
+    Ok(ok)
+}
+
+// print the stats as json or a summary line per store and the top users
+fn stats<W: Write>(out: &mut W, result: Value, json: bool) -> Result<bool> {
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&result)?)?;
+        return Ok(true);
+    }
+
+    let report: StatsReport = serde_json::from_value(result)?;
+    let line = |name: &str, stats: &StoreStats| {
+        format!(
+            "{}: {} active, {} created, {} expired, {} removed, {} of {} validations failed ({:.1}%)",
+            name,
+            stats.active,
+            stats.created,
+            stats.expired,
+            stats.removed,
+            stats.failed,
+            stats.validate.count,
+            stats.failure_rate() * 100.0
+        )
+    };
+    writeln!(out, "{}", line("otp", &report.otp))?;
+    writeln!(out, "{}", line("session", &report.session))?;
+    for user in report.top_users {
+        writeln!(out, "  {:<24} {}", user.user, user.count)?;
+    }
+
+    Ok(true)
+}
+
+// return false if a validation failed or nothing was removed
+fn succeeded(result: &Value) -> bool {
+    match (&result["valid"], &result["removed"]) {
+        (Value::Bool(valid), _) => *valid,
+        (_, Value::Bool(removed)) => *removed,
+        _ => true,
+    }
+}
+
+/// return the error as a json object with a stable code for scripts: unauthorized, forbidden, invalid_params,
+/// method_not_found or server_error from a daemon, io for file and network errors, invalid_data for undecodable
+/// input, otherwise error
+pub fn error_json(e: &anyhow::Error) -> Value {
+    let code = if let Some(remote) = e.downcast_ref::<RemoteError>() {
+        match remote.code {
+            UNAUTHORIZED => "unauthorized",
+            FORBIDDEN => "forbidden",
+            INVALID_PARAMS => "invalid_params",
+            METHOD_NOT_FOUND => "method_not_found",
+            _ => "server_error",
+        }
+    } else if e.downcast_ref::<std::io::Error>().is_some() {
+        "io"
+    } else if e.downcast_ref::<serde_json::Error>().is_some() {
+        "invalid_data"
+    } else {
+        "error"
+    };
+
+    json!({ "error": { "code": code, "message": format!("{:#}", e) } })
+}
+
+/// print a failed command's error: a json object on out with --output json, otherwise a line on stderr
+pub fn print_error<W: Write>(output: Output, out: &mut W, e: &anyhow::Error) {
+    match output {
+        Output::Json => {
+            let _ = writeln!(out, "{}", error_json(e));
+        }
+        Output::Text => eprintln!("otp-session: {:#}", e),
+    }
+}
+
+// print the result; return false if a validation failed or nothing was removed
+fn report<W: Write>(out: &mut W, result: &Value) -> Result<bool> {
+    if let Some(code) = result["code"].as_str() {
+        writeln!(out, "{}", code)?;
+        return Ok(true);
+    }
+
+    if let Some(count) = result["imported"].as_u64() {
+        writeln!(out, "{}", count)?;
+        return Ok(true);
+    }
+
+    if let Some(name) = result["result"].as_str() {
+        writeln!(out, "{}", name)?;
+        return Ok(result["valid"].as_bool().unwrap_or(false));
+    }
+
+    match &result["removed"] {
+        Value::Bool(removed) => {
+            writeln!(out, "{}", if *removed { "removed" } else { "not_found" })?;
+            Ok(*removed)
+        }
+        Value::Number(count) => {
+            writeln!(out, "{}", count)?;
+            Ok(true)
+        }
+        _ => Err(anyhow!("unexpected response: {}", result)),
+    }
+}
+
+// print the enrollment qr code, uri and secret; nothing is stored, so the caller must save the secret
+fn enroll<W: Write>(out: &mut W, user: &str, issuer: &str, output: Output) -> Result<bool> {
+    let totp = Totp::new(issuer, user);
+    let uri = totp.uri();
+    if output == Output::Json {
+        writeln!(out, "{}", json!({ "uri": uri, "secret": totp.secret }))?;
+        return Ok(true);
+    }
+
+    // light modules on a dark background so the code scans on dark terminals
+    let qr = QrCode::new(uri.as_bytes())?
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+
+    writeln!(out, "{}", qr)?;
+    writeln!(out, "{}", uri)?;
+    writeln!(out, "secret: {}", totp.secret)?;
+    Ok(true)
+}
+
+// run the daemon until it is signalled to stop
+fn serve(cli: &Cli) -> Result<bool> {
+    let Command::Serve {
+        listen,
+        probe,
+        resp,
+        config,
+        backend,
+        #[cfg(feature = "plugins")]
+        backend_config,
+        hashing,
+    } = &cli.command
+    else {
+        unreachable!("not a serve command");
+    };
+
+    if cli.connect.is_some() {
+        bail!("serve runs the daemon locally and can't be combined with --connect");
+    }
+    #[cfg(not(feature = "plugins"))]
+    if backend != "memory" {
+        bail!(
+            "unsupported backend: {} (only memory is available)",
+            backend
+        );
+    }
+    #[cfg(feature = "plugins")]
+    if backend != "memory" && !Path::new(backend).is_file() {
+        bail!(
+            "unsupported backend: {} (memory, or the path of a backend plugin)",
+            backend
+        );
+    }
+
+    let config = DaemonConfig {
+        jsonrpc_addr: Some(listen.clone()),
+        jsonrpc_token: cli.token.clone(),
+        resp_addr: resp.clone(),
+        probe_addr: Some(probe.clone()),
+        settings: Config::from_env()?,
+        config: config.clone(),
+        snapshot: Some(cli.file.clone()),
+        hashing: *hashing,
+        #[cfg(feature = "plugins")]
+        backend: (backend != "memory").then(|| crate::plugin::BackendConfig {
+            path: PathBuf::from(backend),
+            config: backend_config.clone(),
+        }),
+        ..Default::default()
+    };
+
+    logging::init_json(LevelFilter::Info)?;
+    Daemon::new(config).run()?;
+    Ok(true)
+}
+
+// prune the snapshot file, or purge the daemon's expired items
+fn prune<W: Write>(cli: &Cli, out: &mut W) -> Result<bool> {
+    let Command::Prune {
+        older_than,
+        dry_run,
+        config,
+    } = &cli.command
+    else {
+        unreachable!("not a prune command");
+    };
+
+    if let Some(addr) = &cli.connect {
+        if older_than.is_some() || *dry_run {
+            bail!("--older-than and --dry-run need a snapshot file; a daemon can only purge expired items");
+        }
+        let result = connect(addr, cli.token.as_deref())?.call("admin.purge", Value::Null)?;
+        let count = result["otp"].as_u64().unwrap_or(0) + result["session"].as_u64().unwrap_or(0);
+        match cli.output {
+            Output::Text => writeln!(out, "pruned {}", count)?,
+            Output::Json => writeln!(out, "{}", json!({ "pruned": count, "dry_run": false }))?,
+        }
+        return Ok(true);
+    }
+
+    let config = match config {
+        Some(path) => Config::load(path)?,
+        None => Config::from_env()?,
+    };
+    let mut snapshot = if cli.file.exists() {
+        Snapshot::load(&cli.file)?
+    } else {
+        Snapshot::default()
+    };
+    let max_age = older_than.map(|age| age.as_secs());
+    let pruned = snapshot.prune(unix_now(), max_age, &config);
+    if !dry_run && !pruned.is_empty() {
+        snapshot.save(&cli.file)?;
+    }
+
+    match cli.output {
+        Output::Text => {
+            for p in &pruned {
+                let code = logging::redact(&p.item.code);
+                let (store, reason) = (p.store.as_str(), p.reason.as_str());
+                writeln!(out, "{} {} {} {}", store, p.item.user, code, reason)?;
+            }
+            let verb = if *dry_run { "would prune" } else { "pruned" };
+            writeln!(out, "{} {}", verb, pruned.len())?;
+        }
+        Output::Json => {
+            let items: Vec<Value> = pruned
+                .iter()
+                .map(|p| {
+                    json!({
+                        "store": p.store.as_str(),
+                        "user": p.item.user,
+                        "code": logging::redact(&p.item.code),
+                        "reason": p.reason.as_str(),
+                    })
+                })
+                .collect();
+            let result = json!({ "pruned": pruned.len(), "dry_run": dry_run, "items": items });
+            writeln!(out, "{}", result)?;
+        }
+    }
+
+    Ok(true)
+}
+
+// format a row of the watch table
+fn watch_row(time: &str, event: &str, user: &str, detail: &str) -> String {
+    format!("{:<8}  {:<26}  {:<20}  {}", time, event, user, detail)
+}
+
+// the event as a watch table row: the utc time of day, and the redacted code or the item count
+fn watch_line(event: &WatchEvent) -> String {
+    let seconds = event.time % 86_400;
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    let detail = match (&event.code, event.count) {
+        (Some(code), _) => code.clone(),
+        (None, Some(count)) => count.to_string(),
+        (None, None) => "-".to_string(),
+    };
+
+    watch_row(
+        &time,
+        &event.event,
+        event.user.as_deref().unwrap_or("-"),
+        &detail,
+    )
+}
+
+// stream the daemon's events as table rows, or json lines, flushing each as it arrives
+fn watch<W: Write>(cli: &Cli, out: &mut W, count: Option<usize>) -> Result<bool> {
+    let Some(addr) = &cli.connect else {
+        bail!("watch streams a daemon's events and needs --connect");
+    };
+    let client = connect(addr, cli.token.as_deref())?;
+
+    if cli.output == Output::Text {
+        writeln!(out, "{}", watch_row("TIME", "EVENT", "USER", "CODE/COUNT"))?;
+        out.flush()?;
+    }
+
+    let mut seen = 0;
+    let mut failed = None;
+    client.watch(|event| {
+        let line = match cli.output {
+            Output::Text => watch_line(&event),
+            Output::Json => json!(event).to_string(),
+        };
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            failed = Some(e);
+            return false;
+        }
+
+        seen += 1;
+        count.map_or(true, |count| seen < count)
+    })?;
+
+    match failed {
+        Some(e) => Err(e.into()),
+        None => Ok(true),
+    }
+}
+
+/// run the command line and print the result to out; return false if a validation failed or there was nothing to
+/// remove, so the binary can exit 1
+pub fn run<W: Write>(cli: &Cli, out: &mut W) -> Result<bool> {
+    if let Command::Enroll { user, issuer } = &cli.command {
+        return enroll(out, user, issuer, cli.output);
+    }
+    if let Command::Serve { .. } = &cli.command {
+        return serve(cli);
+    }
+
+    if let Command::Prune { .. } = &cli.command {
+        return prune(cli, out);
+    }
+    if let Command::Batch = &cli.command {
+        return run_batch(cli, std::io::stdin().lock(), out);
+    }
+    if let Command::Watch { count } = &cli.command {
+        return watch(cli, out, *count);
+    }
+
+    let result = match &cli.connect {
+        Some(addr) => run_remote(addr, cli.token.as_deref(), &cli.command)?,
+        None => run_local(&cli.file, &cli.command)?,
+    };
+
+    print_result(cli.output, out, &cli.command, result)
+}
+
+// print the command's result in the output format; return false if a validation failed or nothing was removed
+fn print_result<W: Write>(
+    output: Output,
+    out: &mut W,
+    command: &Command,
+    result: Value,
+) -> Result<bool> {
+    match command {
+        Command::Export { format, out: path } => {
+            export(out, result, *format, path.as_deref(), output)
+        }
+        Command::Stats { json, .. } => stats(out, result, *json || output == Output::Json),
+        _ if output == Output::Json => {
+            writeln!(out, "{}", result)?;
+            Ok(succeeded(&result))
+        }
+        _ => report(out, &result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc::JsonRpcServer;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn local(file: &Path, command: Command) -> (bool, String) {
+        let cli = Cli {
+            file: file.to_path_buf(),
+            connect: None,
+            token: None,
+            output: Output::Text,
+            command,
+        };
+        let mut out = Vec::new();
+        let ok = run(&cli, &mut out).unwrap();
+        (ok, String::from_utf8(out).unwrap().trim().to_string())
+    }
+
+    #[test]
+    fn snapshot_file() {
+        let file =
+            std::env::temp_dir().join(format!("otp-session-cli-{}.json", std::process::id()));
+        let user = "sally".to_string();
+
+        let (ok, code) = local(
+            &file,
+            Command::Otp(OtpCommand::Create { user: user.clone() }),
+        );
+        assert!(ok);
+        assert_eq!(code.len(), 6);
+
+        let validate = Command::Otp(OtpCommand::Validate {
+            user: user.clone(),
+            code: code.clone(),
+        });
+        assert_eq!(local(&file, validate.clone()), (true, "valid".to_string()));
+        let remove = Command::Otp(OtpCommand::Remove {
+            user: user.clone(),
+            code,
+        });
+        assert_eq!(local(&file, remove.clone()), (true, "removed".to_string()));
+        assert_eq!(local(&file, remove), (false, "not_found".to_string()));
+        assert_eq!(local(&file, validate), (false, "not_found".to_string()));
+
+        local(
+            &file,
+            Command::Session(SessionCommand::Create { user: user.clone() }),
+        );
+        local(
+            &file,
+            Command::Session(SessionCommand::Create { user: user.clone() }),
+        );
+        let revoke = Command::Session(SessionCommand::Revoke { user, code: None });
+        assert_eq!(local(&file, revoke), (true, "2".to_string()));
+
+        // export the file's state and import it into another file
+        local(
+            &file,
+            Command::Otp(OtpCommand::Create {
+                user: "jack".to_string(),
+            }),
+        );
+        let exported = file.with_extension("export");
+        let export = Command::Export {
+            format: Format::Json,
+            out: Some(exported.clone()),
+        };
+        assert_eq!(local(&file, export), (true, "1".to_string()));
+        let copy = file.with_extension("copy");
+        let import = Command::Import {
+            path: exported.clone(),
+            format: Format::Json,
+            strategy: Strategy::Merge,
+        };
+        assert_eq!(local(&copy, import.clone()), (true, "1".to_string()));
+        assert_eq!(local(&copy, import), (true, "0".to_string()));
+
+        let stats = Command::Stats {
+            json: false,
+            top: 10,
+        };
+        let (_, out) = local(&copy, stats);
+        assert!(out.starts_with("otp: 1 active, 0 created"));
+
+        for path in [&file, &exported, &copy] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn enroll_user() {
+        let mut out = Vec::new();
+        assert!(enroll(&mut out, "sally", "acme", Output::Text).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().rev().take(2).collect();
+        assert!(lines[0].starts_with("secret: "));
+        assert!(lines[1].starts_with("otpauth://totp/acme:sally?secret="));
+        assert!(out.contains('\u{2588}'));
+    }
+
+    #[test]
+    fn batch() {
+        let file =
+            std::env::temp_dir().join(format!("otp-session-batch-{}.json", std::process::id()));
+        let cli = Cli {
+            file: file.clone(),
+            connect: None,
+            token: None,
+            output: Output::Text,
+            command: Command::Batch,
+        };
+
+        let input = "# setup\nsession create sally\n\n{\"method\": \"session.create\", \"params\": {\"user\": \"sally\"}}\nsession revoke sally\n";
+        let mut out = Vec::new();
+        assert!(run_batch(&cli, input.as_bytes(), &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().last(), Some("2"));
+
+        // a bad line fails the batch before anything runs
+        let input = "session create jack\nsession frobnicate jack\n";
+        let err = run_batch(&cli, input.as_bytes(), &mut Vec::new()).unwrap_err();
+        assert!(format!("{:#}", err).starts_with("line 2"));
+        let (_, session) = open(&file).unwrap();
+        assert_eq!(session.dbsize(), 0);
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let remote = anyhow::Error::from(RemoteError {
+            method: "admin.export".to_string(),
+            code: FORBIDDEN,
+            message: "admin token with export scope required".to_string(),
+        });
+        assert_eq!(error_json(&remote)["error"]["code"], "forbidden");
+
+        let missing = read_snapshot(Path::new("/nonexistent/otp-session.json"), Format::Json);
+        assert_eq!(error_json(&missing.unwrap_err())["error"]["code"], "io");
+        assert_eq!(error_json(&anyhow!("bad"))["error"]["message"], "bad");
+
+        assert!(!succeeded(&json!({ "valid": false, "result": "expired" })));
+        assert!(!succeeded(&json!({ "removed": false })));
+        assert!(succeeded(&json!({ "removed": 2 })));
+    }
+
+    #[test]
+    fn serve_backend() {
+        let cli = Cli {
+            file: PathBuf::from(DEFAULT_FILE),
+            connect: None,
+            token: None,
+            output: Output::Text,
+            command: Command::Serve {
+                listen: "127.0.0.1:0".to_string(),
+                probe: "127.0.0.1:0".to_string(),
+                resp: None,
+                config: None,
+                backend: "redis://localhost".to_string(),
+                #[cfg(feature = "plugins")]
+                backend_config: String::new(),
+                hashing: Hashing::default(),
+            },
+        };
+        let err = run(&cli, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().starts_with("unsupported backend"));
+    }
+
+    #[test]
+    fn watch_table() {
+        let event = WatchEvent {
+            event: "otp.created".to_string(),
+            store: "otp".to_string(),
+            time: 1_700_000_000,
+            user: Some("sally".to_string()),
+            code: Some("0123456789ab".to_string()),
+            count: None,
+        };
+        let line = watch_line(&event);
+        assert!(line.starts_with("22:13:20  otp.created"));
+        assert!(line.ends_with("sally                 0123456789ab"));
+
+        let event = WatchEvent {
+            event: "session.expired".to_string(),
+            user: None,
+            code: None,
+            count: Some(3),
+            ..event
+        };
+        assert!(watch_line(&event).ends_with("-                     3"));
+
+        let cli = Cli {
+            file: PathBuf::from(DEFAULT_FILE),
+            connect: None,
+            token: None,
+            output: Output::Text,
+            command: Command::Watch { count: None },
+        };
+        let err = run(&cli, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("needs --connect"));
+    }
+
+    #[test]
+    fn daemon() {
+        let session = Session::new();
+        let server = JsonRpcServer::new(Otp::new(), session.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.handle_connection(stream).unwrap();
+        });
+
+        let cli = Cli {
+            file: PathBuf::from(DEFAULT_FILE),
+            connect: Some(addr),
+            token: None,
+            output: Output::Text,
+            command: Command::Session(SessionCommand::Create {
+                user: "jack".to_string(),
+            }),
+        };
+        let mut out = Vec::new();
+        assert!(run(&cli, &mut out).unwrap());
+        let code = String::from_utf8(out).unwrap();
+        assert!(session.is_valid(code.trim(), "jack"));
+    }
+}
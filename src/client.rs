@@ -0,0 +1,344 @@
+/// client for a remote session store served by the json-rpc server
+use crate::db::SessionItem;
+use crate::jsonrpc::{RpcRequest, RpcResponse, WatchEvent};
+use crate::store::SessionStore;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// an error response from the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub method: String,
+    /// the json-rpc error code, e.g. jsonrpc::UNAUTHORIZED
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed ({}): {}",
+            self.method, self.code, self.message
+        )
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+/// a SessionStore backed by a remote json-rpc server
+#[derive(Debug)]
+pub struct SessionClient {
+    conn: Mutex<Connection>,
+    next_id: AtomicU64,
+}
+
+impl SessionClient {
+    /// connect to the server at addr
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<SessionClient> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        };
+
+        Ok(SessionClient {
+            conn: Mutex::new(conn),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// connect to the server at addr and authenticate with the token
+    pub fn connect_with_token<A: ToSocketAddrs>(addr: A, token: &str) -> Result<SessionClient> {
+        let client = SessionClient::connect(addr)?;
+        client.call("auth", json!({ "token": token }))?;
+
+        Ok(client)
+    }
+
+    /// send a request and wait for its response; returns the result or the server error as a RemoteError
+    pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(json!(id)),
+        };
+
+        let mut conn = self.conn.lock().unwrap();
+        writeln!(conn.writer, "{}", serde_json::to_string(&request)?)?;
+        conn.writer.flush()?;
+
+        let mut line = String::new();
+        if conn.reader.read_line(&mut line)? == 0 {
+            bail!("connection closed by server");
+        }
+
+        let resp: RpcResponse = serde_json::from_str(&line)?;
+        response_result(method, resp)
+    }
+
+    /// send the requests as one json-rpc batch, a single round trip, and wait for their responses; return each
+    /// request's result or RemoteError in the order sent
+    pub fn call_batch(&self, calls: &[(&str, Value)]) -> Result<Vec<Result<Value>>> {
+        if calls.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let first = self
+            .next_id
+            .fetch_add(calls.len() as u64, Ordering::Relaxed);
+        let requests: Vec<RpcRequest> = calls
+            .iter()
+            .zip(first..)
+            .map(|((method, params), id)| RpcRequest {
+                jsonrpc: "2.0".to_string(),
+                method: method.to_string(),
+                params: params.clone(),
+                id: Some(json!(id)),
+            })
+            .collect();
+
+        let mut conn = self.conn.lock().unwrap();
+        writeln!(conn.writer, "{}", serde_json::to_string(&requests)?)?;
+        conn.writer.flush()?;
+
+        let mut line = String::new();
+        if conn.reader.read_line(&mut line)? == 0 {
+            bail!("connection closed by server");
+        }
+
+        // match the responses to the requests by id, in case the server reorders them
+        let mut responses: Vec<RpcResponse> = serde_json::from_str(&line)?;
+        let results = calls
+            .iter()
+            .zip(first..)
+            .map(
+                |((method, _), id)| match responses.iter().position(|resp| resp.id == json!(id)) {
+                    Some(n) => response_result(method, responses.swap_remove(n)),
+                    None => Err(anyhow!("{} returned no response", method)),
+                },
+            )
+            .collect();
+
+        Ok(results)
+    }
+
+    /// stream the server's events, calling f with each until it returns false or the server closes the connection;
+    /// needs an admin token with the list scope
+    pub fn watch<F: FnMut(WatchEvent) -> bool>(self, mut f: F) -> Result<()> {
+        self.call("admin.watch", Value::Null)?;
+
+        let mut conn = self.conn.lock().unwrap();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if conn.reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            // blank lines are idle heartbeats
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let notification: RpcRequest = serde_json::from_str(&line)?;
+            if notification.method != "event" {
+                continue;
+            }
+            if !f(serde_json::from_value(notification.params)?) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// return the response's result, or its error as a RemoteError
+fn response_result(method: &str, resp: RpcResponse) -> Result<Value> {
+    if let Some(error) = resp.error {
+        return Err(RemoteError {
+            method: method.to_string(),
+            code: error.code,
+            message: error.message,
+        }
+        .into());
+    }
+
+    resp.result
+        .ok_or_else(|| anyhow!("{} returned no result", method))
+}
+
+impl SessionStore for SessionClient {
+    fn create_user_session(&mut self, user: &str) -> Result<String> {
+        let result = self.call("session.create", json!({ "user": user }))?;
+        result["code"]
+            .as_str()
+            .map(|code| code.to_string())
+            .ok_or_else(|| anyhow!("session.create returned no code"))
+    }
+
+    fn is_valid(&self, code: &str, user: &str) -> Result<bool> {
+        let result = self.call("session.validate", json!({ "code": code, "user": user }))?;
+        Ok(result["valid"].as_bool().unwrap_or(false))
+    }
+
+    fn remove(&mut self, code: &str, user: &str) -> Result<Option<String>> {
+        let result = self.call("session.remove", json!({ "code": code, "user": user }))?;
+        if result["removed"].as_bool().unwrap_or(false) {
+            Ok(Some(code.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn list(&self, user: Option<&str>) -> Result<Vec<SessionItem>> {
+        let result = self.call("session.list", json!({ "user": user }))?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    fn remove_many(&mut self, keys: &[(&str, &str)]) -> Result<usize> {
+        let calls: Vec<(&str, Value)> = keys
+            .iter()
+            .map(|(code, user)| ("session.remove", json!({ "code": code, "user": user })))
+            .collect();
+
+        let mut count = 0;
+        for result in self.call_batch(&calls)? {
+            if result?["removed"].as_bool().unwrap_or(false) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn dbsize(&self) -> Result<usize> {
+        let result = self.call("session.dbsize", Value::Null)?;
+        result
+            .as_u64()
+            .map(|n| n as usize)
+            .ok_or_else(|| anyhow!("session.dbsize returned no count"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::{AdminTokens, Scope};
+    use crate::jsonrpc::JsonRpcServer;
+    use crate::otp::Otp;
+    use crate::session::Session;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // serve a single connection on an ephemeral port and return the address
+    fn start_server(server: JsonRpcServer) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.handle_connection(stream).unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn remote_session() {
+        let session = Session::new();
+        let admin = AdminTokens::new();
+        let token = admin.create("test", &[Scope::List]).unwrap();
+        let server = JsonRpcServer::new(Otp::new(), session.clone()).with_admin(admin);
+        let addr = start_server(server);
+
+        // list is an admin method
+        let mut client = SessionClient::connect_with_token(addr, &token).unwrap();
+
+        let user = "sally";
+        let code = client.create_user_session(user).unwrap();
+        assert!(session.is_valid(&code, user));
+        assert!(SessionStore::is_valid(&client, &code, user).unwrap());
+        assert_eq!(SessionStore::dbsize(&client).unwrap(), 1);
+        assert_eq!(SessionStore::list(&client, Some(user)).unwrap().len(), 1);
+
+        let resp = SessionStore::remove(&mut client, &code, user).unwrap();
+        assert_eq!(resp, Some(code.clone()));
+        assert!(!session.is_valid(&code, user));
+
+        let codes: Vec<String> = (0..3)
+            .map(|_| client.create_user_session(user).unwrap())
+            .collect();
+        let keys: Vec<(&str, &str)> = codes.iter().map(|code| (code.as_str(), user)).collect();
+        assert_eq!(client.remove_many(&keys).unwrap(), 3);
+        assert_eq!(session.dbsize(), 0);
+    }
+
+    #[test]
+    fn remote_watch() {
+        let otp = Otp::new();
+        let admin = AdminTokens::new();
+        let token = admin.create("test", &[Scope::List]).unwrap();
+        let server = JsonRpcServer::new(otp.clone(), Session::new()).with_admin(admin);
+        let addr = start_server(server);
+        let client = SessionClient::connect_with_token(addr, &token).unwrap();
+
+        // events before the watch starts are missed, so keep creating until two arrive
+        let done = Arc::new(AtomicBool::new(false));
+        let creator = {
+            let (mut otp, done) = (otp, done.clone());
+            thread::spawn(move || {
+                for n in 0..500 {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    otp.create_user_otp(&format!("user{}", n)).unwrap();
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+
+        let mut events = Vec::new();
+        client
+            .watch(|event| {
+                events.push(event);
+                events.len() < 2
+            })
+            .unwrap();
+        done.store(true, Ordering::Relaxed);
+        creator.join().unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, "otp.created");
+        assert!(events[0].user.as_deref().unwrap().starts_with("user"));
+        assert_eq!(events[0].code.as_ref().unwrap().len(), 12);
+    }
+
+    #[test]
+    fn remote_auth() {
+        let server = JsonRpcServer::new(Otp::new(), Session::new()).with_token("secret");
+        let addr = start_server(server);
+        let client = SessionClient::connect_with_token(addr, "secret").unwrap();
+        assert_eq!(client.dbsize().unwrap(), 0);
+
+        let server = JsonRpcServer::new(Otp::new(), Session::new()).with_token("secret");
+        let addr = start_server(server);
+        assert!(SessionClient::connect_with_token(addr, "wrong").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+/// the time source for expirations, so deployments can supply their own clock and tests can control time
+use std::fmt;
+#[cfg(any(test, feature = "test-clock"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(test, feature = "test-clock"))]
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the monotonic clock for timing operations
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use std::time::Instant;
+
+/// returns the current unix time in seconds
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// the system wall clock; the default everywhere
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        // a clock set before 1970 reads as the epoch rather than panicking
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+// wasm32-unknown-unknown has no system time, so browsers and workers read javascript's Date instead
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        (js_sys::Date::now() / 1_000.0).max(0.0) as u64
+    }
+}
+
+// std's Instant panics on wasm32-unknown-unknown, so time operations with javascript's Date, in milliseconds
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instant(f64);
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Instant {
+    pub(crate) fn now() -> Instant {
+        Instant(js_sys::Date::now())
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        // Date can step back when the system clock is set
+        Duration::from_secs_f64((js_sys::Date::now() - self.0).max(0.0) / 1_000.0)
+    }
+}
+
+// wait before retrying; wasm32-unknown-unknown can't block its one thread, so it retries at once
+pub(crate) fn sleep(duration: Duration) {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    std::thread::sleep(duration);
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    let _ = duration;
+}
+
+/// a clock that only moves when told to, for fast deterministic expiry tests without sleeping; clones share the
+/// time. available in downstream crates with the test-clock feature
+#[cfg(any(test, feature = "test-clock"))]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+#[cfg(any(test, feature = "test-clock"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-clock"))]
+impl MockClock {
+    /// create a clock stopped at the current system time
+    pub fn new() -> MockClock {
+        MockClock::at(unix_now())
+    }
+
+    /// create a clock stopped at the unix time
+    pub fn at(now: u64) -> MockClock {
+        MockClock {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    /// set the unix time
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    /// move the time forward; sub-second parts are dropped
+    pub fn advance(&self, duration: Duration) {
+        let seconds = duration.as_secs();
+        let _ = self
+            .now
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now| {
+                Some(now.saturating_add(seconds))
+            });
+    }
+}
+
+#[cfg(any(test, feature = "test-clock"))]
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// return the system unix time in seconds
+pub fn unix_now() -> u64 {
+    SystemClock.now()
+}
+
+/// return the system unix time in milliseconds, e.g. for time ordered ids
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// return the system unix time in milliseconds, e.g. for time ordered ids
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub fn unix_millis() -> u64 {
+    js_sys::Date::now().max(0.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::at(1_000);
+        let shared = clock.clone();
+        shared.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), 1_060);
+
+        clock.set(10);
+        assert_eq!(shared.now(), 10);
+        assert!(MockClock::new().now() >= SystemClock.now() - 1);
+    }
+}
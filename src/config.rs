@@ -0,0 +1,652 @@
+/// runtime settings for the otp and session stores, loaded from a file and reloadable without dropping sessions
+use crate::network::IpBinding;
+use crate::otp::{Otp, MAX_RESENDS, OTP_CODE_LENGTH, OTP_CODE_LENGTHS, RESEND_INTERVAL};
+use crate::policy::LoginPolicy;
+use crate::session::{validate_nanoid, IdFormat, Session};
+use anyhow::{anyhow, bail, Result};
+use log::{error, info};
+use otp_session_core::code::{NANOID_ALPHABET, NANOID_LENGTH};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// how often a spawned watcher checks the config file for changes
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the default time between sweeps of expired items
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// the longest accepted keep alive, 30 days
+pub const MAX_TIMEOUT: u64 = 30 * 24 * 60 * 60;
+
+// timeouts are seconds and must be between 1 and MAX_TIMEOUT
+pub(crate) fn validate_timeout(name: &str, timeout: u64) -> Result<()> {
+    if timeout == 0 || timeout > MAX_TIMEOUT {
+        bail!("{} must be between 1 and {} seconds", name, MAX_TIMEOUT);
+    }
+
+    Ok(())
+}
+
+/// parse a duration like `90`, `90s`, `15m`, `12h` or `7d`; a bare number is seconds
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("unknown duration unit in {:?}; use s, m, h or d", text),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("{:?} is not a duration", text))?;
+
+    Ok(Duration::from_secs(number.saturating_mul(seconds)))
+}
+
+fn validate_common(
+    store: &str,
+    max_per_user: Option<usize>,
+    sweep_interval: Duration,
+) -> Result<()> {
+    if max_per_user == Some(0) {
+        bail!("{}_max_per_user must be greater than zero", store);
+    }
+
+    if sweep_interval.as_secs() == 0 {
+        bail!("{}_sweep_interval must be at least one second", store);
+    }
+
+    Ok(())
+}
+
+/// settings for the otp store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtpConfig {
+    /// keep alive in seconds
+    pub timeout: u64,
+    /// number of digits in otp codes
+    pub code_length: usize,
+    /// most unexpired otps a user may hold; None is unlimited
+    pub max_per_user: Option<usize>,
+    /// time between sweeps of expired otps
+    pub sweep_interval: Duration,
+    /// seconds before a user can be sent a code again on the same channel
+    pub resend_interval: u64,
+    /// most times one otp may be resent
+    pub max_resends: u32,
+}
+
+impl Default for OtpConfig {
+    fn default() -> Self {
+        OtpConfig {
+            timeout: crate::OTP_TIMEOUT,
+            code_length: OTP_CODE_LENGTH,
+            max_per_user: None,
+            sweep_interval: SWEEP_INTERVAL,
+            resend_interval: RESEND_INTERVAL,
+            max_resends: MAX_RESENDS,
+        }
+    }
+}
+
+impl OtpConfig {
+    /// reject settings the otp store can't use
+    pub fn validate(&self) -> Result<()> {
+        validate_timeout("otp_timeout", self.timeout)?;
+        if !OTP_CODE_LENGTHS.contains(&self.code_length) {
+            bail!(
+                "otp_code_length must be between {} and {}",
+                OTP_CODE_LENGTHS.start(),
+                OTP_CODE_LENGTHS.end()
+            );
+        }
+
+        validate_common("otp", self.max_per_user, self.sweep_interval)
+    }
+}
+
+/// settings for the session store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// keep alive in seconds
+    pub timeout: u64,
+    /// most unexpired sessions a user may hold; None is unlimited
+    pub max_per_user: Option<usize>,
+    /// time between sweeps of expired sessions
+    pub sweep_interval: Duration,
+    /// what to do when a session bound to a network is validated from another
+    pub ip_binding: IpBinding,
+    /// what to do when a user with active sessions logs in again
+    pub login_policy: LoginPolicy,
+    /// the form of new session codes
+    pub id_format: IdFormat,
+    /// characters in nanoid codes
+    pub nanoid_length: usize,
+    /// the url-safe characters nanoid codes are drawn from
+    pub nanoid_alphabet: String,
+    /// seconds a session may be idle before sensitive operations need re-authentication; None never downgrades
+    pub reauth_after: Option<u64>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            timeout: crate::SESSION_TIMEOUT,
+            max_per_user: None,
+            sweep_interval: SWEEP_INTERVAL,
+            ip_binding: IpBinding::Off,
+            login_policy: LoginPolicy::Allow,
+            id_format: IdFormat::Hex,
+            nanoid_length: NANOID_LENGTH,
+            nanoid_alphabet: NANOID_ALPHABET.to_string(),
+            reauth_after: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// reject settings the session store can't use
+    pub fn validate(&self) -> Result<()> {
+        validate_timeout("session_timeout", self.timeout)?;
+        if self.reauth_after == Some(0) {
+            bail!("session_reauth_after must be greater than zero");
+        }
+        validate_nanoid(self.nanoid_length, &self.nanoid_alphabet)
+            .map_err(|e| anyhow!("session nanoid settings: {}", e))?;
+        validate_common("session", self.max_per_user, self.sweep_interval)
+    }
+}
+
+/// the setting names used in config files; environment variables use the upper case names
+pub const KEYS: &[&str] = &[
+    "otp_timeout",
+    "otp_code_length",
+    "otp_max_per_user",
+    "otp_sweep_interval",
+    "otp_resend_interval",
+    "otp_max_resends",
+    "session_timeout",
+    "session_max_per_user",
+    "session_sweep_interval",
+    "session_ip_binding",
+    "session_login_policy",
+    "session_reauth_after",
+    "session_id_format",
+    "session_nanoid_length",
+    "session_nanoid_alphabet",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub otp: OtpConfig,
+    pub session: SessionConfig,
+}
+
+impl Config {
+    /// parse `key = value` lines; blank lines and `#` comments are ignored, missing keys keep their defaults.
+    /// keys are the store name and setting, e.g. otp_timeout or session_max_per_user
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config = Config::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {}: expected key = value", n + 1))?;
+            config
+                .set(key.trim(), value.trim())
+                .map_err(|e| anyhow!("line {}: {}", n + 1, e))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// read the settings from environment variables named for the upper case keys, e.g. OTP_TIMEOUT; unset
+    /// variables keep their defaults
+    pub fn from_env() -> Result<Config> {
+        Config::default().with_env()
+    }
+
+    /// override these settings with any that are set in the environment
+    pub fn with_env(self) -> Result<Config> {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    // override the settings from a variable lookup, e.g. the environment
+    fn with_vars<F: Fn(&str) -> Option<String>>(mut self, var: F) -> Result<Config> {
+        for key in KEYS {
+            let name = key.to_uppercase();
+            if let Some(value) = var(&name) {
+                self.set(key, value.trim())
+                    .map_err(|e| anyhow!("{}: {}", name, e))?;
+            }
+        }
+
+        self.validate()?;
+        Ok(self)
+    }
+
+    // set one setting from its text value
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| anyhow!("{} is not a number", value))
+        };
+
+        match key {
+            "otp_timeout" => self.otp.timeout = number()?,
+            "otp_code_length" => self.otp.code_length = number()? as usize,
+            "otp_max_per_user" => self.otp.max_per_user = Some(number()? as usize),
+            "otp_sweep_interval" => self.otp.sweep_interval = Duration::from_secs(number()?),
+            "otp_resend_interval" => self.otp.resend_interval = number()?,
+            "otp_max_resends" => self.otp.max_resends = number()? as u32,
+            "session_timeout" => self.session.timeout = number()?,
+            "session_max_per_user" => self.session.max_per_user = Some(number()? as usize),
+            "session_sweep_interval" => {
+                self.session.sweep_interval = Duration::from_secs(number()?)
+            }
+            "session_ip_binding" => self.session.ip_binding = value.parse()?,
+            "session_login_policy" => self.session.login_policy = value.parse()?,
+            "session_reauth_after" => self.session.reauth_after = Some(number()?),
+            "session_id_format" => self.session.id_format = value.parse()?,
+            "session_nanoid_length" => self.session.nanoid_length = number()? as usize,
+            "session_nanoid_alphabet" => self.session.nanoid_alphabet = value.to_string(),
+            key => bail!("unknown setting {}", key),
+        }
+
+        Ok(())
+    }
+
+    /// parse a toml file with `[otp]` and `[session]` tables; missing keys keep their defaults
+    pub fn parse_toml(text: &str) -> Result<Config> {
+        let file: FileConfig = toml::from_str(text)?;
+        file.into_config()
+    }
+
+    /// parse a yaml file with `otp` and `session` maps; missing keys keep their defaults
+    #[cfg(feature = "yaml")]
+    pub fn parse_yaml(text: &str) -> Result<Config> {
+        let file: FileConfig = serde_yaml::from_str(text)?;
+        file.into_config()
+    }
+
+    /// read and parse the config file: toml for .toml, yaml for .yaml or .yml (with the yaml feature), otherwise
+    /// `key = value` lines
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let config = match extension {
+            "toml" => Config::parse_toml(&text),
+            #[cfg(feature = "yaml")]
+            "yaml" | "yml" => Config::parse_yaml(&text),
+            _ => Config::parse(&text),
+        };
+
+        config.map_err(|e| anyhow!("{:?}: {}", path, e))
+    }
+
+    /// read the config file and override it with any environment variables that are set
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        Config::from_file(path)?.with_env()
+    }
+
+    /// reject settings the stores can't use
+    pub fn validate(&self) -> Result<()> {
+        self.otp.validate()?;
+        self.session.validate()
+    }
+
+    /// apply the settings to the stores; items already stored keep their expiration
+    pub fn apply(&self, otp: &Otp, session: &Session) -> Result<()> {
+        self.validate()?;
+        otp.set_keep_alive(self.otp.timeout);
+        otp.set_code_length(self.otp.code_length)?;
+        otp.set_max_per_user(self.otp.max_per_user);
+        otp.set_resend_interval(self.otp.resend_interval);
+        otp.set_max_resends(self.otp.max_resends);
+        session.set_keep_alive(self.session.timeout);
+        session.set_max_per_user(self.session.max_per_user);
+        session.set_ip_binding(self.session.ip_binding);
+        session.set_login_policy(self.session.login_policy);
+        session.set_reauth_after(self.session.reauth_after);
+        session.set_id_format(self.session.id_format);
+        session.set_nanoid(self.session.nanoid_length, &self.session.nanoid_alphabet)?;
+
+        Ok(())
+    }
+
+    /// load the config file, with environment overrides, and return a watcher that reloads it when it changes
+    pub fn watch<P: AsRef<Path>>(path: P) -> Result<ConfigWatcher> {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path)?;
+        let config = Config::load(&path)?;
+
+        Ok(ConfigWatcher {
+            path,
+            config,
+            modified,
+        })
+    }
+}
+
+// the toml and yaml layout; every key is optional so files only list what they change
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    otp: FileOtpConfig,
+    session: FileSessionConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileOtpConfig {
+    timeout: Option<u64>,
+    code_length: Option<usize>,
+    max_per_user: Option<usize>,
+    /// seconds
+    sweep_interval: Option<u64>,
+    /// seconds
+    resend_interval: Option<u64>,
+    max_resends: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileSessionConfig {
+    timeout: Option<u64>,
+    max_per_user: Option<usize>,
+    /// seconds
+    sweep_interval: Option<u64>,
+    /// off, warn or strict
+    ip_binding: Option<String>,
+    /// allow, deny or kick_oldest
+    login_policy: Option<String>,
+    /// seconds
+    reauth_after: Option<u64>,
+    /// hex, uuid_v4, uuid_v7, ulid or nanoid
+    id_format: Option<String>,
+    nanoid_length: Option<usize>,
+    nanoid_alphabet: Option<String>,
+}
+
+impl FileConfig {
+    fn into_config(self) -> Result<Config> {
+        let mut config = Config::default();
+        let (otp, session) = (self.otp, self.session);
+        config.otp.timeout = otp.timeout.unwrap_or(config.otp.timeout);
+        config.otp.code_length = otp.code_length.unwrap_or(config.otp.code_length);
+        config.otp.max_per_user = otp.max_per_user;
+        if let Some(seconds) = otp.sweep_interval {
+            config.otp.sweep_interval = Duration::from_secs(seconds);
+        }
+        config.otp.resend_interval = otp.resend_interval.unwrap_or(config.otp.resend_interval);
+        config.otp.max_resends = otp.max_resends.unwrap_or(config.otp.max_resends);
+        config.session.timeout = session.timeout.unwrap_or(config.session.timeout);
+        config.session.max_per_user = session.max_per_user;
+        if let Some(seconds) = session.sweep_interval {
+            config.session.sweep_interval = Duration::from_secs(seconds);
+        }
+        if let Some(binding) = session.ip_binding {
+            config.session.ip_binding = binding.parse()?;
+        }
+        if let Some(policy) = session.login_policy {
+            config.session.login_policy = policy.parse()?;
+        }
+        config.session.reauth_after = session.reauth_after;
+        if let Some(format) = session.id_format {
+            config.session.id_format = format.parse()?;
+        }
+        config.session.nanoid_length = session
+            .nanoid_length
+            .unwrap_or(config.session.nanoid_length);
+        if let Some(alphabet) = session.nanoid_alphabet {
+            config.session.nanoid_alphabet = alphabet;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    Ok(fs::metadata(path)?.modified()?)
+}
+
+/// tracks a config file and reloads it on demand (e.g. SIGHUP) or when its modified time changes
+#[derive(Debug, Clone)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    config: Config,
+    modified: SystemTime,
+}
+
+impl ConfigWatcher {
+    /// return the most recently loaded config
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// re-read the file; on error the current config is kept
+    pub fn reload(&mut self) -> Result<&Config> {
+        let modified = modified(&self.path)?;
+        self.config = Config::load(&self.path)?;
+        self.modified = modified;
+        info!("loaded config from {:?}", self.path);
+
+        Ok(&self.config)
+    }
+
+    /// reload if the file has changed since the last load; return true if it was reloaded
+    pub fn poll(&mut self) -> Result<bool> {
+        if modified(&self.path)? == self.modified {
+            return Ok(false);
+        }
+
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// watch the file on a background thread and apply each change to the stores; set the returned flag to stop
+    pub fn spawn(mut self, otp: Otp, session: Session) -> Result<Arc<AtomicBool>> {
+        self.config.apply(&otp, &session)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        thread::Builder::new()
+            .name("config-watch".to_string())
+            .spawn(move || {
+                while !flag.load(Ordering::SeqCst) {
+                    thread::sleep(WATCH_INTERVAL);
+                    match self.poll() {
+                        Ok(true) => {
+                            if let Err(e) = self.config.apply(&otp, &session) {
+                                error!("config apply failed: {}", e);
+                            }
+                        }
+                        Ok(false) => (),
+                        Err(e) => error!("config reload failed: {}", e),
+                    }
+                }
+            })?;
+
+        Ok(stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let text =
+            "# short otps\notp_timeout = 120\n\notp_code_length=8\nsession_max_per_user = 5\n";
+        let config = Config::parse(text).unwrap();
+        assert_eq!(config.otp.timeout, 120);
+        assert_eq!(config.otp.code_length, 8);
+        assert_eq!(config.session.timeout, crate::SESSION_TIMEOUT);
+        assert_eq!(config.session.max_per_user, Some(5));
+        assert_eq!(config.otp.sweep_interval, SWEEP_INTERVAL);
+
+        assert!(Config::parse("otp_timeout").is_err());
+        assert!(Config::parse("otp_timeout = soon").is_err());
+        assert!(Config::parse("colour = blue").is_err());
+        assert!(Config::parse("session_timeout = 0").is_err());
+        assert!(Config::parse("otp_code_length = 12").is_err());
+        let config = Config::parse("session_login_policy = kick_oldest").unwrap();
+        assert_eq!(config.session.login_policy, LoginPolicy::KickOldest);
+        assert!(Config::parse("session_login_policy = kick").is_err());
+        let config = Config::parse("session_reauth_after = 900").unwrap();
+        assert_eq!(config.session.reauth_after, Some(900));
+        assert!(Config::parse("session_reauth_after = 0").is_err());
+        let config = Config::parse("session_id_format = uuid_v7").unwrap();
+        assert_eq!(config.session.id_format, IdFormat::UuidV7);
+        let config = Config::parse("session_id_format = ulid").unwrap();
+        assert_eq!(config.session.id_format, IdFormat::Ulid);
+        assert!(Config::parse("session_id_format = uuid").is_err());
+        let config =
+            Config::parse("session_id_format = nanoid\nsession_nanoid_length = 14").unwrap();
+        assert_eq!(
+            (config.session.id_format, config.session.nanoid_length),
+            (IdFormat::Nanoid, 14)
+        );
+        assert!(Config::parse("session_nanoid_alphabet = abc/").is_err());
+        assert!(Config::parse("session_nanoid_length = 8").is_err());
+        let config = Config::parse("otp_resend_interval = 60\notp_max_resends = 5").unwrap();
+        assert_eq!(
+            (config.otp.resend_interval, config.otp.max_resends),
+            (60, 5)
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(604_800));
+        assert!(parse_duration("7w").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn parse_toml() {
+        let text = "[otp]\ntimeout = 120\ncode_length = 8\n\n[session]\nmax_per_user = 5\nsweep_interval = 30\n";
+        let config = Config::parse_toml(text).unwrap();
+        assert_eq!(config.otp.timeout, 120);
+        assert_eq!(config.otp.code_length, 8);
+        assert_eq!(config.session.max_per_user, Some(5));
+        assert_eq!(config.session.sweep_interval, Duration::from_secs(30));
+        assert_eq!(config.session.timeout, crate::SESSION_TIMEOUT);
+        assert_eq!(config.session.ip_binding, IpBinding::Off);
+
+        let config = Config::parse_toml("[session]\nip_binding = \"strict\"\n").unwrap();
+        assert_eq!(config.session.ip_binding, IpBinding::Strict);
+        assert!(Config::parse_toml("[session]\nip_binding = \"loose\"\n").is_err());
+        assert!(Config::parse_toml("[otp]\ncolour = \"blue\"\n").is_err());
+        assert!(Config::parse_toml("[session]\ntimeout = 0\n").is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn parse_yaml() {
+        let text = "otp:\n  timeout: 120\nsession:\n  timeout: 600\n";
+        let config = Config::parse_yaml(text).unwrap();
+        assert_eq!(config.otp.timeout, 120);
+        assert_eq!(config.session.timeout, 600);
+        assert_eq!(config.otp.code_length, OTP_CODE_LENGTH);
+    }
+
+    #[test]
+    fn env() {
+        let vars = |name: &str| match name {
+            "OTP_TIMEOUT" => Some("90".to_string()),
+            "SESSION_MAX_PER_USER" => Some(" 3 ".to_string()),
+            _ => None,
+        };
+        let config = Config::default().with_vars(vars).unwrap();
+        assert_eq!(config.otp.timeout, 90);
+        assert_eq!(config.session.max_per_user, Some(3));
+        assert_eq!(config.session.timeout, crate::SESSION_TIMEOUT);
+
+        let bad = |name: &str| (name == "OTP_CODE_LENGTH").then(|| "six".to_string());
+        let err = Config::default().with_vars(bad).unwrap_err();
+        assert_eq!(err.to_string(), "OTP_CODE_LENGTH: six is not a number");
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let otp = OtpConfig {
+            timeout: MAX_TIMEOUT + 1,
+            ..Default::default()
+        };
+        assert!(otp.validate().is_err());
+
+        let session = SessionConfig {
+            max_per_user: Some(0),
+            ..Default::default()
+        };
+        assert!(session.validate().is_err());
+
+        let session = SessionConfig {
+            sweep_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        assert!(session.validate().is_err());
+    }
+
+    #[test]
+    fn apply_keeps_sessions() {
+        let otp = Otp::new();
+        let mut session = Session::new();
+        let code = session.create_user_session("sally").unwrap();
+        let expires = session.get(&code, "sally").unwrap().expires;
+
+        let mut config = Config::default();
+        config.session.timeout = 60;
+        config.otp.code_length = 4;
+        config.apply(&otp, &session).unwrap();
+
+        assert_eq!(session.keep_alive(), 60);
+        assert_eq!(otp.generate_code().len(), 4);
+        assert_eq!(session.get(&code, "sally").unwrap().expires, expires);
+    }
+
+    #[test]
+    fn watch_reload() {
+        let path = std::env::temp_dir().join(format!("otp-session-{}.conf", fastrand::u64(..)));
+        fs::write(&path, "otp_timeout = 120\n").unwrap();
+
+        let mut watcher = Config::watch(&path).unwrap();
+        assert_eq!(watcher.config().otp.timeout, 120);
+        assert!(!watcher.poll().unwrap());
+
+        // a bad file keeps the last good config
+        fs::write(&path, "otp_timeout = 0\n").unwrap();
+        assert!(watcher.reload().is_err());
+        assert_eq!(watcher.config().otp.timeout, 120);
+
+        fs::write(&path, "otp_timeout = 90\n").unwrap();
+        watcher.reload().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.config().otp.timeout, 90);
+    }
+}
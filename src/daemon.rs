@@ -0,0 +1,664 @@
+/// the network daemon: runs the configured servers, reloads its config on SIGHUP and shuts down gracefully on SIGTERM
+/// or SIGINT. on wasm32-wasi, which has neither threads nor signals, the servers are polled from the run loop instead
+use crate::admin::AdminTokens;
+use crate::config::{Config, ConfigWatcher, WATCH_INTERVAL};
+use crate::db::DataStore;
+use crate::hasher::Hashing;
+use crate::health::ProbeServer;
+use crate::jsonrpc::JsonRpcServer;
+use crate::otp::Otp;
+#[cfg(target_os = "wasi")]
+use crate::poll::{PollServer, Service, POLL_INTERVAL};
+#[cfg(feature = "replication")]
+use crate::replication::Replicator;
+use crate::resp::RespServer;
+use crate::session::Session;
+use crate::snapshot::Snapshot;
+#[cfg(not(target_os = "wasi"))]
+use anyhow::bail;
+use anyhow::Result;
+use log::{error, info};
+#[cfg(not(target_os = "wasi"))]
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(target_os = "wasi"))]
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// default time allowed for the shutdown flush
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// json-rpc listen address
+    pub jsonrpc_addr: Option<String>,
+    /// when set, json-rpc connections must authenticate with this token, and resp connections must send it in `AUTH`
+    pub jsonrpc_token: Option<String>,
+    /// admin tokens accepted by the json-rpc, grpc and resp servers
+    pub admin: AdminTokens,
+    /// redis protocol listen address
+    pub resp_addr: Option<String>,
+    /// healthz/readyz listen address
+    pub probe_addr: Option<String>,
+    /// prometheus metrics listen address
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    /// send the metrics to a statsd agent
+    #[cfg(feature = "statsd")]
+    pub statsd: Option<crate::statsd::StatsdConfig>,
+    /// export traces and metrics over otlp, configured by the OTEL_* environment variables
+    #[cfg(feature = "otel")]
+    pub otel: bool,
+    /// endpoints sent signed lifecycle events
+    #[cfg(feature = "webhooks")]
+    pub webhooks: Vec<crate::webhook::Webhook>,
+    /// grpc listen address
+    #[cfg(feature = "grpc")]
+    pub grpc_addr: Option<std::net::SocketAddr>,
+    /// replication listen address for changes from peers
+    #[cfg(feature = "replication")]
+    pub replication_addr: Option<String>,
+    /// replication addresses of the peers to stream changes to
+    #[cfg(feature = "replication")]
+    pub peers: Vec<String>,
+    /// the secret every replication peer shares, at least replication::SECRET_MIN bytes; replication also needs tls
+    /// with a client_ca, whose roots sign every peer's certificate
+    #[cfg(feature = "replication")]
+    pub replication_secret: Option<String>,
+    /// when set, the json-rpc, redis protocol and grpc servers only accept tls connections
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// a plugin backend the stores are restored from on start and write their changes to
+    #[cfg(feature = "plugins")]
+    pub backend: Option<crate::plugin::BackendConfig>,
+    /// store settings, including the sweep intervals, used when there is no settings file
+    pub settings: Config,
+    /// settings file applied on start and reloaded on SIGHUP or when it changes
+    pub config: Option<PathBuf>,
+    /// snapshot file restored on start and flushed on shutdown
+    pub snapshot: Option<PathBuf>,
+    /// maximum time to spend flushing state on shutdown
+    pub shutdown_deadline: Duration,
+    /// how the stores hash codes and user names; fx only for trusted internal deployments
+    pub hashing: Hashing,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            jsonrpc_addr: Some("127.0.0.1:7400".to_string()),
+            jsonrpc_token: None,
+            admin: AdminTokens::new(),
+            resp_addr: None,
+            probe_addr: Some("127.0.0.1:7401".to_string()),
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            #[cfg(feature = "otel")]
+            otel: false,
+            #[cfg(feature = "webhooks")]
+            webhooks: Vec::new(),
+            #[cfg(feature = "grpc")]
+            grpc_addr: None,
+            #[cfg(feature = "replication")]
+            replication_addr: None,
+            #[cfg(feature = "replication")]
+            peers: Vec::new(),
+            #[cfg(feature = "replication")]
+            replication_secret: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "plugins")]
+            backend: None,
+            settings: Config::default(),
+            config: None,
+            snapshot: None,
+            shutdown_deadline: SHUTDOWN_DEADLINE,
+            hashing: Hashing::default(),
+        }
+    }
+}
+
+fn log_swept(count: usize) {
+    if count > 0 {
+        info!("swept {} expired items", count);
+    }
+}
+
+// bind the address, or take over a listening socket passed in as `fd:N`, e.g. by systemd or wasmtime's --tcplisten,
+// which is the only way a wasi daemon gets a socket
+fn bind(addr: &str) -> Result<TcpListener> {
+    #[cfg(any(unix, target_os = "wasi"))]
+    if let Some(fd) = addr.strip_prefix("fd:") {
+        use std::os::fd::FromRawFd;
+        let fd = fd.parse()?;
+        // safety: the runtime hands the socket to the daemon, which is then its only user
+        return Ok(unsafe { TcpListener::from_raw_fd(fd) });
+    }
+
+    Ok(TcpListener::bind(addr)?)
+}
+
+// bind now so address errors fail startup, then serve on a background thread; on wasi spawning fails with an error
+#[cfg(any(not(target_os = "wasi"), feature = "replication"))]
+fn spawn_listener<F>(name: &'static str, addr: &str, serve: F) -> Result<()>
+where
+    F: FnOnce(TcpListener) -> Result<()> + Send + 'static,
+{
+    let listener = bind(addr)?;
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            if let Err(e) = serve(listener) {
+                error!("{} server failed: {}", name, e);
+            }
+        })?;
+
+    Ok(())
+}
+
+// the blocking tcp servers the daemon runs
+#[cfg(not(target_os = "wasi"))]
+trait Listen: Send + 'static {
+    fn listen(&self, listener: TcpListener) -> Result<()>;
+
+    #[cfg(feature = "tls")]
+    fn listen_tls(&self, listener: TcpListener, tls: Arc<rustls::ServerConfig>) -> Result<()>;
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Listen for JsonRpcServer {
+    fn listen(&self, listener: TcpListener) -> Result<()> {
+        JsonRpcServer::listen(self, listener)
+    }
+
+    #[cfg(feature = "tls")]
+    fn listen_tls(&self, listener: TcpListener, tls: Arc<rustls::ServerConfig>) -> Result<()> {
+        JsonRpcServer::listen_tls(self, listener, tls)
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl Listen for RespServer {
+    fn listen(&self, listener: TcpListener) -> Result<()> {
+        RespServer::listen(self, listener)
+    }
+
+    #[cfg(feature = "tls")]
+    fn listen_tls(&self, listener: TcpListener, tls: Arc<rustls::ServerConfig>) -> Result<()> {
+        RespServer::listen_tls(self, listener, tls)
+    }
+}
+
+// serve with tls when configured, otherwise plain tcp
+#[cfg(not(target_os = "wasi"))]
+fn spawn_server<S: Listen>(
+    name: &'static str,
+    addr: &str,
+    server: S,
+    #[cfg(feature = "tls")] tls: Option<Arc<rustls::ServerConfig>>,
+) -> Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        return spawn_listener(name, addr, move |listener| server.listen_tls(listener, tls));
+    }
+
+    spawn_listener(name, addr, move |listener| server.listen(listener))
+}
+
+#[derive(Debug)]
+pub struct Daemon {
+    config: DaemonConfig,
+    otp: Otp,
+    session: Session,
+    probes: ProbeServer,
+    watcher: Option<ConfigWatcher>,
+    #[cfg(target_os = "wasi")]
+    poller: PollServer,
+    #[cfg(feature = "otel")]
+    otel: Option<crate::otel::OtelGuard>,
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl Daemon {
+    /// create the daemon with empty stores
+    pub fn new(config: DaemonConfig) -> Daemon {
+        let store = || DataStore::create().with_hashing(config.hashing);
+        let otp = Otp::builder()
+            .store(store())
+            .build()
+            .expect("the default otp settings are valid");
+        let session = Session::builder()
+            .store(store())
+            .build()
+            .expect("the default session settings are valid");
+        let probes = ProbeServer::new(otp.clone(), session.clone());
+
+        Daemon {
+            config,
+            otp,
+            session,
+            probes,
+            watcher: None,
+            #[cfg(target_os = "wasi")]
+            poller: PollServer::new(),
+            #[cfg(feature = "otel")]
+            otel: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// return the otp store; clones share the daemon's data
+    pub fn otp(&self) -> &Otp {
+        &self.otp
+    }
+
+    /// return the session store; clones share the daemon's data
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// return the shutdown flag; setting it has the same effect as SIGTERM
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutdown)
+    }
+
+    /// return the reload flag; setting it has the same effect as SIGHUP
+    pub fn reload_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.reload)
+    }
+
+    /// return the settings currently applied to the stores
+    pub fn config(&self) -> Config {
+        self.watcher
+            .as_ref()
+            .map(|watcher| watcher.config().clone())
+            .unwrap_or_else(|| self.config.settings.clone())
+    }
+
+    /// re-read the config file (when forced or changed) and apply it; existing items keep their expiration
+    pub fn reload(&mut self, force: bool) -> Result<()> {
+        if let Some(watcher) = &mut self.watcher {
+            let changed = if force {
+                watcher.reload().map(|_| true)?
+            } else {
+                watcher.poll()?
+            };
+
+            if changed {
+                watcher.config().apply(&self.otp, &self.session)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// load the config, restore the snapshot, if any, and start the configured servers on background threads, or on
+    /// wasi add them to the poller that run serves
+    pub fn start(&mut self) -> Result<()> {
+        match &self.config.config {
+            Some(path) => {
+                let watcher = Config::watch(path)?;
+                watcher.config().apply(&self.otp, &self.session)?;
+                self.watcher = Some(watcher);
+            }
+            None => self.config.settings.apply(&self.otp, &self.session)?,
+        }
+
+        if let Some(path) = &self.config.snapshot {
+            if path.exists() {
+                let snapshot = Snapshot::load(path)?;
+                let count = snapshot.restore(&mut self.otp, &mut self.session)?;
+                info!("restored {} items from {:?}", count, path);
+            }
+        }
+
+        #[cfg(feature = "plugins")]
+        if let Some(backend) = &self.config.backend {
+            let mut plugin = crate::plugin::Plugin::load(&backend.path, &backend.config)?;
+            let count = crate::plugin::restore(&mut plugin, &self.otp, &self.session)?;
+            info!(
+                "restored {} items from the backend {:?}",
+                count, backend.path
+            );
+            crate::plugin::attach(plugin, &self.otp, &self.session)?;
+        }
+
+        #[cfg(all(feature = "tls", target_os = "wasi"))]
+        if self.config.tls.is_some() {
+            anyhow::bail!("tls is not supported on wasi");
+        }
+
+        #[cfg(all(feature = "tls", not(target_os = "wasi")))]
+        let tls = match &self.config.tls {
+            Some(tls) => Some(tls.server_config()?),
+            None => None,
+        };
+
+        if let Some(addr) = &self.config.jsonrpc_addr {
+            let mut server = JsonRpcServer::new(self.otp.clone(), self.session.clone())
+                .with_admin(self.config.admin.clone());
+            if let Some(token) = &self.config.jsonrpc_token {
+                server = server.with_token(token);
+            }
+            #[cfg(not(target_os = "wasi"))]
+            spawn_server(
+                "json-rpc",
+                addr,
+                server,
+                #[cfg(feature = "tls")]
+                tls.clone(),
+            )?;
+            #[cfg(target_os = "wasi")]
+            self.poller.add(bind(addr)?, Service::JsonRpc(server))?;
+        }
+
+        if let Some(addr) = &self.config.resp_addr {
+            let mut server =
+                RespServer::new(self.session.clone()).with_admin(self.config.admin.clone());
+            if let Some(token) = &self.config.jsonrpc_token {
+                server = server.with_token(token);
+            }
+            #[cfg(not(target_os = "wasi"))]
+            spawn_server(
+                "resp",
+                addr,
+                server,
+                #[cfg(feature = "tls")]
+                tls.clone(),
+            )?;
+            #[cfg(target_os = "wasi")]
+            self.poller.add(bind(addr)?, Service::Resp(server))?;
+        }
+
+        #[cfg(feature = "otel")]
+        if self.config.otel {
+            self.otel = Some(crate::otel::init()?);
+        }
+
+        #[cfg(feature = "statsd")]
+        if let Some(statsd) = &self.config.statsd {
+            crate::statsd::install(statsd.clone())?;
+        }
+
+        #[cfg(feature = "webhooks")]
+        if !self.config.webhooks.is_empty() {
+            let dispatcher =
+                crate::webhook::WebhookDispatcher::start(self.config.webhooks.clone())?;
+            self.otp.events().subscribe(dispatcher.clone());
+            self.session.events().subscribe(dispatcher);
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = self.config.metrics_addr {
+            crate::metrics::install_prometheus(addr)?;
+        }
+
+        if let Some(addr) = &self.config.probe_addr {
+            let probes = self.probes.clone();
+            #[cfg(not(target_os = "wasi"))]
+            spawn_listener("probe", addr, move |listener| probes.listen(listener))?;
+            #[cfg(target_os = "wasi")]
+            self.poller.add(bind(addr)?, Service::Probe(probes))?;
+        }
+
+        #[cfg(feature = "replication")]
+        if self.config.replication_addr.is_some() || !self.config.peers.is_empty() {
+            let Some(secret) = &self.config.replication_secret else {
+                bail!("replication needs a replication_secret");
+            };
+            let replicator =
+                Replicator::new(self.otp.clone(), self.session.clone(), secret.as_bytes())?;
+            let replicator = self.replication_tls(replicator)?;
+            if let Some(addr) = &self.config.replication_addr {
+                let replicator = replicator.clone();
+                spawn_listener("replication", addr, move |listener| {
+                    replicator.listen(listener)
+                })?;
+            }
+
+            for peer in &self.config.peers {
+                replicator.connect(peer)?;
+            }
+        }
+
+        #[cfg(feature = "grpc")]
+        if let Some(addr) = self.config.grpc_addr {
+            let runtime = tokio::runtime::Runtime::new()?;
+            let otp = self.otp.clone();
+            let session = self.session.clone();
+            let admin = self.config.admin.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.config.tls.clone();
+            thread::Builder::new()
+                .name("grpc".to_string())
+                .spawn(move || {
+                    #[cfg(feature = "tls")]
+                    let resp = match &tls {
+                        Some(tls) => {
+                            runtime.block_on(crate::grpc::serve_tls(addr, otp, session, admin, tls))
+                        }
+                        None => runtime.block_on(crate::grpc::serve(addr, otp, session, admin)),
+                    };
+                    #[cfg(not(feature = "tls"))]
+                    let resp = runtime.block_on(crate::grpc::serve(addr, otp, session, admin));
+
+                    if let Err(e) = resp {
+                        error!("grpc server failed: {}", e);
+                    }
+                })?;
+        }
+
+        Ok(())
+    }
+
+    // replication only runs over mutual tls, so peers are verified by certificate as well as the secret
+    #[cfg(all(feature = "replication", feature = "tls"))]
+    fn replication_tls(&self, replicator: Replicator) -> Result<Replicator> {
+        match &self.config.tls {
+            Some(tls) => Ok(replicator.with_tls(tls.server_config()?, tls.client_config()?)),
+            None => bail!("replication needs tls with a client_ca"),
+        }
+    }
+
+    #[cfg(all(feature = "replication", not(feature = "tls")))]
+    fn replication_tls(&self, _replicator: Replicator) -> Result<Replicator> {
+        bail!("replication needs the tls feature")
+    }
+
+    /// start the daemon and block until SIGTERM/SIGINT (or the shutdown flag), then shut down gracefully; wasi has no
+    /// signals, so there only the flag stops it
+    pub fn run(&mut self) -> Result<()> {
+        #[cfg(not(target_os = "wasi"))]
+        {
+            signal_hook::flag::register(SIGTERM, self.shutdown_flag())?;
+            signal_hook::flag::register(SIGINT, self.shutdown_flag())?;
+            signal_hook::flag::register(SIGHUP, self.reload_flag())?;
+        }
+
+        self.start()?;
+        info!("daemon started");
+
+        let mut polled = Instant::now();
+        let mut otp_swept = Instant::now();
+        let mut session_swept = Instant::now();
+        while !self.shutdown.load(Ordering::SeqCst) {
+            #[cfg(not(target_os = "wasi"))]
+            thread::sleep(Duration::from_millis(100));
+            // without threads the servers are served here, sleeping only while they are idle
+            #[cfg(target_os = "wasi")]
+            if !self.poller.poll() {
+                thread::sleep(POLL_INTERVAL);
+            }
+
+            let config = self.config();
+            if otp_swept.elapsed() >= config.otp.sweep_interval {
+                otp_swept = Instant::now();
+                log_swept(self.otp.clone().purge_expired());
+            }
+            if session_swept.elapsed() >= config.session.sweep_interval {
+                session_swept = Instant::now();
+                log_swept(self.session.clone().purge_expired());
+            }
+
+            let force = self.reload.swap(false, Ordering::SeqCst);
+            if force || polled.elapsed() >= WATCH_INTERVAL {
+                polled = Instant::now();
+                if let Err(e) = self.reload(force) {
+                    error!("config reload failed: {}", e);
+                }
+            }
+        }
+
+        self.shutdown()
+    }
+
+    /// remove the expired items from both stores; return the number removed
+    pub fn sweep(&self) -> usize {
+        let count = self.otp.clone().purge_expired() + self.session.clone().purge_expired();
+        log_swept(count);
+        count
+    }
+
+    /// stop accepting creates, flush state to the snapshot and log the final stats within the deadline; wasi has no
+    /// thread to bound the flush with, so there it runs to completion
+    pub fn shutdown(&self) -> Result<()> {
+        info!("shutting down");
+        self.probes.set_ready(false);
+        self.otp.set_read_only(true);
+        self.session.set_read_only(true);
+
+        #[cfg(target_os = "wasi")]
+        if let Some(path) = &self.config.snapshot {
+            Snapshot::capture(&self.otp, &self.session).save(path)?;
+        }
+
+        #[cfg(not(target_os = "wasi"))]
+        {
+            let (tx, rx) = mpsc::channel();
+            let otp = self.otp.clone();
+            let session = self.session.clone();
+            let snapshot = self.config.snapshot.clone();
+            thread::spawn(move || {
+                let result = match snapshot {
+                    Some(path) => Snapshot::capture(&otp, &session).save(path),
+                    None => Ok(()),
+                };
+                let _ = tx.send(result);
+            });
+
+            let deadline = self.config.shutdown_deadline;
+            match rx.recv_timeout(deadline) {
+                Ok(result) => result?,
+                Err(_) => bail!(
+                    "shutdown deadline of {:?} exceeded flushing state",
+                    deadline
+                ),
+            }
+        }
+
+        info!(
+            "final stats: otp {}, session {}",
+            self.otp.health().to_json(),
+            self.session.health().to_json()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SessionItem;
+
+    fn create_config() -> DaemonConfig {
+        DaemonConfig {
+            jsonrpc_addr: None,
+            probe_addr: None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn shutdown_stops_creates() {
+        let daemon = Daemon::new(create_config());
+        let mut session = daemon.session().clone();
+        let code = session.create_user_session("sally").unwrap();
+
+        daemon.shutdown().unwrap();
+        assert!(session.create_user_session("jack").is_err());
+        assert!(session.is_valid(&code, "sally"));
+        assert!(!daemon.probes.is_ready());
+    }
+
+    #[test]
+    fn sweep() {
+        let daemon = Daemon::new(create_config());
+        let mut session = daemon.session().clone();
+        session.create_user_session("sally").unwrap();
+        let mut item = SessionItem::new("abc", "jack", 10);
+        item.expires = 1;
+        session.put(item).unwrap();
+
+        assert_eq!(daemon.sweep(), 1);
+        assert_eq!(session.dbsize(), 1);
+    }
+
+    #[test]
+    fn reload_config() {
+        let path = std::env::temp_dir().join(format!("otp-session-{}.conf", fastrand::u64(..)));
+        std::fs::write(&path, "session_timeout = 600\n").unwrap();
+        let config = DaemonConfig {
+            config: Some(path.clone()),
+            ..create_config()
+        };
+
+        let mut daemon = Daemon::new(config);
+        daemon.start().unwrap();
+        assert_eq!(daemon.session().keep_alive(), 600);
+        let code = daemon
+            .session()
+            .clone()
+            .create_user_session("sally")
+            .unwrap();
+
+        std::fs::write(&path, "session_timeout = 300\notp_code_length = 8\n").unwrap();
+        daemon.reload(true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(daemon.config().session.timeout, 300);
+        assert_eq!(daemon.session().keep_alive(), 300);
+        assert_eq!(daemon.otp().generate_code().len(), 8);
+        assert!(daemon.session().is_valid(&code, "sally"));
+    }
+
+    #[test]
+    fn shutdown_flushes_snapshot() {
+        let path = std::env::temp_dir().join(format!("otp-session-{}.json", fastrand::u64(..)));
+        let config = DaemonConfig {
+            snapshot: Some(path.clone()),
+            ..create_config()
+        };
+
+        let daemon = Daemon::new(config.clone());
+        let code = daemon
+            .session()
+            .clone()
+            .create_user_session("sally")
+            .unwrap();
+        daemon.shutdown().unwrap();
+
+        let mut daemon = Daemon::new(config);
+        daemon.start().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(daemon.session().is_valid(&code, "sally"));
+    }
+}
@@ -1,99 +1,1950 @@
 /// a thread safe in-memory db common to otp and session
-use anyhow::Result;
-use hashbrown::HashMap;
-use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::clock::{unix_now, Clock, Instant, SystemClock};
+use crate::hash::random_hex;
+use crate::hasher::{Hashing, StoreHasher};
+use crate::health::Health;
+use anyhow::{bail, Result};
+use hashbrown::{HashMap, HashSet};
+use otp_session_core::{code, expiry};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-#[derive(Debug, Clone)]
+/// an item as the store holds it. fields added after the first snapshot version are optional in the serialized form,
+/// so older snapshots and peers still load; build items with a constructor or `..Default::default()` so new fields
+/// don't break callers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SessionItem {
     pub code: String,
     pub user: String,
     pub expires: u64,
+    /// app data kept with the item and returned by get, e.g. a device name; added in snapshot version 2
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
+}
+
+/// how long the store remembers why a code stopped being valid, in seconds
+pub const TOMBSTONE_TTL: u64 = 3_600;
+
+/// ends a user's namespace, e.g. their tenant, in the user name: acme:sally is in the acme namespace
+pub const NAMESPACE_SEPARATOR: char = ':';
+
+/// the most items DataStore::purge_expired examines under one lock
+pub const PURGE_CHUNK: usize = 10_000;
+
+/// how long a token from DataStore::request_clear can confirm a clear, in seconds
+pub const CLEAR_TOKEN_TTL: u64 = 60;
+
+/// the outcome of validating a code, so callers can tell users why it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Validation {
+    Valid,
+    /// the code timed out
+    Expired,
+    /// the code was never issued, or was removed long enough ago to be forgotten
+    NotFound,
+    /// the code was already used by a consume
+    Consumed,
+    /// the code was removed, alone or with the rest of the user's codes
+    Revoked,
+    /// the user is locked; no code validates until they are unlocked
+    Locked,
+    /// the session is bound to another network and the ip binding is strict
+    WrongNetwork,
+    /// the session is bound to another device fingerprint; the app should ask the user to log in again
+    WrongFingerprint,
+    /// the session has been idle past the re-auth window; fine for reads, but sensitive operations need a fresh login
+    NeedsReauth,
+}
+
+impl Validation {
+    /// return true only for a valid code
+    pub fn is_valid(&self) -> bool {
+        *self == Validation::Valid
+    }
+
+    /// return true if the code may still be used to read, i.e. it is valid or only needs re-authentication
+    pub fn allows_read(&self) -> bool {
+        matches!(self, Validation::Valid | Validation::NeedsReauth)
+    }
+
+    /// return the result name used in logs and responses
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Validation::Valid => "valid",
+            Validation::Expired => "expired",
+            Validation::NotFound => "not_found",
+            Validation::Consumed => "consumed",
+            Validation::Revoked => "revoked",
+            Validation::Locked => "locked",
+            Validation::WrongNetwork => "wrong_network",
+            Validation::WrongFingerprint => "wrong_fingerprint",
+            Validation::NeedsReauth => "needs_reauth",
+        }
+    }
+}
+
+/// a change to the store as seen by subscribers; touches are puts with a later expiration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// the item was stored in the user's generation
+    Put {
+        item: SessionItem,
+        generation: u64,
+    },
+    Remove {
+        code: String,
+        user: String,
+    },
+    /// the user moved to this generation
+    Generation {
+        user: String,
+        generation: u64,
+    },
+    /// the user was locked, or unlocked
+    Lock {
+        user: String,
+        locked: bool,
+    },
+}
+
+/// the error from DataStore::insert when the user already holds a live item with the code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlreadyExists {
+    pub user: String,
+}
+
+impl fmt::Display for AlreadyExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already has an item with this code", self.user)
+    }
+}
+
+impl std::error::Error for AlreadyExists {}
+
+/// the error from DataStore::put_limited when the user already holds the limit of live items and none may be evicted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitReached {
+    pub user: String,
+    pub held: usize,
+}
+
+impl fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already has {} items", self.user, self.held)
+    }
+}
+
+impl std::error::Error for LimitReached {}
+
+/// the store's items as they were when DataStore::iter was called or the SnapshotView taken, so the store is not locked
+/// while they are read. items that expired but have not been purged yet are included unless filtered with unexpired
+#[derive(Debug, Clone)]
+pub struct Items {
+    items: std::vec::IntoIter<SessionItem>,
+    now: u64,
+}
+
+impl Items {
+    /// skip the items that had expired when the snapshot was taken
+    pub fn unexpired(self) -> impl Iterator<Item = SessionItem> {
+        let now = self.now;
+        self.filter(move |item| !item.has_expired_at(now))
+    }
+}
+
+impl Iterator for Items {
+    type Item = SessionItem;
+
+    fn next(&mut self) -> Option<SessionItem> {
+        self.items.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.items.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Items {}
+
+/// where a purge pass by DataStore::purge_expired_chunk left off; a default cursor starts a new pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeCursor {
+    shard: usize,
+    // the records of the shard already examined and kept
+    offset: usize,
+}
+
+/// a valid item borrowed from the store by DataStore::get_ref, read in place instead of copied. it holds a read lock
+/// on the item's shard until dropped, so keep it briefly and don't write to the store for the same user meanwhile
+#[derive(Debug)]
+pub struct ItemRef<'a> {
+    map: RwLockReadGuard<'a, Table>,
+    code: &'a str,
+    user: &'a str,
+    expires: u64,
+}
+
+impl<'a> ItemRef<'a> {
+    /// return the item's code
+    pub fn code(&self) -> &'a str {
+        self.code
+    }
+
+    /// return the item's user
+    pub fn user(&self) -> &'a str {
+        self.user
+    }
+
+    /// return the unix time the item expires
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+
+    /// return the app data kept with the item
+    pub fn meta(&self) -> &BTreeMap<String, String> {
+        let record = self.map.get(lookup(&(self.code, self.user)));
+        &record.expect("the item stays while it is borrowed").meta
+    }
+
+    /// copy the item out, e.g. to keep it after the lock is released
+    pub fn to_item(&self) -> SessionItem {
+        SessionItem {
+            code: self.code.to_string(),
+            user: self.user.to_string(),
+            expires: self.expires,
+            meta: self.meta().clone(),
+        }
+    }
+}
+
+/// a point in time, read-only view of a DataStore from DataStore::snapshot; it is Send and Sync, and holds no lock
+#[derive(Debug, Clone)]
+pub struct SnapshotView {
+    // each shard's records, in shard order
+    records: Vec<Arc<Map<Key, Record>>>,
+    generations: Map<String, u64>,
+    now: u64,
+}
+
+impl SnapshotView {
+    /// return the unix time the view was taken, by the store's clock
+    pub fn taken_at(&self) -> u64 {
+        self.now
+    }
+
+    /// return the number of items, including expired and revoked ones not purged yet, as DataStore::dbsize does
+    pub fn len(&self) -> usize {
+        self.records.iter().map(|records| records.len()).sum()
+    }
+
+    /// return true if the store was empty
+    pub fn is_empty(&self) -> bool {
+        self.records.iter().all(|records| records.is_empty())
+    }
+
+    /// return the item if it was valid when the view was taken
+    pub fn get(&self, code: &str, user: &str) -> Option<SessionItem> {
+        let record = self.records[shard_of(user)].get(lookup(&(code, user)))?;
+        if record.expires <= self.now || is_stale(&self.generations, user, record) {
+            return None;
+        }
+
+        Some(record.item(code.to_string(), user.to_string()))
+    }
+
+    /// return the items, skipping revoked ones; expired ones are included unless filtered with unexpired
+    pub fn iter(&self) -> Items {
+        let items: Vec<SessionItem> = self
+            .records
+            .iter()
+            .flat_map(|records| records.iter())
+            .filter(|(key, record)| !is_stale(&self.generations, &key.user, record))
+            .map(|(key, record)| key.item(record))
+            .collect();
+
+        Items {
+            items: items.into_iter(),
+            now: self.now,
+        }
+    }
+}
+
+// the rest of a stored item, its code and user being in the key, and the generation of its user when it was stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    expires: u64,
+    meta: BTreeMap<String, String>,
+    generation: u64,
+}
+
+impl Record {
+    // rebuild the item from its code and user
+    fn item(&self, code: String, user: String) -> SessionItem {
+        SessionItem {
+            code,
+            user,
+            expires: self.expires,
+            meta: self.meta.clone(),
+        }
+    }
+}
+
+// a code as the store keeps it: decimal codes like otps, lowercase hex codes like session codes, lowercase uuids and
+// upper case ulids are packed into integers, without the heap buffer of a String; anything else stays text
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Code {
+    Digits { value: u64, len: u8 },
+    Hex { bytes: [u8; 16], len: u8 },
+    Uuid([u8; 16]),
+    Ulid([u8; 16]),
+    Text(Box<str>),
+}
+
+// a code borrowed for lookups. a code always packs the same way, so it hashes and compares like the Code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CodeRef<'a> {
+    Digits { value: u64, len: u8 },
+    Hex { bytes: [u8; 16], len: u8 },
+    Uuid([u8; 16]),
+    Ulid([u8; 16]),
+    Text(&'a str),
+}
+
+impl<'a> CodeRef<'a> {
+    fn parse(code: &'a str) -> CodeRef<'a> {
+        let len = code.len();
+        if (1..=19).contains(&len) && code.bytes().all(|b| b.is_ascii_digit()) {
+            if let Ok(value) = code.parse() {
+                let len = len as u8;
+                return CodeRef::Digits { value, len };
+            }
+        }
+        let hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+        if (1..=32).contains(&len) && code.bytes().all(hex) {
+            if let Ok(value) = u128::from_str_radix(code, 16) {
+                let (bytes, len) = (value.to_be_bytes(), len as u8);
+                return CodeRef::Hex { bytes, len };
+            }
+        }
+        // 8-4-4-4-12 hex digits
+        let dash = |i: usize| [8, 13, 18, 23].contains(&i);
+        let uuid = |(i, b): (usize, u8)| if dash(i) { b == b'-' } else { hex(b) };
+        if len == 36 && code.bytes().enumerate().all(uuid) {
+            let digits: String = code.chars().filter(|&c| c != '-').collect();
+            if let Ok(value) = u128::from_str_radix(&digits, 16) {
+                return CodeRef::Uuid(value.to_be_bytes());
+            }
+        }
+        if let Some(value) = code::decode_ulid(code) {
+            return CodeRef::Ulid(value.to_be_bytes());
+        }
+
+        CodeRef::Text(code)
+    }
+}
+
+impl Code {
+    fn parse(code: &str) -> Code {
+        match CodeRef::parse(code) {
+            CodeRef::Digits { value, len } => Code::Digits { value, len },
+            CodeRef::Hex { bytes, len } => Code::Hex { bytes, len },
+            CodeRef::Uuid(bytes) => Code::Uuid(bytes),
+            CodeRef::Ulid(bytes) => Code::Ulid(bytes),
+            CodeRef::Text(text) => Code::Text(text.into()),
+        }
+    }
+
+    fn as_ref(&self) -> CodeRef<'_> {
+        match self {
+            Code::Digits { value, len } => CodeRef::Digits {
+                value: *value,
+                len: *len,
+            },
+            Code::Hex { bytes, len } => CodeRef::Hex {
+                bytes: *bytes,
+                len: *len,
+            },
+            Code::Uuid(bytes) => CodeRef::Uuid(*bytes),
+            Code::Ulid(bytes) => CodeRef::Ulid(*bytes),
+            Code::Text(text) => CodeRef::Text(text),
+        }
+    }
+}
+
+impl Hash for Code {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_ref().hash(state)
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_ref() {
+            CodeRef::Digits { value, len } => write!(f, "{:0width$}", value, width = len as usize),
+            CodeRef::Hex { bytes, len } => {
+                let value = u128::from_be_bytes(bytes);
+                write!(f, "{:0width$x}", value, width = len as usize)
+            }
+            CodeRef::Uuid(bytes) => {
+                let hex = format!("{:032x}", u128::from_be_bytes(bytes));
+                let groups = [
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..],
+                ];
+                f.write_str(&groups.join("-"))
+            }
+            CodeRef::Ulid(bytes) => f.write_str(&code::encode_ulid(u128::from_be_bytes(bytes))),
+            CodeRef::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+// a record's key, its code and user. lookups borrow keys as `dyn KeyPair`, so a `(&str, &str)` finds a record
+// without building a key and validating a stored code allocates nothing. the table interns the user, so the keys of
+// a user's records share one copy of the name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    code: Code,
+    user: Arc<str>,
+}
+
+impl Key {
+    fn new(code: &str, user: &str) -> Key {
+        Key {
+            code: Code::parse(code),
+            user: user.into(),
+        }
+    }
+
+    // the item stored under the key
+    fn item(&self, record: &Record) -> SessionItem {
+        record.item(self.code.to_string(), self.user.to_string())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.code, self.user)
+    }
+}
+
+// the code and user of a key, owned or borrowed; both hash and compare by the pair
+trait KeyPair {
+    fn pair(&self) -> (CodeRef<'_>, &str);
+}
+
+impl KeyPair for Key {
+    fn pair(&self) -> (CodeRef<'_>, &str) {
+        (self.code.as_ref(), &self.user)
+    }
+}
+
+impl KeyPair for (&Code, &str) {
+    fn pair(&self) -> (CodeRef<'_>, &str) {
+        (self.0.as_ref(), self.1)
+    }
+}
+
+impl KeyPair for (&str, &str) {
+    fn pair(&self) -> (CodeRef<'_>, &str) {
+        (CodeRef::parse(self.0), self.1)
+    }
+}
+
+impl<'a> Borrow<dyn KeyPair + 'a> for Key {
+    fn borrow(&self) -> &(dyn KeyPair + 'a) {
+        self
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pair().hash(state)
+    }
+}
+
+impl Hash for dyn KeyPair + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pair().hash(state)
+    }
+}
+
+impl PartialEq for dyn KeyPair + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.pair() == other.pair()
+    }
+}
+
+impl Eq for dyn KeyPair + '_ {}
+
+// borrow a code and user as a key, e.g. `map.get(lookup(&(code, user)))`
+fn lookup<'a>(pair: &'a (&'a str, &'a str)) -> &'a (dyn KeyPair + 'a) {
+    pair
+}
+
+// the number of shards a store's items are spread over by user, so validations of different users rarely wait on
+// the same lock
+const SHARDS: usize = 16;
+
+// pick the user's shard with an fnv-1a hash of the name, so it is the same in every store and process
+fn shard_of(user: &str) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in user.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    (hash % SHARDS as u64) as usize
+}
+
+// one shard of the store: the records by key, with an index of each user's codes so per-user operations don't scan
+// the store, and the users' tombstones, generations and locks. everything about a user lives in their shard, so
+// validating takes a single read lock. reads of the records go through deref; changes go through the methods that
+// keep the index up to date. the records are shared with any snapshot views and copied on the first change while one
+// is alive. the index doubles as the pool of interned user names
+#[derive(Debug, Default)]
+struct Table {
+    records: Arc<Map<Key, Record>>,
+    users: Map<Arc<str>, Codes>,
+    // why recently removed codes went away and when to forget them
+    tombstones: Map<Key, (Validation, u64)>,
+    // each user's current generation; items from earlier generations no longer validate
+    generations: Map<String, u64>,
+    locked: Set<String>,
+}
+
+// the store's maps and sets, hashed as the store was configured
+type Map<K, V> = HashMap<K, V, StoreHasher>;
+type Set<T> = HashSet<T, StoreHasher>;
+
+// the most codes a user's index entry holds in place before moving them to a set
+const INLINE_CODES: usize = 3;
+
+// a user's codes in the index. most users have one to three sessions, so those are kept in place without allocating
+// a set; the codes move to a boxed set once there are more, and back when compacting leaves few enough
+#[derive(Debug, Default)]
+struct Codes {
+    inline: [Option<Code>; INLINE_CODES],
+    spilled: Option<Box<Set<Code>>>,
+}
+
+impl Codes {
+    // add the code, spilling to a set with the hasher when the inline slots are full
+    fn insert(&mut self, code: Code, hasher: &StoreHasher) {
+        if let Some(set) = &mut self.spilled {
+            set.insert(code);
+            return;
+        }
+        if self.inline.iter().flatten().any(|c| *c == code) {
+            return;
+        }
+        match self.inline.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(code),
+            None => {
+                let mut set = Set::with_capacity_and_hasher(INLINE_CODES + 1, hasher.clone());
+                set.extend(self.inline.iter_mut().filter_map(Option::take));
+                set.insert(code);
+                self.spilled = Some(Box::new(set));
+            }
+        }
+    }
+
+    fn remove(&mut self, code: &Code) {
+        match &mut self.spilled {
+            Some(set) => {
+                set.remove(code);
+            }
+            None => {
+                if let Some(slot) = self.inline.iter_mut().find(|c| c.as_ref() == Some(code)) {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match &self.spilled {
+            Some(set) => set.is_empty(),
+            None => self.inline.iter().all(Option::is_none),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Code> {
+        let spilled = self.spilled.iter().flat_map(|set| set.iter());
+        self.inline.iter().flatten().chain(spilled)
+    }
+
+    // move the codes back in place if few enough are left, else give back the set's spare room
+    fn shrink_to_fit(&mut self) {
+        let Some(set) = &mut self.spilled else {
+            return;
+        };
+        if set.len() > INLINE_CODES {
+            set.shrink_to_fit();
+            return;
+        }
+        for (slot, code) in self.inline.iter_mut().zip(set.drain()) {
+            *slot = Some(code);
+        }
+        self.spilled = None;
+    }
+}
+
+impl Table {
+    fn insert(&mut self, mut key: Key, record: Record) -> Option<Record> {
+        match self.users.get_key_value(&*key.user) {
+            // share the name with the user's other records
+            Some((user, _)) => key.user = user.clone(),
+            None => {
+                self.users.insert(key.user.clone(), Codes::default());
+            }
+        }
+        let hasher = self.records.hasher();
+        if let Some(codes) = self.users.get_mut(&*key.user) {
+            codes.insert(key.code.clone(), hasher);
+        }
+        Arc::make_mut(&mut self.records).insert(key, record)
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Record> {
+        if !self.records.contains_key(key) {
+            return None;
+        }
+        let record = Arc::make_mut(&mut self.records).remove(key)?;
+        unindex(&mut self.users, key);
+        Some(record)
+    }
+
+    // take the records and their index, leaving the users' tombstones, generations and locks
+    fn take(&mut self) -> Arc<Map<Key, Record>> {
+        let hasher = self.records.hasher().clone();
+        self.users = Map::with_hasher(hasher.clone());
+        std::mem::replace(&mut self.records, Arc::new(Map::with_hasher(hasher)))
+    }
+
+    fn with_capacity(capacity: usize, hasher: &StoreHasher) -> Table {
+        Table {
+            records: Arc::new(Map::with_capacity_and_hasher(capacity, hasher.clone())),
+            users: Map::with_hasher(hasher.clone()),
+            tombstones: Map::with_hasher(hasher.clone()),
+            generations: Map::with_hasher(hasher.clone()),
+            locked: Set::with_hasher(hasher.clone()),
+        }
+    }
+
+    // move everything into new maps that hash with the hasher
+    fn rehash(&mut self, hasher: &StoreHasher) -> Table {
+        let mut table = Table::with_capacity(self.len(), hasher);
+        for (key, record) in self.take().iter() {
+            table.insert(key.clone(), record.clone());
+        }
+        table.tombstones.extend(self.tombstones.drain());
+        table.generations.extend(self.generations.drain());
+        table.locked.extend(self.locked.drain());
+        table
+    }
+
+    // give back the memory the records, index and tombstones no longer need
+    fn shrink_to_fit(&mut self) {
+        Arc::make_mut(&mut self.records).shrink_to_fit();
+        self.users.shrink_to_fit();
+        for codes in self.users.values_mut() {
+            codes.shrink_to_fit();
+        }
+        self.tombstones.shrink_to_fit();
+    }
+
+    // return the keys of the user's records
+    fn user_keys(&self, user: &str) -> Vec<Key> {
+        let Some((user, codes)) = self.users.get_key_value(user) else {
+            return Vec::new();
+        };
+
+        codes
+            .iter()
+            .map(|code| Key {
+                code: code.clone(),
+                user: user.clone(),
+            })
+            .collect()
+    }
+
+    fn generation(&self, user: &str) -> u64 {
+        self.generations.get(user).copied().unwrap_or(0)
+    }
+
+    // true if the record has not expired and is from the user's current generation
+    fn is_live(&self, user: &str, record: &Record, now: u64) -> bool {
+        !expiry::is_expired(record.expires, now) && !is_stale(&self.generations, user, record)
+    }
+
+    // the record for a new item, in its user's current generation
+    fn record(&self, item: SessionItem) -> Record {
+        Record {
+            expires: item.expires,
+            generation: self.generation(&item.user),
+            meta: item.meta,
+        }
+    }
+
+    // remember why the key was removed until the tombstone ttl passes
+    fn bury(&mut self, key: Key, reason: Validation, now: u64) {
+        let until = now.saturating_add(TOMBSTONE_TTL);
+        self.tombstones.insert(key, (reason, until));
+    }
+
+    // return why the code is or is not valid for the user at now
+    fn validate(&self, code: &str, user: &str, now: u64) -> Validation {
+        if self.locked.contains(user) {
+            return Validation::Locked;
+        }
+
+        // the hot path: look the pair up without building a key or copying the record
+        let key = (code, user);
+        match self.get(lookup(&key)) {
+            Some(record) if is_stale(&self.generations, user, record) => Validation::Revoked,
+            Some(record) if expiry::is_expired(record.expires, now) => Validation::Expired,
+            Some(_) => Validation::Valid,
+            None => match self.tombstones.get(lookup(&key)) {
+                Some((reason, _)) => *reason,
+                None => Validation::NotFound,
+            },
+        }
+    }
+
+    // examine up to limit records from the offset'th on, removing the expired ones as expired and stale ones as
+    // revoked; old tombstones are forgotten once the end is reached. removing doesn't move the other records, so the
+    // records examined and kept are skipped next time. return the number expired, the number examined and the offset
+    // to resume from, None at the end
+    fn purge_expired_chunk(
+        &mut self,
+        offset: usize,
+        limit: usize,
+        now: u64,
+    ) -> (usize, usize, Option<usize>) {
+        let (mut expired, mut stale) = (Vec::new(), Vec::new());
+        let mut examined = 0;
+        for (key, record) in self.records.iter().skip(offset).take(limit) {
+            examined += 1;
+            if record.expires <= now {
+                expired.push(key.clone());
+            } else if is_stale(&self.generations, &key.user, record) {
+                stale.push(key.clone());
+            }
+        }
+        let done = offset + examined >= self.records.len();
+        let kept = examined - expired.len() - stale.len();
+
+        let count = expired.len();
+        for (keys, reason) in [(stale, Validation::Revoked), (expired, Validation::Expired)] {
+            for key in keys {
+                self.remove(&key);
+                self.bury(key, reason, now);
+            }
+        }
+        if done {
+            self.tombstones.retain(|_, (_, until)| *until > now);
+        }
+
+        (count, examined, (!done).then_some(offset + kept))
+    }
+}
+
+impl std::ops::Deref for Table {
+    type Target = Map<Key, Record>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.records
+    }
+}
+
+// drop the key from its user's index, and the user once they have no codes
+fn unindex(users: &mut Map<Arc<str>, Codes>, key: &Key) {
+    if let Some(codes) = users.get_mut(&*key.user) {
+        codes.remove(&key.code);
+        if codes.is_empty() {
+            users.remove(&*key.user);
+        }
+    }
+}
+
+// true if the user has moved to a later generation since the record was stored
+fn is_stale(generations: &Map<String, u64>, user: &str, record: &Record) -> bool {
+    record.generation < generations.get(user).copied().unwrap_or(0)
 }
 
 #[derive(Debug, Clone)]
 pub struct DataStore {
-    db: Arc<RwLock<HashMap<String, u64>>>,
+    // the shards, each holding the users that shard_of picks for it
+    db: Arc<[RwLock<Table>]>,
+    read_only: Arc<AtomicBool>,
+    subscribers: Arc<Mutex<Vec<Sender<Change>>>>,
+    clock: Arc<dyn Clock>,
+    // the pending clear confirmation and when it expires
+    clear_token: Arc<Mutex<Option<(String, u64)>>>,
 }
 
 impl SessionItem {
     pub fn new(code: &str, user: &str, keep_alive: u64) -> SessionItem {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let expires = now.as_secs() + keep_alive;
+        SessionItem::created_at(code, user, keep_alive, unix_now())
+    }
 
+    /// create an item that expires keep_alive seconds after now, capped at the end of time
+    pub fn created_at(code: &str, user: &str, keep_alive: u64, now: u64) -> SessionItem {
         SessionItem {
             code: code.to_string(),
             user: user.to_string(),
-            expires,
+            expires: expiry::expires_at(now, keep_alive),
+            ..Default::default()
+        }
+    }
+
+    /// keep this value with the item, e.g. a device name
+    pub fn with_meta(mut self, key: &str, value: &str) -> SessionItem {
+        self.meta.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// return true if the session has expired by the system clock
+    pub fn has_expired(&self) -> bool {
+        self.has_expired_at(unix_now())
+    }
+
+    /// return true if the session has expired at the unix time
+    pub fn has_expired_at(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires, now)
+    }
+}
+
+impl DataStore {
+    /// create the data store
+    pub fn create() -> DataStore {
+        DataStore::with_capacity(0)
+    }
+
+    /// create the data store with room for capacity items before it grows, e.g. to ride out a login storm without
+    /// rehashing
+    pub fn with_capacity(capacity: usize) -> DataStore {
+        let hasher = StoreHasher::default();
+        let per_shard = (capacity + SHARDS - 1) / SHARDS;
+        DataStore {
+            db: (0..SHARDS)
+                .map(|_| RwLock::new(Table::with_capacity(per_shard, &hasher)))
+                .collect(),
+            read_only: Arc::new(AtomicBool::new(false)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            clock: Arc::new(SystemClock),
+            clear_token: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // the shard holding the user's items
+    fn shard(&self, user: &str) -> &RwLock<Table> {
+        &self.db[shard_of(user)]
+    }
+
+    // lock every shard for reading, for operations that must see the whole store at once
+    fn read_all(&self) -> Vec<RwLockReadGuard<'_, Table>> {
+        self.db.iter().map(|shard| shard.read().unwrap()).collect()
+    }
+
+    // lock every shard for writing, always in the same order so two callers can't deadlock
+    fn write_all(&self) -> Vec<RwLockWriteGuard<'_, Table>> {
+        self.db.iter().map(|shard| shard.write().unwrap()).collect()
+    }
+
+    /// return the user's generation, zero until it is first bumped
+    pub fn generation(&self, user: &str) -> u64 {
+        self.shard(user).read().unwrap().generation(user)
+    }
+
+    /// move the user to a new generation, so all their existing items stop validating at once without scanning the
+    /// store; they are removed at the next purge. return the new generation
+    pub fn bump_generation(&self, user: &str) -> u64 {
+        let generation = {
+            let mut map = self.shard(user).write().unwrap();
+            let generation = map.generations.entry(user.to_string()).or_insert(0);
+            *generation += 1;
+            *generation
+        };
+        let user = user.to_string();
+        self.notify(Change::Generation { user, generation });
+
+        generation
+    }
+
+    /// hash codes and user names with the hashing, e.g. Hashing::Fx for a trusted internal deployment; items already
+    /// stored move to the new maps. clones made afterwards share it
+    pub fn with_hashing(mut self, hashing: Hashing) -> DataStore {
+        let hasher = StoreHasher::new(hashing);
+        self.db = self
+            .db
+            .iter()
+            .map(|shard| RwLock::new(shard.write().unwrap().rehash(&hasher)))
+            .collect();
+        self
+    }
+
+    /// return how the store hashes codes and user names
+    pub fn hashing(&self) -> Hashing {
+        self.db[0].read().unwrap().records.hasher().hashing()
+    }
+
+    /// use this clock for expirations; clones made afterwards share it
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> DataStore {
+        self.clock = clock;
+        self
+    }
+
+    /// return the current unix time by the store's clock
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// return a receiver for every put and remove made through this store or its clones
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    // send the change to the subscribers, dropping any that have gone away
+    fn notify(&self, change: Change) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if !subscribers.is_empty() {
+            subscribers.retain(|tx| tx.send(change.clone()).is_ok());
+        }
+    }
+
+    /// apply a change from a peer without notifying subscribers; a put only wins if it expires later than the current
+    /// item (last write wins on expiry), or at the same time with other meta, and never brings back a removed or
+    /// revoked item while its tombstone lasts, nor one from before the user's current generation. generations only
+    /// move forward. return true if the store changed
+    pub fn merge(&mut self, change: Change) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+
+        let user = match &change {
+            Change::Put { item, .. } => &item.user,
+            Change::Remove { user, .. }
+            | Change::Generation { user, .. }
+            | Change::Lock { user, .. } => user,
+        };
+        let now = self.now();
+        let mut map = self.shard(user).write().unwrap();
+        match change {
+            Change::Put { item, generation } => {
+                let key = self.create_key(&item.code, &item.user);
+                let buried = map
+                    .tombstones
+                    .get(&key)
+                    .is_some_and(|(_, until)| *until > now);
+                let stale = generation < map.generation(&item.user);
+                let wins = match map.get(&key) {
+                    _ if buried || stale => false,
+                    // a record from an earlier generation is revoked, so a current put replaces it
+                    Some(record) if record.generation < generation => true,
+                    Some(record) if record.expires > item.expires => false,
+                    Some(record) => record.expires != item.expires || record.meta != item.meta,
+                    None => true,
+                };
+                if wins {
+                    map.tombstones.remove(&key);
+                    // the peer may have seen the user move to a later generation before this store did
+                    let current = map.generations.entry(item.user.clone()).or_insert(0);
+                    *current = (*current).max(generation);
+                    let record = Record {
+                        expires: item.expires,
+                        generation,
+                        meta: item.meta,
+                    };
+                    map.insert(key, record);
+                }
+                wins
+            }
+            Change::Remove { code, user } => {
+                // bury the key even when the put hasn't arrived yet, so it can't land afterwards
+                let key = self.create_key(&code, &user);
+                let removed = map.remove(&key).is_some();
+                map.bury(key, Validation::Revoked, now);
+                removed
+            }
+            Change::Generation { user, generation } => {
+                let current = map.generations.entry(user).or_insert(0);
+                let moved = generation > *current;
+                *current = (*current).max(generation);
+                moved
+            }
+            Change::Lock { user, locked: true } => map.locked.insert(user),
+            Change::Lock {
+                user,
+                locked: false,
+            } => map.locked.remove(&user),
+        }
+    }
+
+    /// return the changes that bring a peer up to date with the store: each user's generation and lock, the removes
+    /// still remembered by their tombstones, then the live items
+    pub fn replay(&self) -> Vec<Change> {
+        let now = self.now();
+        let maps = self.read_all();
+        let mut changes = Vec::new();
+        for map in &maps {
+            changes.extend(map.generations.iter().map(|(user, generation)| {
+                let (user, generation) = (user.clone(), *generation);
+                Change::Generation { user, generation }
+            }));
+            changes.extend(map.locked.iter().map(|user| Change::Lock {
+                user: user.clone(),
+                locked: true,
+            }));
+            changes.extend(
+                map.tombstones
+                    .iter()
+                    .filter(|(_, (_, until))| *until > now)
+                    .map(|(key, _)| Change::Remove {
+                        code: key.code.to_string(),
+                        user: key.user.to_string(),
+                    }),
+            );
+        }
+        for map in &maps {
+            changes.extend(
+                map.iter()
+                    .filter(|(key, record)| map.is_live(&key.user, record, now))
+                    .map(|(key, record)| Change::Put {
+                        item: key.item(record),
+                        generation: record.generation,
+                    }),
+            );
+        }
+
+        changes
+    }
+
+    // create the db key
+    fn create_key(&self, code: &str, user: &str) -> Key {
+        Key::new(code, user)
+    }
+
+    /// return the number of items in the data store
+    pub fn dbsize(&self) -> usize {
+        self.db
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    /// return the number of items the data store can hold before it grows
+    pub fn capacity(&self) -> usize {
+        self.db
+            .iter()
+            .map(|shard| shard.read().unwrap().capacity())
+            .sum()
+    }
+
+    /// shrink the store's maps to fit the items they hold, e.g. after purging a mass expiry, so memory returns to
+    /// baseline. the store grows again as items are added
+    pub fn compact(&mut self) {
+        for shard in self.db.iter() {
+            shard.write().unwrap().shrink_to_fit();
+        }
+    }
+
+    /// return the number of items still in use: unexpired and from their user's current generation. dbsize counts
+    /// every item until it is purged
+    pub fn dbsize_active(&self) -> usize {
+        let now = self.now();
+        let count = |map: &Table| {
+            map.iter()
+                .filter(|(key, record)| map.is_live(&key.user, record, now))
+                .count()
+        };
+        self.db
+            .iter()
+            .map(|shard| count(&shard.read().unwrap()))
+            .sum()
+    }
+
+    /// return the number of items in use for users in the namespace, e.g. acme for acme:sally, to see each tenant's
+    /// load; only the namespace's users are read, from the per-user index
+    pub fn dbsize_namespace(&self, namespace: &str) -> usize {
+        let now = self.now();
+        let prefix = format!("{}{}", namespace, NAMESPACE_SEPARATOR);
+        let count = |map: &Table| {
+            map.users
+                .iter()
+                .filter(|(user, _)| user.starts_with(&prefix))
+                .flat_map(|(user, codes)| codes.iter().map(move |code| (code, &**user)))
+                .filter(|key| match map.get(key as &dyn KeyPair) {
+                    Some(record) => map.is_live(key.1, record, now),
+                    None => false,
+                })
+                .count()
+        };
+        self.db
+            .iter()
+            .map(|shard| count(&shard.read().unwrap()))
+            .sum()
+    }
+
+    /// return the store health: lock latency, item counts and how long expired items have waited for removal
+    pub fn health(&self) -> Health {
+        let start = Instant::now();
+        let now = self.now();
+        let mut health = Health {
+            healthy: true,
+            ..Default::default()
+        };
+
+        for shard in self.db.iter() {
+            let map = match shard.read() {
+                Ok(map) => map,
+                Err(poisoned) => {
+                    health.healthy = false;
+                    poisoned.into_inner()
+                }
+            };
+            for record in map.values() {
+                if record.expires <= now {
+                    health.expired += 1;
+                    health.sweep_lag = health.sweep_lag.max(now - record.expires);
+                } else {
+                    health.active += 1;
+                }
+            }
+        }
+
+        health.latency_micros = start.elapsed().as_micros() as u64;
+        health
+    }
+
+    /// reject new items when true; reads and removes still work. shared by all clones
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    /// return true if the store is rejecting new items
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// store the item, replacing any item with the same code and user; the same as upsert
+    pub fn put(&mut self, item: SessionItem) -> Result<()> {
+        self.upsert(item)
+    }
+
+    /// store the item unless the user already has a live item with the code, failing with AlreadyExists; expired or
+    /// revoked items that have not been purged yet are replaced
+    pub fn insert(&mut self, item: SessionItem) -> Result<()> {
+        if self.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let now = self.now();
+        let key = self.create_key(&item.code, &item.user);
+        let generation;
+        {
+            let mut map = self.shard(&item.user).write().unwrap();
+            if let Some(record) = map.get(&key) {
+                if map.is_live(&item.user, record, now) {
+                    let user = item.user.clone();
+                    return Err(AlreadyExists { user }.into());
+                }
+            }
+            map.tombstones.remove(&key);
+            let record = map.record(item.clone());
+            generation = record.generation;
+            map.insert(key, record);
+        }
+        self.notify(Change::Put { item, generation });
+
+        Ok(())
+    }
+
+    /// store the item, replacing any item with the same code and user
+    pub fn upsert(&mut self, item: SessionItem) -> Result<()> {
+        if self.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let key = self.create_key(&item.code, &item.user);
+        let generation = {
+            let mut map = self.shard(&item.user).write().unwrap();
+            map.tombstones.remove(&key);
+            let record = map.record(item.clone());
+            let generation = record.generation;
+            let _resp = map.insert(key, record);
+            generation
+        };
+        self.notify(Change::Put { item, generation });
+
+        Ok(())
+    }
+
+    /// store the items under one lock, e.g. to import sessions; subscribers see a put for each
+    pub fn put_many(&mut self, items: Vec<SessionItem>) -> Result<()> {
+        if self.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let mut generations = Vec::with_capacity(items.len());
+        {
+            let mut maps = self.write_all();
+            for item in &items {
+                let map = &mut maps[shard_of(&item.user)];
+                let key = self.create_key(&item.code, &item.user);
+                map.tombstones.remove(&key);
+                let record = map.record(item.clone());
+                generations.push(record.generation);
+                map.insert(key, record);
+            }
+        }
+        for (item, generation) in items.into_iter().zip(generations) {
+            self.notify(Change::Put { item, generation });
+        }
+
+        Ok(())
+    }
+
+    /// store the item unless its user already has limit unexpired items, failing with LimitReached; with evict, remove
+    /// the user's items closest to expiring to make room instead. like insert, a live item with the code fails with
+    /// AlreadyExists. the checks and the changes happen under one lock. return the evicted items
+    pub fn put_limited(
+        &mut self,
+        item: SessionItem,
+        limit: usize,
+        evict: bool,
+    ) -> Result<Vec<SessionItem>> {
+        if self.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let now = self.now();
+        let key = self.create_key(&item.code, &item.user);
+        let mut evicted = Vec::new();
+        let generation = {
+            let mut map = self.shard(&item.user).write().unwrap();
+            if let Some(record) = map.get(&key) {
+                if map.is_live(&item.user, record, now) {
+                    let user = item.user.clone();
+                    return Err(AlreadyExists { user }.into());
+                }
+            }
+            let mut held: Vec<(u64, Key)> = map
+                .user_keys(&item.user)
+                .into_iter()
+                .filter_map(|k| {
+                    let record = map.get(&k)?;
+                    let live = map.is_live(&k.user, record, now);
+                    live.then_some((record.expires, k))
+                })
+                .collect();
+            if held.len() >= limit {
+                if !evict || limit == 0 {
+                    let (user, held) = (item.user.clone(), held.len());
+                    return Err(LimitReached { user, held }.into());
+                }
+
+                // make room for the new item
+                let over = held.len() + 1 - limit;
+                held.sort();
+                for (_, k) in held.into_iter().take(over) {
+                    if let Some(record) = map.remove(&k) {
+                        evicted.push(k.item(&record));
+                        map.bury(k, Validation::Revoked, now);
+                    }
+                }
+            }
+            map.tombstones.remove(&key);
+            let record = map.record(item.clone());
+            let generation = record.generation;
+            map.insert(key, record);
+            generation
+        };
+
+        for old in &evicted {
+            let (code, user) = (old.code.clone(), old.user.clone());
+            self.notify(Change::Remove { code, user });
+        }
+        self.notify(Change::Put { item, generation });
+
+        Ok(evicted)
+    }
+
+    /// return the session item if it exists, has not expired and is from the user's current generation
+    pub fn get(&self, code: &str, user: &str) -> Option<SessionItem> {
+        let now = self.now();
+        let map = self.shard(user).read().unwrap();
+        let record = map.get(lookup(&(code, user)))?;
+        if !map.is_live(user, record, now) {
+            return None;
+        }
+
+        Some(record.item(code.to_string(), user.to_string()))
+    }
+
+    /// return the item like get, but borrowed in place so nothing is copied, e.g. for read-heavy callers that only
+    /// check the expiration or meta. the item's shard stays read locked until the ItemRef is dropped
+    pub fn get_ref<'a>(&'a self, code: &'a str, user: &'a str) -> Option<ItemRef<'a>> {
+        let now = self.now();
+        let map = self.shard(user).read().unwrap();
+        let record = map.get(lookup(&(code, user)))?;
+        if !map.is_live(user, record, now) {
+            return None;
+        }
+
+        let expires = record.expires;
+        Some(ItemRef {
+            map,
+            code,
+            user,
+            expires,
+        })
+    }
+
+    /// if the item is still valid, push its expiration out to at least extra_ttl seconds from now and return it, all
+    /// under one lock so concurrent validators sliding the expiration can't race; an expiration is never shortened.
+    /// return None if the item is not valid or the store is read only
+    pub fn get_and_extend(
+        &mut self,
+        code: &str,
+        user: &str,
+        extra_ttl: u64,
+    ) -> Option<SessionItem> {
+        if self.is_read_only() {
+            return None;
+        }
+
+        let now = self.now();
+        let key = self.create_key(code, user);
+        let (item, generation) = {
+            let mut map = self.shard(user).write().unwrap();
+            let mut record = map.get(&key)?.clone();
+            if !map.is_live(user, &record, now) {
+                return None;
+            }
+
+            record.expires = record.expires.max(now.saturating_add(extra_ttl));
+            let (item, generation) = (key.item(&record), record.generation);
+            map.insert(key, record);
+            (item, generation)
+        };
+        self.notify(Change::Put {
+            item: item.clone(),
+            generation,
+        });
+
+        Some(item)
+    }
+
+    /// set a meta value on the item if it is still valid, under one lock so it can't race a touch, and return the
+    /// updated item; None if the item is not valid or the store is read only
+    pub fn set_meta(
+        &mut self,
+        code: &str,
+        user: &str,
+        key: &str,
+        value: &str,
+    ) -> Option<SessionItem> {
+        if self.is_read_only() {
+            return None;
+        }
+
+        let now = self.now();
+        let db_key = self.create_key(code, user);
+        let (item, generation) = {
+            let mut map = self.shard(user).write().unwrap();
+            let mut record = map.get(&db_key)?.clone();
+            if !map.is_live(user, &record, now) {
+                return None;
+            }
+
+            record.meta.insert(key.to_string(), value.to_string());
+            let (item, generation) = (db_key.item(&record), record.generation);
+            map.insert(db_key, record);
+            (item, generation)
+        };
+        self.notify(Change::Put {
+            item: item.clone(),
+            generation,
+        });
+
+        Some(item)
+    }
+
+    /// set the item's expiration to new only if it is still expected, for optimistic concurrency between replicas:
+    /// read the item, compute the new expiration and retry from a fresh read when this returns false. false also
+    /// means the item is gone or no longer valid
+    pub fn cas_expires(&mut self, code: &str, user: &str, expected: u64, new: u64) -> Result<bool> {
+        if self.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let now = self.now();
+        let key = self.create_key(code, user);
+        let (item, generation) = {
+            let mut map = self.shard(user).write().unwrap();
+            let mut record = match map.get(&key) {
+                Some(record) if map.is_live(user, record, now) => record.clone(),
+                _ => return Ok(false),
+            };
+            if record.expires != expected {
+                return Ok(false);
+            }
+            record.expires = new;
+            let (item, generation) = (key.item(&record), record.generation);
+            map.insert(key, record);
+            (item, generation)
+        };
+        self.notify(Change::Put { item, generation });
+
+        Ok(true)
+    }
+
+    /// return the items for the codes and users that are still valid, in the order asked, reading under one lock
+    pub fn get_many(&self, keys: &[(&str, &str)]) -> Vec<Option<SessionItem>> {
+        let now = self.now();
+        let maps = self.read_all();
+        keys.iter()
+            .map(|key| {
+                let map = &maps[shard_of(key.1)];
+                let record = map.get(lookup(key))?;
+                if !map.is_live(key.1, record, now) {
+                    return None;
+                }
+
+                Some(record.item(key.0.to_string(), key.1.to_string()))
+            })
+            .collect()
+    }
+
+    /// return why the code is or is not valid for the user
+    pub fn validate(&self, code: &str, user: &str) -> Validation {
+        let now = self.now();
+        self.shard(user).read().unwrap().validate(code, user, now)
+    }
+
+    /// validate the code and remove it if valid, so it can only be used once; return the validation
+    pub fn consume(&mut self, code: &str, user: &str) -> Validation {
+        let now = self.now();
+        {
+            // check and remove under one lock so two callers can't both consume the code
+            let mut map = self.shard(user).write().unwrap();
+            let validation = map.validate(code, user, now);
+            if !validation.is_valid() {
+                return validation;
+            }
+
+            let key = self.create_key(code, user);
+            map.remove(&key);
+            map.bury(key, Validation::Consumed, now);
+        }
+
+        let (code, user) = (code.to_string(), user.to_string());
+        self.notify(Change::Remove { code, user });
+        Validation::Valid
+    }
+
+    /// lock the user so none of their codes validate; return false if already locked
+    pub fn lock_user(&self, user: &str) -> bool {
+        let locked = self
+            .shard(user)
+            .write()
+            .unwrap()
+            .locked
+            .insert(user.to_string());
+        if locked {
+            let user = user.to_string();
+            self.notify(Change::Lock { user, locked });
+        }
+
+        locked
+    }
+
+    /// unlock the user; return false if they were not locked
+    pub fn unlock_user(&self, user: &str) -> bool {
+        let unlocked = self.shard(user).write().unwrap().locked.remove(user);
+        if unlocked {
+            let user = user.to_string();
+            self.notify(Change::Lock {
+                user,
+                locked: false,
+            });
+        }
+
+        unlocked
+    }
+
+    /// return true if the user is locked
+    pub fn is_locked(&self, user: &str) -> bool {
+        self.shard(user).read().unwrap().locked.contains(user)
+    }
+
+    /// return all items that have not expired
+    pub fn list(&self) -> Vec<SessionItem> {
+        self.iter().unexpired().collect()
+    }
+
+    /// iterate over a snapshot of the stored items, e.g. for exports, audits and admin tools, in no particular order.
+    /// expired items are included until they are purged; items revoked by a generation bump are not
+    pub fn iter(&self) -> Items {
+        self.snapshot().iter()
+    }
+
+    /// return a read-only view of the store as it is now, e.g. to export on another thread while writes continue.
+    /// taking one only clones a pointer per shard under the locks; the next write copies the records so the view
+    /// never changes
+    pub fn snapshot(&self) -> SnapshotView {
+        let maps = self.read_all();
+        let records = maps.iter().map(|map| Arc::clone(&map.records)).collect();
+        let mut generations = Map::with_hasher(maps[0].generations.hasher().clone());
+        for map in &maps {
+            let users = map.generations.iter();
+            generations.extend(users.map(|(user, generation)| (user.clone(), *generation)));
+        }
+
+        SnapshotView {
+            records,
+            generations,
+            now: self.now(),
+        }
+    }
+
+    /// return the number of the user's items that have not expired
+    pub fn count_user(&self, user: &str) -> usize {
+        let now = self.now();
+        let map = self.shard(user).read().unwrap();
+        map.users.get(user).map_or(0, |codes| {
+            codes
+                .iter()
+                .filter_map(|code| map.get(&(code, user) as &dyn KeyPair))
+                .filter(|record| map.is_live(user, record, now))
+                .count()
+        })
+    }
+
+    /// return the user's items that have not expired, in no particular order, from the store's index of each
+    /// user's items rather than a scan of every item
+    pub fn scan_user(&self, user: &str) -> Vec<SessionItem> {
+        let now = self.now();
+        let map = self.shard(user).read().unwrap();
+        let Some(codes) = map.users.get(user) else {
+            return Vec::new();
+        };
+
+        codes
+            .iter()
+            .filter_map(|code| {
+                let record = map.get(&(code, user) as &dyn KeyPair)?;
+                if !map.is_live(user, record, now) {
+                    return None;
+                }
+
+                Some(record.item(code.to_string(), user.to_string()))
+            })
+            .collect()
+    }
+
+    /// remove all of the user's items; return the number removed
+    pub fn remove_user(&mut self, user: &str) -> usize {
+        let now = self.now();
+        let changes: Vec<Change> = {
+            let mut map = self.shard(user).write().unwrap();
+            let keys = map.user_keys(user);
+            keys.into_iter()
+                .map(|key| {
+                    map.remove(&key);
+                    let (code, user) = (key.code.to_string(), key.user.to_string());
+                    map.bury(key, Validation::Revoked, now);
+                    Change::Remove { code, user }
+                })
+                .collect()
+        };
+
+        let count = changes.len();
+        for change in changes {
+            self.notify(change);
+        }
+
+        count
+    }
+
+    /// start a clear: return a token that confirms it within CLEAR_TOKEN_TTL seconds, replacing any earlier one
+    pub fn request_clear(&self) -> String {
+        let token = format!("clr_{}", random_hex(16));
+        let expires = self.now().saturating_add(CLEAR_TOKEN_TTL);
+        *self.clear_token.lock().unwrap() = Some((token.clone(), expires));
+        token
+    }
+
+    /// remove every item, confirmed by the token from request_clear, so one mistaken call can't wipe the store. the
+    /// token works once; a wrong or expired one fails and cancels the request. subscribers see a remove for each item.
+    /// return the number removed
+    pub fn clear(&mut self, token: &str) -> Result<usize> {
+        let now = self.now();
+        match self.clear_token.lock().unwrap().take() {
+            Some((pending, expires)) if pending == token && now < expires => (),
+            Some(_) => bail!("clear token is wrong or expired; request a new one"),
+            None => bail!("no clear was requested"),
+        }
+
+        let mut changes = Vec::new();
+        for mut map in self.write_all() {
+            for key in map.take().keys() {
+                let (code, user) = (key.code.to_string(), key.user.to_string());
+                map.bury(key.clone(), Validation::Revoked, now);
+                changes.push(Change::Remove { code, user });
+            }
+        }
+
+        let count = changes.len();
+        for change in changes {
+            self.notify(change);
+        }
+
+        Ok(count)
+    }
+
+    /// remove the expired items, keeping a tombstone so they still validate as expired for a while, and forget old
+    /// tombstones; items from a user's earlier generations are removed as revoked. the store is swept PURGE_CHUNK
+    /// items at a time, releasing the lock between chunks, so writers never wait on the whole sweep. return the number
+    /// expired
+    pub fn purge_expired(&mut self) -> usize {
+        let (mut count, mut cursor) = (0, PurgeCursor::default());
+        loop {
+            let (expired, next) = self.purge_expired_chunk(cursor, PURGE_CHUNK);
+            count += expired;
+            match next {
+                Some(next) => cursor = next,
+                None => return count,
+            }
+        }
+    }
+
+    /// purge as purge_expired does, but examine at most limit items, so a sweep of a huge store can be spread over
+    /// many calls, e.g. one per tick. start a pass with PurgeCursor::default() and call again with the returned cursor
+    /// until it is None; items added while a pass is under way may wait for the next pass. return the number expired
+    pub fn purge_expired_chunk(
+        &mut self,
+        cursor: PurgeCursor,
+        limit: usize,
+    ) -> (usize, Option<PurgeCursor>) {
+        let now = self.now();
+        let (mut count, mut budget, mut cursor) = (0, limit.max(1), cursor);
+        while budget > 0 && cursor.shard < SHARDS {
+            let chunk = self.db[cursor.shard].write().unwrap().purge_expired_chunk(
+                cursor.offset,
+                budget,
+                now,
+            );
+            let (expired, examined, next) = chunk;
+            count += expired;
+            budget -= examined;
+            cursor = match next {
+                Some(offset) => PurgeCursor { offset, ..cursor },
+                None => PurgeCursor {
+                    shard: cursor.shard + 1,
+                    offset: 0,
+                },
+            };
+        }
+
+        (count, (cursor.shard < SHARDS).then_some(cursor))
+    }
+
+    /// remove the item; return true if it was removed, false if not found
+    pub fn remove(&mut self, code: &str, user: &str) -> bool {
+        let now = self.now();
+        let key = self.create_key(code, user);
+        let removed = {
+            let mut map = self.shard(user).write().unwrap();
+            let removed = map.remove(&key).is_some();
+            if removed {
+                map.bury(key, Validation::Revoked, now);
+            }
+            removed
+        };
+        if removed {
+            let (code, user) = (code.to_string(), user.to_string());
+            self.notify(Change::Remove { code, user });
+        }
+
+        removed
+    }
+
+    /// remove the items for the codes and users under one lock, e.g. for a mass revocation; return the items that
+    /// were stored, expired or not
+    pub fn remove_many(&mut self, keys: &[(&str, &str)]) -> Vec<SessionItem> {
+        let now = self.now();
+        let mut removed = Vec::new();
+        {
+            let mut maps = self.write_all();
+            for (code, user) in keys {
+                let map = &mut maps[shard_of(user)];
+                let key = self.create_key(code, user);
+                if let Some(record) = map.remove(&key) {
+                    removed.push(key.item(&record));
+                    map.bury(key, Validation::Revoked, now);
+                }
+            }
+        }
+
+        for item in &removed {
+            let (code, user) = (item.code.clone(), item.user.clone());
+            self.notify(Change::Remove { code, user });
+        }
+
+        removed
+    }
+
+    /// run the closure's operations as one unit under the store's write locks: nobody sees them until it returns and
+    /// every change is undone if it returns an error, e.g. to consume a code and insert the item it is exchanged for.
+    /// subscribers only hear about committed changes. fails at once if the store is read only
+    pub fn transact<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T>,
+    {
+        if self.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let (result, changes) = {
+            let mut maps = self.write_all();
+            let mut txn = Transaction::new(&mut maps, self.now());
+            let result = f(&mut txn);
+            let changes = txn.finish(result.is_ok());
+            (result, changes)
+        };
+        for change in changes {
+            self.notify(change);
+        }
+
+        result
+    }
+
+    /// run a transaction across this store and another, e.g. an otp store and a session store, holding both stores'
+    /// locks so the operations on each commit or roll back together. the locks are always taken in the same order, so
+    /// two callers can't deadlock. fails if the stores are clones of each other; use transact instead
+    pub fn transact_with<T, F>(&mut self, other: &mut DataStore, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction, &mut Transaction) -> Result<T>,
+    {
+        let (this, that) = (
+            Arc::as_ptr(&self.db) as *const RwLock<Table>,
+            Arc::as_ptr(&other.db) as *const RwLock<Table>,
+        );
+        if this == that {
+            bail!("transact_with needs two different stores; use transact");
+        }
+        if self.is_read_only() || other.is_read_only() {
+            bail!("data store is read only");
+        }
+
+        let (result, changes, other_changes) = {
+            let (mut maps, mut other_maps) = if this < that {
+                let maps = self.write_all();
+                (maps, other.write_all())
+            } else {
+                let other_maps = other.write_all();
+                (self.write_all(), other_maps)
+            };
+            let mut txn = Transaction::new(&mut maps, self.now());
+            let mut other_txn = Transaction::new(&mut other_maps, other.now());
+            let result = f(&mut txn, &mut other_txn);
+            let changes = txn.finish(result.is_ok());
+            let other_changes = other_txn.finish(result.is_ok());
+            (result, changes, other_changes)
+        };
+        for change in changes {
+            self.notify(change);
+        }
+        for change in other_changes {
+            other.notify(change);
+        }
+
+        result
+    }
+}
+
+/// the operations available inside DataStore::transact. they apply to the store as they run, so later ones see
+/// earlier ones, and are undone if the transaction fails
+pub struct Transaction<'a> {
+    // the store's shards, locked for the whole transaction
+    maps: Vec<&'a mut Table>,
+    now: u64,
+    // each changed key's record before its first change, to restore on rollback
+    undo: Vec<(Key, Option<Record>)>,
+    // the changes in order, with why each remove happened, for the tombstones and subscribers on commit
+    changes: Vec<(Change, Validation)>,
+}
+
+impl<'a> Transaction<'a> {
+    fn new<'g: 'a>(guards: &'a mut [RwLockWriteGuard<'g, Table>], now: u64) -> Transaction<'a> {
+        Transaction {
+            maps: guards.iter_mut().map(|guard| &mut **guard).collect(),
+            now,
+            undo: Vec::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// return the unix time the transaction started, by the store's clock; it uses this time throughout
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    // the shard holding the user's items
+    fn map(&self, user: &str) -> &Table {
+        &*self.maps[shard_of(user)]
+    }
+
+    fn map_mut(&mut self, user: &str) -> &mut Table {
+        &mut *self.maps[shard_of(user)]
+    }
+
+    /// return the item if it is still valid, as DataStore::get does
+    pub fn get(&self, code: &str, user: &str) -> Option<SessionItem> {
+        let map = self.map(user);
+        let record = map.get(lookup(&(code, user)))?;
+        if !map.is_live(user, record, self.now) {
+            return None;
         }
-    }
 
-    /// return true if the session has expired
-    pub fn has_expired(&self) -> bool {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        self.expires <= now.as_secs()
+        Some(record.item(code.to_string(), user.to_string()))
     }
-}
 
-impl DataStore {
-    /// create the data store
-    pub fn create() -> DataStore {
-        DataStore {
-            db: Arc::new(RwLock::new(HashMap::new())),
+    /// return why the code is or is not valid for the user, counting removes earlier in the transaction
+    pub fn validate(&self, code: &str, user: &str) -> Validation {
+        let map = self.map(user);
+        let validation = map.validate(code, user, self.now);
+        if map.locked.contains(user) || map.contains_key(lookup(&(code, user))) {
+            return validation;
         }
-    }
 
-    // create the db key
-    fn create_key(&self, code: &str, user: &str) -> String {
-        format!("{}:{}", code, user)
+        let removed = self
+            .changes
+            .iter()
+            .rev()
+            .find_map(|(change, reason)| match change {
+                Change::Remove { code: c, user: u } if c == code && u == user => Some(*reason),
+                _ => None,
+            });
+        removed.unwrap_or(validation)
     }
 
-    /// return the number of items in the data store
-    pub fn dbsize(&self) -> usize {
-        let map = self.db.read().unwrap();
-        map.len()
+    // remember the key's record before its first change
+    fn save(&mut self, key: &Key) {
+        if !self.undo.iter().any(|(k, _)| k == key) {
+            let record = self.map(&key.user).get(key).cloned();
+            self.undo.push((key.clone(), record));
+        }
     }
 
-    /// store this in the database
-    pub fn put(&mut self, item: SessionItem) -> Result<()> {
-        let key = self.create_key(&item.code, &item.user);
-        let mut map = self.db.write().unwrap();
-        let _resp = map.insert(key, item.expires);
-
-        Ok(())
+    /// store the item, replacing any item with the same code and user
+    pub fn put(&mut self, item: SessionItem) {
+        let key = Key::new(&item.code, &item.user);
+        self.save(&key);
+        let map = self.map_mut(&item.user);
+        let record = map.record(item.clone());
+        let generation = record.generation;
+        map.insert(key, record);
+        self.changes
+            .push((Change::Put { item, generation }, Validation::Valid));
     }
 
-    /// return the session item if it exists and has not expired
-    pub fn get(&self, code: &str, user: &str) -> Option<SessionItem> {
-        let key = self.create_key(code, user);
-        let value = {
-            let map = self.db.read().unwrap();
-            let value = map.get(&key);
-            if value.is_none() {
-                value?;
+    /// store the item unless the user already has a live item with the code, failing with AlreadyExists
+    pub fn insert(&mut self, item: SessionItem) -> Result<()> {
+        let map = self.map(&item.user);
+        if let Some(record) = map.get(lookup(&(item.code.as_str(), item.user.as_str()))) {
+            if map.is_live(&item.user, record, self.now) {
+                let user = item.user.clone();
+                return Err(AlreadyExists { user }.into());
             }
-            *value.unwrap()
-        };
+        }
+        self.put(item);
 
-        let item = SessionItem {
-            code: code.to_string(),
-            user: user.to_string(),
-            expires: value,
-        };
+        Ok(())
+    }
 
-        if item.has_expired() {
-            None
-        } else {
-            Some(item)
+    /// use the code up if it is valid and return Valid, otherwise return why it is not; as DataStore::consume does
+    pub fn consume(&mut self, code: &str, user: &str) -> Validation {
+        let validation = self.validate(code, user);
+        if validation.is_valid() {
+            self.delete(code, user, Validation::Consumed);
         }
+
+        validation
     }
 
     /// remove the item; return true if it was removed, false if not found
     pub fn remove(&mut self, code: &str, user: &str) -> bool {
-        let key = self.create_key(code, user);
-        let mut map = self.db.write().unwrap();
-        let v = map.remove(&key);
-        v.is_some()
+        self.delete(code, user, Validation::Revoked)
+    }
+
+    // remove the item, recording why for its tombstone
+    fn delete(&mut self, code: &str, user: &str, reason: Validation) -> bool {
+        let key = Key::new(code, user);
+        if !self.map(user).contains_key(&key) {
+            return false;
+        }
+
+        self.save(&key);
+        self.map_mut(user).remove(&key);
+        let (code, user) = (code.to_string(), user.to_string());
+        self.changes.push((Change::Remove { code, user }, reason));
+        true
+    }
+
+    // on commit, update the tombstones and return the changes for the subscribers; otherwise restore every changed
+    // record and return none
+    fn finish(self, commit: bool) -> Vec<Change> {
+        let Transaction {
+            mut maps,
+            now,
+            undo,
+            changes,
+        } = self;
+        if !commit {
+            for (key, record) in undo {
+                let map = &mut maps[shard_of(&key.user)];
+                match record {
+                    Some(record) => map.insert(key, record),
+                    None => map.remove(&key),
+                };
+            }
+            return Vec::new();
+        }
+
+        let mut committed = Vec::with_capacity(changes.len());
+        for (change, reason) in changes {
+            match &change {
+                Change::Put { item, .. } => {
+                    let map = &mut maps[shard_of(&item.user)];
+                    map.tombstones
+                        .remove(lookup(&(item.code.as_str(), item.user.as_str())));
+                }
+                Change::Remove { code, user } => {
+                    let map = &mut maps[shard_of(user)];
+                    map.bury(Key::new(code, user), reason, now);
+                }
+                // transactions only put and remove
+                Change::Generation { .. } | Change::Lock { .. } => (),
+            }
+            committed.push(change);
+        }
+
+        committed
     }
 }
 
@@ -101,11 +1952,46 @@ impl DataStore {
 mod tests {
     use super::*;
     use crate::otp::Otp;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // counts each thread's heap allocations, to check that validation allocates nothing
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
 
     fn create_otp() -> Otp {
         Otp::new()
     }
 
+    // a put in the first generation
+    fn put(item: SessionItem) -> Change {
+        Change::Put {
+            item,
+            generation: 0,
+        }
+    }
+
     #[test]
     fn create() {
         let store = DataStore::create();
@@ -152,7 +2038,7 @@ mod tests {
         let mut store = DataStore::create();
         assert_eq!(store.dbsize(), 0);
 
-        let _resp = store.put(item).unwrap();
+        store.put(item).unwrap();
         assert_eq!(store.dbsize(), 1);
 
         let copy_item = store.get(&code, user);
@@ -164,28 +2050,351 @@ mod tests {
         let code = otp.generate_code();
         let user = "sammy";
         let item = SessionItem::new(&code, user, 0u64);
-        let _resp = store.put(item).unwrap();
+        store.put(item).unwrap();
         assert_eq!(store.dbsize(), 2);
 
         let non_item = store.get(&code, user);
         assert!(non_item.is_none());
     }
 
+    #[test]
+    fn insert_upsert() {
+        let code = create_otp().generate_code();
+        let user = "sammy";
+        let mut store = DataStore::create();
+        store.put(SessionItem::new(&code, user, 0)).unwrap();
+
+        // an expired item can be replaced by insert, a live one only by upsert
+        store.insert(SessionItem::new(&code, user, 60)).unwrap();
+        let err = store
+            .insert(SessionItem::new(&code, user, 120))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AlreadyExists>(),
+            Some(&AlreadyExists {
+                user: user.to_string()
+            })
+        );
+        store.upsert(SessionItem::new(&code, user, 120)).unwrap();
+        assert_eq!(store.dbsize(), 1);
+        assert!(store.get(&code, user).is_some());
+    }
+
+    #[test]
+    fn read_only() {
+        let mut store = DataStore::create();
+        store
+            .put(SessionItem::new("100000", "jack", 60u64))
+            .unwrap();
+
+        store.clone().set_read_only(true);
+        assert!(store.is_read_only());
+        assert!(store
+            .put(SessionItem::new("200000", "jack", 60u64))
+            .is_err());
+        assert!(store.get("100000", "jack").is_some());
+        assert!(store.remove("100000", "jack"));
+
+        store.set_read_only(false);
+        assert!(store.put(SessionItem::new("200000", "jack", 60u64)).is_ok());
+    }
+
+    #[test]
+    fn list() {
+        let otp = create_otp();
+        let mut store = DataStore::create();
+        let code = otp.generate_code();
+        store.put(SessionItem::new(&code, "jack", 60u64)).unwrap();
+        store
+            .put(SessionItem::new(&otp.generate_code(), "sammy", 0u64))
+            .unwrap();
+        assert_eq!(store.dbsize(), 2);
+
+        let items = store.list();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code, code);
+        assert_eq!(items[0].user, "jack");
+
+        let items = store.iter();
+        assert_eq!(items.len(), 2);
+        let users: Vec<String> = items.unexpired().map(|item| item.user).collect();
+        assert_eq!(users, ["jack"]);
+        store.bump_generation("jack");
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(store.iter().unexpired().count(), 0);
+    }
+
+    #[test]
+    fn health() {
+        let mut store = DataStore::create();
+        let health = store.health();
+        assert!(health.healthy);
+        assert_eq!(health.active, 0);
+        assert_eq!(health.expired, 0);
+        assert_eq!(health.sweep_lag, 0);
+
+        store
+            .put(SessionItem::new("100000", "jack", 60u64))
+            .unwrap();
+        let mut item = SessionItem::new("200000", "sammy", 0u64);
+        item.expires -= 30;
+        store.put(item).unwrap();
+
+        let health = store.health();
+        assert_eq!(health.active, 1);
+        assert_eq!(health.expired, 1);
+        assert!(health.sweep_lag >= 30);
+    }
+
+    #[test]
+    fn batch() {
+        let mut store = DataStore::create();
+        let rx = store.subscribe();
+        let items = vec![
+            SessionItem::new("a1", "sally", 60),
+            SessionItem::new("b2", "jack", 60),
+            SessionItem::new("c3", "jack", 0),
+        ];
+        store.put_many(items.clone()).unwrap();
+        assert_eq!(store.dbsize(), 3);
+        assert_eq!(rx.try_iter().count(), 3);
+
+        let found = store.get_many(&[
+            ("b2", "jack"),
+            ("c3", "jack"),
+            ("a1", "jack"),
+            ("a1", "sally"),
+        ]);
+        assert_eq!(
+            found,
+            [Some(items[1].clone()), None, None, Some(items[0].clone())]
+        );
+
+        let removed = store.remove_many(&[("a1", "sally"), ("c3", "jack"), ("zz", "jack")]);
+        assert_eq!(removed, [items[0].clone(), items[2].clone()]);
+        assert_eq!(store.validate("a1", "sally"), Validation::Revoked);
+        assert_eq!(store.dbsize(), 1);
+        assert_eq!(rx.try_iter().count(), 2);
+
+        store.set_read_only(true);
+        assert!(store.put_many(items).is_err());
+    }
+
+    #[test]
+    fn scan_user() {
+        let mut store = DataStore::create();
+        store.put(SessionItem::new("a1", "sally", 60)).unwrap();
+        store.put(SessionItem::new("b2", "sally", 60)).unwrap();
+        store.put(SessionItem::new("c3", "sally", 0)).unwrap();
+        store.put(SessionItem::new("d4", "jack", 60)).unwrap();
+
+        let mut codes: Vec<String> = store
+            .scan_user("sally")
+            .into_iter()
+            .map(|i| i.code)
+            .collect();
+        codes.sort();
+        assert_eq!(codes, ["a1", "b2"]);
+        assert!(store.scan_user("sammy").is_empty());
+
+        store.remove("a1", "sally");
+        store.purge_expired();
+        assert_eq!(store.scan_user("sally").len(), 1);
+        assert_eq!(store.count_user("sally"), 1);
+        store.bump_generation("jack");
+        assert!(store.scan_user("jack").is_empty());
+        store.remove_user("sally");
+        assert!(store.scan_user("sally").is_empty());
+        assert!(!store
+            .shard("sally")
+            .read()
+            .unwrap()
+            .users
+            .contains_key("sally"));
+    }
+
+    #[test]
+    fn remove_user() {
+        let mut store = DataStore::create();
+        store
+            .put(SessionItem::new("100000", "jack", 60u64))
+            .unwrap();
+        store
+            .put(SessionItem::new("200000", "jack", 60u64))
+            .unwrap();
+        store
+            .put(SessionItem::new("300000", "sammy", 60u64))
+            .unwrap();
+        store
+            .put(SessionItem::new("400000", "jack:x", 60u64))
+            .unwrap();
+
+        assert_eq!(store.remove_user("jack"), 2);
+        assert_eq!(store.remove_user("jack"), 0);
+        assert_eq!(store.dbsize(), 2);
+    }
+
+    #[test]
+    fn purge_expired() {
+        let mut store = DataStore::create();
+        store
+            .put(SessionItem::new("100000", "jack", 60u64))
+            .unwrap();
+        store
+            .put(SessionItem::new("200000", "sammy", 0u64))
+            .unwrap();
+
+        assert_eq!(store.purge_expired(), 1);
+        assert_eq!(store.dbsize(), 1);
+        assert!(store.get("100000", "jack").is_some());
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut db = DataStore::create();
+        let rx = db.subscribe();
+        let item = SessionItem::new("123456", "sally", 60);
+        db.put(item.clone()).unwrap();
+        db.remove("123456", "sally");
+        db.remove("123456", "sally");
+
+        assert_eq!(rx.try_recv().unwrap(), put(item));
+        let change = rx.try_recv().unwrap();
+        assert!(matches!(change, Change::Remove { code, .. } if code == "123456"));
+        assert!(rx.try_recv().is_err());
+
+        // dropped subscribers are pruned on the next change
+        drop(rx);
+        db.put(SessionItem::new("654321", "jack", 60)).unwrap();
+        assert!(db.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge() {
+        let mut db = DataStore::create();
+        let rx = db.subscribe();
+        let item = SessionItem::new("123456", "sally", 60);
+        assert!(db.merge(put(item.clone())));
+
+        // an earlier expiration loses
+        let stale = SessionItem::new("123456", "sally", 30);
+        assert!(!db.merge(put(stale)));
+        assert_eq!(db.get("123456", "sally").unwrap().expires, item.expires);
+
+        let touched = SessionItem::new("123456", "sally", 120);
+        assert!(db.merge(put(touched.clone())));
+        assert_eq!(db.get("123456", "sally"), Some(touched));
+
+        let (code, user) = ("123456".to_string(), "sally".to_string());
+        assert!(db.merge(Change::Remove { code, user }));
+        assert_eq!(db.dbsize(), 0);
+
+        // a late put doesn't undo the remove, even one that arrives first
+        let late = SessionItem::new("123456", "sally", 300);
+        assert!(!db.merge(put(late)));
+        let (code, user) = ("654321".to_string(), "sally".to_string());
+        assert!(!db.merge(Change::Remove { code, user }));
+        assert!(!db.merge(put(SessionItem::new("654321", "sally", 60))));
+        assert_eq!(db.validate("654321", "sally"), Validation::Revoked);
+
+        // generations only move forward and revoke the older items
+        assert!(db.merge(put(SessionItem::new("111111", "jack", 60))));
+        let (user, generation) = ("jack".to_string(), 2);
+        assert!(db.merge(Change::Generation { user, generation }));
+        let (user, generation) = ("jack".to_string(), 1);
+        assert!(!db.merge(Change::Generation { user, generation }));
+        assert_eq!(db.generation("jack"), 2);
+        assert_eq!(db.validate("111111", "jack"), Validation::Revoked);
+        assert!(!db.merge(put(SessionItem::new("111111", "jack", 90))));
+
+        let user = "jack".to_string();
+        assert!(db.merge(Change::Lock { user, locked: true }));
+        assert!(db.is_locked("jack"));
+        let user = "jack".to_string();
+        assert!(db.merge(Change::Lock {
+            user,
+            locked: false
+        }));
+        assert!(!db.is_locked("jack"));
+
+        // merged changes are not sent to subscribers
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn merge_generation_before_put() {
+        let mut db = DataStore::create();
+        let mut peer = DataStore::create();
+        let rx = peer.subscribe();
+        peer.put(SessionItem::new("111111", "jack", 60)).unwrap();
+        peer.get_and_extend("111111", "jack", 120).unwrap();
+        let stale: Vec<Change> = rx.try_iter().collect();
+
+        // logout-everywhere reaches the store before the peer's put and touch of the old session
+        let (user, generation) = ("jack".to_string(), 1);
+        assert!(db.merge(Change::Generation { user, generation }));
+        for change in stale {
+            assert!(!db.merge(change));
+        }
+        assert_eq!(db.validate("111111", "jack"), Validation::NotFound);
+
+        // a put from the current generation lands, and one from a later generation moves the store forward
+        let item = SessionItem::new("222222", "jack", 60);
+        let generation = 1;
+        assert!(db.merge(Change::Put { item, generation }));
+        assert!(db.validate("222222", "jack").is_valid());
+        let item = SessionItem::new("333333", "jack", 60);
+        let generation = 2;
+        assert!(db.merge(Change::Put { item, generation }));
+        assert_eq!(db.generation("jack"), 2);
+        assert_eq!(db.validate("222222", "jack"), Validation::Revoked);
+        assert!(db.validate("333333", "jack").is_valid());
+    }
+
+    #[test]
+    fn replay() {
+        let mut db = DataStore::create();
+        let rx = db.subscribe();
+        let item = SessionItem::new("123456", "sally", 60).with_meta("device", "laptop");
+        db.put(item.clone()).unwrap();
+        db.put(SessionItem::new("654321", "sally", 60)).unwrap();
+        db.remove("654321", "sally");
+        db.bump_generation("jack");
+        db.lock_user("jack");
+        db.lock_user("jack");
+        db.unlock_user("sally");
+
+        // subscribers hear about generations and locks too
+        let changes: Vec<Change> = rx.try_iter().collect();
+        assert_eq!(changes.len(), 5);
+        let (user, generation) = ("jack".to_string(), 1);
+        assert_eq!(changes[3], Change::Generation { user, generation });
+        let user = "jack".to_string();
+        assert_eq!(changes[4], Change::Lock { user, locked: true });
+
+        let mut peer = DataStore::create();
+        for change in db.replay() {
+            peer.merge(change);
+        }
+        assert_eq!(peer.get("123456", "sally"), Some(item));
+        assert_eq!(peer.validate("654321", "sally"), Validation::Revoked);
+        assert_eq!(peer.generation("jack"), 1);
+        assert!(peer.is_locked("jack"));
+    }
+
     #[test]
     fn has_expired() {
         let otp = create_otp();
         let code = otp.generate_code();
         let user = "jack";
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let now = unix_now();
         let expires = now + 60;
 
         let item = SessionItem {
             code: code.to_string(),
             user: user.to_string(),
             expires,
+            ..Default::default()
         };
         assert!(!item.has_expired());
 
@@ -193,10 +2402,362 @@ mod tests {
             code: code.to_string(),
             user: user.to_string(),
             expires: now - 10,
+            ..Default::default()
         };
         assert!(item.has_expired());
     }
 
+    #[test]
+    fn clock() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut store = DataStore::create().with_clock(Arc::new(clock.clone()));
+        store
+            .put(SessionItem::created_at("100000", "jack", 60, store.now()))
+            .unwrap();
+        assert!(store.get("100000", "jack").is_some());
+
+        clock.advance(std::time::Duration::from_secs(60));
+        assert!(store.get("100000", "jack").is_none());
+        assert_eq!(store.health().expired, 1);
+        assert_eq!(store.purge_expired(), 1);
+    }
+
+    #[test]
+    fn get_and_extend() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut store = DataStore::create().with_clock(Arc::new(clock.clone()));
+        store
+            .put(SessionItem::created_at("100000", "jack", 60, 1_000))
+            .unwrap();
+
+        clock.set(1_030);
+        let item = store.get_and_extend("100000", "jack", 60).unwrap();
+        assert_eq!(item.expires, 1_090);
+        assert_eq!(store.get("100000", "jack"), Some(item));
+        // never shortened
+        let item = store.get_and_extend("100000", "jack", 10).unwrap();
+        assert_eq!(item.expires, 1_090);
+        assert!(store.get_and_extend("100000", "sally", 60).is_none());
+
+        clock.set(1_090);
+        assert!(store.get_and_extend("100000", "jack", 60).is_none());
+    }
+
+    #[test]
+    fn meta() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut store = DataStore::create().with_clock(clock);
+        let item =
+            SessionItem::created_at("100000", "jack", 60, 1_000).with_meta("device", "laptop");
+        store.put(item.clone()).unwrap();
+        assert_eq!(store.get("100000", "jack"), Some(item.clone()));
+
+        // changes to the expiration keep the rest of the item
+        let extended = store.get_and_extend("100000", "jack", 120).unwrap();
+        assert_eq!(extended.meta["device"], "laptop");
+        assert!(store.cas_expires("100000", "jack", 1_120, 1_200).unwrap());
+        let items = store.get_many(&[("100000", "jack")]);
+        assert_eq!(items[0].as_ref().unwrap().meta, item.meta);
+        assert_eq!(store.snapshot().iter().next().unwrap().meta, item.meta);
+
+        let updated = store
+            .set_meta("100000", "jack", "network", "10.0.0.0/8")
+            .unwrap();
+        assert_eq!(updated.expires, 1_200);
+        assert_eq!(updated.meta["device"], "laptop");
+        assert_eq!(store.get("100000", "jack"), Some(updated.clone()));
+        assert!(store.set_meta("999999", "jack", "network", "").is_none());
+
+        // a peer takes a meta change that keeps the expiration
+        let mut peer = DataStore::create().with_clock(Arc::new(crate::clock::MockClock::at(1_000)));
+        peer.put(SessionItem {
+            meta: item.meta,
+            ..updated.clone()
+        })
+        .unwrap();
+        assert!(peer.merge(put(updated.clone())));
+        assert!(!peer.merge(put(updated.clone())));
+        assert_eq!(peer.get("100000", "jack"), Some(updated));
+    }
+
+    #[test]
+    fn cas_expires() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut store = DataStore::create().with_clock(Arc::new(clock.clone()));
+        store
+            .put(SessionItem::created_at("100000", "jack", 60, 1_000))
+            .unwrap();
+
+        assert!(store.cas_expires("100000", "jack", 1_060, 1_120).unwrap());
+        // a writer that read the old expiration loses and must read again
+        assert!(!store.cas_expires("100000", "jack", 1_060, 1_090).unwrap());
+        assert_eq!(store.get("100000", "jack").unwrap().expires, 1_120);
+        assert!(!store.cas_expires("100000", "sally", 1_120, 1_200).unwrap());
+
+        store.set_read_only(true);
+        assert!(store.cas_expires("100000", "jack", 1_120, 1_200).is_err());
+    }
+
+    #[test]
+    fn dbsize_namespace() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut store = DataStore::create().with_clock(clock);
+        let items = [
+            ("100000", "acme:sally", 60),
+            ("200000", "acme:jack", 60),
+            ("300000", "acme:jack", 0),
+            ("400000", "acmecorp:sammy", 60),
+            ("500000", "globex:sammy", 60),
+        ];
+        for (code, user, ttl) in items {
+            store
+                .put(SessionItem::created_at(code, user, ttl, 1_000))
+                .unwrap();
+        }
+        store.bump_generation("globex:sammy");
+
+        assert_eq!(store.dbsize(), 5);
+        assert_eq!(store.dbsize_active(), 3);
+        assert_eq!(store.dbsize_namespace("acme"), 2);
+        assert_eq!(store.dbsize_namespace("globex"), 0);
+        assert_eq!(store.dbsize_namespace("initech"), 0);
+    }
+
+    #[test]
+    fn clear() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut store = DataStore::create().with_clock(Arc::new(clock.clone()));
+        store
+            .put(SessionItem::created_at("100000", "jack", 60, 1_000))
+            .unwrap();
+        store
+            .put(SessionItem::created_at("200000", "sammy", 60, 1_000))
+            .unwrap();
+        assert!(store.clear("clr_nope").is_err());
+
+        // a wrong token cancels the request
+        let token = store.request_clear();
+        assert!(store.clear("clr_nope").is_err());
+        assert!(store.clear(&token).is_err());
+        assert_eq!(store.dbsize(), 2);
+
+        let token = store.request_clear();
+        clock.set(1_000 + CLEAR_TOKEN_TTL);
+        assert!(store.clear(&token).is_err());
+
+        let rx = store.subscribe();
+        let token = store.request_clear();
+        assert_eq!(store.clear(&token).unwrap(), 2);
+        assert_eq!(store.dbsize(), 0);
+        assert_eq!(store.validate("100000", "jack"), Validation::Revoked);
+        assert_eq!(rx.try_iter().count(), 2);
+        assert!(store.clear(&token).is_err());
+    }
+
+    #[test]
+    fn snapshot() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut store = DataStore::create().with_clock(clock);
+        store
+            .put(SessionItem::created_at("100000", "jack", 60, 1_000))
+            .unwrap();
+        store
+            .put(SessionItem::created_at("200000", "sammy", 60, 1_000))
+            .unwrap();
+        let view = store.snapshot();
+
+        // writes after the view don't change it
+        store.remove("100000", "jack");
+        store
+            .put(SessionItem::created_at("300000", "sally", 60, 1_000))
+            .unwrap();
+        store.bump_generation("sammy");
+        assert_eq!(store.dbsize(), 2);
+        assert_eq!((view.len(), view.taken_at()), (2, 1_000));
+        assert!(view.get("100000", "jack").is_some());
+        assert!(view.get("300000", "sally").is_none());
+
+        let users = std::thread::spawn(move || {
+            let mut users: Vec<String> = view.iter().map(|item| item.user).collect();
+            users.sort();
+            users
+        });
+        assert_eq!(users.join().unwrap(), ["jack", "sammy"]);
+        assert_eq!(store.snapshot().iter().count(), 1);
+    }
+
+    #[test]
+    fn transact() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut otps = DataStore::create().with_clock(clock.clone());
+        let mut sessions = DataStore::create().with_clock(clock);
+        otps.put(SessionItem::created_at("123456", "jack", 60, 1_000))
+            .unwrap();
+        let rx = sessions.subscribe();
+
+        // exchange the otp for a session
+        let expires = otps
+            .transact_with(&mut sessions, |otp, session| {
+                if !otp.consume("123456", "jack").is_valid() {
+                    bail!("bad code");
+                }
+                let item = SessionItem::created_at("abcdef", "jack", 600, session.now());
+                session.insert(item.clone())?;
+                Ok(item.expires)
+            })
+            .unwrap();
+        assert_eq!(expires, 1_600);
+        assert_eq!(otps.validate("123456", "jack"), Validation::Consumed);
+        assert!(sessions.get("abcdef", "jack").is_some());
+        assert!(matches!(rx.try_recv(), Ok(Change::Put { .. })));
+
+        // a failure part way leaves the store as it was
+        otps.put(SessionItem::created_at("654321", "jack", 60, 1_000))
+            .unwrap();
+        let result: Result<()> = otps.transact(|txn| {
+            assert!(txn.remove("654321", "jack"));
+            assert_eq!(txn.validate("654321", "jack"), Validation::Revoked);
+            txn.put(SessionItem::created_at("111111", "jack", 60, 1_000));
+            txn.insert(SessionItem::created_at("111111", "jack", 60, 1_000))
+        });
+        assert!(result.unwrap_err().is::<AlreadyExists>());
+        assert!(otps.get("654321", "jack").is_some());
+        assert!(otps.get("111111", "jack").is_none());
+        assert_eq!(otps.validate("654321", "jack"), Validation::Valid);
+
+        assert!(otps
+            .transact_with(&mut otps.clone(), |_, _| Ok(()))
+            .is_err());
+        sessions.set_read_only(true);
+        assert!(sessions.transact(|_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut store = DataStore::create().with_clock(Arc::new(clock.clone()));
+        for code in ["100000", "200000", "300000"] {
+            store
+                .put(SessionItem::created_at(code, "jack", 60, store.now()))
+                .unwrap();
+        }
+        assert_eq!(store.validate("100000", "jack"), Validation::Valid);
+        assert_eq!(store.validate("100000", "sally"), Validation::NotFound);
+
+        assert_eq!(store.consume("100000", "jack"), Validation::Valid);
+        assert_eq!(store.consume("100000", "jack"), Validation::Consumed);
+        assert!(store.remove("200000", "jack"));
+        assert_eq!(store.validate("200000", "jack"), Validation::Revoked);
+
+        assert!(store.lock_user("jack"));
+        assert_eq!(store.validate("300000", "jack"), Validation::Locked);
+        assert!(store.unlock_user("jack"));
+
+        clock.advance(std::time::Duration::from_secs(60));
+        assert_eq!(store.validate("300000", "jack"), Validation::Expired);
+        assert_eq!(store.purge_expired(), 1);
+        assert_eq!(store.validate("300000", "jack"), Validation::Expired);
+
+        clock.advance(std::time::Duration::from_secs(TOMBSTONE_TTL));
+        store.purge_expired();
+        assert_eq!(store.validate("300000", "jack"), Validation::NotFound);
+        assert_eq!(store.validate("100000", "jack"), Validation::NotFound);
+    }
+
+    #[test]
+    fn validate_allocations() {
+        let mut store = DataStore::create();
+        store.put(SessionItem::new("100000", "jack", 60)).unwrap();
+        store.put(SessionItem::new("200000", "sammy", 0)).unwrap();
+        assert!(store.validate("100000", "jack").is_valid());
+
+        let before = allocations();
+        for _ in 0..100 {
+            assert!(store.validate("100000", "jack").is_valid());
+            assert_eq!(store.validate("200000", "sammy"), Validation::Expired);
+        }
+        assert_eq!(allocations(), before);
+    }
+
+    #[test]
+    fn purge_expired_chunk() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut store = DataStore::create().with_clock(clock.clone());
+        for n in 0..100 {
+            let keep_alive = if n % 5 < 3 { 60 } else { 600 };
+            let item = SessionItem::created_at(
+                &format!("{n:06}"),
+                &format!("user-{n}"),
+                keep_alive,
+                1_000,
+            );
+            store.put(item).unwrap();
+        }
+        store.bump_generation("user-4");
+
+        clock.set(1_100);
+        let (mut count, mut calls, mut cursor) = (0, 0, PurgeCursor::default());
+        loop {
+            let (expired, next) = store.purge_expired_chunk(cursor, 7);
+            count += expired;
+            calls += 1;
+            match next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert_eq!(count, 60);
+        assert!(calls >= 100 / 7);
+        assert_eq!(store.dbsize(), 39);
+        assert_eq!(store.validate("000000", "user-0"), Validation::Expired);
+        assert_eq!(store.validate("000004", "user-4"), Validation::Revoked);
+        assert_eq!(store.validate("000003", "user-3"), Validation::Valid);
+
+        // a pass in one call finds nothing left
+        assert_eq!(
+            store.purge_expired_chunk(PurgeCursor::default(), 1_000),
+            (0, None)
+        );
+    }
+
+    #[test]
+    fn get_ref() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut store = DataStore::create().with_clock(clock.clone());
+        let item =
+            SessionItem::created_at("100000", "jack", 60, 1_000).with_meta("device", "laptop");
+        store.put(item.clone()).unwrap();
+        store
+            .put(SessionItem::created_at("200000", "jack", 120, 1_000))
+            .unwrap();
+
+        let before = allocations();
+        {
+            let found = store.get_ref("100000", "jack").unwrap();
+            assert_eq!(
+                (found.code(), found.user(), found.expires()),
+                ("100000", "jack", 1_060)
+            );
+            assert_eq!(found.meta()["device"], "laptop");
+            // other reads don't wait on the borrow
+            assert!(store.get_ref("100000", "sally").is_none());
+        }
+        assert_eq!(allocations(), before);
+        assert_eq!(store.get_ref("100000", "jack").unwrap().to_item(), item);
+
+        clock.set(1_060);
+        assert!(store.get_ref("100000", "jack").is_none());
+        store.bump_generation("jack");
+        assert!(store.get_ref("200000", "jack").is_none());
+    }
+
+    #[test]
+    fn saturating_expiry() {
+        let item = SessionItem::created_at("100000", "jack", u64::MAX, 1_000);
+        assert_eq!(item.expires, u64::MAX);
+        assert!(!item.has_expired());
+    }
+
     #[test]
     fn create_key() {
         let store = DataStore::create();
@@ -204,6 +2765,190 @@ mod tests {
         let user = "jack";
 
         let key = store.create_key(code, user);
-        assert_eq!(key, "100000:jack");
+        assert_eq!(key.to_string(), "100000:jack");
+    }
+
+    #[test]
+    fn interned_users() {
+        let mut store = DataStore::create();
+        for code in ["100000", "100001", "100002"] {
+            store.put(SessionItem::new(code, "jack", 60)).unwrap();
+        }
+        store.put(SessionItem::new("100003", "sally", 60)).unwrap();
+
+        let map = store.shard("jack").read().unwrap();
+        let jack: Vec<&Arc<str>> = map
+            .keys()
+            .map(|key| &key.user)
+            .filter(|user| &***user == "jack")
+            .collect();
+        assert_eq!(jack.len(), 3);
+        assert!(jack.iter().all(|user| Arc::ptr_eq(user, jack[0])));
+        // the keys and the index share the one copy
+        assert_eq!(Arc::strong_count(jack[0]), 4);
+        drop(map);
+
+        assert_eq!(store.get("100001", "jack").unwrap().user, "jack");
+        assert_eq!(store.remove_user("jack"), 3);
+        assert!(!store
+            .shard("jack")
+            .read()
+            .unwrap()
+            .users
+            .contains_key("jack"));
+    }
+
+    #[test]
+    fn user_codes() {
+        let mut store = DataStore::create();
+        let codes: Vec<String> = (0..5).map(|n| format!("{:06}", 100_000 + n)).collect();
+        let spilled = |store: &DataStore| {
+            store.shard("jack").read().unwrap().users["jack"]
+                .spilled
+                .is_some()
+        };
+        for code in &codes[..INLINE_CODES] {
+            store.put(SessionItem::new(code, "jack", 60)).unwrap();
+        }
+        assert!(!spilled(&store));
+        for code in &codes[INLINE_CODES..] {
+            store.put(SessionItem::new(code, "jack", 60)).unwrap();
+        }
+        assert!(spilled(&store));
+        assert_eq!(store.count_user("jack"), 5);
+
+        // compacting moves the codes back in place once few enough are left
+        store.remove(&codes[0], "jack");
+        store.remove(&codes[1], "jack");
+        store.compact();
+        assert!(!spilled(&store));
+        assert_eq!(store.scan_user("jack").len(), 3);
+        store.put(SessionItem::new(&codes[0], "jack", 60)).unwrap();
+        assert_eq!(store.count_user("jack"), 4);
+    }
+
+    #[test]
+    fn shards() {
+        // users spread over the shards, and every store agrees on each user's shard
+        let shards: HashSet<usize> = (0..100).map(|n| shard_of(&format!("user-{n}"))).collect();
+        assert!(shards.len() > SHARDS / 2);
+        assert_eq!(shard_of("jack"), shard_of("jack"));
+
+        let store = DataStore::create();
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let mut store = store.clone();
+                scope.spawn(move || {
+                    for n in 0..100 {
+                        let (code, user) = (format!("{n:06}"), format!("user-{t}-{n}"));
+                        store.put(SessionItem::new(&code, &user, 60)).unwrap();
+                        assert_eq!(store.validate(&code, &user), Validation::Valid);
+                        assert_eq!(store.consume(&code, &user), Validation::Valid);
+                        assert_eq!(store.validate(&code, &user), Validation::Consumed);
+                    }
+                });
+            }
+        });
+        assert_eq!(store.dbsize(), 0);
+    }
+
+    #[test]
+    fn with_hashing() {
+        let mut store = DataStore::create();
+        assert_eq!(store.hashing(), Hashing::AHash);
+        store.put(SessionItem::new("100000", "jack", 60)).unwrap();
+        store.put(SessionItem::new("100001", "jack", 60)).unwrap();
+        store.remove("100001", "jack");
+        store.lock_user("sally");
+        store.bump_generation("sammy");
+
+        // items, tombstones, locks and generations move to the new maps
+        let store = store.with_hashing(Hashing::Fx);
+        assert_eq!(store.hashing(), Hashing::Fx);
+        assert_eq!(store.validate("100000", "jack"), Validation::Valid);
+        assert_eq!(store.validate("100001", "jack"), Validation::Revoked);
+        assert_eq!(store.scan_user("jack").len(), 1);
+        assert!(store.is_locked("sally"));
+        assert_eq!(store.generation("sammy"), 1);
+        assert_eq!(store.snapshot().get("100000", "jack").unwrap().user, "jack");
+    }
+
+    #[test]
+    fn compact() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let mut store = DataStore::with_capacity(1_000).with_clock(clock.clone());
+        assert!(store.capacity() >= 1_000);
+        for n in 0..1_000 {
+            let item = SessionItem::created_at(&format!("{n:06}"), "jack", 60, 1_000);
+            store.put(item).unwrap();
+        }
+        store
+            .put(SessionItem::created_at("999999", "sally", 600, 1_000))
+            .unwrap();
+        let view = store.snapshot();
+
+        clock.set(1_100);
+        assert_eq!(store.purge_expired(), 1_000);
+        let grown = store.capacity();
+        store.compact();
+        assert!(store.capacity() < grown);
+        assert_eq!(store.dbsize(), 1);
+        assert_eq!(store.validate("999999", "sally"), Validation::Valid);
+        assert_eq!(store.validate("000001", "jack"), Validation::Expired);
+        // a snapshot taken before keeps its own copy
+        assert_eq!(view.len(), 1_001);
+    }
+
+    #[test]
+    fn code() {
+        let uuid = "0189a1b2-c3d4-7e5f-8a6b-7c8d9e0f1a2b";
+        let ulid = "01ARYZ6S41TSV4RRFFQ69G5FAV";
+        for text in [
+            "000123",
+            "1234567890",
+            "0a1b",
+            "00ff",
+            "ABC",
+            "12-34",
+            "",
+            uuid,
+            ulid,
+        ] {
+            assert_eq!(Code::parse(text).to_string(), text);
+        }
+        assert!(matches!(
+            Code::parse("004217"),
+            Code::Digits {
+                value: 4217,
+                len: 6
+            }
+        ));
+        assert!(matches!(
+            Code::parse(&random_hex(16)),
+            Code::Hex { len: 32, .. }
+        ));
+        assert!(matches!(Code::parse("ABC"), Code::Text(_)));
+        assert!(matches!(Code::parse(uuid), Code::Uuid(_)));
+        assert!(matches!(Code::parse(&uuid.to_uppercase()), Code::Text(_)));
+        assert!(matches!(Code::parse(ulid), Code::Ulid(_)));
+        assert!(matches!(Code::parse(&ulid.to_lowercase()), Code::Text(_)));
+
+        // codes that differ only in leading zeros are different codes
+        assert_ne!(Code::parse("0123"), Code::parse("123"));
+
+        let mut store = DataStore::create();
+        let session = random_hex(16);
+        store.put(SessionItem::new("004217", "jack", 60)).unwrap();
+        store.put(SessionItem::new(&session, "jack", 60)).unwrap();
+        assert_eq!(store.validate("004217", "jack"), Validation::Valid);
+        assert_eq!(store.validate("4217", "jack"), Validation::NotFound);
+        assert_eq!(store.get(&session, "jack").unwrap().code, session);
+        let mut codes: Vec<String> = store
+            .scan_user("jack")
+            .into_iter()
+            .map(|item| item.code)
+            .collect();
+        codes.sort();
+        assert_eq!(codes, ["004217".to_string(), session]);
     }
 }
@@ -0,0 +1,399 @@
+/// oauth 2.0 device authorization grant (rfc 8628): a device shows a short user code, the user approves it on another
+/// screen, and the device polls with its device code until it is approved, denied or expired
+use crate::clock::Clock;
+use crate::db::{DataStore, SessionItem, Validation};
+use crate::hash::{random_hex, random_string, sha256_hex};
+use crate::logging;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a device code is good for
+pub const DEVICE_CODE_TTL: u64 = 600;
+
+/// the default seconds a device must wait between polls
+pub const POLL_INTERVAL: u64 = 5;
+
+/// the seconds added to the interval each time a device polls too fast
+pub const SLOW_DOWN: u64 = 5;
+
+/// the number of random bytes in a device code
+pub const DEVICE_CODE_BYTES: usize = 32;
+
+/// the characters of a user code: consonants only, so codes can't spell words or mix up 0/O and 1/I
+pub const USER_CODE_CHARS: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// the number of characters in a user code, shown as two halves, e.g. WDJB-MJHT
+pub const USER_CODE_LENGTH: usize = 8;
+
+// every device code is stored under this user, since its user is only known once approved
+const DEVICE_USER: &str = "device";
+
+/// the device authorization response, returned to the device when it starts the flow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceAuthorization {
+    /// the secret the device polls with
+    pub device_code: String,
+    /// the code the user types in, e.g. WDJB-MJHT
+    pub user_code: String,
+    pub verification_uri: String,
+    /// the verification uri with the user code filled in, e.g. for a qr code
+    pub verification_uri_complete: String,
+    /// seconds until the codes expire
+    pub expires_in: u64,
+    /// seconds the device must wait between polls
+    pub interval: u64,
+}
+
+/// a pending request as shown on the approval page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRequest {
+    pub user_code: String,
+    pub client_id: String,
+    pub scope: Option<String>,
+    pub expires: u64,
+}
+
+/// the answer to a device's poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DevicePoll {
+    /// the user has not answered yet
+    Pending,
+    /// the device polled too fast; it must add SLOW_DOWN seconds to its interval
+    SlowDown,
+    /// the user approved; the device code is used up
+    Approved {
+        user: String,
+        client_id: String,
+        scope: Option<String>,
+    },
+    Denied,
+    Expired,
+    /// an unknown or already used device code
+    NotFound,
+}
+
+impl DevicePoll {
+    /// return the rfc 8628 token endpoint error, or None when approved
+    pub fn error(&self) -> Option<&'static str> {
+        match self {
+            DevicePoll::Pending => Some("authorization_pending"),
+            DevicePoll::SlowDown => Some("slow_down"),
+            DevicePoll::Approved { .. } => None,
+            DevicePoll::Denied => Some("access_denied"),
+            DevicePoll::Expired => Some("expired_token"),
+            DevicePoll::NotFound => Some("invalid_grant"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Request {
+    user_code: String,
+    client_id: String,
+    scope: Option<String>,
+    expires: u64,
+    interval: u64,
+    last_poll: Option<u64>,
+    approved: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Requests {
+    // keyed by the device code's sha-256
+    pending: HashMap<String, Request>,
+    // the normalized user code to the device code's sha-256
+    user_codes: HashMap<String, String>,
+}
+
+/// the device authorization requests; the device codes are kept hashed in a DataStore for their expiry and reuse
+/// tracking. clones share the requests
+#[derive(Debug, Clone)]
+pub struct DeviceFlow {
+    store: DataStore,
+    requests: Arc<RwLock<Requests>>,
+    verification_uri: String,
+    ttl: u64,
+    interval: u64,
+}
+
+// upper case the code and drop the dash and spaces users type
+pub(crate) fn normalize(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// a random user code from the secure generator, shown with a dash between the halves
+pub(crate) fn user_code() -> String {
+    let chars = random_string(USER_CODE_CHARS, USER_CODE_LENGTH);
+    let (first, second) = chars.split_at(USER_CODE_LENGTH / 2);
+    format!("{}-{}", first, second)
+}
+
+impl DeviceFlow {
+    /// create the flow for users approving at this uri, e.g. https://example.com/device
+    pub fn new(verification_uri: &str) -> DeviceFlow {
+        DeviceFlow {
+            store: DataStore::create(),
+            requests: Arc::new(RwLock::new(Requests::default())),
+            verification_uri: verification_uri.to_string(),
+            ttl: DEVICE_CODE_TTL,
+            interval: POLL_INTERVAL,
+        }
+    }
+
+    /// codes are good for this many seconds
+    pub fn with_ttl(mut self, ttl: u64) -> DeviceFlow {
+        self.ttl = ttl;
+        self
+    }
+
+    /// devices must wait this many seconds between polls
+    pub fn with_interval(mut self, interval: u64) -> DeviceFlow {
+        self.interval = interval;
+        self
+    }
+
+    /// use this clock for expirations and poll intervals instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> DeviceFlow {
+        self.store = self.store.with_clock(clock);
+        self
+    }
+
+    /// start a request for the client; return the codes and where to send the user
+    pub fn start(&mut self, client_id: &str, scope: Option<&str>) -> Result<DeviceAuthorization> {
+        let device_code = random_hex(DEVICE_CODE_BYTES);
+        let key = sha256_hex(device_code.as_bytes());
+        let now = self.store.now();
+        self.store
+            .put(SessionItem::created_at(&key, DEVICE_USER, self.ttl, now))?;
+
+        let mut requests = self.requests.write().unwrap();
+        let mut code = user_code();
+        while requests.user_codes.contains_key(&normalize(&code)) {
+            code = user_code();
+        }
+        requests.user_codes.insert(normalize(&code), key.clone());
+        let request = Request {
+            user_code: code.clone(),
+            client_id: client_id.to_string(),
+            scope: scope.map(|scope| scope.to_string()),
+            expires: now.saturating_add(self.ttl),
+            interval: self.interval,
+            last_poll: None,
+            approved: None,
+        };
+        requests.pending.insert(key, request);
+        drop(requests);
+        logging::event(
+            "device.start",
+            &[("client", client_id), ("code", &device_code)],
+        );
+
+        Ok(DeviceAuthorization {
+            device_code,
+            verification_uri_complete: format!("{}?user_code={}", self.verification_uri, code),
+            user_code: code,
+            verification_uri: self.verification_uri.clone(),
+            expires_in: self.ttl,
+            interval: self.interval,
+        })
+    }
+
+    // return the device code key for the user code if its request is still open
+    fn open(&self, user_code: &str) -> Result<String> {
+        let requests = self.requests.read().unwrap();
+        let Some(key) = requests.user_codes.get(&normalize(user_code)) else {
+            bail!("user code not valid: {}", Validation::NotFound.as_str());
+        };
+
+        let result = self.store.validate(key, DEVICE_USER);
+        if !result.is_valid() {
+            bail!("user code not valid: {}", result.as_str());
+        }
+        if requests
+            .pending
+            .get(key)
+            .is_some_and(|r| r.approved.is_some())
+        {
+            bail!("user code not valid: already approved");
+        }
+
+        Ok(key.clone())
+    }
+
+    /// return the open request for the user code, to show the user what they are approving
+    pub fn lookup(&self, user_code: &str) -> Result<DeviceRequest> {
+        let key = self.open(user_code)?;
+        let requests = self.requests.read().unwrap();
+        match requests.pending.get(&key) {
+            Some(request) => Ok(DeviceRequest {
+                user_code: request.user_code.clone(),
+                client_id: request.client_id.clone(),
+                scope: request.scope.clone(),
+                expires: request.expires,
+            }),
+            None => bail!("user code not valid: {}", Validation::NotFound.as_str()),
+        }
+    }
+
+    /// approve the request for the signed in user; the device's next poll returns them
+    pub fn approve(&mut self, user_code: &str, user: &str) -> Result<()> {
+        let key = self.open(user_code)?;
+        if let Some(request) = self.requests.write().unwrap().pending.get_mut(&key) {
+            request.approved = Some(user.to_string());
+        }
+        logging::event("device.approve", &[("user", user)]);
+
+        Ok(())
+    }
+
+    /// deny the request; the device's next poll is told so
+    pub fn deny(&mut self, user_code: &str) -> Result<()> {
+        let key = self.open(user_code)?;
+        self.store.remove(&key, DEVICE_USER);
+        self.requests
+            .write()
+            .unwrap()
+            .user_codes
+            .remove(&normalize(user_code));
+        logging::event("device.deny", &[]);
+
+        Ok(())
+    }
+
+    /// answer the device's poll; once approved the device code is used up, so only one poll gets the user
+    pub fn poll(&mut self, device_code: &str) -> DevicePoll {
+        let key = sha256_hex(device_code.as_bytes());
+        let now = self.store.now();
+        match self.store.validate(&key, DEVICE_USER) {
+            Validation::Valid => (),
+            Validation::Expired => return DevicePoll::Expired,
+            Validation::Revoked => return DevicePoll::Denied,
+            _ => return DevicePoll::NotFound,
+        }
+
+        let mut requests = self.requests.write().unwrap();
+        let Some(request) = requests.pending.get_mut(&key) else {
+            return DevicePoll::NotFound;
+        };
+
+        let last_poll = request.last_poll.replace(now);
+        if last_poll.is_some_and(|last| now < last.saturating_add(request.interval)) {
+            request.interval += SLOW_DOWN;
+            return DevicePoll::SlowDown;
+        }
+        let Some(user) = request.approved.clone() else {
+            return DevicePoll::Pending;
+        };
+
+        if !self.store.consume(&key, DEVICE_USER).is_valid() {
+            return DevicePoll::NotFound;
+        }
+        let Some(request) = requests.pending.remove(&key) else {
+            return DevicePoll::NotFound;
+        };
+        requests.user_codes.remove(&normalize(&request.user_code));
+        drop(requests);
+        logging::event(
+            "device.token",
+            &[("user", &user), ("client", &request.client_id)],
+        );
+
+        DevicePoll::Approved {
+            user,
+            client_id: request.client_id,
+            scope: request.scope,
+        }
+    }
+
+    /// forget the expired requests; return the number removed
+    pub fn purge_expired(&mut self) -> usize {
+        self.store.purge_expired();
+        let now = self.store.now();
+        let mut requests = self.requests.write().unwrap();
+        let before = requests.pending.len();
+        requests.pending.retain(|_, request| request.expires > now);
+        let Requests {
+            pending,
+            user_codes,
+        } = &mut *requests;
+        user_codes.retain(|_, key| pending.contains_key(key));
+
+        before - pending.len()
+    }
+
+    /// return the number of open requests, including expired ones not yet purged
+    pub fn len(&self) -> usize {
+        self.requests.read().unwrap().pending.len()
+    }
+
+    /// return true if there are no open requests
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn approve_poll() {
+        let clock = MockClock::at(1_000);
+        let mut flow = DeviceFlow::new("https://example.com/device")
+            .with_ttl(300)
+            .with_clock(Arc::new(clock.clone()));
+        let auth = flow.start("tv-app", Some("profile")).unwrap();
+        assert_eq!((auth.expires_in, auth.interval), (300, POLL_INTERVAL));
+        assert_eq!(auth.user_code.len(), USER_CODE_LENGTH + 1);
+        assert!(auth.verification_uri_complete.ends_with(&auth.user_code));
+
+        assert_eq!(flow.poll(&auth.device_code), DevicePoll::Pending);
+        clock.set(1_002);
+        assert_eq!(flow.poll(&auth.device_code), DevicePoll::SlowDown);
+        assert_eq!(flow.poll(&auth.device_code).error(), Some("slow_down"));
+
+        // users may type the code in lower case and without the dash
+        let typed = auth.user_code.replace('-', "").to_lowercase();
+        assert_eq!(flow.lookup(&typed).unwrap().client_id, "tv-app");
+        flow.approve(&typed, "sally").unwrap();
+        assert!(flow.approve(&auth.user_code, "mallory").is_err());
+
+        clock.set(1_100);
+        let approved = DevicePoll::Approved {
+            user: "sally".to_string(),
+            client_id: "tv-app".to_string(),
+            scope: Some("profile".to_string()),
+        };
+        assert_eq!(flow.poll(&auth.device_code), approved);
+        assert_eq!(flow.poll(&auth.device_code), DevicePoll::NotFound);
+        assert!(flow.is_empty());
+    }
+
+    #[test]
+    fn deny_expire() {
+        let clock = MockClock::at(1_000);
+        let mut flow =
+            DeviceFlow::new("https://example.com/device").with_clock(Arc::new(clock.clone()));
+        let denied = flow.start("cli", None).unwrap();
+        flow.deny(&denied.user_code).unwrap();
+        assert_eq!(flow.poll(&denied.device_code), DevicePoll::Denied);
+        assert!(flow.lookup(&denied.user_code).is_err());
+
+        let expired = flow.start("cli", None).unwrap();
+        clock.set(1_000 + DEVICE_CODE_TTL);
+        assert_eq!(
+            flow.poll(&expired.device_code).error(),
+            Some("expired_token")
+        );
+        let err = flow.approve(&expired.user_code, "sally").unwrap_err();
+        assert_eq!(err.to_string(), "user code not valid: expired");
+        assert_eq!(flow.purge_expired(), 2);
+        assert_eq!(flow.poll("nope"), DevicePoll::NotFound);
+    }
+}
@@ -0,0 +1,229 @@
+/// an smtp OtpSender for small apps that want email otps without wiring their own transport
+use crate::clock::{Clock, SystemClock};
+use crate::sender::{OtpSender, SendContext};
+use crate::template::{Channel, Template, Templates};
+use anyhow::{anyhow, bail, Result};
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the timeout for each smtp send
+pub const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how the connection to the smtp server is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// tls from the start, usually port 465
+    Tls,
+    /// upgrade a plain connection with STARTTLS, usually port 587
+    #[default]
+    StartTls,
+    /// no tls, only for a local test server like mailpit
+    Off,
+}
+
+impl SmtpTls {
+    /// return the name used in config files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SmtpTls::Tls => "tls",
+            SmtpTls::StartTls => "starttls",
+            SmtpTls::Off => "off",
+        }
+    }
+}
+
+impl FromStr for SmtpTls {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<SmtpTls> {
+        match text {
+            "tls" => Ok(SmtpTls::Tls),
+            "starttls" => Ok(SmtpTls::StartTls),
+            "off" => Ok(SmtpTls::Off),
+            _ => bail!("{} is not an smtp tls mode; use tls, starttls or off", text),
+        }
+    }
+}
+
+/// the smtp server and sender address
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// the server port; None uses the default for the tls mode
+    pub port: Option<u16>,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// the from address, e.g. `Example <no-reply@example.com>`
+    pub from: String,
+}
+
+impl fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("username", &self.username)
+            .field("from", &self.from)
+            .finish()
+    }
+}
+
+/// sends otps by email over smtp. the recipient is the context's `email` or `to` field if set, otherwise the user
+#[derive(Clone)]
+pub struct EmailSender {
+    transport: SmtpTransport,
+    config: SmtpConfig,
+    from: Mailbox,
+    templates: Templates,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for EmailSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailSender")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl EmailSender {
+    /// create a sender for the server; nothing is sent until the first code
+    pub fn new(config: SmtpConfig) -> Result<EmailSender> {
+        let from: Mailbox = config
+            .from
+            .parse()
+            .map_err(|_| anyhow!("{} is not an email address", config.from))?;
+        let builder = match config.tls {
+            SmtpTls::Tls => SmtpTransport::relay(&config.host)?,
+            SmtpTls::StartTls => SmtpTransport::starttls_relay(&config.host)?,
+            SmtpTls::Off => SmtpTransport::builder_dangerous(config.host.as_str()),
+        };
+        let builder = match config.port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+        let builder = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                builder.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            (None, None) => builder,
+            _ => bail!("smtp auth needs both a username and a password"),
+        };
+
+        Ok(EmailSender {
+            transport: builder.timeout(Some(SMTP_TIMEOUT)).build(),
+            config,
+            from,
+            templates: Templates::default(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// render messages from these templates, e.g. ones shared with the sms sender
+    pub fn with_templates(mut self, templates: Templates) -> EmailSender {
+        self.templates = templates;
+        self
+    }
+
+    /// use this template for purposes without their own
+    pub fn with_template(mut self, template: Template) -> EmailSender {
+        self.templates = self.templates.with(Channel::Email, template);
+        self
+    }
+
+    /// use this template for codes sent for the purpose, e.g. verify_email
+    pub fn with_purpose_template(mut self, purpose: &str, template: Template) -> EmailSender {
+        self.templates = self
+            .templates
+            .with_purpose(Channel::Email, purpose, template);
+        self
+    }
+
+    /// use this clock for the minutes left in the message instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> EmailSender {
+        self.clock = clock;
+        self
+    }
+
+    /// return the subject and body that would be sent for the code
+    pub fn render(&self, user: &str, code: &str, context: &SendContext) -> (String, String) {
+        self.templates
+            .render(Channel::Email, user, code, context, self.clock.now())
+    }
+}
+
+impl OtpSender for EmailSender {
+    fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()> {
+        let to = context.recipient(Channel::Email, user);
+        let to: Mailbox = to
+            .parse()
+            .map_err(|_| anyhow!("{} is not an email address", to))?;
+        let (subject, body) = self.render(user, code, context);
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        self.transport.send(&message)?;
+
+        Ok(())
+    }
+
+    fn channel(&self) -> Option<Channel> {
+        Some(Channel::Email)
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<lettre::transport::smtp::Error>()
+            .is_some_and(|e| e.is_transient() || e.is_timeout())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn render() {
+        let config = SmtpConfig {
+            host: "localhost".to_string(),
+            tls: SmtpTls::Off,
+            from: "no-reply@example.com".to_string(),
+            ..Default::default()
+        };
+        let verify = Template::new("Verify {app}", "Hi {user}, use {code} for {purpose}.");
+        let sender = EmailSender::new(config)
+            .unwrap()
+            .with_purpose_template("verify_email", verify)
+            .with_clock(Arc::new(MockClock::at(1_000)));
+
+        let mut context = SendContext::new("login");
+        context.expires = 1_290;
+        let (subject, body) = sender.render("sally@example.com", "123456", &context);
+        assert_eq!(subject, "Your code is 123456");
+        assert!(body.starts_with("Your one-time code is 123456. It expires in 5 minutes."));
+
+        let context = SendContext::new("verify_email").with("app", "Acme");
+        let (subject, body) = sender.render("sally", "654321", &context);
+        assert_eq!(subject, "Verify Acme");
+        assert_eq!(body, "Hi sally, use 654321 for verify_email.");
+
+        assert!("ssl".parse::<SmtpTls>().is_err());
+        let config = SmtpConfig {
+            from: "nobody".to_string(),
+            ..Default::default()
+        };
+        assert!(EmailSender::new(config).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+/// the lifecycle event bus: subscribers observe otp and session events without the stores knowing about them
+use crate::clock::unix_now;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// the store an event or change belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Store {
+    Otp,
+    Session,
+}
+
+impl Store {
+    /// return the store name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Store::Otp => "otp",
+            Store::Session => "session",
+        }
+    }
+}
+
+impl fmt::Display for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// a new code was generated and stored
+    Created,
+    /// a caller supplied item was stored
+    Put,
+    /// a session's expiration was extended
+    Touched,
+    /// a code was checked; ok is false for unknown or expired codes
+    Validated { ok: bool },
+    /// a code was removed
+    Removed,
+    /// all of a user's codes were removed
+    UserRemoved { count: usize },
+    /// expired items were purged
+    Expired { count: usize },
+    /// a traded refresh code was used again, a sign it was stolen; its token family was revoked
+    RefreshReused,
+    /// an admin started a session as the event's user
+    Impersonated { admin: String },
+    /// an otp was sent again; ok is false if the resend was throttled or failed
+    Resent { ok: bool },
+    /// an admin removed every item in the store
+    Cleared { count: usize },
+}
+
+impl EventKind {
+    /// return the event name, e.g. validation_failed
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Put => "put",
+            EventKind::Touched => "touched",
+            EventKind::Validated { ok: true } => "validated",
+            EventKind::Validated { ok: false } => "validation_failed",
+            EventKind::Removed => "removed",
+            EventKind::UserRemoved { .. } => "user_removed",
+            EventKind::Expired { .. } => "expired",
+            EventKind::RefreshReused => "refresh_reused",
+            EventKind::Impersonated { .. } => "impersonated",
+            EventKind::Resent { ok: true } => "resent",
+            EventKind::Resent { ok: false } => "resend_failed",
+            EventKind::Cleared { .. } => "cleared",
+        }
+    }
+}
+
+/// a lifecycle event; the code is the live value, so subscribers that persist or forward it should redact it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub store: Store,
+    pub user: Option<String>,
+    pub code: Option<String>,
+    /// unix time in seconds
+    pub time: u64,
+}
+
+impl Event {
+    /// create an event stamped with the current system time
+    pub fn new(kind: EventKind, store: Store, user: Option<&str>, code: Option<&str>) -> Event {
+        Event::at(kind, store, user, code, unix_now())
+    }
+
+    /// create an event stamped with the unix time, e.g. from the store's clock
+    pub fn at(
+        kind: EventKind,
+        store: Store,
+        user: Option<&str>,
+        code: Option<&str>,
+        time: u64,
+    ) -> Event {
+        Event {
+            kind,
+            store,
+            user: user.map(|u| u.to_string()),
+            code: code.map(|c| c.to_string()),
+            time,
+        }
+    }
+
+    /// return the qualified event name, e.g. session.created
+    pub fn name(&self) -> String {
+        format!("{}.{}", self.store, self.kind.name())
+    }
+}
+
+/// observes lifecycle events; called synchronously on the thread that made the change, so keep it quick
+pub trait EventSubscriber: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> EventSubscriber for F {
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// identifies a subscription so it can be removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriberId(u64);
+
+type Subscribers = Vec<(SubscriberId, Arc<dyn EventSubscriber>)>;
+
+/// the event bus; clones share the same subscribers
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<RwLock<Subscribers>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field("subscribers", &self.len())
+            .finish()
+    }
+}
+
+impl Events {
+    /// create a bus with no subscribers
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// add a subscriber for every future event
+    pub fn subscribe(&self, subscriber: Arc<dyn EventSubscriber>) -> SubscriberId {
+        let id = SubscriberId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.subscribers.write().unwrap().push((id, subscriber));
+        id
+    }
+
+    /// remove the subscriber; return true if it was subscribed
+    pub fn unsubscribe(&self, id: SubscriberId) -> bool {
+        let mut subscribers = self.subscribers.write().unwrap();
+        let before = subscribers.len();
+        subscribers.retain(|(sid, _)| *sid != id);
+        subscribers.len() < before
+    }
+
+    /// return the number of subscribers
+    pub fn len(&self) -> usize {
+        self.subscribers.read().unwrap().len()
+    }
+
+    /// return true if there are no subscribers
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// send the event to each subscriber
+    pub fn publish(&self, event: &Event) {
+        let subscribers = self.subscribers.read().unwrap();
+        for (_, subscriber) in subscribers.iter() {
+            subscriber.on_event(event);
+        }
+    }
+
+    // build the event only when someone is listening
+    pub(crate) fn emit(
+        &self,
+        kind: EventKind,
+        store: Store,
+        user: Option<&str>,
+        code: Option<&str>,
+        time: u64,
+    ) {
+        if !self.is_empty() {
+            self.publish(&Event::at(kind, store, user, code, time));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn subscribe_publish() {
+        let events = Events::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let id = events.subscribe(Arc::new(move |event: &Event| {
+            log.lock().unwrap().push(event.kind.clone());
+        }));
+
+        // clones share the subscribers
+        let shared = events.clone();
+        shared.emit(
+            EventKind::Created,
+            Store::Otp,
+            Some("sally"),
+            Some("123456"),
+            unix_now(),
+        );
+        assert_eq!(*seen.lock().unwrap(), vec![EventKind::Created]);
+
+        assert!(events.unsubscribe(id));
+        assert!(!events.unsubscribe(id));
+        shared.emit(
+            EventKind::Removed,
+            Store::Otp,
+            Some("sally"),
+            Some("123456"),
+            unix_now(),
+        );
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn event() {
+        let event = Event::new(EventKind::Expired { count: 2 }, Store::Session, None, None);
+        assert_eq!(event.store.to_string(), "session");
+        assert_eq!(event.name(), "session.expired");
+        assert!(event.user.is_none());
+        assert!(event.time > 0);
+    }
+}
@@ -0,0 +1,364 @@
+/// gRPC frontend for the otp and session stores, generated from proto/otp_session.proto
+use crate::admin::{AdminTokens, Scope};
+use crate::db::SessionItem;
+use crate::otp::Otp;
+use crate::session::Session;
+use log::info;
+use std::net::SocketAddr;
+use tonic::{transport::Server, Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("otp_session");
+}
+
+use proto::otp_service_server::{OtpService, OtpServiceServer};
+use proto::session_service_server::{SessionService, SessionServiceServer};
+use proto::{
+    CreateRequest, CreateResponse, Item, ListRequest, ListResponse, RemoveRequest, RemoveResponse,
+    ValidateRequest, ValidateResponse,
+};
+
+// map the store items to the wire format
+fn list_response(items: Vec<SessionItem>) -> ListResponse {
+    let items = items
+        .into_iter()
+        .map(|item| Item {
+            code: item.code,
+            user: item.user,
+            expires: item.expires,
+        })
+        .collect();
+
+    ListResponse { items }
+}
+
+// admin calls carry "authorization: Bearer <token>" metadata for a token with the scope
+fn authorize<T>(admin: &AdminTokens, request: &Request<T>, scope: Scope) -> Result<(), Status> {
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if admin.authorize(token, scope) {
+        Ok(())
+    } else {
+        let msg = format!("admin token with {} scope required", scope);
+        Err(Status::permission_denied(msg))
+    }
+}
+
+// an empty user in a list request means all users
+fn user_filter(user: &str) -> Option<&str> {
+    if user.is_empty() {
+        None
+    } else {
+        Some(user)
+    }
+}
+
+/// grpc service wrapper for Otp; clones share the same store
+#[derive(Debug, Clone)]
+pub struct OtpGrpc {
+    otp: Otp,
+    admin: AdminTokens,
+}
+
+impl OtpGrpc {
+    /// create the service from an existing otp
+    pub fn new(otp: Otp) -> OtpGrpc {
+        OtpGrpc {
+            otp,
+            admin: AdminTokens::new(),
+        }
+    }
+
+    /// accept admin tokens from this registry; list requires a token with the list scope
+    pub fn with_admin(mut self, admin: AdminTokens) -> OtpGrpc {
+        self.admin = admin;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl OtpService for OtpGrpc {
+    async fn create(
+        &self,
+        request: Request<CreateRequest>,
+    ) -> Result<Response<CreateResponse>, Status> {
+        let req = request.into_inner();
+        if req.user.is_empty() {
+            return Err(Status::invalid_argument("user is required"));
+        }
+
+        let mut otp = self.otp.clone();
+        let code = otp
+            .create_user_otp(&req.user)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(CreateResponse { code }))
+    }
+
+    async fn validate(
+        &self,
+        request: Request<ValidateRequest>,
+    ) -> Result<Response<ValidateResponse>, Status> {
+        let req = request.into_inner();
+        let valid = self.otp.is_valid(&req.code, &req.user);
+
+        Ok(Response::new(ValidateResponse { valid }))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveResponse>, Status> {
+        let req = request.into_inner();
+        let mut otp = self.otp.clone();
+        let removed = otp.remove(&req.code, &req.user).is_some();
+
+        Ok(Response::new(RemoveResponse { removed }))
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        authorize(&self.admin, &request, Scope::List)?;
+        let req = request.into_inner();
+        let items = self.otp.list(user_filter(&req.user));
+
+        Ok(Response::new(list_response(items)))
+    }
+}
+
+/// grpc service wrapper for Session; clones share the same store
+#[derive(Debug, Clone)]
+pub struct SessionGrpc {
+    session: Session,
+    admin: AdminTokens,
+}
+
+impl SessionGrpc {
+    /// create the service from an existing session
+    pub fn new(session: Session) -> SessionGrpc {
+        SessionGrpc {
+            session,
+            admin: AdminTokens::new(),
+        }
+    }
+
+    /// accept admin tokens from this registry; list requires a token with the list scope
+    pub fn with_admin(mut self, admin: AdminTokens) -> SessionGrpc {
+        self.admin = admin;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl SessionService for SessionGrpc {
+    async fn create(
+        &self,
+        request: Request<CreateRequest>,
+    ) -> Result<Response<CreateResponse>, Status> {
+        let req = request.into_inner();
+        if req.user.is_empty() {
+            return Err(Status::invalid_argument("user is required"));
+        }
+
+        let mut session = self.session.clone();
+        let code = session
+            .create_user_session(&req.user)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(CreateResponse { code }))
+    }
+
+    async fn validate(
+        &self,
+        request: Request<ValidateRequest>,
+    ) -> Result<Response<ValidateResponse>, Status> {
+        let req = request.into_inner();
+        let valid = self.session.is_valid(&req.code, &req.user);
+
+        Ok(Response::new(ValidateResponse { valid }))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveResponse>, Status> {
+        let req = request.into_inner();
+        let mut session = self.session.clone();
+        let removed = session.remove(&req.code, &req.user).is_some();
+
+        Ok(Response::new(RemoveResponse { removed }))
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        authorize(&self.admin, &request, Scope::List)?;
+        let req = request.into_inner();
+        let items = self.session.list(user_filter(&req.user));
+
+        Ok(Response::new(list_response(items)))
+    }
+}
+
+/// serve the otp and session services on addr; runs until the server fails
+pub async fn serve(
+    addr: SocketAddr,
+    otp: Otp,
+    session: Session,
+    admin: AdminTokens,
+) -> anyhow::Result<()> {
+    info!("grpc server listening on {}", addr);
+    Server::builder()
+        .add_service(OtpServiceServer::new(
+            OtpGrpc::new(otp).with_admin(admin.clone()),
+        ))
+        .add_service(SessionServiceServer::new(
+            SessionGrpc::new(session).with_admin(admin),
+        ))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+/// serve the services over tls, verifying client certificates when the config has a client ca
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    addr: SocketAddr,
+    otp: Otp,
+    session: Session,
+    admin: AdminTokens,
+    tls: &crate::tls::TlsConfig,
+) -> anyhow::Result<()> {
+    use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+    let identity = Identity::from_pem(std::fs::read(&tls.cert)?, std::fs::read(&tls.key)?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca) = &tls.client_ca {
+        config = config.client_ca_root(Certificate::from_pem(std::fs::read(client_ca)?));
+    }
+
+    info!("grpc server listening with tls on {}", addr);
+    Server::builder()
+        .tls_config(config)?
+        .add_service(OtpServiceServer::new(
+            OtpGrpc::new(otp).with_admin(admin.clone()),
+        ))
+        .add_service(SessionServiceServer::new(
+            SessionGrpc::new(session).with_admin(admin),
+        ))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin_request<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        let value = format!("Bearer {}", token).parse().unwrap();
+        request.metadata_mut().insert("authorization", value);
+        request
+    }
+
+    #[tokio::test]
+    async fn otp_lifecycle() {
+        let admin = AdminTokens::new();
+        let token = admin.create("test", &[Scope::List]).unwrap();
+        let service = OtpGrpc::new(Otp::new()).with_admin(admin);
+        let user = "sally".to_string();
+
+        let resp = service
+            .create(Request::new(CreateRequest { user: user.clone() }))
+            .await
+            .unwrap();
+        let code = resp.into_inner().code;
+        assert_eq!(code.len(), 6);
+
+        let resp = service
+            .validate(Request::new(ValidateRequest {
+                code: code.clone(),
+                user: user.clone(),
+            }))
+            .await
+            .unwrap();
+        assert!(resp.into_inner().valid);
+
+        let resp = service
+            .list(Request::new(ListRequest {
+                user: String::new(),
+            }))
+            .await;
+        assert_eq!(resp.unwrap_err().code(), tonic::Code::PermissionDenied);
+
+        let resp = service
+            .list(admin_request(
+                ListRequest {
+                    user: String::new(),
+                },
+                &token,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.into_inner().items.len(), 1);
+
+        let resp = service
+            .remove(Request::new(RemoveRequest {
+                code: code.clone(),
+                user: user.clone(),
+            }))
+            .await
+            .unwrap();
+        assert!(resp.into_inner().removed);
+
+        let resp = service
+            .validate(Request::new(ValidateRequest { code, user }))
+            .await
+            .unwrap();
+        assert!(!resp.into_inner().valid);
+    }
+
+    #[tokio::test]
+    async fn session_lifecycle() {
+        let admin = AdminTokens::new();
+        let token = admin.create("test", &Scope::ALL).unwrap();
+        let service = SessionGrpc::new(Session::new()).with_admin(admin);
+        let user = "jack".to_string();
+
+        let resp = service
+            .create(Request::new(CreateRequest { user: user.clone() }))
+            .await
+            .unwrap();
+        let code = resp.into_inner().code;
+
+        let resp = service
+            .list(admin_request(ListRequest { user: user.clone() }, &token))
+            .await
+            .unwrap();
+        let items = resp.into_inner().items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code, code);
+
+        let resp = service
+            .remove(Request::new(RemoveRequest { code, user }))
+            .await
+            .unwrap();
+        assert!(resp.into_inner().removed);
+    }
+
+    #[tokio::test]
+    async fn create_requires_user() {
+        let service = SessionGrpc::new(Session::new());
+        let resp = service
+            .create(Request::new(CreateRequest {
+                user: String::new(),
+            }))
+            .await;
+        assert!(resp.is_err());
+    }
+}
@@ -0,0 +1,210 @@
+/// hashing and random token helpers for secrets that must not be stored in the clear
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// return the lowercase hex encoding of the bytes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// return the sha-256 digest of the data
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+/// return the hex encoded sha-256 digest of the data
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&sha256(data))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac
+}
+
+/// return the hmac-sha256 of the data
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac(key, data).finalize().into_bytes().to_vec()
+}
+
+/// return the hex encoded hmac-sha256 of the data
+pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    to_hex(&hmac_sha256(key, data))
+}
+
+/// return true if the tag is the hmac-sha256 of the data, comparing in constant time
+pub fn verify_hmac_sha256(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    hmac(key, data).verify_slice(tag).is_ok()
+}
+
+/// encrypt or decrypt the data by xoring it with hmac-sha256 blocks of the nonce and a block counter (a prf in
+/// counter mode). never reuse a nonce with the key, keep the key apart from mac keys, and mac the result, since
+/// this hides the data but doesn't protect it from changes
+pub fn hmac_sha256_xor(key: &[u8], nonce: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (counter, chunk) in data.chunks(32).enumerate() {
+        let mut block = nonce.to_vec();
+        block.extend_from_slice(&(counter as u64).to_be_bytes());
+        let pad = hmac_sha256(key, &block);
+        out.extend(chunk.iter().zip(pad).map(|(b, p)| b ^ p));
+    }
+    out
+}
+
+/// return the standard, padded base64 encoding of the bytes, e.g. for a basic auth header
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+// the url and filename safe base64 alphabet
+const BASE64URL: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// return the unpadded base64url encoding of the bytes, safe in urls and cookies
+pub fn base64url(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            out.push(BASE64URL[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+/// decode unpadded base64url, or return None if the text isn't
+pub fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let digit = BASE64URL.iter().position(|b| b == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+/// return the given number of random bytes from the operating system's secure generator, for secrets like tokens
+/// and keys that must not be predictable from ones already seen
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    getrandom::getrandom(&mut bytes).expect("the operating system's random generator failed");
+    bytes
+}
+
+/// generate a random hex token from the given number of secure random bytes
+pub fn random_hex(len: usize) -> String {
+    to_hex(&random_bytes(len))
+}
+
+/// return len characters drawn evenly from the alphabet (at most 256 ascii characters) with the secure generator.
+/// bytes past the largest multiple of the alphabet's length are skipped, since they would favor its first characters
+pub fn random_string(alphabet: &[u8], len: usize) -> String {
+    assert!(!alphabet.is_empty() && alphabet.len() <= 256);
+    let usable = 256 / alphabet.len() * alphabet.len();
+    let mut out = String::with_capacity(len);
+    while out.len() < len {
+        for byte in random_bytes(len - out.len()) {
+            if (byte as usize) < usable {
+                out.push(alphabet[byte as usize % alphabet.len()] as char);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0, 15, 16, 255]), "000f10ff");
+        assert_eq!(to_hex(&[]), "");
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn base64url_round_trip() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foobar", &[0xfb, 0xff]] {
+            assert_eq!(base64url_decode(&base64url(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(base64url_decode("Zm9v+"), None);
+        assert_eq!(base64url_decode("Zm9vY"), None);
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn hmac() {
+        // rfc 4231 test case 2
+        assert_eq!(
+            hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let tag = hmac_sha256(b"Jefe", b"data");
+        assert!(verify_hmac_sha256(b"Jefe", b"data", &tag));
+        assert!(!verify_hmac_sha256(b"Jefe", b"data!", &tag));
+        assert!(!verify_hmac_sha256(b"Jefe", b"data", &tag[1..]));
+    }
+
+    #[test]
+    fn hmac_xor() {
+        let data = b"a value longer than one hmac-sha256 block of 32 bytes";
+        let hidden = hmac_sha256_xor(b"key", b"nonce", data);
+        assert_eq!(hidden.len(), data.len());
+        assert_ne!(&hidden[..], &data[..]);
+        assert_eq!(hmac_sha256_xor(b"key", b"nonce", &hidden), data);
+        assert_ne!(hmac_sha256_xor(b"key", b"other", data), hidden);
+        assert!(hmac_sha256_xor(b"key", b"nonce", b"").is_empty());
+    }
+
+    #[test]
+    fn random() {
+        let token = random_hex(16);
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, random_hex(16));
+
+        let code = random_string(b"ABC", 64);
+        assert_eq!(code.len(), 64);
+        assert!(code.bytes().all(|c| b"ABC".contains(&c)));
+        assert_ne!(code, random_string(b"ABC", 64));
+        assert!(random_string(b"ABC", 0).is_empty());
+    }
+}
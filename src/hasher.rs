@@ -0,0 +1,235 @@
+/// the hash function the stores' maps use for codes and user names, chosen when a store is created
+use anyhow::{bail, Result};
+use hashbrown::hash_map::DefaultHashBuilder;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+
+/// how a store hashes its keys. the keyed hashers pick random keys for each store, so clients can't choose codes or
+/// user names that collide and slow the store down; fx is faster but anyone can predict its collisions, so keep it to
+/// trusted internal deployments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Hashing {
+    /// ahash with random keys: fast and resistant to collision attacks
+    #[default]
+    AHash,
+    /// the standard library's siphash with random keys: slower, the most conservative choice
+    Sip,
+    /// the unkeyed hash used by rustc: fastest, with no protection from chosen collisions
+    Fx,
+}
+
+impl Hashing {
+    /// return the name used in config files and flags
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Hashing::AHash => "ahash",
+            Hashing::Sip => "sip",
+            Hashing::Fx => "fx",
+        }
+    }
+
+    /// return true if the hasher is keyed, so its collisions can't be predicted
+    pub fn is_keyed(&self) -> bool {
+        !matches!(self, Hashing::Fx)
+    }
+}
+
+impl fmt::Display for Hashing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Hashing {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Hashing> {
+        match text {
+            "ahash" => Ok(Hashing::AHash),
+            "sip" => Ok(Hashing::Sip),
+            "fx" => Ok(Hashing::Fx),
+            _ => bail!("{} is not a hashing; use ahash, sip or fx", text),
+        }
+    }
+}
+
+// builds the hashers for a store's maps; clones share the keys, so every map of a store hashes alike
+#[derive(Debug, Clone)]
+pub(crate) struct StoreHasher(State);
+
+#[derive(Debug, Clone)]
+enum State {
+    AHash(DefaultHashBuilder),
+    Sip(RandomState),
+    Fx,
+}
+
+impl StoreHasher {
+    // a builder for the hashing, with new random keys for the keyed ones
+    pub(crate) fn new(hashing: Hashing) -> StoreHasher {
+        StoreHasher(match hashing {
+            Hashing::AHash => State::AHash(DefaultHashBuilder::default()),
+            Hashing::Sip => State::Sip(RandomState::new()),
+            Hashing::Fx => State::Fx,
+        })
+    }
+
+    // the hashing this builder uses
+    pub(crate) fn hashing(&self) -> Hashing {
+        match self.0 {
+            State::AHash(_) => Hashing::AHash,
+            State::Sip(_) => Hashing::Sip,
+            State::Fx => Hashing::Fx,
+        }
+    }
+}
+
+impl Default for StoreHasher {
+    fn default() -> Self {
+        StoreHasher::new(Hashing::default())
+    }
+}
+
+impl BuildHasher for StoreHasher {
+    type Hasher = KeyHasher;
+
+    fn build_hasher(&self) -> KeyHasher {
+        KeyHasher(match &self.0 {
+            State::AHash(state) => Inner::AHash(state.build_hasher()),
+            State::Sip(state) => Inner::Sip(state.build_hasher()),
+            State::Fx => Inner::Fx(FxHasher::default()),
+        })
+    }
+}
+
+// a hasher from StoreHasher
+pub(crate) struct KeyHasher(Inner);
+
+enum Inner {
+    AHash(<DefaultHashBuilder as BuildHasher>::Hasher),
+    Sip(DefaultHasher),
+    Fx(FxHasher),
+}
+
+// pass each call to the chosen hasher, keeping the fixed width writes that fx hashes a word at a time
+macro_rules! dispatch {
+    ($self:ident, $method:ident ( $($arg:expr),* )) => {
+        match &mut $self.0 {
+            Inner::AHash(hasher) => hasher.$method($($arg),*),
+            Inner::Sip(hasher) => hasher.$method($($arg),*),
+            Inner::Fx(hasher) => hasher.$method($($arg),*),
+        }
+    };
+}
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        dispatch!(self, write(bytes))
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        dispatch!(self, write_u8(i))
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        dispatch!(self, write_u32(i))
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        dispatch!(self, write_u64(i))
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        dispatch!(self, write_usize(i))
+    }
+
+    fn finish(&self) -> u64 {
+        match &self.0 {
+            Inner::AHash(hasher) => hasher.finish(),
+            Inner::Sip(hasher) => hasher.finish(),
+            Inner::Fx(hasher) => hasher.finish(),
+        }
+    }
+}
+
+// the rustc hasher: a rotate, xor and multiply per word
+#[derive(Default)]
+struct FxHasher {
+    hash: u64,
+}
+
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FxHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hash;
+
+    fn hash<T: Hash>(hasher: &StoreHasher, value: T) -> u64 {
+        let mut state = hasher.build_hasher();
+        value.hash(&mut state);
+        state.finish()
+    }
+
+    #[test]
+    fn hashing() {
+        for hashing in [Hashing::AHash, Hashing::Sip, Hashing::Fx] {
+            assert_eq!(hashing.as_str().parse::<Hashing>().unwrap(), hashing);
+            let hasher = StoreHasher::new(hashing);
+            assert_eq!(hasher.hashing(), hashing);
+            // clones hash alike, so every map of a store agrees
+            assert_eq!(hash(&hasher, "jack"), hash(&hasher.clone(), "jack"));
+            assert_ne!(hash(&hasher, "jack"), hash(&hasher, "sally"));
+        }
+        assert!("md5".parse::<Hashing>().is_err());
+        assert_eq!(Hashing::default(), Hashing::AHash);
+        assert!(!Hashing::Fx.is_keyed());
+
+        // keyed hashers differ between stores; fx is the same everywhere
+        let sip = (
+            StoreHasher::new(Hashing::Sip),
+            StoreHasher::new(Hashing::Sip),
+        );
+        assert_ne!(hash(&sip.0, "jack"), hash(&sip.1, "jack"));
+        let fx = (StoreHasher::new(Hashing::Fx), StoreHasher::new(Hashing::Fx));
+        assert_eq!(hash(&fx.0, "jack"), hash(&fx.1, "jack"));
+    }
+}
@@ -0,0 +1,287 @@
+/// store health reporting and the http liveness/readiness probe server
+use crate::otp::Otp;
+use crate::session::Session;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how long a probe client has to send its whole request
+pub const PROBE_DEADLINE: Duration = Duration::from_secs(2);
+
+/// the most bytes of request line and headers read from a probe client
+pub const MAX_PROBE_REQUEST: u64 = 8 * 1024;
+
+/// a point in time health report for a single store
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    /// false if the store lock has been poisoned by a panic
+    pub healthy: bool,
+    /// time to acquire the store lock and count the items
+    pub latency_micros: u64,
+    /// items that have not expired
+    pub active: usize,
+    /// expired items still waiting to be removed
+    pub expired: usize,
+    /// seconds the oldest expired item has been waiting for removal
+    pub sweep_lag: u64,
+}
+
+impl Health {
+    /// return the report as a json object
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"healthy":{},"latency_micros":{},"active":{},"expired":{},"sweep_lag":{}}}"#,
+            self.healthy, self.latency_micros, self.active, self.expired, self.sweep_lag
+        )
+    }
+}
+
+// a client stream whose reads fail once the deadline has passed, however slowly the bytes trickle in
+struct Deadline {
+    stream: TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "probe request too slow",
+            ));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// serves `/healthz` (liveness) and `/readyz` (readiness) over plain http
+#[derive(Debug, Clone)]
+pub struct ProbeServer {
+    otp: Otp,
+    session: Session,
+    ready: Arc<AtomicBool>,
+}
+
+impl ProbeServer {
+    /// create the probe server; it reports ready until set_ready(false) is called
+    pub fn new(otp: Otp, session: Session) -> ProbeServer {
+        ProbeServer {
+            otp,
+            session,
+            ready: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// set the readiness flag, e.g. false while draining for shutdown; shared by all clones
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+
+    /// return the readiness flag
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// bind to addr and answer probes; runs until accept fails
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.listen(listener)
+    }
+
+    /// serve connections from an already bound listener; runs until accept fails
+    pub fn listen(&self, listener: TcpListener) -> Result<()> {
+        info!("probe server listening on {}", listener.local_addr()?);
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    warn!("probe connection error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// answer a single http request and close the connection. the request must arrive within PROBE_DEADLINE and
+    /// MAX_PROBE_REQUEST bytes, so a slow or endless client can't hold the connection
+    pub fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let deadline = Deadline {
+            stream: stream.try_clone()?,
+            until: Instant::now() + PROBE_DEADLINE,
+        };
+        let mut reader = BufReader::new(deadline).take(MAX_PROBE_REQUEST);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        // drain the headers
+        let mut header = String::new();
+        loop {
+            header.clear();
+            if reader.read_line(&mut header)? == 0 {
+                if reader.limit() == 0 {
+                    bail!("probe request longer than {} bytes", MAX_PROBE_REQUEST);
+                }
+                break;
+            }
+            if header.trim().is_empty() {
+                break;
+            }
+        }
+
+        stream.write_all(self.response(&request_line).as_bytes())?;
+        stream.flush()?;
+
+        Ok(())
+    }
+
+    /// return the whole http response, headers and body, for a request line such as `GET /readyz HTTP/1.1`
+    pub fn response(&self, request_line: &str) -> String {
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+        let (status, body) = self.respond(method, path);
+
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    /// return the status line and json body for a request
+    pub fn respond(&self, method: &str, path: &str) -> (&'static str, String) {
+        if method != "GET" {
+            return (
+                "405 Method Not Allowed",
+                r#"{"error":"method not allowed"}"#.to_string(),
+            );
+        }
+
+        let live = match path {
+            "/healthz" => true,
+            "/readyz" => false,
+            _ => return ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        };
+
+        let otp = self.otp.health();
+        let session = self.session.health();
+        let ok = otp.healthy && session.healthy && (live || self.is_ready());
+        let body = format!(
+            r#"{{"status":"{}","otp":{},"session":{}}}"#,
+            if ok { "ok" } else { "unavailable" },
+            otp.to_json(),
+            session.to_json()
+        );
+
+        if ok {
+            ("200 OK", body)
+        } else {
+            ("503 Service Unavailable", body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_json() {
+        let health = Health {
+            healthy: true,
+            latency_micros: 12,
+            active: 3,
+            expired: 1,
+            sweep_lag: 40,
+        };
+
+        assert_eq!(
+            health.to_json(),
+            r#"{"healthy":true,"latency_micros":12,"active":3,"expired":1,"sweep_lag":40}"#
+        );
+    }
+
+    #[test]
+    fn probes() {
+        let mut session = Session::new();
+        session.create_user_session("sally").unwrap();
+        let probes = ProbeServer::new(Otp::new(), session);
+
+        let (status, body) = probes.respond("GET", "/healthz");
+        assert_eq!(status, "200 OK");
+        assert!(body.contains(r#""session":{"healthy":true"#));
+        assert!(body.contains(r#""active":1"#));
+
+        let (status, _) = probes.respond("GET", "/readyz");
+        assert_eq!(status, "200 OK");
+
+        // clones share the readiness flag
+        probes.clone().set_ready(false);
+        let (status, _) = probes.respond("GET", "/readyz");
+        assert_eq!(status, "503 Service Unavailable");
+        let (status, _) = probes.respond("GET", "/healthz");
+        assert_eq!(status, "200 OK");
+
+        let (status, _) = probes.respond("GET", "/metrics");
+        assert_eq!(status, "404 Not Found");
+        let (status, _) = probes.respond("POST", "/healthz");
+        assert_eq!(status, "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn http_request() {
+        let probes = ProbeServer::new(Otp::new(), Session::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            probes.handle_connection(stream).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.contains(r#""status":"ok""#));
+    }
+
+    #[test]
+    fn stalled_clients() {
+        let probes = ProbeServer::new(Otp::new(), Session::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || probes.listen(listener));
+
+        // a client that never finishes its request doesn't hold up the next probe
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        write!(stalled, "GET /healthz HTTP/1.1\r\nX-Slow: ").unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // endless headers are cut off without an answer
+        let mut endless = TcpStream::connect(addr).unwrap();
+        let header = format!("X-Pad: {}\r\n", "x".repeat(1024));
+        let _ = write!(endless, "GET /healthz HTTP/1.1\r\n{}", header.repeat(16));
+        let mut resp = String::new();
+        let _ = endless.read_to_string(&mut resp);
+        assert!(resp.is_empty());
+    }
+}
@@ -0,0 +1,878 @@
+/// JSON-RPC 2.0 server over TCP; one request (or batch) per line, responses written in request order
+use crate::admin::{AdminTokens, Scope};
+use crate::events::{Event, EventKind, EventSubscriber, Store};
+use crate::logging::redact;
+use crate::otp::Otp;
+use crate::session::Session;
+use crate::snapshot::{Snapshot, Strategy};
+use crate::stats::StatsReport;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use otp_session_core::code::codes_match;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const UNAUTHORIZED: i64 = -32001;
+pub const FORBIDDEN: i64 = -32003;
+
+/// the longest request line, batches included, a connection may send; a longer one gets a parse error and the
+/// connection is closed, so a client can't grow the line until the daemon runs out of memory
+pub const MAX_LINE: usize = 1024 * 1024;
+
+/// how often a watching connection is sent a blank line when idle, so closed connections are noticed
+pub const WATCH_HEARTBEAT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl RpcResponse {
+    fn ok(id: Value, result: Value) -> RpcResponse {
+        RpcResponse {
+            jsonrpc: "2.0".to_string(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+
+    fn err(id: Value, error: RpcError) -> RpcResponse {
+        RpcResponse {
+            jsonrpc: "2.0".to_string(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+}
+
+/// the params of the `event` notifications streamed after `admin.watch`; codes are redacted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    /// the qualified event name, e.g. otp.created
+    pub event: String,
+    pub store: String,
+    /// unix time in seconds
+    pub time: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// the number of items removed or expired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+impl From<&Event> for WatchEvent {
+    fn from(event: &Event) -> WatchEvent {
+        let count = match event.kind {
+            EventKind::UserRemoved { count }
+            | EventKind::Expired { count }
+            | EventKind::Cleared { count } => Some(count),
+            _ => None,
+        };
+
+        WatchEvent {
+            event: event.name(),
+            store: event.store.to_string(),
+            time: event.time,
+            user: event.user.clone(),
+            code: event.code.as_deref().map(redact),
+            count,
+        }
+    }
+}
+
+// forwards one store's events to a watching connection
+struct Watcher {
+    store: Store,
+    tx: Mutex<Sender<WatchEvent>>,
+}
+
+impl EventSubscriber for Watcher {
+    fn on_event(&self, event: &Event) {
+        // otp and session may share a bus, so each watcher only forwards its own store
+        if event.store == self.store {
+            let _ = self.tx.lock().unwrap().send(WatchEvent::from(event));
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthParams {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserParams {
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeParams {
+    code: String,
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportParams {
+    snapshot: Snapshot,
+    #[serde(default)]
+    strategy: Strategy,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsParams {
+    #[serde(default = "default_top")]
+    top: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    user: Option<String>,
+}
+
+/// the serialized parse error response sent for a request line longer than MAX_LINE
+pub fn line_too_long() -> String {
+    let error = RpcError::new(PARSE_ERROR, &format!("line longer than {} bytes", MAX_LINE));
+    serde_json::to_string(&RpcResponse::err(Value::Null, error)).unwrap_or_default()
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: &Value) -> Result<T, RpcError> {
+    serde_json::from_value(params.clone())
+        .map_err(|e| RpcError::new(INVALID_PARAMS, &e.to_string()))
+}
+
+/// the state of a single client connection
+#[derive(Debug, Default)]
+pub struct Connection {
+    authenticated: bool,
+    scopes: Vec<Scope>,
+    watching: bool,
+}
+
+impl Connection {
+    /// return true once the connection has called `admin.watch` and only streams events
+    pub fn is_watching(&self) -> bool {
+        self.watching
+    }
+
+    // admin methods need an admin token with the scope
+    fn require(&self, scope: Scope) -> Result<(), RpcError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            let msg = format!("admin token with {} scope required", scope);
+            Err(RpcError::new(FORBIDDEN, &msg))
+        }
+    }
+}
+
+/// json-rpc frontend for the otp and session stores; clones share the same stores
+#[derive(Debug, Clone)]
+pub struct JsonRpcServer {
+    otp: Otp,
+    session: Session,
+    token: Option<String>,
+    admin: AdminTokens,
+}
+
+impl JsonRpcServer {
+    /// create the server without authentication
+    pub fn new(otp: Otp, session: Session) -> JsonRpcServer {
+        JsonRpcServer {
+            otp,
+            session,
+            token: None,
+            admin: AdminTokens::new(),
+        }
+    }
+
+    /// require each connection to call `auth` with this token before any other method
+    pub fn with_token(mut self, token: &str) -> JsonRpcServer {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// accept admin tokens from this registry; admin methods require a token with the matching scope. once any are
+    /// registered, connections must authenticate
+    pub fn with_admin(mut self, admin: AdminTokens) -> JsonRpcServer {
+        self.admin = admin;
+        self
+    }
+
+    /// create the state for a new connection
+    pub fn connection(&self) -> Connection {
+        Connection {
+            authenticated: self.token.is_none() && self.admin.is_empty(),
+            scopes: Vec::new(),
+            watching: false,
+        }
+    }
+
+    /// bind to addr and serve each connection on its own thread; runs until accept fails
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.listen(listener)
+    }
+
+    /// serve connections from an already bound listener; runs until accept fails
+    pub fn listen(&self, listener: TcpListener) -> Result<()> {
+        info!("json-rpc server listening on {}", listener.local_addr()?);
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    warn!("json-rpc connection error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// serve tls connections from an already bound listener; runs until accept fails
+    #[cfg(feature = "tls")]
+    pub fn listen_tls(
+        &self,
+        listener: TcpListener,
+        tls: std::sync::Arc<rustls::ServerConfig>,
+    ) -> Result<()> {
+        info!(
+            "json-rpc server listening with tls on {}",
+            listener.local_addr()?
+        );
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            let tls = tls.clone();
+            thread::spawn(move || {
+                let resp = crate::tls::accept(&tls, stream)
+                    .and_then(|stream| server.handle_connection(stream));
+                if let Err(e) = resp {
+                    warn!("json-rpc connection error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// read requests until the client disconnects; responses are flushed once the pipelined input is drained. after
+    /// `admin.watch` the connection only streams events
+    pub fn handle_connection<S: Read + Write>(&self, stream: S) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut out = Vec::new();
+        let mut conn = self.connection();
+        let mut line = String::new();
+        let limit = MAX_LINE as u64 + 1;
+
+        loop {
+            line.clear();
+            if reader.by_ref().take(limit).read_line(&mut line)? == 0 {
+                break;
+            }
+            if line.len() as u64 == limit && !line.ends_with('\n') {
+                writeln!(out, "{}", line_too_long())?;
+                reader.get_mut().write_all(&out)?;
+                reader.get_mut().flush()?;
+                bail!("json-rpc request line too long");
+            }
+
+            if let Some(resp) = self.handle_line(&mut conn, line.trim()) {
+                writeln!(out, "{}", resp)?;
+            }
+
+            if conn.watching {
+                return self.watch(reader.into_inner(), &out);
+            }
+
+            if reader.buffer().is_empty() && !out.is_empty() {
+                reader.get_mut().write_all(&out)?;
+                reader.get_mut().flush()?;
+                out.clear();
+            }
+        }
+
+        reader.get_mut().write_all(&out)?;
+        reader.get_mut().flush()?;
+        Ok(())
+    }
+
+    // subscribe before sending the pending responses so no event after the watch response is missed, then stream
+    fn watch<W: Write>(&self, stream: W, pending: &[u8]) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let otp = self.otp.events().subscribe(Arc::new(Watcher {
+            store: Store::Otp,
+            tx: Mutex::new(tx.clone()),
+        }));
+        let session = self.session.events().subscribe(Arc::new(Watcher {
+            store: Store::Session,
+            tx: Mutex::new(tx),
+        }));
+
+        let result = stream_events(stream, pending, &rx);
+        self.otp.events().unsubscribe(otp);
+        self.session.events().unsubscribe(session);
+
+        result
+    }
+
+    /// handle a single line of input; returns the serialized response, or None for notifications
+    pub fn handle_line(&self, conn: &mut Connection, line: &str) -> Option<String> {
+        if line.is_empty() {
+            return None;
+        }
+
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(e) => {
+                let resp =
+                    RpcResponse::err(Value::Null, RpcError::new(PARSE_ERROR, &e.to_string()));
+                return serde_json::to_string(&resp).ok();
+            }
+        };
+
+        match value {
+            Value::Array(batch) => {
+                if batch.is_empty() {
+                    let error = RpcError::new(INVALID_REQUEST, "empty batch");
+                    return serde_json::to_string(&RpcResponse::err(Value::Null, error)).ok();
+                }
+
+                let responses: Vec<RpcResponse> = batch
+                    .into_iter()
+                    .filter_map(|value| self.handle_value(conn, value))
+                    .collect();
+
+                if responses.is_empty() {
+                    None
+                } else {
+                    serde_json::to_string(&responses).ok()
+                }
+            }
+            value => self
+                .handle_value(conn, value)
+                .and_then(|resp| serde_json::to_string(&resp).ok()),
+        }
+    }
+
+    fn handle_value(&self, conn: &mut Connection, value: Value) -> Option<RpcResponse> {
+        let request: RpcRequest = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(INVALID_REQUEST, &e.to_string());
+                return Some(RpcResponse::err(Value::Null, error));
+            }
+        };
+
+        let result = if request.jsonrpc != "2.0" {
+            Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be 2.0"))
+        } else {
+            self.dispatch(conn, &request.method, &request.params)
+        };
+
+        // notifications never get a response
+        let id = request.id?;
+        let resp = match result {
+            Ok(result) => RpcResponse::ok(id, result),
+            Err(error) => RpcResponse::err(id, error),
+        };
+
+        Some(resp)
+    }
+
+    fn dispatch(
+        &self,
+        conn: &mut Connection,
+        method: &str,
+        args: &Value,
+    ) -> Result<Value, RpcError> {
+        if method == "auth" {
+            let p: AuthParams = params(args)?;
+            if let Some(admin) = self.admin.lookup(&p.token) {
+                conn.authenticated = true;
+                conn.scopes = admin.scopes;
+                return Ok(json!(true));
+            }
+
+            conn.scopes.clear();
+            conn.authenticated = self
+                .token
+                .as_ref()
+                .is_some_and(|token| codes_match(token, &p.token));
+
+            return if conn.authenticated {
+                Ok(json!(true))
+            } else {
+                Err(RpcError::new(UNAUTHORIZED, "invalid token"))
+            };
+        }
+
+        if !conn.authenticated {
+            return Err(RpcError::new(UNAUTHORIZED, "not authenticated"));
+        }
+
+        match method {
+            "otp.create" => {
+                let p: UserParams = params(args)?;
+                let mut otp = self.otp.clone();
+                let code = otp
+                    .create_user_otp(&p.user)
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, &e.to_string()))?;
+                Ok(json!({ "code": code }))
+            }
+            "otp.validate" => {
+                let p: CodeParams = params(args)?;
+                let result = self.otp.validate(&p.code, &p.user);
+                Ok(json!({ "valid": result.is_valid(), "result": result.as_str() }))
+            }
+            "otp.remove" => {
+                let p: CodeParams = params(args)?;
+                let mut otp = self.otp.clone();
+                Ok(json!({ "removed": otp.remove(&p.code, &p.user).is_some() }))
+            }
+            "otp.list" => {
+                conn.require(Scope::List)?;
+                let p: ListParams = if args.is_null() {
+                    ListParams::default()
+                } else {
+                    params(args)?
+                };
+                Ok(json!(self.otp.list(p.user.as_deref())))
+            }
+            "otp.dbsize" => Ok(json!(self.otp.dbsize())),
+            "otp.revoke_all" => {
+                conn.require(Scope::Revoke)?;
+                let p: UserParams = params(args)?;
+                let mut otp = self.otp.clone();
+                Ok(json!({ "removed": otp.remove_user(&p.user) }))
+            }
+            "session.create" => {
+                let p: UserParams = params(args)?;
+                let mut session = self.session.clone();
+                let code = session
+                    .create_user_session(&p.user)
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, &e.to_string()))?;
+                Ok(json!({ "code": code }))
+            }
+            "session.validate" => {
+                let p: CodeParams = params(args)?;
+                let result = self.session.validate(&p.code, &p.user);
+                Ok(json!({ "valid": result.is_valid(), "result": result.as_str() }))
+            }
+            "session.remove" => {
+                let p: CodeParams = params(args)?;
+                let mut session = self.session.clone();
+                Ok(json!({ "removed": session.remove(&p.code, &p.user).is_some() }))
+            }
+            "session.list" => {
+                conn.require(Scope::List)?;
+                let p: ListParams = if args.is_null() {
+                    ListParams::default()
+                } else {
+                    params(args)?
+                };
+                Ok(json!(self.session.list(p.user.as_deref())))
+            }
+            "session.dbsize" => Ok(json!(self.session.dbsize())),
+            "session.revoke_all" => {
+                conn.require(Scope::Revoke)?;
+                let p: UserParams = params(args)?;
+                let mut session = self.session.clone();
+                Ok(json!({ "removed": session.remove_user(&p.user) }))
+            }
+            "admin.purge" => {
+                conn.require(Scope::Purge)?;
+                let mut otp = self.otp.clone();
+                let mut session = self.session.clone();
+                Ok(json!({ "otp": otp.purge_expired(), "session": session.purge_expired() }))
+            }
+            "admin.stats" => {
+                conn.require(Scope::List)?;
+                let top = if args.is_null() {
+                    default_top()
+                } else {
+                    params::<StatsParams>(args)?.top
+                };
+                Ok(json!(StatsReport::collect(&self.otp, &self.session, top)))
+            }
+            "admin.watch" => {
+                conn.require(Scope::List)?;
+                conn.watching = true;
+                Ok(json!(true))
+            }
+            "admin.export" => {
+                conn.require(Scope::Export)?;
+                Ok(json!(Snapshot::capture(&self.otp, &self.session)))
+            }
+            "admin.import" => {
+                conn.require(Scope::Import)?;
+                let p: ImportParams = params(args)?;
+                let mut otp = self.otp.clone();
+                let mut session = self.session.clone();
+                let count = p
+                    .snapshot
+                    .import(&mut otp, &mut session, p.strategy)
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, &e.to_string()))?;
+                Ok(json!({ "imported": count }))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, method)),
+        }
+    }
+}
+
+// write each event as an `event` notification until the client goes away
+fn stream_events<W: Write>(mut stream: W, pending: &[u8], rx: &Receiver<WatchEvent>) -> Result<()> {
+    stream.write_all(pending)?;
+    stream.flush()?;
+
+    loop {
+        let line = match rx.recv_timeout(WATCH_HEARTBEAT) {
+            Ok(event) => serde_json::to_string(&RpcRequest {
+                jsonrpc: "2.0".to_string(),
+                method: "event".to_string(),
+                params: json!(event),
+                id: None,
+            })?,
+            Err(RecvTimeoutError::Timeout) => String::new(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        // a failed write means the client disconnected, which is how watching normally ends
+        if writeln!(stream, "{}", line)
+            .and_then(|_| stream.flush())
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_server() -> JsonRpcServer {
+        JsonRpcServer::new(Otp::new(), Session::new())
+    }
+
+    fn call(server: &JsonRpcServer, conn: &mut Connection, line: &str) -> RpcResponse {
+        let resp = server.handle_line(conn, line).unwrap();
+        serde_json::from_str(&resp).unwrap()
+    }
+
+    struct MockStream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        let server = create_server();
+        let ping = r#"{"jsonrpc":"2.0","method":"otp.dbsize","id":1}"#;
+        let input = format!("{}\n{}{}\n", ping, " ".repeat(MAX_LINE), ping);
+        let mut stream = MockStream {
+            input: std::io::Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        };
+        assert!(server.handle_connection(&mut stream).is_err());
+
+        // the request before the long line is answered, then the connection closes with a parse error
+        let output = String::from_utf8(stream.output).unwrap();
+        let responses: Vec<RpcResponse> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].error.is_none());
+        assert_eq!(responses[1].error.as_ref().unwrap().code, PARSE_ERROR);
+    }
+
+    #[test]
+    fn otp_create_validate() {
+        let server = create_server();
+        let mut conn = server.connection();
+
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"otp.create","params":{"user":"sally"},"id":1}"#,
+        );
+        assert_eq!(resp.id, json!(1));
+        let code = resp.result.unwrap()["code"].as_str().unwrap().to_string();
+        assert_eq!(code.len(), 6);
+
+        let line = format!(
+            r#"{{"jsonrpc":"2.0","method":"otp.validate","params":{{"code":"{}","user":"sally"}},"id":2}}"#,
+            code
+        );
+        let resp = call(&server, &mut conn, &line);
+        assert_eq!(resp.result.unwrap()["valid"], json!(true));
+    }
+
+    #[test]
+    fn batch() {
+        let server = create_server();
+        let mut conn = server.connection();
+        let line = r#"[
+            {"jsonrpc":"2.0","method":"session.create","params":{"user":"jack"},"id":1},
+            {"jsonrpc":"2.0","method":"session.create","params":{"user":"jack"}},
+            {"jsonrpc":"2.0","method":"session.dbsize","id":2}
+        ]"#
+        .replace('\n', "");
+
+        let resp = server.handle_line(&mut conn, &line).unwrap();
+        let responses: Vec<RpcResponse> = serde_json::from_str(&resp).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].result, Some(json!(2)));
+    }
+
+    #[test]
+    fn notification() {
+        let server = create_server();
+        let mut conn = server.connection();
+        let resp = server.handle_line(
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"session.create","params":{"user":"jack"}}"#,
+        );
+        assert!(resp.is_none());
+        assert_eq!(server.session.dbsize(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        let server = create_server();
+        let mut conn = server.connection();
+
+        let resp = call(&server, &mut conn, "{not json");
+        assert_eq!(resp.error.unwrap().code, PARSE_ERROR);
+
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"nope","id":1}"#,
+        );
+        assert_eq!(resp.error.unwrap().code, METHOD_NOT_FOUND);
+
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"otp.create","params":{},"id":1}"#,
+        );
+        assert_eq!(resp.error.unwrap().code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn auth() {
+        let server = create_server().with_token("secret");
+        let mut conn = server.connection();
+
+        let create = r#"{"jsonrpc":"2.0","method":"otp.create","params":{"user":"sally"},"id":1}"#;
+        let resp = call(&server, &mut conn, create);
+        assert_eq!(resp.error.unwrap().code, UNAUTHORIZED);
+
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"auth","params":{"token":"wrong"},"id":2}"#,
+        );
+        assert_eq!(resp.error.unwrap().code, UNAUTHORIZED);
+
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"auth","params":{"token":"secret"},"id":3}"#,
+        );
+        assert_eq!(resp.result, Some(json!(true)));
+
+        let resp = call(&server, &mut conn, create);
+        assert!(resp.result.is_some());
+
+        // a new connection starts unauthenticated
+        let mut other = server.connection();
+        let resp = call(&server, &mut other, create);
+        assert!(resp.error.is_some());
+    }
+
+    #[test]
+    fn admin_only() {
+        let admin = AdminTokens::new();
+        let lister = admin.create("lister", &[Scope::List]).unwrap();
+        let server = create_server().with_admin(admin);
+        let mut conn = server.connection();
+
+        // admin tokens without a shared token still require every connection to authenticate
+        let create =
+            r#"{"jsonrpc":"2.0","method":"session.create","params":{"user":"sally"},"id":1}"#;
+        let resp = call(&server, &mut conn, create);
+        assert_eq!(resp.error.unwrap().code, UNAUTHORIZED);
+
+        // and a wrong token doesn't pass for the missing shared one
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"auth","params":{"token":"wrong"},"id":2}"#,
+        );
+        assert_eq!(resp.error.unwrap().code, UNAUTHORIZED);
+        let resp = call(&server, &mut conn, create);
+        assert_eq!(resp.error.unwrap().code, UNAUTHORIZED);
+
+        let auth = format!(
+            r#"{{"jsonrpc":"2.0","method":"auth","params":{{"token":"{}"}},"id":3}}"#,
+            lister
+        );
+        assert_eq!(call(&server, &mut conn, &auth).result, Some(json!(true)));
+        assert!(call(&server, &mut conn, create).result.is_some());
+    }
+
+    #[test]
+    fn admin_scopes() {
+        let admin = AdminTokens::new();
+        let lister = admin.create("lister", &[Scope::List]).unwrap();
+        let root = admin.create("root", &Scope::ALL).unwrap();
+        let server = create_server().with_token("secret").with_admin(admin);
+
+        let list = r#"{"jsonrpc":"2.0","method":"session.list","id":1}"#;
+        let revoke =
+            r#"{"jsonrpc":"2.0","method":"session.revoke_all","params":{"user":"jack"},"id":2}"#;
+        let purge = r#"{"jsonrpc":"2.0","method":"admin.purge","id":3}"#;
+        let export = r#"{"jsonrpc":"2.0","method":"admin.export","id":4}"#;
+        let auth = |token: &str| {
+            format!(
+                r#"{{"jsonrpc":"2.0","method":"auth","params":{{"token":"{}"}},"id":0}}"#,
+                token
+            )
+        };
+
+        // the shared token grants no admin scopes
+        let mut conn = server.connection();
+        call(&server, &mut conn, &auth("secret"));
+        let resp = call(&server, &mut conn, list);
+        assert_eq!(resp.error.unwrap().code, FORBIDDEN);
+
+        // an admin token also authenticates the connection
+        let mut conn = server.connection();
+        let resp = call(&server, &mut conn, &auth(&lister));
+        assert_eq!(resp.result, Some(json!(true)));
+        assert!(call(&server, &mut conn, list).result.is_some());
+        assert_eq!(
+            call(&server, &mut conn, revoke).error.unwrap().code,
+            FORBIDDEN
+        );
+
+        let mut conn = server.connection();
+        call(&server, &mut conn, &auth(&root));
+        assert!(call(&server, &mut conn, revoke).result.is_some());
+        assert!(call(&server, &mut conn, purge).result.is_some());
+        assert!(call(&server, &mut conn, export).result.is_some());
+    }
+
+    #[test]
+    fn watch() {
+        let mut otp = Otp::new();
+        let mut session = Session::new().with_events(otp.events().clone());
+        let (tx, rx) = mpsc::channel();
+        let id = otp.events().subscribe(Arc::new(Watcher {
+            store: Store::Otp,
+            tx: Mutex::new(tx),
+        }));
+
+        let code = otp.create_user_otp("sally").unwrap();
+        session.create_user_session("jack").unwrap();
+        otp.events().unsubscribe(id);
+
+        let mut out = Vec::new();
+        stream_events(&mut out, b"", &rx).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+
+        let notification: RpcRequest = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(notification.method, "event");
+        assert!(notification.id.is_none());
+        let event: WatchEvent = serde_json::from_value(notification.params).unwrap();
+        assert_eq!(event.event, "otp.created");
+        assert_eq!(event.user.as_deref(), Some("sally"));
+        assert_eq!(event.code, Some(redact(&code)));
+
+        // watching needs the list scope
+        let admin = AdminTokens::new();
+        let server = create_server().with_token("secret").with_admin(admin);
+        let mut conn = server.connection();
+        let line = r#"{"jsonrpc":"2.0","method":"auth","params":{"token":"secret"},"id":1}"#;
+        call(&server, &mut conn, line);
+        let resp = call(
+            &server,
+            &mut conn,
+            r#"{"jsonrpc":"2.0","method":"admin.watch","id":2}"#,
+        );
+        assert_eq!(resp.error.unwrap().code, FORBIDDEN);
+        assert!(!conn.watching);
+    }
+}
@@ -1,6 +1,67 @@
+pub mod admin;
+pub mod apikey;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "daemon")]
+pub mod daemon;
 pub mod db;
+pub mod device;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod events;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hash;
+pub mod hasher;
+pub mod health;
+#[cfg(feature = "jsonrpc")]
+pub mod jsonrpc;
+pub mod logging;
+pub mod magiclink;
+pub mod metrics;
+pub mod network;
+#[cfg(feature = "otel")]
+pub mod otel;
 pub mod otp;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod policy;
+#[cfg(feature = "daemon")]
+pub mod poll;
+pub mod refresh;
+#[cfg(feature = "replication")]
+pub mod replication;
+pub mod reset;
+#[cfg(feature = "resp")]
+pub mod resp;
+pub mod sender;
 pub mod session;
+#[cfg(feature = "sms")]
+pub mod sms;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+pub mod stats;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+pub mod store;
+pub mod template;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tokens;
+pub mod totp;
+pub mod transfer;
+pub mod trusted;
+pub mod verify;
+pub mod voice;
+pub mod webauthn;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+#[cfg(feature = "wasm")]
+pub mod webstorage;
 
 /// the current application version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
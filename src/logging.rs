@@ -0,0 +1,127 @@
+/// structured json lifecycle events with redacted codes, and a log4rs setup that writes json lines
+use crate::hash::{hmac_sha256_hex, random_bytes};
+use log::{info, log_enabled, Level};
+use std::sync::OnceLock;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use {
+    anyhow::Result,
+    log::LevelFilter,
+    log4rs::{
+        append::console::ConsoleAppender,
+        config::{Appender, Config, Root},
+        encode::json::JsonEncoder,
+    },
+};
+
+/// the log target for lifecycle events, so they can be routed separately
+pub const EVENT_TARGET: &str = "otp_session::events";
+
+/// number of hex characters kept from the hash of a redacted code
+pub const REDACTED_LENGTH: usize = 12;
+
+// the random bytes in the redaction key
+const REDACT_KEY_BYTES: usize = 32;
+
+// the key codes are redacted under, drawn once per process so a log can't be matched against the hashes of every otp
+fn redact_key() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| random_bytes(REDACT_KEY_BYTES))
+}
+
+/// return a short hmac-sha256 prefix of the code under a per-process random key, so it can be logged and correlated
+/// within the process without revealing the code
+pub fn redact(code: &str) -> String {
+    let mut hash = hmac_sha256_hex(redact_key(), code.as_bytes());
+    hash.truncate(REDACTED_LENGTH);
+    hash
+}
+
+// escape a value for a json string
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// format an event as a json object; any `code` field is redacted
+pub fn format_event(name: &str, fields: &[(&str, &str)]) -> String {
+    let mut json = format!(r#"{{"event":"{}""#, escape(name));
+    for (key, value) in fields {
+        let value = if *key == "code" {
+            redact(value)
+        } else {
+            escape(value)
+        };
+        json.push_str(&format!(r#","{}":"{}""#, escape(key), value));
+    }
+    json.push('}');
+
+    json
+}
+
+/// log a lifecycle event at info level on the events target
+pub(crate) fn event(name: &str, fields: &[(&str, &str)]) {
+    if log_enabled!(target: EVENT_TARGET, Level::Info) {
+        info!(target: EVENT_TARGET, "{}", format_event(name, fields));
+    }
+}
+
+/// write every log record to stdout as a json line, e.g. for the daemon in a container. not on wasm32-unknown-unknown,
+/// which has no stdout; install a logger that writes to the javascript console there
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub fn init_json(level: LevelFilter) -> Result<()> {
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(JsonEncoder::new()))
+        .build();
+    let config = Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .build(Root::builder().appender("stdout").build(level))?;
+    log4rs::init_config(config)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_codes() {
+        let json = format_event("otp.create", &[("user", "sally"), ("code", "123456")]);
+        assert!(!json.contains("123456"));
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"event":"otp.create","user":"sally","code":"{}"}}"#,
+                redact("123456")
+            )
+        );
+        assert_eq!(redact("123456").len(), REDACTED_LENGTH);
+        assert_eq!(redact("123456"), redact("123456"));
+        assert_ne!(redact("123456"), redact("123457"));
+
+        // keyed, so hashing the code space doesn't find it
+        let unkeyed = crate::hash::sha256_hex(b"123456");
+        assert_ne!(redact("123456"), unkeyed[..REDACTED_LENGTH]);
+    }
+
+    #[test]
+    fn escapes_values() {
+        let json = format_event("session.remove_user", &[("user", "sa\"l\nly")]);
+        assert_eq!(
+            json,
+            r#"{"event":"session.remove_user","user":"sa\"l\nly"}"#
+        );
+    }
+}
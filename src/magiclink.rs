@@ -0,0 +1,238 @@
+/// single use magic login links: long random tokens with their own ttl, optionally bound to an email and redirect
+use crate::clock::{Clock, SystemClock};
+use crate::db::Validation;
+use crate::hash::{random_hex, sha256_hex};
+use crate::logging;
+use crate::session::Session;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a magic link is good for
+pub const MAGIC_LINK_TTL: u64 = 900;
+
+/// the number of random bytes in a magic link token
+pub const TOKEN_BYTES: usize = 32;
+
+/// an issued magic link; the token only exists here, the store keeps its hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagicLink {
+    /// the hex token to put in the link
+    pub token: String,
+    pub user: String,
+    /// the address the link was sent to; consuming must present the same address
+    pub email: Option<String>,
+    /// where to send the user after logging in, kept server side so the link can't be pointed elsewhere
+    pub redirect: Option<String>,
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    link: MagicLink,
+    used: bool,
+}
+
+/// the issued magic links, keyed by the token's sha-256; clones share them
+#[derive(Debug, Clone)]
+pub struct MagicLinks {
+    links: Arc<RwLock<HashMap<String, Entry>>>,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MagicLinks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MagicLinks {
+    /// create the store with the default ttl
+    pub fn new() -> MagicLinks {
+        MagicLinks {
+            links: Arc::new(RwLock::new(HashMap::new())),
+            ttl: MAGIC_LINK_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// links are good for this many seconds
+    pub fn with_ttl(mut self, ttl: u64) -> MagicLinks {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> MagicLinks {
+        self.clock = clock;
+        self
+    }
+
+    /// return the seconds a link is good for
+    pub fn ttl(&self) -> u64 {
+        self.ttl
+    }
+
+    /// issue a link for the user
+    pub fn issue(&self, user: &str) -> MagicLink {
+        self.issue_for(user, None, None)
+    }
+
+    /// issue a link for the user, bound to the email it is sent to and the page to land on
+    pub fn issue_for(&self, user: &str, email: Option<&str>, redirect: Option<&str>) -> MagicLink {
+        let link = MagicLink {
+            token: random_hex(TOKEN_BYTES),
+            user: user.to_string(),
+            email: email.map(|email| email.trim().to_lowercase()),
+            redirect: redirect.map(|redirect| redirect.to_string()),
+            expires: self.clock.now().saturating_add(self.ttl),
+        };
+        let entry = Entry {
+            link: link.clone(),
+            used: false,
+        };
+        self.links
+            .write()
+            .unwrap()
+            .insert(sha256_hex(link.token.as_bytes()), entry);
+        logging::event("magiclink.issue", &[("user", user), ("code", &link.token)]);
+
+        link
+    }
+
+    /// return why the token is or is not valid, without using it up
+    pub fn validate(&self, token: &str) -> Validation {
+        let key = sha256_hex(token.as_bytes());
+        match self.links.read().unwrap().get(&key) {
+            None => Validation::NotFound,
+            Some(entry) if entry.used => Validation::Consumed,
+            Some(entry) if entry.link.expires <= self.clock.now() => Validation::Expired,
+            Some(_) => Validation::Valid,
+        }
+    }
+
+    /// validate and use up the token, returning the link; a bound link needs the same email (ignoring case), and a
+    /// wrong email uses it up too, so a leaked link can't be retried
+    pub fn consume(&self, token: &str, email: Option<&str>) -> Result<MagicLink> {
+        let key = sha256_hex(token.as_bytes());
+        let now = self.clock.now();
+        let mut links = self.links.write().unwrap();
+        let Some(entry) = links.get_mut(&key) else {
+            bail!("magic link not valid: {}", Validation::NotFound.as_str());
+        };
+
+        if entry.used {
+            bail!("magic link not valid: {}", Validation::Consumed.as_str());
+        }
+        if entry.link.expires <= now {
+            bail!("magic link not valid: {}", Validation::Expired.as_str());
+        }
+
+        entry.used = true;
+        let link = entry.link.clone();
+        drop(links);
+        logging::event(
+            "magiclink.consume",
+            &[("user", &link.user), ("code", token)],
+        );
+
+        if let Some(bound) = &link.email {
+            let matches = email.is_some_and(|email| email.trim().to_lowercase() == *bound);
+            if !matches {
+                bail!("magic link not valid: email does not match");
+            }
+        }
+
+        Ok(link)
+    }
+
+    /// consume the token and create a session for its user; return the session code and the link
+    pub fn login(
+        &self,
+        token: &str,
+        email: Option<&str>,
+        session: &mut Session,
+    ) -> Result<(String, MagicLink)> {
+        let link = self.consume(token, email)?;
+        let code = session.create_user_session(&link.user)?;
+        Ok((code, link))
+    }
+
+    /// forget expired and used links once they have expired; return the number removed
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut links = self.links.write().unwrap();
+        let before = links.len();
+        links.retain(|_, entry| entry.link.expires > now);
+        before - links.len()
+    }
+
+    /// return the number of links remembered, including used ones that have not expired
+    pub fn len(&self) -> usize {
+        self.links.read().unwrap().len()
+    }
+
+    /// return true if no links are remembered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn consume_once() {
+        let clock = MockClock::at(1_000);
+        let links = MagicLinks::new()
+            .with_ttl(60)
+            .with_clock(Arc::new(clock.clone()));
+        let link = links.issue("sally");
+        assert_eq!(link.token.len(), TOKEN_BYTES * 2);
+        assert_eq!(link.expires, 1_060);
+        assert_eq!(links.validate(&link.token), Validation::Valid);
+
+        let mut session = Session::new();
+        let (code, used) = links.login(&link.token, None, &mut session).unwrap();
+        assert_eq!(used.user, "sally");
+        assert!(session.is_valid(&code, "sally"));
+        assert_eq!(links.validate(&link.token), Validation::Consumed);
+        let err = links.consume(&link.token, None).unwrap_err();
+        assert_eq!(err.to_string(), "magic link not valid: consumed");
+        assert_eq!(links.validate("nope"), Validation::NotFound);
+
+        let link = links.issue("jack");
+        clock.set(1_060);
+        assert_eq!(links.validate(&link.token), Validation::Expired);
+        assert!(links.consume(&link.token, None).is_err());
+        assert_eq!(links.purge_expired(), 2);
+        assert!(links.is_empty());
+    }
+
+    #[test]
+    fn bound_email() {
+        let links = MagicLinks::new();
+        let link = links.issue_for("sally", Some("Sally@Example.com"), Some("/billing"));
+        assert_eq!(link.email.as_deref(), Some("sally@example.com"));
+
+        let used = links
+            .consume(&link.token, Some("sally@EXAMPLE.com"))
+            .unwrap();
+        assert_eq!(used.redirect.as_deref(), Some("/billing"));
+
+        // a wrong email uses the link up
+        let link = links.issue_for("sally", Some("sally@example.com"), None);
+        assert!(links
+            .consume(&link.token, Some("mallory@example.com"))
+            .is_err());
+        assert!(links
+            .consume(&link.token, Some("sally@example.com"))
+            .is_err());
+        assert!(links
+            .consume(&links.issue_for("sally", Some("a@b.c"), None).token, None)
+            .is_err());
+    }
+}
@@ -0,0 +1,100 @@
+/// store metrics recorded through the `metrics` facade, and operation spans exported with the otel feature; every
+/// call is a no-op unless those features are enabled
+use std::time::Duration;
+
+/// otps or sessions created, labeled by store
+pub const CREATED: &str = "otp_session_created_total";
+/// validations, labeled by store and result (ok or failed)
+pub const VALIDATIONS: &str = "otp_session_validations_total";
+/// expired items removed by a sweep, labeled by store
+pub const EXPIRED: &str = "otp_session_expired_total";
+/// items in the store, labeled by store
+pub const DBSIZE: &str = "otp_session_dbsize";
+/// time taken to sweep expired items in seconds, labeled by store
+pub const SWEEP_DURATION: &str = "otp_session_sweep_duration_seconds";
+/// time taken by a store operation in seconds, labeled by store and op (create, validate or remove)
+pub const OPERATION_DURATION: &str = "otp_session_operation_duration_seconds";
+/// seconds from creation (or the last touch) to removal, labeled by store
+pub const LIFETIME: &str = "otp_session_lifetime_seconds";
+
+/// the store label for otp metrics
+pub const OTP: &str = "otp";
+/// the store label for session metrics
+pub const SESSION: &str = "session";
+
+/// a span covering a store operation; it ends when dropped
+pub(crate) struct Span {
+    #[cfg(feature = "otel")]
+    _span: opentelemetry::global::BoxedSpan,
+}
+
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+pub(crate) fn span(name: &'static str) -> Span {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::Tracer;
+        let _span = opentelemetry::global::tracer(crate::otel::SCOPE).start(name);
+        Span { _span }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    Span {}
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn created(store: &'static str, dbsize: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(CREATED, "store" => store).increment(1);
+        ::metrics::gauge!(DBSIZE, "store" => store).set(dbsize as f64);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn validated(store: &'static str, ok: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if ok { "ok" } else { "failed" };
+        ::metrics::counter!(VALIDATIONS, "store" => store, "result" => result).increment(1);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn removed(store: &'static str, dbsize: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(DBSIZE, "store" => store).set(dbsize as f64);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn swept(store: &'static str, expired: usize, dbsize: usize, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(EXPIRED, "store" => store).increment(expired as u64);
+        ::metrics::gauge!(DBSIZE, "store" => store).set(dbsize as f64);
+        ::metrics::histogram!(SWEEP_DURATION, "store" => store).record(elapsed.as_secs_f64());
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn latency(store: &'static str, op: &'static str, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(OPERATION_DURATION, "store" => store, "op" => op)
+        .record(elapsed.as_secs_f64());
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn lifetime(store: &'static str, seconds: u64) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(LIFETIME, "store" => store).record(seconds as f64);
+}
+
+/// install the prometheus recorder and serve the metrics over http at addr/metrics
+#[cfg(feature = "metrics")]
+pub fn install_prometheus(addr: std::net::SocketAddr) -> anyhow::Result<()> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+    log::info!("prometheus metrics listening on {}", addr);
+
+    Ok(())
+}
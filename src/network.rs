@@ -0,0 +1,154 @@
+/// client network binding for sessions: the ip or cidr block a session was created from, and how strictly to hold
+/// later validations to it
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// what to do when a bound session is validated from another network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpBinding {
+    /// ignore recorded networks
+    #[default]
+    Off,
+    /// log the mismatch but let the session validate
+    Warn,
+    /// reject the validation as WrongNetwork
+    Strict,
+}
+
+impl IpBinding {
+    /// return the name used in config files and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpBinding::Off => "off",
+            IpBinding::Warn => "warn",
+            IpBinding::Strict => "strict",
+        }
+    }
+
+    // the binding as stored in an atomic
+    pub(crate) fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_u8(value: u8) -> IpBinding {
+        match value {
+            1 => IpBinding::Warn,
+            2 => IpBinding::Strict,
+            _ => IpBinding::Off,
+        }
+    }
+}
+
+impl FromStr for IpBinding {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<IpBinding> {
+        match text {
+            "off" => Ok(IpBinding::Off),
+            "warn" => Ok(IpBinding::Warn),
+            "strict" => Ok(IpBinding::Strict),
+            _ => bail!("{} is not an ip binding; use off, warn or strict", text),
+        }
+    }
+}
+
+/// an ip address or cidr block, e.g. 203.0.113.7, 203.0.113.0/24 or 2001:db8::/32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// return the network of just this address
+    pub fn host(addr: IpAddr) -> Network {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Network { addr, prefix }
+    }
+
+    /// return the prefix length in bits
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// return true if the address is in the network; v4 and v6 networks never match each other
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Network> {
+        let text = text.trim();
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("{} is not an ip address or cidr block", text))?;
+
+        let network = Network::host(addr);
+        let Some(prefix) = prefix else {
+            return Ok(network);
+        };
+        match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= network.prefix => Ok(Network { addr, prefix }),
+            _ => bail!("{} has a bad prefix length", text),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn contains() {
+        let host: Network = "203.0.113.7".parse().unwrap();
+        assert_eq!(host.prefix(), 32);
+        assert!(host.contains(ip("203.0.113.7")));
+        assert!(!host.contains(ip("203.0.113.8")));
+
+        let block: Network = "203.0.113.0/24".parse().unwrap();
+        assert!(block.contains(ip("203.0.113.200")));
+        assert!(!block.contains(ip("198.51.100.1")));
+        assert!(!block.contains(ip("::1")));
+        assert_eq!(block.to_string(), "203.0.113.0/24");
+
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("198.51.100.1")));
+        let v6: Network = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::9")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+
+        assert!("203.0.113.0/33".parse::<Network>().is_err());
+        assert!("localhost".parse::<Network>().is_err());
+        assert_eq!("strict".parse::<IpBinding>().unwrap(), IpBinding::Strict);
+        assert!("loose".parse::<IpBinding>().is_err());
+    }
+}
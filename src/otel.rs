@@ -0,0 +1,173 @@
+/// OTLP export of the store traces and metrics, configured by the standard OTEL_* environment variables
+use anyhow::{anyhow, Result};
+use log::info;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use opentelemetry::metrics::{Meter, MeterProvider as _};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_sdk::metrics::MeterProvider;
+use opentelemetry_sdk::runtime::Tokio;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// the instrumentation scope for the exported traces and metrics
+pub const SCOPE: &str = "otp-session";
+
+// the metric labels as otel attributes
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels()
+        .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+struct OtelCounter {
+    counter: opentelemetry::metrics::Counter<u64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl CounterFn for OtelCounter {
+    fn increment(&self, value: u64) {
+        self.counter.add(value, &self.attributes);
+    }
+
+    fn absolute(&self, _value: u64) {}
+}
+
+// otel has no synchronous gauge, so track the last value and send the difference
+struct OtelGauge {
+    gauge: opentelemetry::metrics::UpDownCounter<f64>,
+    attributes: Vec<KeyValue>,
+    value: Mutex<f64>,
+}
+
+impl GaugeFn for OtelGauge {
+    fn increment(&self, value: f64) {
+        *self.value.lock().unwrap() += value;
+        self.gauge.add(value, &self.attributes);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.increment(-value);
+    }
+
+    fn set(&self, value: f64) {
+        let mut last = self.value.lock().unwrap();
+        self.gauge.add(value - *last, &self.attributes);
+        *last = value;
+    }
+}
+
+struct OtelHistogram {
+    histogram: opentelemetry::metrics::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtelHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
+
+/// a `metrics` recorder that forwards the store metrics to an otel meter
+pub struct OtelRecorder {
+    meter: Meter,
+}
+
+impl OtelRecorder {
+    /// create the recorder for this meter
+    pub fn new(meter: Meter) -> OtelRecorder {
+        OtelRecorder { meter }
+    }
+}
+
+impl Recorder for OtelRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let counter = self.meter.u64_counter(key.name().to_string()).init();
+        Counter::from_arc(Arc::new(OtelCounter {
+            counter,
+            attributes: attributes(key),
+        }))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let gauge = self
+            .meter
+            .f64_up_down_counter(key.name().to_string())
+            .init();
+        Gauge::from_arc(Arc::new(OtelGauge {
+            gauge,
+            attributes: attributes(key),
+            value: Mutex::new(0.0),
+        }))
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let histogram = self.meter.f64_histogram(key.name().to_string()).init();
+        Histogram::from_arc(Arc::new(OtelHistogram {
+            histogram,
+            attributes: attributes(key),
+        }))
+    }
+}
+
+/// keeps the exporters running; dropping it flushes and shuts them down
+pub struct OtelGuard {
+    meter_provider: MeterProvider,
+    // the batch exporters run on this runtime
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl fmt::Debug for OtelGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtelGuard").finish_non_exhaustive()
+    }
+}
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        global::shutdown_tracer_provider();
+        if let Err(e) = self.meter_provider.shutdown() {
+            log::error!("otel metrics shutdown failed: {}", e);
+        }
+    }
+}
+
+/// install the otlp trace and metric exporters and the `metrics` recorder; the endpoint, service name and
+/// resource attributes come from OTEL_EXPORTER_OTLP_ENDPOINT, OTEL_SERVICE_NAME and OTEL_RESOURCE_ATTRIBUTES
+pub fn init() -> Result<OtelGuard> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("otel")
+        .enable_all()
+        .build()?;
+    let _enter = runtime.enter();
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .install_batch(Tokio)?;
+
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(Tokio)
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .build()?;
+    global::set_meter_provider(meter_provider.clone());
+
+    let recorder = OtelRecorder::new(meter_provider.meter(SCOPE));
+    metrics::set_global_recorder(recorder)
+        .map_err(|_| anyhow!("a metrics recorder is already installed"))?;
+    info!("otlp export started");
+
+    Ok(OtelGuard {
+        meter_provider,
+        _runtime: runtime,
+    })
+}
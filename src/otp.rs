@@ -1,12 +1,84 @@
 /// otp generator
-use crate::db::{DataStore, SessionItem};
-use anyhow::Result;
-use log::debug;
+use crate::clock::{Clock, Instant};
+use crate::config::OtpConfig;
+use crate::db::{AlreadyExists, Change, DataStore, LimitReached, SessionItem, Validation};
+use crate::events::{EventKind, Events, Store};
+use crate::health::Health;
+use crate::logging;
+use crate::metrics;
+use crate::policy::TtlPolicies;
+use crate::sender::{Delivery, DeliveryState, OtpSender, SendContext};
+use crate::stats::{Operation, Stats, StoreStats};
+use crate::template::Channel;
+use anyhow::{bail, Result};
+use fastrand::Rng;
+use hashbrown::HashMap;
+use otp_session_core::code;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
+/// the default number of digits in an otp code
+pub const OTP_CODE_LENGTH: usize = 6;
+
+/// the supported range of otp code lengths
+pub const OTP_CODE_LENGTHS: std::ops::RangeInclusive<usize> = 4..=10;
+
+/// codes generated before create_user_otp gives up on finding one the user doesn't already hold
+pub const CODE_ATTEMPTS: usize = 5;
+
+/// the default seconds before a user can be sent a code again on the same channel
+pub const RESEND_INTERVAL: u64 = 30;
+
+/// the default most times one otp may be resent
+pub const MAX_RESENDS: u32 = 3;
+
+/// why Otp::resend refused to send; the error downcasts to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResendError {
+    /// every channel sent the user a code too recently; try again after retry_after seconds
+    TooSoon { retry_after: u64 },
+    /// the otp was already resent the most times allowed
+    Exhausted { resends: u32 },
+}
+
+impl fmt::Display for ResendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResendError::TooSoon { retry_after } => {
+                write!(
+                    f,
+                    "otp resent too soon; try again in {} seconds",
+                    retry_after
+                )
+            }
+            ResendError::Exhausted { resends } => write!(f, "otp already resent {} times", resends),
+        }
+    }
+}
+
+impl std::error::Error for ResendError {}
+
+// when each user was last sent a code on each channel, None for senders that don't say
+type LastSent = HashMap<(String, Option<Channel>), u64>;
+
+// the timeout, code length and per-user limit are shared by clones so a config reload reaches every frontend
 #[derive(Debug, Clone)]
 pub struct Otp {
-    keep_alive: u64,
+    keep_alive: Arc<AtomicU64>,
+    code_length: Arc<AtomicUsize>,
+    max_per_user: Arc<AtomicUsize>,
     db: DataStore,
+    events: Events,
+    policies: TtlPolicies,
+    sender: Option<Arc<dyn OtpSender>>,
+    // how each sent otp, by code and user, was delivered
+    deliveries: Arc<RwLock<HashMap<(String, String), Delivery>>>,
+    resend_interval: Arc<AtomicU64>,
+    max_resends: Arc<AtomicU32>,
+    // when each user was last sent a code, to throttle resends
+    last_sent: Arc<RwLock<LastSent>>,
+    stats: Stats,
 }
 
 impl Default for Otp {
@@ -15,53 +87,784 @@ impl Default for Otp {
     }
 }
 
+/// configures and builds an Otp, e.g. `Otp::builder().timeout(120).code_length(8).build()`
+#[derive(Debug, Clone, Default)]
+pub struct OtpBuilder {
+    config: OtpConfig,
+    store: Option<DataStore>,
+    events: Option<Events>,
+    policies: Option<TtlPolicies>,
+    sender: Option<Arc<dyn OtpSender>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl OtpBuilder {
+    /// start from these settings
+    pub fn config(mut self, config: OtpConfig) -> OtpBuilder {
+        self.config = config;
+        self
+    }
+
+    /// the otp keep alive in seconds
+    pub fn timeout(mut self, timeout: u64) -> OtpBuilder {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// the number of digits in new otp codes
+    pub fn code_length(mut self, length: usize) -> OtpBuilder {
+        self.config.code_length = length;
+        self
+    }
+
+    /// the most unexpired otps a user may hold
+    pub fn max_per_user(mut self, max: usize) -> OtpBuilder {
+        self.config.max_per_user = Some(max);
+        self
+    }
+
+    /// the seconds before a user can be sent a code again on the same channel
+    pub fn resend_interval(mut self, seconds: u64) -> OtpBuilder {
+        self.config.resend_interval = seconds;
+        self
+    }
+
+    /// the most times one otp may be resent
+    pub fn max_resends(mut self, max: u32) -> OtpBuilder {
+        self.config.max_resends = max;
+        self
+    }
+
+    /// keep the otps in this store instead of a new one, e.g. one restored from a snapshot
+    pub fn store(mut self, store: DataStore) -> OtpBuilder {
+        self.store = Some(store);
+        self
+    }
+
+    /// publish lifecycle events on this bus
+    pub fn events(mut self, events: Events) -> OtpBuilder {
+        self.events = Some(events);
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> OtpBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// give users in some classes a different keep alive
+    pub fn ttl_policies(mut self, policies: TtlPolicies) -> OtpBuilder {
+        self.policies = Some(policies);
+        self
+    }
+
+    /// deliver codes from create_and_send with this sender
+    pub fn sender(mut self, sender: Arc<dyn OtpSender>) -> OtpBuilder {
+        self.sender = Some(sender);
+        self
+    }
+
+    /// validate the settings and build the otp
+    pub fn build(self) -> Result<Otp> {
+        let config = self.config;
+        config.validate()?;
+        let mut db = self.store.unwrap_or_else(DataStore::create);
+        if let Some(clock) = self.clock {
+            db = db.with_clock(clock);
+        }
+
+        Ok(Otp {
+            keep_alive: Arc::new(AtomicU64::new(config.timeout)),
+            code_length: Arc::new(AtomicUsize::new(config.code_length)),
+            max_per_user: Arc::new(AtomicUsize::new(config.max_per_user.unwrap_or(0))),
+            db,
+            events: self.events.unwrap_or_default(),
+            policies: self.policies.unwrap_or_default(),
+            sender: self.sender,
+            deliveries: Arc::new(RwLock::new(HashMap::new())),
+            resend_interval: Arc::new(AtomicU64::new(config.resend_interval)),
+            max_resends: Arc::new(AtomicU32::new(config.max_resends)),
+            last_sent: Arc::new(RwLock::new(HashMap::new())),
+            stats: Stats::new(metrics::OTP),
+        })
+    }
+}
+
 impl Otp {
+    /// return a builder for an otp with non-default settings
+    pub fn builder() -> OtpBuilder {
+        OtpBuilder::default()
+    }
+
     /// create a new Otp struct
     pub fn new() -> Otp {
         let db = DataStore::create();
-        let keep_alive = crate::OTP_TIMEOUT;
+        let keep_alive = Arc::new(AtomicU64::new(crate::OTP_TIMEOUT));
+        let code_length = Arc::new(AtomicUsize::new(OTP_CODE_LENGTH));
 
-        Otp { keep_alive, db }
+        Otp {
+            keep_alive,
+            code_length,
+            max_per_user: Arc::new(AtomicUsize::new(0)),
+            db,
+            events: Events::new(),
+            policies: TtlPolicies::new(),
+            sender: None,
+            deliveries: Arc::new(RwLock::new(HashMap::new())),
+            resend_interval: Arc::new(AtomicU64::new(RESEND_INTERVAL)),
+            max_resends: Arc::new(AtomicU32::new(MAX_RESENDS)),
+            last_sent: Arc::new(RwLock::new(HashMap::new())),
+            stats: Stats::new(metrics::OTP),
+        }
     }
 
-    /// generate the 6 digit otp code
+    /// generate the otp code, 6 digits unless configured otherwise
     pub fn generate_code(&self) -> String {
-        let range = 100_000..1_000_000_u64;
-        format!("{}", fastrand::u64(range))
+        code::otp_code(&mut Rng::new(), self.code_length() as u32)
+    }
+
+    /// return the otp keep alive in seconds
+    pub fn keep_alive(&self) -> u64 {
+        self.keep_alive.load(Ordering::Relaxed)
+    }
+
+    /// return the keep alive for the user's new otps: their class policy if there is one, otherwise the default
+    pub fn keep_alive_for(&self, user: &str) -> u64 {
+        self.policies
+            .keep_alive(user)
+            .unwrap_or_else(|| self.keep_alive())
+    }
+
+    /// return the current unix time by the store's clock
+    pub fn now(&self) -> u64 {
+        self.db.now()
+    }
+
+    /// return the keep alive policies by user class; clones share them
+    pub fn ttl_policies(&self) -> &TtlPolicies {
+        &self.policies
+    }
+
+    /// set the keep alive for new otps; existing otps keep their expiration
+    pub fn set_keep_alive(&self, keep_alive: u64) {
+        self.keep_alive.store(keep_alive, Ordering::Relaxed);
+    }
+
+    /// return the number of digits in new otp codes
+    pub fn code_length(&self) -> usize {
+        self.code_length.load(Ordering::Relaxed)
+    }
+
+    /// set the number of digits in new otp codes
+    pub fn set_code_length(&self, length: usize) -> Result<()> {
+        if !OTP_CODE_LENGTHS.contains(&length) {
+            bail!(
+                "otp code length must be between {} and {}",
+                OTP_CODE_LENGTHS.start(),
+                OTP_CODE_LENGTHS.end()
+            );
+        }
+
+        self.code_length.store(length, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// return the most unexpired otps a user may hold; None is unlimited
+    pub fn max_per_user(&self) -> Option<usize> {
+        match self.max_per_user.load(Ordering::Relaxed) {
+            0 => None,
+            max => Some(max),
+        }
+    }
+
+    /// limit the unexpired otps a user may hold; creates beyond the limit fail
+    pub fn set_max_per_user(&self, max: Option<usize>) {
+        self.max_per_user.store(max.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// return the seconds before a user can be sent a code again on the same channel
+    pub fn resend_interval(&self) -> u64 {
+        self.resend_interval.load(Ordering::Relaxed)
+    }
+
+    /// set the seconds before a user can be sent a code again on the same channel
+    pub fn set_resend_interval(&self, seconds: u64) {
+        self.resend_interval.store(seconds, Ordering::Relaxed);
+    }
+
+    /// return the most times one otp may be resent
+    pub fn max_resends(&self) -> u32 {
+        self.max_resends.load(Ordering::Relaxed)
+    }
+
+    /// set the most times one otp may be resent; zero turns resends off
+    pub fn set_max_resends(&self, max: u32) {
+        self.max_resends.store(max, Ordering::Relaxed);
     }
 
     /// create a new user otp and store it with standard expiration timestamp
     pub fn create_user_otp(&mut self, user: &str) -> Result<String> {
-        let code = self.generate_code();
-        debug!("user: {}, code: {}", user, &code);
-
-        let ss = SessionItem::new(code.as_str(), user, self.keep_alive);
-        self.db.put(ss)?;
+        let _span = metrics::span("otp.create");
+        let start = Instant::now();
+        let code = self.insert_code(user)?;
+        logging::event("otp.create", &[("user", user), ("code", &code)]);
+        self.events.emit(
+            EventKind::Created,
+            Store::Otp,
+            Some(user),
+            Some(&code),
+            self.db.now(),
+        );
+        metrics::created(metrics::OTP, self.db.dbsize());
+        self.stats.created();
+        self.stats.latency(Operation::Create, start.elapsed());
 
         Ok(code)
     }
 
+    // store a new code for the user, generating another if the user already holds it rather than replacing theirs.
+    // with max_per_user the count and the insert happen under one store lock, so concurrent creates can't pass it
+    fn insert_code(&mut self, user: &str) -> Result<String> {
+        for _ in 0..CODE_ATTEMPTS {
+            let code = self.generate_code();
+            let item =
+                SessionItem::created_at(&code, user, self.keep_alive_for(user), self.db.now());
+            let inserted = match self.max_per_user() {
+                Some(max) => self.db.put_limited(item, max, false).map(|_| ()),
+                None => self.db.insert(item),
+            };
+            match inserted {
+                Ok(()) => return Ok(code),
+                Err(e) if e.is::<AlreadyExists>() => continue,
+                Err(e) => match e.downcast_ref::<LimitReached>() {
+                    Some(l) => bail!("{} already has {} otps", l.user, l.held),
+                    None => return Err(e),
+                },
+            }
+        }
+
+        bail!(
+            "no unused otp code for {} after {} attempts",
+            user,
+            CODE_ATTEMPTS
+        )
+    }
+
+    /// create a new user otp and deliver it with the sender, recording how it went out; a code that can't be delivered
+    /// is removed. like resend, channels that sent the user a code within resend_interval are skipped, and no code is
+    /// created, with a ResendError, while every channel is cooling down. return when the code expires
+    pub fn create_and_send(&mut self, user: &str, mut context: SendContext) -> Result<u64> {
+        let Some(sender) = self.sender.clone() else {
+            bail!("no otp sender configured");
+        };
+        context.skip = match self.throttled(user, &sender.channels(), self.db.now()) {
+            Ok(skip) => skip,
+            Err(e) => {
+                logging::event("otp.send", &[("user", user), ("result", "throttled")]);
+                return Err(e.into());
+            }
+        };
+
+        let code = self.create_user_otp(user)?;
+        context.expires = self.db.get(&code, user).map_or(0, |item| item.expires);
+        let key = (code.clone(), user.to_string());
+        self.deliveries
+            .write()
+            .unwrap()
+            .insert(key.clone(), Delivery::default());
+        let delivery = match sender.deliver(user, &code, &context) {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                logging::event(
+                    "otp.send",
+                    &[("user", user), ("code", &code), ("result", "failed")],
+                );
+                self.remove(&code, user);
+                bail!("otp not sent: {}", e);
+            }
+        };
+        let channel = delivery.channel.map_or("", |channel| channel.as_str());
+        logging::event(
+            "otp.send",
+            &[
+                ("user", user),
+                ("code", &code),
+                ("result", "sent"),
+                ("channel", channel),
+            ],
+        );
+        self.sent(user, delivery.channel);
+        self.deliveries.write().unwrap().insert(key, delivery);
+
+        Ok(context.expires)
+    }
+
+    /// send the user's otp again with the sender, keeping its code and expiration. resends are refused with a
+    /// ResendError once the otp has been resent max_resends times, or while every channel the sender uses sent the
+    /// user a code within resend_interval; channels still cooling down are skipped. return the new delivery
+    pub fn resend(&mut self, code: &str, user: &str, mut context: SendContext) -> Result<Delivery> {
+        let Some(sender) = self.sender.clone() else {
+            bail!("no otp sender configured");
+        };
+        let Some(item) = self.db.get(code, user) else {
+            bail!("otp not found");
+        };
+
+        let now = self.db.now();
+        let checked = self.reserve_resend(code, user).and_then(|resends| {
+            context.skip = self.throttled(user, &sender.channels(), now)?;
+            Ok(resends)
+        });
+        let resends = match checked {
+            Ok(resends) => resends,
+            Err(e) => {
+                if matches!(e, ResendError::TooSoon { .. }) {
+                    self.release_resend(code, user);
+                }
+                let result = match e {
+                    ResendError::TooSoon { .. } => "throttled",
+                    ResendError::Exhausted { .. } => "exhausted",
+                };
+                self.resent(user, code, result, None);
+                return Err(e.into());
+            }
+        };
+
+        context.expires = item.expires;
+        let mut delivery = match sender.deliver(user, code, &context) {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                self.release_resend(code, user);
+                self.resent(user, code, "failed", None);
+                bail!("otp not resent: {}", e);
+            }
+        };
+        delivery.resends = resends;
+        self.sent(user, delivery.channel);
+        self.resent(user, code, "sent", delivery.channel);
+        let key = (code.to_string(), user.to_string());
+        self.deliveries
+            .write()
+            .unwrap()
+            .insert(key, delivery.clone());
+
+        Ok(delivery)
+    }
+
+    // count a resend of the otp before sending it, so concurrent resends can't pass the cap; return the new count
+    fn reserve_resend(&self, code: &str, user: &str) -> Result<u32, ResendError> {
+        let key = (code.to_string(), user.to_string());
+        let mut deliveries = self.deliveries.write().unwrap();
+        let delivery = deliveries.entry(key).or_default();
+        if delivery.resends >= self.max_resends() {
+            return Err(ResendError::Exhausted {
+                resends: delivery.resends,
+            });
+        }
+
+        delivery.resends += 1;
+        Ok(delivery.resends)
+    }
+
+    // give back a resend that was not sent
+    fn release_resend(&self, code: &str, user: &str) {
+        let key = (code.to_string(), user.to_string());
+        if let Some(delivery) = self.deliveries.write().unwrap().get_mut(&key) {
+            delivery.resends = delivery.resends.saturating_sub(1);
+        }
+    }
+
+    // return the channels that sent the user a code within the resend interval, or TooSoon if that is all of them
+    fn throttled(
+        &self,
+        user: &str,
+        channels: &[Channel],
+        now: u64,
+    ) -> Result<Vec<Channel>, ResendError> {
+        let interval = self.resend_interval();
+        let last_sent = self.last_sent.read().unwrap();
+        let wait = |channel: Option<Channel>| {
+            last_sent
+                .get(&(user.to_string(), channel))
+                .map_or(0, |sent| (sent + interval).saturating_sub(now))
+        };
+
+        // a sender that doesn't name its channels is throttled as one
+        let waits: Vec<(Option<Channel>, u64)> = match channels {
+            [] => vec![(None, wait(None))],
+            channels => channels
+                .iter()
+                .map(|c| (Some(*c), wait(Some(*c))))
+                .collect(),
+        };
+        if waits.iter().all(|(_, wait)| *wait > 0) {
+            let retry_after = waits.iter().map(|(_, wait)| *wait).min().unwrap_or(0);
+            return Err(ResendError::TooSoon { retry_after });
+        }
+
+        Ok(waits
+            .into_iter()
+            .filter(|(_, wait)| *wait > 0)
+            .filter_map(|(channel, _)| channel)
+            .collect())
+    }
+
+    // remember when the user was last sent a code on the channel
+    fn sent(&self, user: &str, channel: Option<Channel>) {
+        let now = self.db.now();
+        self.last_sent
+            .write()
+            .unwrap()
+            .insert((user.to_string(), channel), now);
+    }
+
+    // audit a resend in the log and on the event bus
+    fn resent(&self, user: &str, code: &str, result: &str, channel: Option<Channel>) {
+        logging::event(
+            "otp.resend",
+            &[
+                ("user", user),
+                ("code", code),
+                ("result", result),
+                ("channel", channel.map_or("", |channel| channel.as_str())),
+            ],
+        );
+        let kind = EventKind::Resent {
+            ok: result == "sent",
+        };
+        self.events
+            .emit(kind, Store::Otp, Some(user), Some(code), self.db.now());
+    }
+
+    /// return how the otp was delivered, if create_and_send sent it and it has not been used or removed
+    pub fn delivery(&self, code: &str, user: &str) -> Option<Delivery> {
+        let key = (code.to_string(), user.to_string());
+        self.deliveries.read().unwrap().get(&key).cloned()
+    }
+
+    /// return how the user's outstanding otps were delivered, without their codes, e.g. so a ui can say the phone
+    /// couldn't be reached
+    pub fn deliveries(&self, user: &str) -> Vec<Delivery> {
+        self.deliveries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|((_, u), _)| u == user)
+            .map(|(_, delivery)| delivery.clone())
+            .collect()
+    }
+
+    /// move the otp's delivery to the state, e.g. failed when the app learns the message bounced. states only move
+    /// forward and delivered or failed is final; return true if it changed
+    pub fn set_delivery_state(&self, code: &str, user: &str, state: DeliveryState) -> bool {
+        let key = (code.to_string(), user.to_string());
+        let mut deliveries = self.deliveries.write().unwrap();
+        match deliveries.get_mut(&key) {
+            Some(delivery) => advance(user, delivery, state),
+            None => false,
+        }
+    }
+
+    /// move the delivery with the provider's message id to the state, from a provider's status callback; return true
+    /// if it changed
+    pub fn update_delivery(&self, message_id: &str, state: DeliveryState) -> bool {
+        let mut deliveries = self.deliveries.write().unwrap();
+        let found = deliveries
+            .iter_mut()
+            .find(|(_, delivery)| delivery.message_id.as_deref() == Some(message_id));
+        match found {
+            Some(((_, user), delivery)) => advance(user, delivery, state),
+            None => false,
+        }
+    }
+
+    // drop the delivery record of a removed otp
+    fn forget_delivery(&self, code: &str, user: &str) {
+        let key = (code.to_string(), user.to_string());
+        self.deliveries.write().unwrap().remove(&key);
+    }
+
+    // drop the delivery records of otps that are gone, after a bulk removal
+    fn forget_deliveries(&self) {
+        let db = self.db.clone();
+        self.deliveries
+            .write()
+            .unwrap()
+            .retain(|(code, user), _| db.get(code, user).is_some());
+    }
+
+    /// store an otp with a caller supplied code
+    pub fn put(&mut self, item: SessionItem) -> Result<()> {
+        logging::event("otp.put", &[("user", &item.user), ("code", &item.code)]);
+        self.db.put(item.clone())?;
+        self.events.emit(
+            EventKind::Put,
+            Store::Otp,
+            Some(&item.user),
+            Some(&item.code),
+            self.db.now(),
+        );
+
+        Ok(())
+    }
+
     /// validate this otp for the given user
     pub fn is_valid(&self, code: &str, user: &str) -> bool {
-        debug!("validate: {}:{}", code, user);
-        let resp = self.db.get(code, user);
-        resp.is_some()
+        self.validate(code, user).is_valid()
+    }
+
+    /// validate this otp for the given user and return why it is or is not valid
+    pub fn validate(&self, code: &str, user: &str) -> Validation {
+        let _span = metrics::span("otp.validate");
+        let start = Instant::now();
+        let result = self.db.validate(code, user);
+        self.validated(code, user, result, start)
+    }
+
+    /// validate this otp and remove it if valid, so it can only be used once
+    pub fn consume(&mut self, code: &str, user: &str) -> Validation {
+        let _span = metrics::span("otp.consume");
+        let start = Instant::now();
+        let item = self.db.get(code, user);
+        let result = self.db.consume(code, user);
+        if let (Validation::Valid, Some(item)) = (result, item) {
+            self.stats
+                .ended(&item, self.keep_alive_for(user), self.db.now());
+            self.events.emit(
+                EventKind::Removed,
+                Store::Otp,
+                Some(user),
+                Some(code),
+                self.db.now(),
+            );
+            metrics::removed(metrics::OTP, self.db.dbsize());
+            self.stats.removed(1);
+            self.forget_delivery(code, user);
+        }
+        self.validated(code, user, result, start)
+    }
+
+    // log, emit and count a validation
+    fn validated(&self, code: &str, user: &str, result: Validation, start: Instant) -> Validation {
+        let valid = result.is_valid();
+        logging::event(
+            "otp.validate",
+            &[("user", user), ("code", code), ("result", result.as_str())],
+        );
+        let kind = EventKind::Validated { ok: valid };
+        self.events
+            .emit(kind, Store::Otp, Some(user), Some(code), self.db.now());
+        metrics::validated(metrics::OTP, valid);
+        if !valid {
+            self.stats.failed();
+        }
+        self.stats.latency(Operation::Validate, start.elapsed());
+        result
+    }
+
+    /// lock the user so none of their otps validate until unlocked; return false if already locked
+    pub fn lock_user(&self, user: &str) -> bool {
+        logging::event("otp.lock_user", &[("user", user)]);
+        self.db.lock_user(user)
+    }
+
+    /// unlock the user; return false if they were not locked
+    pub fn unlock_user(&self, user: &str) -> bool {
+        logging::event("otp.unlock_user", &[("user", user)]);
+        self.db.unlock_user(user)
     }
 
     /// remove the code for this user
     pub fn remove(&mut self, code: &str, user: &str) -> Option<String> {
-        debug!("remove otp {}:{}", code, user);
+        let _span = metrics::span("otp.remove");
+        let start = Instant::now();
+        let item = self.db.get(code, user);
         if self.db.remove(code, user) {
+            if let Some(item) = item {
+                self.stats
+                    .ended(&item, self.keep_alive_for(user), self.db.now());
+            }
+            self.forget_delivery(code, user);
+            logging::event("otp.remove", &[("user", user), ("code", code)]);
+            self.events.emit(
+                EventKind::Removed,
+                Store::Otp,
+                Some(user),
+                Some(code),
+                self.db.now(),
+            );
+            metrics::removed(metrics::OTP, self.db.dbsize());
+            self.stats.removed(1);
+            self.stats.latency(Operation::Remove, start.elapsed());
             Some(code.to_string())
         } else {
             None
         }
     }
 
+    /// remove all of the user's otps; return the number removed
+    pub fn remove_user(&mut self, user: &str) -> usize {
+        let count = self.db.remove_user(user);
+        self.deliveries
+            .write()
+            .unwrap()
+            .retain(|(_, owner), _| owner != user);
+        logging::event(
+            "otp.remove_user",
+            &[("user", user), ("count", &count.to_string())],
+        );
+        let kind = EventKind::UserRemoved { count };
+        self.events
+            .emit(kind, Store::Otp, Some(user), None, self.db.now());
+        self.stats.removed(count);
+        metrics::removed(metrics::OTP, self.db.dbsize());
+        count
+    }
+
+    /// start clearing the store; return the token that confirms it, see DataStore::request_clear
+    pub fn request_clear(&self) -> String {
+        self.db.request_clear()
+    }
+
+    /// remove every otp, confirmed by the token from request_clear; the clear is logged and emitted as an event for
+    /// the audit trail. return the number removed
+    pub fn clear(&mut self, token: &str) -> Result<usize> {
+        let count = self.db.clear(token)?;
+        self.deliveries.write().unwrap().clear();
+        self.last_sent.write().unwrap().clear();
+        logging::event("otp.clear", &[("count", &count.to_string())]);
+        let kind = EventKind::Cleared { count };
+        self.events
+            .emit(kind, Store::Otp, None, None, self.db.now());
+        self.stats.removed(count);
+        metrics::removed(metrics::OTP, 0);
+        Ok(count)
+    }
+
+    /// remove the expired otps; return the number removed
+    pub fn purge_expired(&mut self) -> usize {
+        let _span = metrics::span("otp.purge_expired");
+        let start = Instant::now();
+        let count = self.db.purge_expired();
+        self.forget_deliveries();
+        let (now, interval) = (self.db.now(), self.resend_interval());
+        self.last_sent
+            .write()
+            .unwrap()
+            .retain(|_, sent| *sent + interval > now);
+        if count > 0 {
+            self.events.emit(
+                EventKind::Expired { count },
+                Store::Otp,
+                None,
+                None,
+                self.db.now(),
+            );
+        }
+        self.stats.expired(count);
+        metrics::swept(metrics::OTP, count, self.db.dbsize(), start.elapsed());
+        count
+    }
+
+    /// return the active otps, optionally filtered to a single user
+    pub fn list(&self, user: Option<&str>) -> Vec<SessionItem> {
+        match user {
+            Some(user) => self.db.scan_user(user),
+            None => self.db.list(),
+        }
+    }
+
+    /// return the totals since startup (or the last reset) and the lifetime and latency histograms; clones
+    /// share them
+    pub fn stats(&self) -> StoreStats {
+        self.stats.snapshot(self.db.dbsize())
+    }
+
+    /// zero the totals and clear the histograms
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// return the lifecycle event bus; clones share it
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// publish lifecycle events on this bus instead, e.g. to share one bus between otp and session
+    pub fn with_events(mut self, events: Events) -> Otp {
+        self.events = events;
+        self
+    }
+
+    /// deliver codes from create_and_send with this sender
+    pub fn with_sender(mut self, sender: Arc<dyn OtpSender>) -> Otp {
+        self.sender = Some(sender);
+        self
+    }
+
+    /// return a receiver for the otp puts and removes, e.g. to replicate them to a peer
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<Change> {
+        self.db.subscribe()
+    }
+
+    /// apply a change from a peer; return true if the store changed
+    pub fn merge(&mut self, change: Change) -> bool {
+        self.db.merge(change)
+    }
+
+    /// return the changes that bring a peer up to date, as DataStore::replay does
+    pub fn replay(&self) -> Vec<Change> {
+        self.db.replay()
+    }
+
+    /// reject new otps while true, e.g. while draining for shutdown
+    pub fn set_read_only(&self, read_only: bool) {
+        self.db.set_read_only(read_only);
+    }
+
+    /// return the health of the underlying store
+    pub fn health(&self) -> Health {
+        self.db.health()
+    }
+
     /// return the number of otp sessions in the database
     pub fn dbsize(&self) -> usize {
         self.db.dbsize()
     }
+
+    /// return the number of otps that are still valid, leaving out expired ones waiting for a purge
+    pub fn dbsize_active(&self) -> usize {
+        self.db.dbsize_active()
+    }
+
+    /// return the number of valid otps for users in the namespace, e.g. acme for acme:sally
+    pub fn dbsize_namespace(&self, namespace: &str) -> usize {
+        self.db.dbsize_namespace(namespace)
+    }
+
+    /// shrink the store to fit the otps it holds, e.g. after purging a mass expiry
+    pub fn compact(&mut self) {
+        self.db.compact();
+    }
+}
+
+// move the delivery forward to the state, logging the change; final states and backward moves are ignored
+fn advance(user: &str, delivery: &mut Delivery, state: DeliveryState) -> bool {
+    if delivery.state.is_final() || state <= delivery.state {
+        return false;
+    }
+
+    delivery.state = state;
+    logging::event(
+        "otp.delivery",
+        &[
+            ("user", user),
+            ("state", state.as_str()),
+            ("message_id", delivery.message_id.as_deref().unwrap_or("")),
+        ],
+    );
+    true
 }
 
 #[cfg(test)]
@@ -108,6 +911,145 @@ mod tests {
         assert!(resp.is_none());
     }
 
+    #[derive(Debug, Default)]
+    struct Outbox {
+        sent: std::sync::Mutex<Vec<(String, String, SendContext)>>,
+    }
+
+    impl OtpSender for Outbox {
+        fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()> {
+            if user == "nobody" {
+                bail!("no phone number");
+            }
+            let sent = (user.to_string(), code.to_string(), context.clone());
+            self.sent.lock().unwrap().push(sent);
+            Ok(())
+        }
+
+        fn deliver(&self, user: &str, code: &str, context: &SendContext) -> Result<Delivery> {
+            self.send(user, code, context)?;
+            Ok(Delivery {
+                attempts: 1,
+                state: DeliveryState::Sent,
+                message_id: Some(format!("SM{}", code)),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn create_and_send() {
+        let mut otp = create_otp();
+        assert!(otp
+            .create_and_send("sally", SendContext::new("login"))
+            .is_err());
+
+        let outbox = Arc::new(Outbox::default());
+        let mut otp = Otp::builder().sender(outbox.clone()).build().unwrap();
+        let context = SendContext::new("login").with("ip", "203.0.113.7");
+        let expires = otp.create_and_send("sally", context).unwrap();
+        let (user, code, context) = outbox.sent.lock().unwrap().pop().unwrap();
+        assert_eq!(user, "sally");
+        assert_eq!(
+            (context.purpose.as_str(), context.expires),
+            ("login", expires)
+        );
+        assert_eq!(context.get("ip"), Some("203.0.113.7"));
+        assert!(otp.is_valid(&code, "sally"));
+        let delivery = otp.delivery(&code, "sally").unwrap();
+        assert_eq!((delivery.channel, delivery.attempts), (None, 1));
+        assert_eq!(delivery.state, DeliveryState::Sent);
+        assert_eq!(otp.deliveries("sally"), [delivery]);
+        assert!(!otp.set_delivery_state(&code, "sally", DeliveryState::Queued));
+        assert!(!otp.update_delivery("SM", DeliveryState::Failed));
+        assert!(otp.update_delivery(&format!("SM{}", code), DeliveryState::Failed));
+        assert!(!otp.set_delivery_state(&code, "sally", DeliveryState::Delivered));
+        assert_eq!(
+            otp.delivery(&code, "sally").unwrap().state,
+            DeliveryState::Failed
+        );
+        assert!(otp.consume(&code, "sally").is_valid());
+        assert!(otp.delivery(&code, "sally").is_none());
+
+        let err = otp
+            .create_and_send("nobody", SendContext::new("login"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "otp not sent: no phone number");
+        assert!(otp.list(Some("nobody")).is_empty());
+    }
+
+    #[test]
+    fn resend() {
+        let clock = Arc::new(crate::clock::MockClock::at(1_000));
+        let outbox = Arc::new(Outbox::default());
+        let mut otp = Otp::builder()
+            .sender(outbox.clone())
+            .clock(clock.clone())
+            .max_resends(2)
+            .build()
+            .unwrap();
+        let context = SendContext::new("login");
+        otp.create_and_send("sally", context.clone()).unwrap();
+        let (_, code, _) = outbox.sent.lock().unwrap().pop().unwrap();
+
+        // a new code is throttled on the same channels, and none is created
+        let err = otp.create_and_send("sally", context.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ResendError>(),
+            Some(&ResendError::TooSoon { retry_after: 30 })
+        );
+        assert_eq!(otp.list(Some("sally")).len(), 1);
+
+        let err = otp.resend(&code, "sally", context.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ResendError>(),
+            Some(&ResendError::TooSoon { retry_after: 30 })
+        );
+        for resends in 1..=2 {
+            clock.set(1_000 + 30 * resends as u64);
+            let delivery = otp.resend(&code, "sally", context.clone()).unwrap();
+            assert_eq!(delivery.resends, resends);
+        }
+        clock.set(1_100);
+        let err = otp.resend(&code, "sally", context.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ResendError>(),
+            Some(&ResendError::Exhausted { resends: 2 })
+        );
+        assert_eq!(outbox.sent.lock().unwrap().len(), 2);
+        assert!(otp.is_valid(&code, "sally"));
+        assert!(otp.resend("nope", "sally", context).is_err());
+    }
+
+    #[test]
+    fn consume() {
+        let mut otp = create_otp();
+        let user = "sally";
+        let code = otp.create_user_otp(user).unwrap();
+
+        assert_eq!(otp.consume(&code, user), Validation::Valid);
+        assert_eq!(otp.validate(&code, user), Validation::Consumed);
+        assert_eq!(otp.consume(&code, user), Validation::Consumed);
+        assert_eq!(otp.dbsize(), 0);
+
+        let stats = otp.stats();
+        assert_eq!((stats.removed, stats.failed), (1, 2));
+    }
+
+    #[test]
+    fn list() {
+        let mut otp = create_otp();
+        let code = otp.create_user_otp("sally").unwrap();
+        otp.create_user_otp("jack").unwrap();
+
+        assert_eq!(otp.list(None).len(), 2);
+
+        let items = otp.list(Some("sally"));
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code, code);
+        assert!(otp.list(Some("john")).is_empty());
+    }
+
     #[test]
     fn generate_code() {
         let otp = create_otp();
@@ -116,6 +1058,60 @@ mod tests {
         assert_eq!(code.len(), 6);
     }
 
+    #[test]
+    fn settings() {
+        let otp = create_otp();
+        let shared = otp.clone();
+        assert_eq!(otp.keep_alive(), crate::OTP_TIMEOUT);
+
+        // clones see the new settings
+        shared.set_keep_alive(60);
+        shared.set_code_length(8).unwrap();
+        assert_eq!(otp.keep_alive(), 60);
+        assert_eq!(otp.generate_code().len(), 8);
+
+        assert!(otp.set_code_length(3).is_err());
+        assert!(otp.set_code_length(11).is_err());
+        assert_eq!(otp.code_length(), 8);
+    }
+
+    #[test]
+    fn builder() {
+        let store = DataStore::create();
+        let mut otp = Otp::builder()
+            .timeout(120)
+            .code_length(8)
+            .store(store.clone())
+            .build()
+            .unwrap();
+        assert_eq!(otp.keep_alive(), 120);
+
+        let code = otp.create_user_otp("sally").unwrap();
+        assert_eq!(code.len(), 8);
+        assert!(store.get(&code, "sally").is_some());
+
+        assert!(Otp::builder().code_length(20).build().is_err());
+        assert!(Otp::builder().timeout(0).build().is_err());
+    }
+
+    #[test]
+    fn max_per_user() {
+        let mut otp = Otp::builder().max_per_user(2).build().unwrap();
+        otp.create_user_otp("sally").unwrap();
+        let code = otp.create_user_otp("sally").unwrap();
+        let err = otp.create_user_otp("sally").unwrap_err();
+        assert_eq!(err.to_string(), "sally already has 2 otps");
+        assert_eq!(otp.list(Some("sally")).len(), 2);
+        otp.create_user_otp("jack").unwrap();
+
+        otp.remove(&code, "sally");
+        otp.create_user_otp("sally").unwrap();
+
+        otp.set_max_per_user(None);
+        otp.create_user_otp("sally").unwrap();
+        assert_eq!(otp.list(Some("sally")).len(), 3);
+    }
+
     #[test]
     fn create() {
         let otp = create_otp();
@@ -0,0 +1,566 @@
+/// storage backends loaded at runtime from shared libraries through a versioned c abi, so proprietary backends ship
+/// separately from the daemon. a plugin written in rust implements Backend and exports it with export_backend!
+use crate::db::{Change, SessionItem};
+use crate::events::Store;
+use crate::otp::Otp;
+use crate::session::Session;
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// the version of the backend abi, bumped on any change to RawBackend or RawItem; a plugin built for another version
+/// is refused
+pub const ABI_VERSION: u32 = 1;
+
+/// the symbol every plugin exports, an EntryPoint
+pub const ENTRY_POINT: &[u8] = b"otp_session_backend\0";
+
+/// `RawBackend *otp_session_backend(uint32_t abi_version, const char *config)`: open the backend with the config
+/// string, or return null if the plugin doesn't speak the abi version
+pub type EntryPoint =
+    unsafe extern "C" fn(abi_version: u32, config: *const c_char) -> *mut RawBackend;
+
+/// an item as it crosses the abi; the strings are nul terminated utf-8, borrowed for the length of the call
+#[repr(C)]
+#[derive(Debug)]
+pub struct RawItem {
+    pub code: *const c_char,
+    pub user: *const c_char,
+    /// unix time in seconds
+    pub expires: u64,
+}
+
+/// the table of functions a plugin's entry point returns; abi_version stays the first field in every version. the
+/// store is "otp" or "session" and calls return 0 on success; after a failure, or when the entry point couldn't open
+/// the backend, `error` returns the message. calls come one at a time, though not always from the same thread
+#[repr(C)]
+#[derive(Debug)]
+pub struct RawBackend {
+    pub abi_version: u32,
+    pub state: *mut c_void,
+    /// call visit with each of the store's items and ctx
+    pub load: unsafe extern "C" fn(
+        state: *mut c_void,
+        store: *const c_char,
+        visit: unsafe extern "C" fn(ctx: *mut c_void, item: *const RawItem),
+        ctx: *mut c_void,
+    ) -> i32,
+    pub put:
+        unsafe extern "C" fn(state: *mut c_void, store: *const c_char, item: *const RawItem) -> i32,
+    pub remove: unsafe extern "C" fn(
+        state: *mut c_void,
+        store: *const c_char,
+        code: *const c_char,
+        user: *const c_char,
+    ) -> i32,
+    /// the last failure's message, valid until the next call, or null
+    pub error: unsafe extern "C" fn(state: *mut c_void) -> *const c_char,
+    /// free the state and the table itself
+    pub close: unsafe extern "C" fn(backend: *mut RawBackend),
+}
+
+/// a storage backend: the daemon restores the stores from it at start, then sends it every put and remove
+pub trait Backend: Send {
+    /// return the store's items
+    fn load(&mut self, store: Store) -> Result<Vec<SessionItem>>;
+
+    /// keep the item, replacing any with the same code and user
+    fn put(&mut self, store: Store, item: &SessionItem) -> Result<()>;
+
+    /// forget the item, if it is kept
+    fn remove(&mut self, store: Store, code: &str, user: &str) -> Result<()>;
+}
+
+/// a plugin backend for the daemon: the shared library and the config string its backend is opened with, e.g. a
+/// connection url
+#[derive(Debug, Clone, Default)]
+pub struct BackendConfig {
+    pub path: PathBuf,
+    pub config: String,
+}
+
+// borrow a nul terminated utf-8 string from across the abi
+unsafe fn text<'a>(ptr: *const c_char) -> Result<&'a str> {
+    if ptr.is_null() {
+        bail!("null string");
+    }
+
+    Ok(CStr::from_ptr(ptr).to_str()?)
+}
+
+unsafe fn store(ptr: *const c_char) -> Result<Store> {
+    match text(ptr)? {
+        "otp" => Ok(Store::Otp),
+        "session" => Ok(Store::Session),
+        other => bail!("unknown store: {}", other),
+    }
+}
+
+unsafe fn item(raw: *const RawItem) -> Result<SessionItem> {
+    let raw = raw.as_ref().ok_or_else(|| anyhow!("null item"))?;
+    Ok(SessionItem {
+        code: text(raw.code)?.to_string(),
+        user: text(raw.user)?.to_string(),
+        expires: raw.expires,
+        ..Default::default()
+    })
+}
+
+fn c_string(text: &str) -> Result<CString> {
+    CString::new(text).map_err(|_| anyhow!("{:?} has a nul byte", text))
+}
+
+// the plugin side state behind RawBackend.state; the backend is None if it couldn't be opened
+struct Exported<B> {
+    backend: Option<B>,
+    error: Option<CString>,
+}
+
+impl<B: Backend> Exported<B> {
+    // run the call and keep its error for `error`; a panic must not unwind into the daemon, so it is an error too
+    unsafe fn call(state: *mut c_void, call: impl FnOnce(&mut B) -> Result<()>) -> i32 {
+        let exported = &mut *(state as *mut Exported<B>);
+        let result = match exported.backend.as_mut() {
+            Some(backend) => panic::catch_unwind(AssertUnwindSafe(|| call(backend)))
+                .unwrap_or_else(|_| Err(anyhow!("the backend panicked"))),
+            None => Err(anyhow!("the backend is not open")),
+        };
+
+        match result {
+            Ok(()) => {
+                exported.error = None;
+                0
+            }
+            Err(e) => {
+                exported.error = c_string(&e.to_string().replace('\0', " ")).ok();
+                -1
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn load<B: Backend>(
+    state: *mut c_void,
+    name: *const c_char,
+    visit: unsafe extern "C" fn(ctx: *mut c_void, item: *const RawItem),
+    ctx: *mut c_void,
+) -> i32 {
+    Exported::call(state, |backend: &mut B| {
+        for item in backend.load(store(name)?)? {
+            let (code, user) = (c_string(&item.code)?, c_string(&item.user)?);
+            let raw = RawItem {
+                code: code.as_ptr(),
+                user: user.as_ptr(),
+                expires: item.expires,
+            };
+            visit(ctx, &raw);
+        }
+
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn put<B: Backend>(
+    state: *mut c_void,
+    name: *const c_char,
+    raw: *const RawItem,
+) -> i32 {
+    Exported::call(state, |backend: &mut B| {
+        backend.put(store(name)?, &item(raw)?)
+    })
+}
+
+unsafe extern "C" fn remove<B: Backend>(
+    state: *mut c_void,
+    name: *const c_char,
+    code: *const c_char,
+    user: *const c_char,
+) -> i32 {
+    Exported::call(state, |backend: &mut B| {
+        backend.remove(store(name)?, text(code)?, text(user)?)
+    })
+}
+
+unsafe extern "C" fn error<B: Backend>(state: *mut c_void) -> *const c_char {
+    let exported = &*(state as *const Exported<B>);
+    exported
+        .error
+        .as_ref()
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
+
+unsafe extern "C" fn close<B: Backend>(backend: *mut RawBackend) {
+    let table = Box::from_raw(backend);
+    drop(Box::from_raw(table.state as *mut Exported<B>));
+}
+
+/// the body of the entry point export_backend! writes
+///
+/// # Safety
+///
+/// config must be a nul terminated string
+#[doc(hidden)]
+pub unsafe fn export<B: Backend>(
+    abi_version: u32,
+    config: *const c_char,
+    open: fn(&str) -> Result<B>,
+) -> *mut RawBackend {
+    if abi_version != ABI_VERSION {
+        return ptr::null_mut();
+    }
+
+    let opened = panic::catch_unwind(|| open(text(config)?))
+        .unwrap_or_else(|_| Err(anyhow!("the backend panicked")));
+    let exported = match opened {
+        Ok(backend) => Exported {
+            backend: Some(backend),
+            error: None,
+        },
+        Err(e) => Exported {
+            backend: None,
+            error: c_string(&e.to_string().replace('\0', " ")).ok(),
+        },
+    };
+
+    Box::into_raw(Box::new(RawBackend {
+        abi_version: ABI_VERSION,
+        state: Box::into_raw(Box::new(exported)) as *mut c_void,
+        load: load::<B>,
+        put: put::<B>,
+        remove: remove::<B>,
+        error: error::<B>,
+        close: close::<B>,
+    }))
+}
+
+/// export a Backend from a plugin's cdylib; open creates it from the config string the daemon was given, e.g.
+/// `otp_session_lib::export_backend!(RedisBackend::open);`
+#[macro_export]
+macro_rules! export_backend {
+    ($open:path) => {
+        /// the entry point the daemon loads
+        ///
+        /// # Safety
+        ///
+        /// config must be a nul terminated string
+        #[no_mangle]
+        pub unsafe extern "C" fn otp_session_backend(
+            abi_version: u32,
+            config: *const ::std::ffi::c_char,
+        ) -> *mut $crate::plugin::RawBackend {
+            $crate::plugin::export(abi_version, config, $open)
+        }
+    };
+}
+
+/// a backend in a shared library loaded at runtime; the library stays loaded until the plugin is dropped
+#[derive(Debug)]
+pub struct Plugin {
+    raw: *mut RawBackend,
+    // dropped after the backend is closed
+    _library: Option<libloading::Library>,
+}
+
+// the abi has backends take calls from any thread, one at a time, which &mut self ensures
+unsafe impl Send for Plugin {}
+
+impl Plugin {
+    /// load the shared library at path and open its backend with the config. loading runs the library's code, so
+    /// only load plugins you trust
+    pub fn load(path: &Path, config: &str) -> Result<Plugin> {
+        let config = c_string(config)?;
+        // safety: the entry point has the abi's signature, and the library outlives the table it returns
+        unsafe {
+            let library = libloading::Library::new(path)
+                .map_err(|e| anyhow!("load the plugin {:?}: {}", path, e))?;
+            let entry = *library
+                .get::<EntryPoint>(ENTRY_POINT)
+                .map_err(|e| anyhow!("{:?} is not a backend plugin: {}", path, e))?;
+            let raw = entry(ABI_VERSION, config.as_ptr());
+            Plugin::open(raw, Some(library))
+        }
+    }
+
+    // take a table from an entry point. one of another abi version is leaked, as its close can't be trusted
+    unsafe fn open(raw: *mut RawBackend, library: Option<libloading::Library>) -> Result<Plugin> {
+        if raw.is_null() {
+            bail!(
+                "the plugin does not support backend abi version {}",
+                ABI_VERSION
+            );
+        }
+        if (*raw).abi_version != ABI_VERSION {
+            bail!(
+                "the plugin has backend abi version {}, not {}",
+                (*raw).abi_version,
+                ABI_VERSION
+            );
+        }
+
+        let plugin = Plugin {
+            raw,
+            _library: library,
+        };
+        if let Some(e) = plugin.last_error() {
+            bail!("open the backend: {}", e);
+        }
+
+        Ok(plugin)
+    }
+
+    fn last_error(&self) -> Option<String> {
+        // safety: the table is open until drop, and the message is valid until the next call
+        unsafe {
+            let message = ((*self.raw).error)((*self.raw).state);
+            (!message.is_null()).then(|| CStr::from_ptr(message).to_string_lossy().into_owned())
+        }
+    }
+
+    // turn a call's status into a result with the backend's message
+    fn status(&self, status: i32) -> Result<()> {
+        if status == 0 {
+            return Ok(());
+        }
+
+        match self.last_error() {
+            Some(e) => bail!("{}", e),
+            None => bail!("the backend failed with status {}", status),
+        }
+    }
+}
+
+impl Backend for Plugin {
+    fn load(&mut self, store: Store) -> Result<Vec<SessionItem>> {
+        // collect the items the plugin visits; one that isn't utf-8 is skipped
+        unsafe extern "C" fn visit(ctx: *mut c_void, raw: *const RawItem) {
+            let items = &mut *(ctx as *mut Vec<SessionItem>);
+            if let Ok(item) = item(raw) {
+                items.push(item);
+            }
+        }
+
+        let name = c_string(store.as_str())?;
+        let mut items: Vec<SessionItem> = Vec::new();
+        let ctx = &mut items as *mut Vec<SessionItem> as *mut c_void;
+        let status = unsafe { ((*self.raw).load)((*self.raw).state, name.as_ptr(), visit, ctx) };
+        self.status(status)?;
+
+        Ok(items)
+    }
+
+    fn put(&mut self, store: Store, item: &SessionItem) -> Result<()> {
+        let name = c_string(store.as_str())?;
+        let (code, user) = (c_string(&item.code)?, c_string(&item.user)?);
+        let raw = RawItem {
+            code: code.as_ptr(),
+            user: user.as_ptr(),
+            expires: item.expires,
+        };
+        let status = unsafe { ((*self.raw).put)((*self.raw).state, name.as_ptr(), &raw) };
+        self.status(status)
+    }
+
+    fn remove(&mut self, store: Store, code: &str, user: &str) -> Result<()> {
+        let name = c_string(store.as_str())?;
+        let (code, user) = (c_string(code)?, c_string(user)?);
+        let status = unsafe {
+            ((*self.raw).remove)(
+                (*self.raw).state,
+                name.as_ptr(),
+                code.as_ptr(),
+                user.as_ptr(),
+            )
+        };
+        self.status(status)
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // safety: open checked the table's version, and it isn't used after this
+        unsafe { ((*self.raw).close)(self.raw) }
+    }
+}
+
+/// merge the backend's unexpired items into the stores without notifying their subscribers, so they aren't written
+/// back; return the number merged. backends don't keep generations, so items of a user who has moved past the first
+/// generation stay revoked
+pub fn restore(backend: &mut dyn Backend, otp: &Otp, session: &Session) -> Result<usize> {
+    let put = |item| Change::Put {
+        item,
+        generation: 0,
+    };
+    let mut count = 0;
+    for item in backend.load(Store::Otp)? {
+        count += usize::from(!item.has_expired() && otp.clone().merge(put(item)));
+    }
+    for item in backend.load(Store::Session)? {
+        count += usize::from(!item.has_expired() && session.clone().merge(put(item)));
+    }
+
+    Ok(count)
+}
+
+/// send every later put and remove on the stores to the backend from background threads, one per store; a change
+/// the backend fails to take is logged and dropped
+pub fn attach<B: Backend + 'static>(backend: B, otp: &Otp, session: &Session) -> Result<()> {
+    let backend = Arc::new(Mutex::new(backend));
+    for (store, changes) in [
+        (Store::Otp, otp.subscribe()),
+        (Store::Session, session.subscribe()),
+    ] {
+        let backend = Arc::clone(&backend);
+        thread::Builder::new()
+            .name(format!("backend-{}", store.as_str()))
+            .spawn(move || {
+                for change in changes {
+                    let mut backend = backend.lock().unwrap_or_else(|e| e.into_inner());
+                    let result = match &change {
+                        Change::Put { item, .. } => backend.put(store, item),
+                        Change::Remove { code, user } => backend.remove(store, code, user),
+                        // backends keep items; generations and locks stay in the stores
+                        Change::Generation { .. } | Change::Lock { .. } => Ok(()),
+                    };
+                    if let Err(e) = result {
+                        warn!("backend {} write failed: {}", store, e);
+                    }
+                }
+            })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    // expiries by store, code and user
+    type Expiries = BTreeMap<(&'static str, String, String), u64>;
+
+    // items shared by clones
+    #[derive(Debug, Clone, Default)]
+    struct MemoryBackend(Arc<Mutex<Expiries>>);
+
+    impl MemoryBackend {
+        fn open(config: &str) -> Result<MemoryBackend> {
+            match config {
+                "fail" => bail!("no such database"),
+                "panic" => panic!("open panicked"),
+                _ => Ok(MemoryBackend::default()),
+            }
+        }
+    }
+
+    impl Backend for MemoryBackend {
+        fn load(&mut self, store: Store) -> Result<Vec<SessionItem>> {
+            let items = self.0.lock().unwrap();
+            let items = items
+                .iter()
+                .filter(|((name, _, _), _)| *name == store.as_str())
+                .map(|((_, code, user), expires)| SessionItem {
+                    code: code.clone(),
+                    user: user.clone(),
+                    expires: *expires,
+                    ..Default::default()
+                });
+
+            Ok(items.collect())
+        }
+
+        fn put(&mut self, store: Store, item: &SessionItem) -> Result<()> {
+            if item.user == "panic" {
+                panic!("put panicked");
+            }
+            let key = (store.as_str(), item.code.clone(), item.user.clone());
+            self.0.lock().unwrap().insert(key, item.expires);
+            Ok(())
+        }
+
+        fn remove(&mut self, store: Store, code: &str, user: &str) -> Result<()> {
+            let key = (store.as_str(), code.to_string(), user.to_string());
+            match self.0.lock().unwrap().remove(&key) {
+                Some(_) => Ok(()),
+                None => bail!("{} has no {}", user, code),
+            }
+        }
+    }
+
+    // open the backend through the abi, as a loaded plugin would be
+    fn open(abi_version: u32, config: &str) -> Result<Plugin> {
+        let config = CString::new(config).unwrap();
+        unsafe {
+            let raw = export(abi_version, config.as_ptr(), MemoryBackend::open);
+            Plugin::open(raw, None)
+        }
+    }
+
+    #[test]
+    fn plugin_abi() {
+        let mut plugin = open(ABI_VERSION, "").unwrap();
+        let item = SessionItem::new("123456", "sally", 60);
+        plugin.put(Store::Otp, &item).unwrap();
+        plugin
+            .put(Store::Session, &SessionItem::new("abc", "jack", 60))
+            .unwrap();
+        assert_eq!(plugin.load(Store::Otp).unwrap(), vec![item]);
+
+        plugin.remove(Store::Session, "abc", "jack").unwrap();
+        assert!(plugin.load(Store::Session).unwrap().is_empty());
+        let e = plugin.remove(Store::Session, "abc", "jack").unwrap_err();
+        assert_eq!(e.to_string(), "jack has no abc");
+        let e = plugin
+            .put(Store::Otp, &SessionItem::new("1", "panic", 60))
+            .unwrap_err();
+        assert_eq!(e.to_string(), "the backend panicked");
+
+        let e = open(ABI_VERSION + 1, "").unwrap_err();
+        assert!(e.to_string().contains("abi version 1"), "{}", e);
+        let e = open(ABI_VERSION, "fail").unwrap_err();
+        assert_eq!(e.to_string(), "open the backend: no such database");
+        assert!(open(ABI_VERSION, "panic").is_err());
+    }
+
+    #[test]
+    fn restore_attach() {
+        let otp = Otp::builder().build().unwrap();
+        let session = Session::builder().build().unwrap();
+        let mut backend = MemoryBackend::default();
+        backend
+            .put(Store::Session, &SessionItem::new("abc", "sally", 60))
+            .unwrap();
+        backend
+            .put(Store::Otp, &SessionItem::new("123456", "gone", 0))
+            .unwrap();
+
+        assert_eq!(restore(&mut backend, &otp, &session).unwrap(), 1);
+        assert!(session.is_valid("abc", "sally"));
+
+        attach(backend.clone(), &otp, &session).unwrap();
+        let code = otp.clone().create_user_otp("jack").unwrap();
+        session.clone().remove("abc", "sally");
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while backend.load(Store::Session).unwrap().len() == 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        while backend.load(Store::Otp).unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(backend.load(Store::Session).unwrap().is_empty());
+        let codes: Vec<String> = backend
+            .load(Store::Otp)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.code)
+            .collect();
+        assert!(codes.contains(&code));
+    }
+}
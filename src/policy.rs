@@ -0,0 +1,147 @@
+/// keep alive policies by user class, e.g. shorter sessions for admins and longer ones for service accounts, and what
+/// to do when a user with sessions logs in again
+use crate::config::validate_timeout;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+type Classifier = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// maps users to classes and classes to keep alives; clones share the policies
+#[derive(Clone, Default)]
+pub struct TtlPolicies {
+    timeouts: Arc<RwLock<HashMap<String, u64>>>,
+    classifier: Arc<RwLock<Option<Classifier>>>,
+}
+
+impl fmt::Debug for TtlPolicies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TtlPolicies")
+            .field("timeouts", &self.timeouts.read().unwrap())
+            .finish()
+    }
+}
+
+impl TtlPolicies {
+    /// create an empty set of policies; every user gets the store's keep alive
+    pub fn new() -> TtlPolicies {
+        TtlPolicies::default()
+    }
+
+    /// set the function that returns a user's class, e.g. from a role lookup; None means no class
+    pub fn set_classifier<F>(&self, classifier: F)
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        *self.classifier.write().unwrap() = Some(Arc::new(classifier));
+    }
+
+    /// set the keep alive in seconds for users in the class
+    pub fn set(&self, class: &str, timeout: u64) -> Result<()> {
+        validate_timeout(&format!("the {} timeout", class), timeout)?;
+        self.timeouts
+            .write()
+            .unwrap()
+            .insert(class.to_string(), timeout);
+
+        Ok(())
+    }
+
+    /// remove the class policy; return true if it was set
+    pub fn remove(&self, class: &str) -> bool {
+        self.timeouts.write().unwrap().remove(class).is_some()
+    }
+
+    /// return the keep alive for the class, if it has a policy
+    pub fn get(&self, class: &str) -> Option<u64> {
+        self.timeouts.read().unwrap().get(class).copied()
+    }
+
+    /// return the keep alive for the user's class, or None if the user has no class or the class no policy
+    pub fn keep_alive(&self, user: &str) -> Option<u64> {
+        let classifier = self.classifier.read().unwrap().clone()?;
+        self.get(&classifier(user)?)
+    }
+}
+
+/// what happens when a user logs in while they already have active sessions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoginPolicy {
+    /// create the session, up to max_per_user
+    #[default]
+    Allow,
+    /// refuse the login while the user has any active session
+    Deny,
+    /// end the user's oldest sessions, those closest to expiring, to stay within max_per_user (or one session when
+    /// there is no limit)
+    KickOldest,
+}
+
+impl LoginPolicy {
+    /// return the name used in config files and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginPolicy::Allow => "allow",
+            LoginPolicy::Deny => "deny",
+            LoginPolicy::KickOldest => "kick_oldest",
+        }
+    }
+
+    // the policy as stored in an atomic
+    pub(crate) fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_u8(value: u8) -> LoginPolicy {
+        match value {
+            1 => LoginPolicy::Deny,
+            2 => LoginPolicy::KickOldest,
+            _ => LoginPolicy::Allow,
+        }
+    }
+}
+
+impl FromStr for LoginPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<LoginPolicy> {
+        match text {
+            "allow" => Ok(LoginPolicy::Allow),
+            "deny" => Ok(LoginPolicy::Deny),
+            "kick_oldest" => Ok(LoginPolicy::KickOldest),
+            _ => bail!(
+                "{} is not a login policy; use allow, deny or kick_oldest",
+                text
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive() {
+        let policies = TtlPolicies::new();
+        policies.set("admin", 900).unwrap();
+        policies.set("service", 86_400).unwrap();
+        assert!(policies.set("admin", 0).is_err());
+        assert_eq!(policies.keep_alive("root"), None);
+
+        let shared = policies.clone();
+        shared.set_classifier(|user| match user {
+            "root" => Some("admin".to_string()),
+            u if u.starts_with("svc-") => Some("service".to_string()),
+            _ => None,
+        });
+        assert_eq!(policies.keep_alive("root"), Some(900));
+        assert_eq!(policies.keep_alive("svc-backup"), Some(86_400));
+        assert_eq!(policies.keep_alive("sally"), None);
+
+        assert!(policies.remove("admin"));
+        assert_eq!(policies.keep_alive("root"), None);
+    }
+}
@@ -0,0 +1,414 @@
+/// a single threaded server for targets without threads, e.g. wasm32-wasi: nonblocking listeners and connections the
+/// daemon polls between sweeps, answering json-rpc, resp and probe requests as complete ones arrive
+use crate::health::{ProbeServer, MAX_PROBE_REQUEST};
+use crate::jsonrpc::{line_too_long, Connection, JsonRpcServer, MAX_LINE};
+use crate::resp::{self, read_command, RespServer};
+use log::{info, warn};
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// how long the daemon sleeps between polls when no connection had anything to do
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// the most bytes read from one connection in a poll, so a client sending without pause can't starve the others
+pub const READ_BUDGET: usize = 64 * 1024;
+
+/// a server whose connections the poller answers
+#[derive(Debug, Clone)]
+pub enum Service {
+    JsonRpc(JsonRpcServer),
+    Resp(RespServer),
+    Probe(ProbeServer),
+}
+
+impl Service {
+    fn name(&self) -> &'static str {
+        match self {
+            Service::JsonRpc(_) => "json-rpc",
+            Service::Resp(_) => "resp",
+            Service::Probe(_) => "probe",
+        }
+    }
+}
+
+// an accepted connection with the input not yet answered and the output not yet sent
+#[derive(Debug)]
+struct Client {
+    stream: TcpStream,
+    // index of the listener that accepted it
+    listener: usize,
+    // json-rpc state; unused by the other services
+    rpc: Connection,
+    // resp state; unused by the other services
+    resp: resp::Connection,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // the client has closed its side
+    eof: bool,
+    // close once the output is sent
+    closing: bool,
+}
+
+impl Client {
+    // read what has arrived, up to READ_BUDGET bytes; return true if anything did
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        let mut busy = false;
+        let mut budget = READ_BUDGET;
+        while !self.eof && budget > 0 {
+            let len = budget.min(buf.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    budget -= n;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            busy = true;
+        }
+
+        Ok(busy)
+    }
+
+    // send as much output as the connection takes; return true if any was sent
+    fn write(&mut self) -> io::Result<bool> {
+        let mut busy = false;
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                    busy = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(busy)
+    }
+
+    // answer each complete line; a line longer than MAX_LINE gets a parse error and closes the connection
+    fn json_rpc(&mut self, server: &JsonRpcServer) {
+        loop {
+            let end = self.input.iter().position(|&b| b == b'\n');
+            if end.unwrap_or(self.input.len()) > MAX_LINE {
+                warn!("json-rpc request line too long");
+                self.output.extend_from_slice(line_too_long().as_bytes());
+                self.output.push(b'\n');
+                self.close();
+                return;
+            }
+            let Some(end) = end else {
+                return;
+            };
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(resp) = server.handle_line(&mut self.rpc, line.trim()) {
+                self.output.extend_from_slice(resp.as_bytes());
+                self.output.push(b'\n');
+            }
+
+            // events are pushed from the stores' threads, which the poller doesn't have
+            if self.rpc.is_watching() {
+                warn!("admin.watch is not supported by the polling server");
+                self.close();
+                return;
+            }
+        }
+    }
+
+    // answer each complete command; a complete command ends with a newline, so only parse up to the last one
+    fn resp(&mut self, server: &RespServer) {
+        let Some(end) = self.input.iter().rposition(|&b| b == b'\n') else {
+            return;
+        };
+        let mut reader = Cursor::new(&self.input[..=end]);
+        let mut closing = false;
+        loop {
+            let start = reader.position();
+            match read_command(&mut reader) {
+                Ok(Some(args)) if args.is_empty() => continue,
+                Ok(Some(args)) => {
+                    // writing to a vec can't fail
+                    let _ = server
+                        .handle(&mut self.resp, &args)
+                        .write_to(&mut self.output);
+                    if args[0].eq_ignore_ascii_case("QUIT") {
+                        closing = true;
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    reader.set_position(start);
+                    break;
+                }
+                Err(e) => {
+                    warn!("resp connection error: {}", e);
+                    if e.kind() == ErrorKind::InvalidData {
+                        let _ = resp::protocol_error(&e).write_to(&mut self.output);
+                    }
+                    closing = true;
+                    break;
+                }
+            }
+        }
+
+        let read = reader.position() as usize;
+        self.input.drain(..read);
+        if closing {
+            self.close();
+        }
+    }
+
+    // answer the request once its headers have arrived, then close; a request over MAX_PROBE_REQUEST is dropped
+    fn probe(&mut self, server: &ProbeServer) {
+        let request = String::from_utf8_lossy(&self.input);
+        if !(self.eof || request.contains("\r\n\r\n") || request.contains("\n\n")) {
+            if self.input.len() as u64 > MAX_PROBE_REQUEST {
+                warn!("probe request longer than {} bytes", MAX_PROBE_REQUEST);
+                self.close();
+            }
+            return;
+        }
+        let response = server.response(request.lines().next().unwrap_or_default());
+        self.output.extend_from_slice(response.as_bytes());
+        self.close();
+    }
+
+    fn close(&mut self) {
+        self.input.clear();
+        self.closing = true;
+    }
+}
+
+/// nonblocking listeners and their connections, served a step at a time by poll
+#[derive(Debug, Default)]
+pub struct PollServer {
+    listeners: Vec<(TcpListener, Service)>,
+    clients: Vec<Client>,
+}
+
+impl PollServer {
+    /// create a server with no listeners
+    pub fn new() -> PollServer {
+        PollServer::default()
+    }
+
+    /// serve the listener's connections with the service; the listener is made nonblocking
+    pub fn add(&mut self, listener: TcpListener, service: Service) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        // wasi can't always report the address of a listener the runtime passed in
+        match listener.local_addr() {
+            Ok(addr) => info!("{} server polling on {}", service.name(), addr),
+            Err(_) => info!("{} server polling", service.name()),
+        }
+        self.listeners.push((listener, service));
+
+        Ok(())
+    }
+
+    /// return the number of open connections
+    pub fn connections(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// accept the waiting connections, answer the complete requests that have arrived and send what output the
+    /// connections take; return true if there was anything to do, so the caller only sleeps when idle
+    pub fn poll(&mut self) -> bool {
+        let mut busy = self.accept();
+        let listeners = &self.listeners;
+        self.clients.retain_mut(|client| {
+            let service = &listeners[client.listener].1;
+            match serve(client, service) {
+                Ok(served) => {
+                    busy |= served;
+                    !(client.output.is_empty() && (client.closing || client.eof))
+                }
+                Err(e) => {
+                    warn!("{} connection error: {}", service.name(), e);
+                    false
+                }
+            }
+        });
+
+        busy
+    }
+
+    // accept every waiting connection; return true if there were any
+    fn accept(&mut self) -> bool {
+        let mut busy = false;
+        for (index, (listener, service)) in self.listeners.iter().enumerate() {
+            loop {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("{} accept failed: {}", service.name(), e);
+                        break;
+                    }
+                };
+                busy = true;
+                if let Err(e) = stream.set_nonblocking(true) {
+                    warn!("{} connection error: {}", service.name(), e);
+                    continue;
+                }
+                let rpc = match service {
+                    Service::JsonRpc(server) => server.connection(),
+                    _ => Connection::default(),
+                };
+                let resp = match service {
+                    Service::Resp(server) => server.connection(),
+                    _ => resp::Connection::default(),
+                };
+                self.clients.push(Client {
+                    stream,
+                    listener: index,
+                    rpc,
+                    resp,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                    eof: false,
+                    closing: false,
+                });
+            }
+        }
+
+        busy
+    }
+}
+
+// read, answer and write one connection's step
+fn serve(client: &mut Client, service: &Service) -> io::Result<bool> {
+    let mut busy = false;
+    if !client.closing {
+        busy |= client.read()?;
+        match service {
+            Service::JsonRpc(server) => client.json_rpc(server),
+            Service::Resp(server) => client.resp(server),
+            Service::Probe(server) => client.probe(server),
+        }
+    }
+    busy |= client.write()?;
+
+    Ok(busy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::otp::Otp;
+    use crate::session::Session;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    fn listener() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0").unwrap()
+    }
+
+    // a client that fails rather than hangs if the server never answers
+    fn connect(addr: std::net::SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    #[test]
+    fn poll_server() {
+        let otp = Otp::builder().build().unwrap();
+        let session = Session::builder().build().unwrap();
+        let (rpc, resp, probe) = (listener(), listener(), listener());
+        let addrs = [rpc.local_addr(), resp.local_addr(), probe.local_addr()].map(Result::unwrap);
+
+        let mut server = PollServer::new();
+        let jsonrpc = JsonRpcServer::new(otp.clone(), session.clone());
+        server.add(rpc, Service::JsonRpc(jsonrpc)).unwrap();
+        server
+            .add(resp, Service::Resp(RespServer::new(session.clone())))
+            .unwrap();
+        server
+            .add(probe, Service::Probe(ProbeServer::new(otp, session)))
+            .unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = connect(addrs[0]);
+            stream
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"otp.dbsize\",\"id\":1}\n")
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            assert!(line.contains(r#""result":0"#), "{}", line);
+
+            // a command split across writes is answered once it is complete
+            let mut stream = connect(addrs[1]);
+            stream.write_all(b"*2\r\n$4\r\nECHO\r\n$2\r").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"\nhi\r\nQUIT\r\n").unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, "$2\r\nhi\r\n+OK\r\n");
+
+            let mut stream = connect(addrs[2]);
+            stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"), "{}", reply);
+        });
+
+        while !client.is_finished() {
+            if !server.poll() {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        client.join().unwrap();
+        while server.connections() > 0 {
+            server.poll();
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        let otp = Otp::builder().build().unwrap();
+        let session = Session::builder().build().unwrap();
+        let (rpc, probe) = (listener(), listener());
+        let addrs = [rpc.local_addr(), probe.local_addr()].map(Result::unwrap);
+
+        let mut server = PollServer::new();
+        let jsonrpc = JsonRpcServer::new(otp.clone(), session.clone());
+        server.add(rpc, Service::JsonRpc(jsonrpc)).unwrap();
+        server
+            .add(probe, Service::Probe(ProbeServer::new(otp, session)))
+            .unwrap();
+
+        let client = thread::spawn(move || {
+            // a line without an end gets a parse error once it passes MAX_LINE, and the connection closes
+            let mut stream = connect(addrs[0]);
+            stream.write_all(&vec![b' '; MAX_LINE + 1]).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, format!("{}\n", line_too_long()));
+
+            let mut stream = connect(addrs[1]);
+            let request = vec![b'x'; MAX_PROBE_REQUEST as usize + 1];
+            stream.write_all(&request).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert!(reply.is_empty(), "{}", reply);
+        });
+
+        while !client.is_finished() {
+            if !server.poll() {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        client.join().unwrap();
+    }
+}
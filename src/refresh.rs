@@ -0,0 +1,225 @@
+/// refresh code families: a long lived refresh code is traded for a new access session and refresh code, and using a
+/// traded code again revokes the whole family
+use crate::hash::random_bytes;
+use hashbrown::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds the access session of a token pair is good for
+pub const ACCESS_TTL: u64 = 900;
+
+/// the default seconds a refresh code is good for, 30 days
+pub const REFRESH_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// the secure random bytes in a refresh code, sent as hex
+pub const REFRESH_BYTES: usize = 32;
+
+/// a short lived access session and the long lived refresh code that replaces it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenPair {
+    pub user: String,
+    /// an ordinary session code, valid until access_expires
+    pub access: String,
+    pub access_expires: u64,
+    /// trade this for the next pair with `Session::refresh`; it works once
+    pub refresh: String,
+    pub refresh_expires: u64,
+}
+
+/// the error from refreshing with a code that was already traded, a sign it was stolen; its family was revoked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefreshReused {
+    pub user: String,
+    /// the access sessions removed
+    pub revoked: usize,
+}
+
+// a family id from the secure generator, so ids can't be predicted from ones already seen
+fn new_family() -> u64 {
+    let bytes = random_bytes(8);
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+impl fmt::Display for RefreshReused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "refresh code reused for {}; its token family was revoked",
+            self.user
+        )
+    }
+}
+
+impl std::error::Error for RefreshReused {}
+
+#[derive(Debug, Clone)]
+struct RefreshEntry {
+    user: String,
+    family: u64,
+    // the access session issued with this refresh code
+    access: String,
+    expires: u64,
+    used: bool,
+}
+
+// the outcome of trading in a refresh code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Redeemed {
+    Valid {
+        user: String,
+        family: u64,
+        access: String,
+    },
+    Expired,
+    NotFound,
+    /// the family was forgotten; these are its access sessions
+    Reused {
+        user: String,
+        access: Vec<String>,
+    },
+}
+
+/// the refresh codes issued by a session store, kept until they expire so reuse can be spotted; clones share them
+#[derive(Debug, Clone)]
+pub struct RefreshTokens {
+    codes: Arc<RwLock<HashMap<String, RefreshEntry>>>,
+    access_ttl: u64,
+    refresh_ttl: u64,
+}
+
+impl Default for RefreshTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RefreshTokens {
+    /// create the registry with the default access and refresh ttls
+    pub fn new() -> RefreshTokens {
+        RefreshTokens::with_ttls(ACCESS_TTL, REFRESH_TTL)
+    }
+
+    /// create the registry with these access session and refresh code ttls in seconds
+    pub fn with_ttls(access_ttl: u64, refresh_ttl: u64) -> RefreshTokens {
+        RefreshTokens {
+            codes: Arc::new(RwLock::new(HashMap::new())),
+            access_ttl,
+            refresh_ttl,
+        }
+    }
+
+    /// return the seconds an access session is good for
+    pub fn access_ttl(&self) -> u64 {
+        self.access_ttl
+    }
+
+    /// return the seconds a refresh code is good for
+    pub fn refresh_ttl(&self) -> u64 {
+        self.refresh_ttl
+    }
+
+    /// return the number of refresh codes remembered, including traded ones
+    pub fn len(&self) -> usize {
+        self.codes.read().unwrap().len()
+    }
+
+    /// return true if no refresh codes are remembered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // remember a new refresh code for the access session, starting a family if none is given; return its expiry
+    pub(crate) fn issue(
+        &self,
+        code: &str,
+        user: &str,
+        family: Option<u64>,
+        access: &str,
+        now: u64,
+    ) -> u64 {
+        let expires = now.saturating_add(self.refresh_ttl);
+        let entry = RefreshEntry {
+            user: user.to_string(),
+            family: family.unwrap_or_else(new_family),
+            access: access.to_string(),
+            expires,
+            used: false,
+        };
+        self.codes.write().unwrap().insert(code.to_string(), entry);
+
+        expires
+    }
+
+    // trade in the code; marking it used under the lock means only one caller can trade it
+    pub(crate) fn redeem(&self, code: &str, now: u64) -> Redeemed {
+        let mut codes = self.codes.write().unwrap();
+        let Some(entry) = codes.get_mut(code) else {
+            return Redeemed::NotFound;
+        };
+
+        if entry.expires <= now {
+            return Redeemed::Expired;
+        }
+        if !entry.used {
+            entry.used = true;
+            return Redeemed::Valid {
+                user: entry.user.clone(),
+                family: entry.family,
+                access: entry.access.clone(),
+            };
+        }
+
+        let (user, family) = (entry.user.clone(), entry.family);
+        let mut access = Vec::new();
+        codes.retain(|_, entry| {
+            if entry.family == family {
+                access.push(entry.access.clone());
+                false
+            } else {
+                true
+            }
+        });
+
+        Redeemed::Reused { user, access }
+    }
+
+    // forget the expired refresh codes; return the number forgotten
+    pub(crate) fn purge_expired(&self, now: u64) -> usize {
+        let mut codes = self.codes.write().unwrap();
+        let before = codes.len();
+        codes.retain(|_, entry| entry.expires > now);
+        before - codes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redeem() {
+        let tokens = RefreshTokens::with_ttls(60, 600);
+        assert_eq!(tokens.issue("r1", "sally", None, "a1", 1_000), 1_600);
+
+        let Redeemed::Valid { family, access, .. } = tokens.redeem("r1", 1_010) else {
+            panic!("not valid");
+        };
+        assert_eq!(access, "a1");
+        tokens.issue("r2", "sally", Some(family), "a2", 1_010);
+        tokens.issue("r3", "jack", None, "a3", 1_010);
+
+        // trading r1 again revokes r2 too, but not jack's family
+        let Redeemed::Reused { user, mut access } = tokens.redeem("r1", 1_020) else {
+            panic!("not reused");
+        };
+        access.sort();
+        assert_eq!(
+            (user.as_str(), access),
+            ("sally", vec!["a1".to_string(), "a2".to_string()])
+        );
+        assert_eq!(tokens.redeem("r2", 1_020), Redeemed::NotFound);
+        assert_eq!(tokens.redeem("r3", 1_610), Redeemed::Expired);
+        assert_eq!(tokens.purge_expired(1_610), 1);
+        assert!(tokens.is_empty());
+    }
+}
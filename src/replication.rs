@@ -0,0 +1,664 @@
+/// peer-to-peer replication: streams otp and session changes to peers and merges theirs, last write wins on expiry
+use crate::db::{Change, SessionItem};
+pub use crate::events::Store;
+use crate::hash::{hmac_sha256_hex, random_hex};
+use crate::otp::Otp;
+use crate::session::Session;
+use anyhow::{anyhow, bail, Result};
+use log::{error, info, warn};
+use otp_session_core::code::codes_match;
+use std::fmt;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// how long to wait before reconnecting to a peer
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// the most changes written to a peer before a flush
+pub const PENDING_MAX: usize = 1_024;
+
+/// the fewest bytes a replication secret may have
+pub const SECRET_MIN: usize = 32;
+
+// the random bytes in a handshake challenge
+const NONCE_BYTES: usize = 32;
+
+// the longest handshake line read from a peer that hasn't authenticated yet
+const HANDSHAKE_LINE_MAX: u64 = 256;
+
+// the side that proves the secret in a handshake answer, so one side's answer never passes for the other's
+const CLIENT: &str = "client";
+const SERVER: &str = "server";
+
+// the secret every peer shares; never printed
+#[derive(Clone)]
+struct Secret(Arc<Vec<u8>>);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Secret {
+    // the side's proof of the secret, bound to both ends' challenges so it can't be replayed on another connection
+    // or relayed as the other side's
+    fn answer(&self, side: &str, server_nonce: &str, client_nonce: &str) -> String {
+        let message = format!("{}\t{}\t{}", side, server_nonce, client_nonce);
+        hmac_sha256_hex(&self.0, message.as_bytes())
+    }
+}
+
+// read one handshake line without its terminator, refusing overlong ones
+fn read_handshake<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader.take(HANDSHAKE_LINE_MAX).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        bail!("bad replication handshake");
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// write one handshake line and flush it
+fn write_handshake<W: Write>(writer: &mut W, line: &str) -> Result<()> {
+    writer.write_all(format!("{}\n", line).as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// escape a meta key or value for a tab separated field
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// undo escape
+fn unescape(field: &str) -> Result<String> {
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => value.push('\\'),
+            Some('t') => value.push('\t'),
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            _ => bail!("bad escape in replication field: {}", field),
+        }
+    }
+
+    Ok(value)
+}
+
+/// encode a change as a tab separated line, with an item's meta as escaped key and value fields after its
+/// expiration and generation; codes and users with tabs or newlines can't be replicated
+pub fn encode(store: Store, change: &Change) -> Option<String> {
+    let (code, user) = match change {
+        Change::Put { item, .. } => (item.code.as_str(), &item.user),
+        Change::Remove { code, user } => (code.as_str(), user),
+        Change::Generation { user, .. } | Change::Lock { user, .. } => ("", user),
+    };
+    if [code, user].iter().any(|s| s.contains(['\t', '\n', '\r'])) {
+        return None;
+    }
+
+    let store = store.as_str();
+    let line = match change {
+        Change::Put { item, generation } => {
+            let mut line = format!(
+                "put\t{}\t{}\t{}\t{}\t{}",
+                store, code, user, item.expires, generation
+            );
+            for (key, value) in &item.meta {
+                line.push_str(&format!("\t{}\t{}", escape(key), escape(value)));
+            }
+            line + "\n"
+        }
+        Change::Remove { .. } => format!("del\t{}\t{}\t{}\n", store, code, user),
+        Change::Generation { generation, .. } => {
+            format!("gen\t{}\t{}\t{}\n", store, user, generation)
+        }
+        Change::Lock { locked, .. } => {
+            format!("lock\t{}\t{}\t{}\n", store, user, u8::from(*locked))
+        }
+    };
+
+    Some(line)
+}
+
+/// decode a line written by encode
+pub fn decode(line: &str) -> Result<(Store, Change)> {
+    let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+    let store = match fields.get(1) {
+        Some(&"otp") => Store::Otp,
+        Some(&"session") => Store::Session,
+        _ => bail!("unknown store in replication line: {}", line),
+    };
+
+    let change = match fields.as_slice() {
+        ["put", _, code, user, expires, generation, meta @ ..] if meta.len() % 2 == 0 => {
+            let mut item = SessionItem {
+                code: code.to_string(),
+                user: user.to_string(),
+                expires: expires.parse()?,
+                ..Default::default()
+            };
+            for pair in meta.chunks(2) {
+                item.meta.insert(unescape(pair[0])?, unescape(pair[1])?);
+            }
+            Change::Put {
+                item,
+                generation: generation.parse()?,
+            }
+        }
+        ["del", _, code, user] => Change::Remove {
+            code: code.to_string(),
+            user: user.to_string(),
+        },
+        ["gen", _, user, generation] => Change::Generation {
+            user: user.to_string(),
+            generation: generation.parse()?,
+        },
+        ["lock", _, user, locked @ ("0" | "1")] => Change::Lock {
+            user: user.to_string(),
+            locked: *locked == "1",
+        },
+        _ => bail!("bad replication line: {}", line),
+    };
+
+    Ok((store, change))
+}
+
+// write the queued changes, flushing whenever the queue is drained or PENDING_MAX lines are waiting. lines stay
+// pending until a flush succeeds, so the next connection can send them again
+fn send_changes(
+    writer: &mut impl Write,
+    rx: &Receiver<(Store, Change)>,
+    pending: &mut Vec<String>,
+) -> Result<()> {
+    loop {
+        if pending.len() >= PENDING_MAX {
+            writer.flush()?;
+            pending.clear();
+        }
+
+        let (store, change) = match rx.try_recv() {
+            Ok(next) => next,
+            Err(_) => {
+                writer.flush()?;
+                pending.clear();
+                rx.recv()?
+            }
+        };
+        if let Some(line) = encode(store, &change) {
+            writer.write_all(line.as_bytes())?;
+            pending.push(line);
+        }
+    }
+}
+
+// forward a store's changes into the shared peer queue
+fn forward(store: Store, changes: Receiver<Change>, tx: Sender<(Store, Change)>) -> Result<()> {
+    thread::Builder::new()
+        .name(format!("replicate-{}", store.as_str()))
+        .spawn(move || {
+            for change in changes {
+                if tx.send((store, change)).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// replicates the otp and session stores with peers; every node connects to every peer (full mesh)
+/// because merged changes are not forwarded again. both ends prove they hold the shared secret before any change is
+/// sent or accepted, and with tls the connection is encrypted and both ends present certificates
+#[derive(Debug, Clone)]
+pub struct Replicator {
+    otp: Otp,
+    session: Session,
+    secret: Secret,
+    #[cfg(feature = "tls")]
+    tls: Option<(Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>)>,
+}
+
+impl Replicator {
+    /// create the replicator for these stores, authenticating peers with the shared secret of at least SECRET_MIN
+    /// bytes
+    pub fn new(otp: Otp, session: Session, secret: &[u8]) -> Result<Replicator> {
+        if secret.len() < SECRET_MIN {
+            bail!("replication secret must be at least {} bytes", SECRET_MIN);
+        }
+
+        Ok(Replicator {
+            otp,
+            session,
+            secret: Secret(Arc::new(secret.to_vec())),
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// accept peers over tls with the server config and connect to them with the client config; both should verify
+    /// the other end's certificate, e.g. from TlsConfig with a client_ca
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        server: Arc<rustls::ServerConfig>,
+        client: Arc<rustls::ClientConfig>,
+    ) -> Replicator {
+        self.tls = Some((server, client));
+        self
+    }
+
+    /// merge a change from a peer into the matching store; return true if the store changed
+    pub fn apply(&self, store: Store, change: Change) -> bool {
+        match store {
+            Store::Otp => self.otp.clone().merge(change),
+            Store::Session => self.session.clone().merge(change),
+        }
+    }
+
+    /// accept peer connections on addr; runs until the listener fails
+    pub fn serve(&self, addr: &str) -> Result<()> {
+        self.listen(TcpListener::bind(addr)?)
+    }
+
+    /// accept peer connections and merge their changes, one thread per peer
+    pub fn listen(&self, listener: TcpListener) -> Result<()> {
+        info!("replication listening on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let replicator = self.clone();
+            thread::spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                if let Err(e) = replicator.accept(stream) {
+                    warn!("replication peer {} failed: {}", peer, e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    // complete tls when configured, then handle the peer
+    fn accept(&self, stream: TcpStream) -> Result<()> {
+        #[cfg(feature = "tls")]
+        if let Some((server, _)) = &self.tls {
+            return self.handle_connection(crate::tls::accept(server, stream)?);
+        }
+
+        self.handle_connection(stream)
+    }
+
+    /// challenge the peer to prove it holds the secret, prove it back to the peer's own challenge, then merge each
+    /// line from it until it disconnects
+    pub fn handle_connection<S: Read + Write>(&self, stream: S) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let nonce = random_hex(NONCE_BYTES);
+        write_handshake(reader.get_mut(), &format!("challenge\t{}", nonce))?;
+        let line = read_handshake(&mut reader)?;
+        let (client_nonce, answer) = line
+            .strip_prefix("auth\t")
+            .and_then(|auth| auth.split_once('\t'))
+            .unwrap_or_default();
+        if !codes_match(answer, &self.secret.answer(CLIENT, &nonce, client_nonce)) {
+            bail!("replication peer failed authentication");
+        }
+        let proof = self.secret.answer(SERVER, &nonce, client_nonce);
+        write_handshake(reader.get_mut(), &format!("ok\t{}", proof))?;
+
+        for line in reader.lines() {
+            let (store, change) = decode(&line?)?;
+            self.apply(store, change);
+        }
+
+        Ok(())
+    }
+
+    // answer the peer's challenge with one of our own, and only trust the peer once it proves the secret back; until
+    // then nothing is sent, since the stores hold live codes
+    fn authenticate<S: Read + Write>(&self, reader: &mut BufReader<S>) -> Result<()> {
+        let line = read_handshake(reader)?;
+        let Some(server_nonce) = line.strip_prefix("challenge\t") else {
+            bail!("bad replication handshake");
+        };
+        let nonce = random_hex(NONCE_BYTES);
+        let answer = self.secret.answer(CLIENT, server_nonce, &nonce);
+        write_handshake(reader.get_mut(), &format!("auth\t{}\t{}", nonce, answer))?;
+
+        let line = read_handshake(reader)?;
+        let Some(proof) = line.strip_prefix("ok\t") else {
+            bail!("peer rejected the replication secret");
+        };
+        if !codes_match(proof, &self.secret.answer(SERVER, server_nonce, &nonce)) {
+            bail!("replication peer failed authentication");
+        }
+
+        Ok(())
+    }
+
+    /// stream local changes to the peer from a background thread; every (re)connect resends what the last one may
+    /// have lost and then the full state
+    pub fn connect(&self, peer: &str) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        forward(Store::Otp, self.otp.subscribe(), tx.clone())?;
+        forward(Store::Session, self.session.subscribe(), tx)?;
+
+        let replicator = self.clone();
+        let peer = peer.to_string();
+        thread::Builder::new()
+            .name("replicate".to_string())
+            .spawn(move || {
+                let mut pending = Vec::new();
+                loop {
+                    match replicator.stream_to(&peer, &rx, &mut pending) {
+                        Err(e) if e.is::<mpsc::RecvError>() => break,
+                        Err(e) => warn!("replication to {} failed: {}", peer, e),
+                        Ok(()) => (),
+                    }
+                    thread::sleep(RECONNECT_DELAY);
+                }
+                error!("replication to {} stopped", peer);
+            })?;
+
+        Ok(())
+    }
+
+    // connect, over tls when configured, and replicate until the connection fails
+    fn stream_to(
+        &self,
+        peer: &str,
+        rx: &Receiver<(Store, Change)>,
+        pending: &mut Vec<String>,
+    ) -> Result<()> {
+        let stream = TcpStream::connect(peer).map_err(|e| anyhow!("connect: {}", e))?;
+        #[cfg(feature = "tls")]
+        if let Some((_, client)) = &self.tls {
+            let stream = crate::tls::connect(client, peer, stream)?;
+            return self.replicate(peer, stream, rx, pending);
+        }
+
+        self.replicate(peer, stream, rx, pending)
+    }
+
+    // authenticate, then send the changes the last connection may have lost, the stores' state and the queued
+    // changes until the connection fails. the state comes after the lost changes so it has the last word
+    fn replicate<S: Read + Write>(
+        &self,
+        peer: &str,
+        stream: S,
+        rx: &Receiver<(Store, Change)>,
+        pending: &mut Vec<String>,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        self.authenticate(&mut reader)?;
+        let mut writer = BufWriter::new(reader.into_inner());
+        info!("replicating to {}", peer);
+
+        for line in pending.iter() {
+            writer.write_all(line.as_bytes())?;
+        }
+        let otp = self.otp.replay().into_iter().map(|c| (Store::Otp, c));
+        let session = self
+            .session
+            .replay()
+            .into_iter()
+            .map(|c| (Store::Session, c));
+        for (store, change) in otp.chain(session) {
+            if let Some(line) = encode(store, &change) {
+                writer.write_all(line.as_bytes())?;
+            }
+        }
+        writer.flush()?;
+        pending.clear();
+
+        send_changes(&mut writer, rx, pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Validation;
+    use std::time::Instant;
+
+    const SECRET: &[u8] = b"a replication secret for the tests";
+
+    // serve a replicator on a local port and return its address
+    fn serve(replicator: Replicator) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || replicator.listen(listener));
+        addr
+    }
+
+    // connect to the peer and answer its challenge with the secret; return the reply and the proof the peer should
+    // reply with
+    fn handshake(addr: &str, secret: &[u8]) -> (BufReader<TcpStream>, String, String) {
+        let secret = Secret(Arc::new(secret.to_vec()));
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+        let line = read_handshake(&mut reader).unwrap();
+        let nonce = line.strip_prefix("challenge\t").unwrap();
+        let answer = secret.answer(CLIENT, nonce, "abc");
+        write_handshake(reader.get_mut(), &format!("auth\tabc\t{}", answer)).unwrap();
+        let reply = read_handshake(&mut reader).unwrap_or_default();
+        (reader, reply, secret.answer(SERVER, nonce, "abc"))
+    }
+
+    // a put in the first generation
+    fn put(item: SessionItem) -> Change {
+        Change::Put {
+            item,
+            generation: 0,
+        }
+    }
+
+    // wait for the condition or fail
+    fn eventually(mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(5), "peer not updated");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let item = SessionItem::new("123456", "sally", 60);
+        let line = encode(Store::Otp, &put(item.clone())).unwrap();
+        assert_eq!(decode(&line).unwrap(), (Store::Otp, put(item)));
+
+        let change = Change::Remove {
+            code: "abc".to_string(),
+            user: "jack".to_string(),
+        };
+        let line = encode(Store::Session, &change).unwrap();
+        assert_eq!(line, "del\tsession\tabc\tjack\n");
+        assert_eq!(decode(&line).unwrap(), (Store::Session, change));
+
+        let item = SessionItem::new("123456", "sally", 60)
+            .with_meta("device", "lap\ttop\\")
+            .with_meta("verifier", "abc\n");
+        let line = encode(Store::Session, &put(item.clone())).unwrap();
+        assert_eq!(line.matches('\n').count(), 1);
+        assert_eq!(decode(&line).unwrap(), (Store::Session, put(item)));
+
+        let changes = [
+            Change::Generation {
+                user: "jack".to_string(),
+                generation: 3,
+            },
+            Change::Lock {
+                user: "jack".to_string(),
+                locked: true,
+            },
+        ];
+        for change in changes {
+            let line = encode(Store::Session, &change).unwrap();
+            assert_eq!(decode(&line).unwrap(), (Store::Session, change));
+        }
+
+        let bad = SessionItem::new("123456", "sal\tly", 60);
+        assert!(encode(Store::Otp, &put(bad)).is_none());
+        assert!(decode("put\tcache\tabc\tjack\t1\t0").is_err());
+        assert!(decode("put\totp\tabc\tjack\tsoon\t0").is_err());
+        assert!(decode("put\totp\tabc\tjack\t1").is_err());
+        assert!(decode("put\totp\tabc\tjack\t1\t0\tdevice").is_err());
+        assert!(decode("put\totp\tabc\tjack\t1\t0\tdevice\tbad\\x").is_err());
+        assert!(decode("lock\totp\tjack\tyes").is_err());
+    }
+
+    #[test]
+    fn send_changes_keeps_unflushed_lines() {
+        // a writer whose flushes fail, like a connection that has gone away
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let item = SessionItem::new("abc", "sally", 60);
+        tx.send((Store::Session, put(item.clone()))).unwrap();
+        let mut pending = Vec::new();
+        assert!(send_changes(&mut Broken, &rx, &mut pending).is_err());
+        assert_eq!(pending, vec![encode(Store::Session, &put(item)).unwrap()]);
+    }
+
+    #[test]
+    fn handle_connection() {
+        let session = Session::new();
+        let addr = serve(Replicator::new(Otp::new(), session.clone(), SECRET).unwrap());
+        let (mut reader, reply, proof) = handshake(&addr, SECRET);
+        assert_eq!(reply, format!("ok\t{}", proof));
+
+        let item = SessionItem::new("abc", "sally", 60);
+        let input = format!(
+            "{}{}",
+            encode(Store::Session, &put(item)).unwrap(),
+            "put\tsession\tdef\tjack\t1\t0\n"
+        );
+        reader.get_mut().write_all(input.as_bytes()).unwrap();
+
+        eventually(|| session.is_valid("abc", "sally"));
+        assert!(!session.is_valid("def", "jack"));
+    }
+
+    #[test]
+    fn rejects_unauthenticated_peers() {
+        assert!(Replicator::new(Otp::new(), Session::new(), b"short").is_err());
+
+        let session = Session::new();
+        let addr = serve(Replicator::new(Otp::new(), session.clone(), SECRET).unwrap());
+        let item = SessionItem::new("abc", "sally", 60);
+        let line = encode(Store::Session, &put(item)).unwrap();
+
+        // a change line instead of the handshake, then a wrong secret
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(line.as_bytes()).unwrap();
+        let wrong = b"not the replication secret for the tests";
+        let (mut reader, reply, _) = handshake(&addr, wrong);
+        assert_eq!(reply, "");
+        let _ = reader.get_mut().write_all(line.as_bytes());
+
+        // a replicator with the wrong secret never gets a change through
+        let mut other = Session::new();
+        other.create_user_session("jack").unwrap();
+        Replicator::new(Otp::new(), other.clone(), wrong)
+            .unwrap()
+            .connect(&addr)
+            .unwrap();
+        other.create_user_session("jack").unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(session.dbsize(), 0);
+    }
+
+    #[test]
+    fn rejects_unauthenticated_listeners() {
+        // a listener without the secret that accepts any answer, or echoes back the one it was given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().take(2).enumerate() {
+                let mut reader = BufReader::new(stream.unwrap());
+                write_handshake(reader.get_mut(), "challenge\tabc").unwrap();
+                let auth = read_handshake(&mut reader).unwrap();
+                let echo = auth.rsplit('\t').next().unwrap().to_string();
+                let reply = if n == 0 {
+                    "ok".to_string()
+                } else {
+                    format!("ok\t{}", echo)
+                };
+                write_handshake(reader.get_mut(), &reply).unwrap();
+                let mut rest = String::new();
+                let _ = reader.read_to_string(&mut rest);
+                tx.send(rest).unwrap();
+            }
+        });
+
+        let mut session = Session::new();
+        session.create_user_session("jack").unwrap();
+        let replicator = Replicator::new(Otp::new(), session, SECRET).unwrap();
+        let (_tx, changes) = mpsc::channel();
+
+        // the replicator refuses both and sends nothing of the store
+        for _ in 0..2 {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let result = replicator.replicate(&addr, stream, &changes, &mut Vec::new());
+            assert!(result.is_err());
+            assert_eq!(rx.recv().unwrap(), "");
+        }
+    }
+
+    #[test]
+    fn replicate_to_peer() {
+        let remote = Session::new();
+        let addr = serve(Replicator::new(Otp::new(), remote.clone(), SECRET).unwrap());
+
+        let mut session = Session::new();
+        let existing = session.create_user_session("sally").unwrap();
+        let bob = session.create_user_session("bob").unwrap();
+        Replicator::new(Otp::new(), session.clone(), SECRET)
+            .unwrap()
+            .connect(&addr)
+            .unwrap();
+        let code = session.create_user_session("jack").unwrap();
+        session.remove(&existing, "sally");
+        session.lock_user("bob");
+
+        eventually(|| remote.dbsize() == 2 && remote.is_valid(&code, "jack"));
+        assert!(!remote.is_valid(&existing, "sally"));
+        eventually(|| remote.validate(&bob, "bob") == Validation::Locked);
+    }
+}
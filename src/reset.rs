@@ -0,0 +1,227 @@
+/// purpose scoped single use tokens, e.g. password resets, kept apart from otps and sessions
+use crate::clock::{Clock, SystemClock};
+use crate::db::Validation;
+use crate::hash::{random_hex, sha256_hex};
+use crate::logging;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a reset token is good for
+pub const RESET_TTL: u64 = 600;
+
+/// the purpose of the default reset tokens
+pub const PASSWORD_RESET: &str = "password_reset";
+
+/// the number of random bytes in a reset token
+pub const RESET_TOKEN_BYTES: usize = 24;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    user: String,
+    expires: u64,
+    // Valid until used (Consumed) or replaced by a newer token (Revoked)
+    state: Validation,
+}
+
+#[derive(Debug, Default)]
+struct Tokens {
+    // keyed by the hash of the purpose and token
+    entries: HashMap<String, Entry>,
+    // the key of each user's live token
+    users: HashMap<String, String>,
+}
+
+/// single use tokens for one purpose; a user has at most one live token, and issuing another revokes it. clones share
+/// the tokens
+#[derive(Debug, Clone)]
+pub struct ResetTokens {
+    purpose: String,
+    tokens: Arc<RwLock<Tokens>>,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for ResetTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResetTokens {
+    /// create password reset tokens with the default ttl
+    pub fn new() -> ResetTokens {
+        ResetTokens::for_purpose(PASSWORD_RESET)
+    }
+
+    /// create tokens for another purpose, e.g. email_change; tokens only work for the purpose they were issued for
+    pub fn for_purpose(purpose: &str) -> ResetTokens {
+        ResetTokens {
+            purpose: purpose.to_string(),
+            tokens: Arc::new(RwLock::new(Tokens::default())),
+            ttl: RESET_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// tokens are good for this many seconds
+    pub fn with_ttl(mut self, ttl: u64) -> ResetTokens {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ResetTokens {
+        self.clock = clock;
+        self
+    }
+
+    /// return the purpose
+    pub fn purpose(&self) -> &str {
+        &self.purpose
+    }
+
+    // the purpose is hashed with the token, so a token from one purpose never matches another
+    fn key(&self, token: &str) -> String {
+        sha256_hex(format!("{}:{}", self.purpose, token).as_bytes())
+    }
+
+    /// issue a token for the user, revoking any earlier one; return the token and its expiry
+    pub fn issue(&self, user: &str) -> (String, u64) {
+        let token = random_hex(RESET_TOKEN_BYTES);
+        let key = self.key(&token);
+        let expires = self.clock.now().saturating_add(self.ttl);
+
+        let mut tokens = self.tokens.write().unwrap();
+        if let Some(old) = tokens.users.insert(user.to_string(), key.clone()) {
+            if let Some(entry) = tokens.entries.get_mut(&old) {
+                entry.state = Validation::Revoked;
+            }
+        }
+        let entry = Entry {
+            user: user.to_string(),
+            expires,
+            state: Validation::Valid,
+        };
+        tokens.entries.insert(key, entry);
+        drop(tokens);
+
+        let name = format!("{}.issue", self.purpose);
+        logging::event(&name, &[("user", user), ("code", &token)]);
+
+        (token, expires)
+    }
+
+    /// return why the token is or is not valid for the user, without using it up
+    pub fn validate(&self, token: &str, user: &str) -> Validation {
+        let tokens = self.tokens.read().unwrap();
+        match tokens.entries.get(&self.key(token)) {
+            Some(entry) if entry.user != user => Validation::NotFound,
+            Some(entry) if entry.state != Validation::Valid => entry.state,
+            Some(entry) if entry.expires <= self.clock.now() => Validation::Expired,
+            Some(_) => Validation::Valid,
+            None => Validation::NotFound,
+        }
+    }
+
+    /// validate the token for the user and use it up
+    pub fn consume(&self, token: &str, user: &str) -> Result<()> {
+        let key = self.key(token);
+        let now = self.clock.now();
+        let mut tokens = self.tokens.write().unwrap();
+        let result = match tokens.entries.get_mut(&key) {
+            Some(entry) if entry.user != user => Validation::NotFound,
+            Some(entry) if entry.state != Validation::Valid => entry.state,
+            Some(entry) if entry.expires <= now => Validation::Expired,
+            Some(entry) => {
+                entry.state = Validation::Consumed;
+                Validation::Valid
+            }
+            None => Validation::NotFound,
+        };
+
+        if !result.is_valid() {
+            bail!("{} token not valid: {}", self.purpose, result.as_str());
+        }
+        tokens.users.remove(user);
+        drop(tokens);
+
+        let name = format!("{}.consume", self.purpose);
+        logging::event(&name, &[("user", user), ("code", token)]);
+
+        Ok(())
+    }
+
+    /// revoke the user's live token, e.g. after their password changed another way; return false if they had none
+    pub fn revoke(&self, user: &str) -> bool {
+        let mut tokens = self.tokens.write().unwrap();
+        let Some(key) = tokens.users.remove(user) else {
+            return false;
+        };
+
+        match tokens.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.state = Validation::Revoked;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// forget the expired tokens; return the number removed
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut tokens = self.tokens.write().unwrap();
+        let before = tokens.entries.len();
+        tokens.entries.retain(|_, entry| entry.expires > now);
+        let Tokens { entries, users } = &mut *tokens;
+        users.retain(|_, key| entries.contains_key(key));
+
+        before - entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn single_use() {
+        let clock = MockClock::at(1_000);
+        let resets = ResetTokens::new()
+            .with_ttl(60)
+            .with_clock(Arc::new(clock.clone()));
+        let (token, expires) = resets.issue("sally");
+        assert_eq!((token.len(), expires), (RESET_TOKEN_BYTES * 2, 1_060));
+        assert_eq!(resets.validate(&token, "jack"), Validation::NotFound);
+        assert_eq!(resets.validate(&token, "sally"), Validation::Valid);
+
+        resets.consume(&token, "sally").unwrap();
+        let err = resets.consume(&token, "sally").unwrap_err();
+        assert_eq!(err.to_string(), "password_reset token not valid: consumed");
+
+        // a new token revokes the old one
+        let (first, _) = resets.issue("sally");
+        let (second, _) = resets.issue("sally");
+        assert_eq!(resets.validate(&first, "sally"), Validation::Revoked);
+        assert!(resets.revoke("sally"));
+        assert_eq!(resets.validate(&second, "sally"), Validation::Revoked);
+        assert!(!resets.revoke("sally"));
+
+        let (token, _) = resets.issue("jack");
+        clock.set(1_060);
+        assert!(resets.consume(&token, "jack").is_err());
+        assert_eq!(resets.purge_expired(), 4);
+    }
+
+    #[test]
+    fn purposes() {
+        let resets = ResetTokens::new();
+        let shared = resets.clone();
+        let changes = ResetTokens::for_purpose("email_change");
+        let (token, _) = resets.issue("sally");
+        assert_eq!(changes.validate(&token, "sally"), Validation::NotFound);
+        assert_eq!(shared.validate(&token, "sally"), Validation::Valid);
+    }
+}
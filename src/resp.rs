@@ -0,0 +1,635 @@
+/// minimal redis protocol (RESP2) frontend for the session store; keys are `code:user`
+use crate::admin::AdminTokens;
+use crate::db::SessionItem;
+use crate::session::Session;
+use anyhow::Result;
+use log::{info, warn};
+use otp_session_core::code::codes_match;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::thread;
+
+/// the most arguments a command may have
+pub const MAX_ARGS: usize = 1024;
+
+/// the longest bulk string or line a client may send, far more than any code or user name needs
+pub const MAX_BULK: usize = 64 * 1024;
+
+/// a single RESP reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Nil,
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Status("OK".to_string())
+    }
+
+    fn error(msg: &str) -> Reply {
+        Reply::Error(format!("ERR {}", msg))
+    }
+
+    /// write the reply in wire format
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(s) => write!(out, "+{}\r\n", s),
+            Reply::Error(s) => write!(out, "-{}\r\n", s),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(s) => write!(out, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Nil => write!(out, "$-1\r\n"),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// read a CRLF terminated line of at most MAX_BULK bytes without the terminator; None at end of stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let limit = MAX_BULK as u64 + 2;
+    if reader.by_ref().take(limit).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() as u64 == limit && !line.ends_with('\n') {
+        return Err(invalid("line too long"));
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// read the next command, either a RESP array of bulk strings or an inline command; None at end of stream
+pub fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let count = match line.strip_prefix('*') {
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count <= MAX_ARGS => count,
+            Ok(_) => return Err(invalid("too many arguments")),
+            Err(_) => return Err(invalid("bad array length")),
+        },
+        None => {
+            // inline command, e.g. from telnet
+            let args = line.split_whitespace().map(|s| s.to_string()).collect();
+            return Ok(Some(args));
+        }
+    };
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of stream")
+        })?;
+        let len = header
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| invalid("expected bulk string"))?;
+        let size = len
+            .checked_add(2)
+            .filter(|_| len <= MAX_BULK)
+            .ok_or_else(|| invalid("bulk string too long"))?;
+
+        let mut buf = vec![0u8; size];
+        reader.read_exact(&mut buf)?;
+        buf.truncate(len);
+        let arg = String::from_utf8(buf).map_err(|_| invalid("bulk string is not utf-8"))?;
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// the state of a single client connection
+#[derive(Debug, Default)]
+pub struct Connection {
+    authenticated: bool,
+}
+
+/// redis protocol server backed by a session; clones share the same store
+#[derive(Debug, Clone)]
+pub struct RespServer {
+    session: Session,
+    token: Option<String>,
+    admin: AdminTokens,
+}
+
+impl RespServer {
+    /// create the server for this session store, without authentication
+    pub fn new(session: Session) -> RespServer {
+        RespServer {
+            session,
+            token: None,
+            admin: AdminTokens::new(),
+        }
+    }
+
+    /// require each connection to send `AUTH` with this token before any command but `PING` and `QUIT`
+    pub fn with_token(mut self, token: &str) -> RespServer {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// also accept admin tokens from this registry in `AUTH`; once any are registered, connections must authenticate
+    pub fn with_admin(mut self, admin: AdminTokens) -> RespServer {
+        self.admin = admin;
+        self
+    }
+
+    /// create the state for a new connection
+    pub fn connection(&self) -> Connection {
+        Connection {
+            authenticated: self.token.is_none() && self.admin.is_empty(),
+        }
+    }
+
+    /// bind to addr and serve each connection on its own thread; runs until accept fails
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.listen(listener)
+    }
+
+    /// serve connections from an already bound listener; runs until accept fails
+    pub fn listen(&self, listener: TcpListener) -> Result<()> {
+        info!("resp server listening on {}", listener.local_addr()?);
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    warn!("resp connection error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// serve tls connections from an already bound listener; runs until accept fails
+    #[cfg(feature = "tls")]
+    pub fn listen_tls(
+        &self,
+        listener: TcpListener,
+        tls: std::sync::Arc<rustls::ServerConfig>,
+    ) -> Result<()> {
+        info!(
+            "resp server listening with tls on {}",
+            listener.local_addr()?
+        );
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            let tls = tls.clone();
+            thread::spawn(move || {
+                let resp = crate::tls::accept(&tls, stream)
+                    .and_then(|stream| server.handle_connection(stream));
+                if let Err(e) = resp {
+                    warn!("resp connection error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// read commands until the client disconnects or sends QUIT
+    pub fn handle_connection<S: Read + Write>(&self, stream: S) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut out = Vec::new();
+        let mut conn = self.connection();
+
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) if args.is_empty() => continue,
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(e) => {
+                    // tell the client why before closing, as redis does
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        protocol_error(&e).write_to(&mut out)?;
+                        reader.get_mut().write_all(&out)?;
+                        reader.get_mut().flush()?;
+                    }
+                    return Err(e.into());
+                }
+            };
+
+            let quit = args[0].eq_ignore_ascii_case("QUIT");
+            self.handle(&mut conn, &args).write_to(&mut out)?;
+
+            if quit {
+                break;
+            }
+
+            if reader.buffer().is_empty() {
+                reader.get_mut().write_all(&out)?;
+                reader.get_mut().flush()?;
+                out.clear();
+            }
+        }
+
+        reader.get_mut().write_all(&out)?;
+        reader.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// execute a command from the connection: `AUTH`, `PING` and `QUIT` always, anything else once it has
+    /// authenticated
+    pub fn handle<S: AsRef<str>>(&self, conn: &mut Connection, args: &[S]) -> Reply {
+        let cmd = args.first().map(|cmd| cmd.as_ref().to_ascii_uppercase());
+        match cmd.as_deref() {
+            Some("AUTH") => self.auth(conn, &args[1..]),
+            Some("PING" | "QUIT") => self.execute(args),
+            _ if !conn.authenticated => Reply::Error("NOAUTH Authentication required.".to_string()),
+            _ => self.execute(args),
+        }
+    }
+
+    // AUTH token, or AUTH user token as redis 6 clients send it; the user is ignored
+    fn auth<S: AsRef<str>>(&self, conn: &mut Connection, args: &[S]) -> Reply {
+        let token = match args {
+            [token] | [_, token] => token.as_ref(),
+            _ => return Reply::error("wrong number of arguments for 'auth' command"),
+        };
+        if self.token.is_none() && self.admin.is_empty() {
+            return Reply::error("AUTH called without any token configured");
+        }
+
+        let shared = self
+            .token
+            .as_deref()
+            .is_some_and(|expected| codes_match(expected, token));
+        conn.authenticated = shared || self.admin.lookup(token).is_some();
+        match conn.authenticated {
+            true => Reply::ok(),
+            false => Reply::Error("WRONGPASS invalid token".to_string()),
+        }
+    }
+
+    /// execute a single command and return the reply, without authentication, e.g. for an in-process caller
+    pub fn execute<S: AsRef<str>>(&self, args: &[S]) -> Reply {
+        let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+        let cmd = match args.first() {
+            Some(cmd) => cmd.to_ascii_uppercase(),
+            None => return Reply::error("empty command"),
+        };
+
+        match (cmd.as_str(), &args[1..]) {
+            ("PING", []) => Reply::Status("PONG".to_string()),
+            ("PING", [msg]) => Reply::Bulk(msg.to_string()),
+            ("ECHO", [msg]) => Reply::Bulk(msg.to_string()),
+            ("QUIT", []) => Reply::ok(),
+            ("DBSIZE", []) => Reply::Integer(self.session.dbsize() as i64),
+            ("GET", [key]) => self.get(key),
+            ("SET", [key, _value, opts @ ..]) => self.set(key, opts),
+            ("DEL", keys) if !keys.is_empty() => self.del(keys),
+            ("EXISTS", keys) if !keys.is_empty() => self.exists(keys),
+            ("TTL", [key]) => self.ttl(key),
+            ("PING" | "ECHO" | "QUIT" | "DBSIZE" | "GET" | "SET" | "DEL" | "EXISTS" | "TTL", _) => {
+                Reply::error(&format!(
+                    "wrong number of arguments for '{}' command",
+                    cmd.to_lowercase()
+                ))
+            }
+            _ => Reply::error(&format!("unknown command '{}'", args[0])),
+        }
+    }
+
+    // the value of a session key is its user
+    fn get(&self, key: &str) -> Reply {
+        match self.lookup(key) {
+            Some(item) => Reply::Bulk(item.user),
+            None => Reply::Nil,
+        }
+    }
+
+    // SET code:user value [EX seconds | PX milliseconds]; the value is ignored
+    fn set(&self, key: &str, opts: &[&str]) -> Reply {
+        let (code, user) = match key.split_once(':') {
+            Some((code, user)) if !code.is_empty() && !user.is_empty() => (code, user),
+            _ => return Reply::error("key must be code:user"),
+        };
+
+        let keep_alive = match opts {
+            [] => self.session.keep_alive(),
+            [opt, n] if opt.eq_ignore_ascii_case("EX") => match n.parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => return Reply::error("invalid expire time in 'set' command"),
+            },
+            [opt, n] if opt.eq_ignore_ascii_case("PX") => match n.parse::<u64>() {
+                Ok(ms) if ms > 0 => ms.saturating_add(999) / 1000,
+                _ => return Reply::error("invalid expire time in 'set' command"),
+            },
+            _ => return Reply::error("syntax error"),
+        };
+
+        let mut session = self.session.clone();
+        let item = SessionItem::created_at(code, user, keep_alive, session.now());
+        match session.put(item) {
+            Ok(()) => Reply::ok(),
+            Err(e) => Reply::error(&e.to_string()),
+        }
+    }
+
+    fn del(&self, keys: &[&str]) -> Reply {
+        let mut session = self.session.clone();
+        let count = keys
+            .iter()
+            .filter_map(|key| key.split_once(':'))
+            .filter(|(code, user)| session.remove(code, user).is_some())
+            .count();
+
+        Reply::Integer(count as i64)
+    }
+
+    fn exists(&self, keys: &[&str]) -> Reply {
+        let count = keys.iter().filter(|key| self.lookup(key).is_some()).count();
+        Reply::Integer(count as i64)
+    }
+
+    // remaining seconds, or -2 when the key does not exist
+    fn ttl(&self, key: &str) -> Reply {
+        match self.lookup(key) {
+            Some(item) => {
+                let ttl = item.expires.saturating_sub(self.session.now());
+                Reply::Integer(ttl.min(i64::MAX as u64) as i64)
+            }
+            None => Reply::Integer(-2),
+        }
+    }
+
+    // a split session's selector is not a key here, since session get needs the verifier for it
+    fn lookup(&self, key: &str) -> Option<SessionItem> {
+        let (code, user) = key.split_once(':')?;
+        self.session.get(code, user)
+    }
+}
+
+/// the reply to a command that can't be read
+pub fn protocol_error(e: &io::Error) -> Reply {
+    Reply::Error(format!("ERR Protocol error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn create_server() -> RespServer {
+        RespServer::new(Session::new())
+    }
+
+    #[test]
+    fn set_get_del() {
+        let server = create_server();
+
+        assert_eq!(server.execute(&["GET", "abc:jack"]), Reply::Nil);
+        assert_eq!(server.execute(&["SET", "abc:jack", "1"]), Reply::ok());
+        assert_eq!(
+            server.execute(&["get", "abc:jack"]),
+            Reply::Bulk("jack".to_string())
+        );
+        assert_eq!(server.execute(&["DBSIZE"]), Reply::Integer(1));
+        assert_eq!(
+            server.execute(&["EXISTS", "abc:jack", "abc:sally"]),
+            Reply::Integer(1)
+        );
+        assert_eq!(
+            server.execute(&["DEL", "abc:jack", "abc:sally"]),
+            Reply::Integer(1)
+        );
+        assert_eq!(server.execute(&["GET", "abc:jack"]), Reply::Nil);
+    }
+
+    #[test]
+    fn ttl() {
+        let server = create_server();
+
+        assert_eq!(server.execute(&["TTL", "abc:jack"]), Reply::Integer(-2));
+        assert_eq!(
+            server.execute(&["SET", "abc:jack", "1", "EX", "60"]),
+            Reply::ok()
+        );
+        match server.execute(&["TTL", "abc:jack"]) {
+            Reply::Integer(n) => assert!(n > 58 && n <= 60),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+
+        assert_eq!(
+            server.execute(&["SET", "xyz:jack", "1", "PX", "1500"]),
+            Reply::ok()
+        );
+        match server.execute(&["TTL", "xyz:jack"]) {
+            Reply::Integer(n) => assert!(n > 0 && n <= 2),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+
+        // huge expirations saturate instead of overflowing
+        let max = u64::MAX.to_string();
+        assert_eq!(
+            server.execute(&["SET", "big:jack", "1", "PX", &max]),
+            Reply::ok()
+        );
+        assert_eq!(
+            server.execute(&["SET", "big:jack", "1", "EX", &max]),
+            Reply::ok()
+        );
+        assert_eq!(
+            server.execute(&["TTL", "big:jack"]),
+            Reply::Integer(i64::MAX)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let server = create_server();
+
+        assert!(matches!(
+            server.execute(&["SET", "nouser", "1"]),
+            Reply::Error(_)
+        ));
+        assert!(matches!(
+            server.execute(&["SET", "abc:jack", "1", "EX", "0"]),
+            Reply::Error(_)
+        ));
+        assert!(matches!(
+            server.execute(&["SET", "abc:jack", "1", "NX"]),
+            Reply::Error(_)
+        ));
+        assert!(matches!(server.execute(&["GET"]), Reply::Error(_)));
+        assert!(matches!(server.execute(&["FLUSHALL"]), Reply::Error(_)));
+        assert_eq!(server.session.dbsize(), 0);
+    }
+
+    #[test]
+    fn split_sessions() {
+        let server = create_server();
+        let token = server.session.clone().create_split_session("jack").unwrap();
+        let (selector, _) = token.split_once('.').unwrap();
+        let key = format!("{}:jack", selector);
+
+        assert_eq!(server.execute(&["GET", &key]), Reply::Nil);
+        assert_eq!(server.execute(&["EXISTS", &key]), Reply::Integer(0));
+    }
+
+    // an in-memory connection that records everything written to it
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pipelined_connection() {
+        let server = create_server();
+        let input = "PING\r\nSET abc:jack 1\r\nGET abc:jack\r\nQUIT\r\nPING\r\n";
+        let mut stream = MockStream {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+
+        server.handle_connection(&mut stream).unwrap();
+        assert_eq!(
+            String::from_utf8(stream.output).unwrap(),
+            "+PONG\r\n+OK\r\n$4\r\njack\r\n+OK\r\n"
+        );
+    }
+
+    #[test]
+    fn auth() {
+        let server = create_server().with_token("secret");
+        let mut conn = server.connection();
+        let noauth = Reply::Error("NOAUTH Authentication required.".to_string());
+        assert_eq!(server.handle(&mut conn, &["SET", "abc:jack", "1"]), noauth);
+        assert_eq!(server.handle(&mut conn, &["GET", "abc:jack"]), noauth);
+        assert_eq!(
+            server.handle(&mut conn, &["PING"]),
+            Reply::Status("PONG".to_string())
+        );
+        assert!(matches!(
+            server.handle(&mut conn, &["AUTH", "wrong"]),
+            Reply::Error(_)
+        ));
+        assert_eq!(server.handle(&mut conn, &["SET", "abc:jack", "1"]), noauth);
+        assert_eq!(server.session.dbsize(), 0);
+
+        assert_eq!(
+            server.handle(&mut conn, &["AUTH", "default", "secret"]),
+            Reply::ok()
+        );
+        assert_eq!(
+            server.handle(&mut conn, &["SET", "abc:jack", "1"]),
+            Reply::ok()
+        );
+        // each connection authenticates on its own
+        assert_eq!(server.handle(&mut server.connection(), &["DBSIZE"]), noauth);
+
+        // an admin token authenticates too, even without a shared token
+        let admin = AdminTokens::new();
+        let token = admin.create("ops", &[crate::admin::Scope::List]).unwrap();
+        let server = create_server().with_admin(admin);
+        let mut conn = server.connection();
+        assert_eq!(server.handle(&mut conn, &["DBSIZE"]), noauth);
+        assert_eq!(server.handle(&mut conn, &["AUTH", &token]), Reply::ok());
+        assert_eq!(server.handle(&mut conn, &["DBSIZE"]), Reply::Integer(0));
+
+        let server = create_server();
+        let mut conn = server.connection();
+        assert!(matches!(
+            server.handle(&mut conn, &["AUTH", "secret"]),
+            Reply::Error(_)
+        ));
+        assert_eq!(server.handle(&mut conn, &["DBSIZE"]), Reply::Integer(0));
+
+        let input = "SET abc:jack 1\r\nAUTH secret\r\nSET abc:jack 1\r\nQUIT\r\n";
+        let mut stream = MockStream {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        let server = create_server().with_token("secret");
+        server.handle_connection(&mut stream).unwrap();
+        assert_eq!(
+            String::from_utf8(stream.output).unwrap(),
+            "-NOAUTH Authentication required.\r\n+OK\r\n+OK\r\n+OK\r\n"
+        );
+    }
+
+    #[test]
+    fn read_commands() {
+        let input = "*3\r\n$3\r\nSET\r\n$8\r\nabc:jack\r\n$1\r\n1\r\nPING\r\n";
+        let mut reader = Cursor::new(input.as_bytes());
+
+        let args = read_command(&mut reader).unwrap().unwrap();
+        assert_eq!(args, vec!["SET", "abc:jack", "1"]);
+
+        let args = read_command(&mut reader).unwrap().unwrap();
+        assert_eq!(args, vec!["PING"]);
+
+        assert!(read_command(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_commands() {
+        let too_long = |input: String| {
+            let mut reader = Cursor::new(input.into_bytes());
+            read_command(&mut reader).unwrap_err().kind() == io::ErrorKind::InvalidData
+        };
+
+        assert!(too_long("*999999999999\r\n".to_string()));
+        assert!(too_long(format!("*{}\r\n", MAX_ARGS + 1)));
+        assert!(too_long(format!("*1\r\n${}\r\n", usize::MAX)));
+        assert!(too_long(format!("*1\r\n${}\r\n", MAX_BULK + 1)));
+        assert!(too_long("x".repeat(MAX_BULK + 10)));
+
+        let server = create_server();
+        let mut stream = MockStream {
+            input: Cursor::new(b"*999999999999\r\n".to_vec()),
+            output: Vec::new(),
+        };
+        assert!(server.handle_connection(&mut stream).is_err());
+        assert_eq!(
+            String::from_utf8(stream.output).unwrap(),
+            "-ERR Protocol error: too many arguments\r\n"
+        );
+    }
+
+    #[test]
+    fn write_replies() {
+        let mut out = Vec::new();
+        Reply::ok().write_to(&mut out).unwrap();
+        Reply::Integer(-2).write_to(&mut out).unwrap();
+        Reply::Bulk("jack".to_string()).write_to(&mut out).unwrap();
+        Reply::Nil.write_to(&mut out).unwrap();
+        Reply::error("syntax error").write_to(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "+OK\r\n:-2\r\n$4\r\njack\r\n$-1\r\n-ERR syntax error\r\n"
+        );
+    }
+}
@@ -0,0 +1,357 @@
+/// otp delivery: an app supplies an OtpSender, e.g. for sms or email, and Otp::create_and_send calls it with each new
+/// code. a DeliveryPipeline retries transient failures and falls back to other channels
+use crate::clock;
+use crate::logging;
+use crate::template::Channel;
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// attempts per channel in a pipeline, including the first
+pub const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// delay before a pipeline's first retry; doubled for each later retry
+pub const DELIVERY_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// what a sender needs to know about the code besides the user, e.g. to pick a template
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SendContext {
+    /// why the code was requested, e.g. login or verify_phone
+    pub purpose: String,
+    /// unix time the code expires; create_and_send fills it in
+    pub expires: u64,
+    /// extra values for the message, e.g. the requesting ip or the app name
+    pub fields: Vec<(String, String)>,
+    /// the user's language, e.g. fr-CA, to pick localized templates
+    pub locale: Option<String>,
+    /// channels not to use, e.g. ones a resend is throttled on; a pipeline skips them
+    pub skip: Vec<Channel>,
+}
+
+impl SendContext {
+    /// create a context for a code requested for this purpose
+    pub fn new(purpose: &str) -> SendContext {
+        SendContext {
+            purpose: purpose.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// add a value for the message
+    pub fn with(mut self, key: &str, value: &str) -> SendContext {
+        self.fields.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// send the message in this language, e.g. fr-CA
+    pub fn with_locale(mut self, locale: &str) -> SendContext {
+        self.locale = Some(locale.to_string());
+        self
+    }
+
+    /// return the value for the key, if one was added
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// return the recipient on the channel: its own field (`email` or `phone`) if set, then the `to` field, otherwise
+    /// the user
+    pub fn recipient<'a>(&'a self, channel: Channel, user: &'a str) -> &'a str {
+        let field = match channel {
+            Channel::Email => "email",
+            Channel::Sms | Channel::Voice => "phone",
+        };
+        self.get(field).or_else(|| self.get("to")).unwrap_or(user)
+    }
+}
+
+/// where an otp is on its way to the user, in order; provider callbacks move it from sent to delivered or failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeliveryState {
+    /// created and waiting for the sender
+    #[default]
+    Queued,
+    /// accepted by the provider
+    Sent,
+    /// the provider confirmed it reached the user
+    Delivered,
+    /// the provider could not reach the user, e.g. the phone is off
+    Failed,
+}
+
+impl DeliveryState {
+    /// return the name used in logs and callbacks, e.g. delivered
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryState::Queued => "queued",
+            DeliveryState::Sent => "sent",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Failed => "failed",
+        }
+    }
+
+    /// return true once the state can't change, delivered or failed
+    pub fn is_final(&self) -> bool {
+        matches!(self, DeliveryState::Delivered | DeliveryState::Failed)
+    }
+}
+
+impl FromStr for DeliveryState {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<DeliveryState> {
+        match text {
+            "queued" => Ok(DeliveryState::Queued),
+            "sent" => Ok(DeliveryState::Sent),
+            "delivered" => Ok(DeliveryState::Delivered),
+            "failed" => Ok(DeliveryState::Failed),
+            _ => bail!("{} is not a delivery state", text),
+        }
+    }
+}
+
+/// how an otp was delivered, recorded on the otp
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// the channel that delivered it, if the sender knows
+    pub channel: Option<Channel>,
+    /// the sends tried, across every channel
+    pub attempts: u32,
+    /// where the message is; provider callbacks move it on after the send
+    pub state: DeliveryState,
+    /// the provider's id for the message, to match its status callbacks
+    pub message_id: Option<String>,
+    /// the times the code was resent; Otp::resend counts them
+    pub resends: u32,
+}
+
+/// delivers an otp to its user; called on the thread that created the code, so slow transports should queue
+pub trait OtpSender: fmt::Debug + Send + Sync {
+    /// send the code to the user; an error means it was not delivered
+    fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()>;
+
+    /// return the channel this sender delivers on, if it is a single one
+    fn channel(&self) -> Option<Channel> {
+        None
+    }
+
+    /// return the channels this sender may deliver on, in the order it tries them
+    fn channels(&self) -> Vec<Channel> {
+        self.channel().into_iter().collect()
+    }
+
+    /// return true if the error from send is worth retrying, e.g. a timeout or rate limit
+    fn is_transient(&self, _error: &anyhow::Error) -> bool {
+        false
+    }
+
+    /// send the code and say how it went out; create_and_send records the result on the otp. senders whose provider
+    /// reports delivery override it to return the message id
+    fn deliver(&self, user: &str, code: &str, context: &SendContext) -> Result<Delivery> {
+        self.send(user, code, context)?;
+        Ok(Delivery {
+            channel: self.channel(),
+            attempts: 1,
+            state: DeliveryState::Sent,
+            ..Default::default()
+        })
+    }
+}
+
+/// a sender that tries each channel in order, e.g. sms then email, retrying transient failures with backoff before
+/// falling back to the next
+#[derive(Debug, Clone)]
+pub struct DeliveryPipeline {
+    channels: Vec<(Channel, Arc<dyn OtpSender>)>,
+    attempts: u32,
+    delay: Duration,
+}
+
+impl Default for DeliveryPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeliveryPipeline {
+    /// create a pipeline with no channels and the default retries
+    pub fn new() -> DeliveryPipeline {
+        DeliveryPipeline {
+            channels: Vec::new(),
+            attempts: DELIVERY_ATTEMPTS,
+            delay: DELIVERY_RETRY_DELAY,
+        }
+    }
+
+    /// try this channel after the ones already added
+    pub fn channel(mut self, channel: Channel, sender: Arc<dyn OtpSender>) -> DeliveryPipeline {
+        self.channels.push((channel, sender));
+        self
+    }
+
+    /// try each channel up to this many times, waiting delay before the first retry and doubling it after
+    pub fn with_retry(mut self, attempts: u32, delay: Duration) -> DeliveryPipeline {
+        self.attempts = attempts.max(1);
+        self.delay = delay;
+        self
+    }
+}
+
+impl OtpSender for DeliveryPipeline {
+    fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()> {
+        self.deliver(user, code, context).map(|_| ())
+    }
+
+    fn channels(&self) -> Vec<Channel> {
+        self.channels.iter().map(|(channel, _)| *channel).collect()
+    }
+
+    fn deliver(&self, user: &str, code: &str, context: &SendContext) -> Result<Delivery> {
+        if self.channels.is_empty() {
+            bail!("no delivery channels configured");
+        }
+        let channels = self
+            .channels
+            .iter()
+            .filter(|(channel, _)| !context.skip.contains(channel));
+
+        let mut attempts = 0;
+        let mut last = None;
+        for (channel, sender) in channels {
+            let mut delay = self.delay;
+            for attempt in 1..=self.attempts {
+                attempts += 1;
+                let result = sender.deliver(user, code, context);
+                let retry = result.as_ref().is_err_and(|e| sender.is_transient(e));
+                let outcome = match (&result, retry) {
+                    (Ok(_), _) => "sent",
+                    (Err(_), true) => "transient",
+                    (Err(_), false) => "failed",
+                };
+                logging::event(
+                    "otp.deliver",
+                    &[
+                        ("user", user),
+                        ("channel", channel.as_str()),
+                        ("attempt", &attempt.to_string()),
+                        ("result", outcome),
+                    ],
+                );
+
+                match result {
+                    Ok(delivery) => {
+                        return Ok(Delivery {
+                            channel: Some(*channel),
+                            attempts,
+                            ..delivery
+                        })
+                    }
+                    Err(e) => {
+                        last = Some(anyhow!("{}: {}", channel.as_str(), e));
+                        if !retry || attempt == self.attempts {
+                            break;
+                        }
+                    }
+                }
+                clock::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+        }
+
+        Err(last.unwrap_or_else(|| anyhow!("every delivery channel was skipped")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // fails the first `failures` sends, transiently if asked
+    #[derive(Debug)]
+    struct Flaky {
+        failures: u32,
+        transient: bool,
+        sends: AtomicU32,
+    }
+
+    impl Flaky {
+        fn new(failures: u32, transient: bool) -> Arc<Flaky> {
+            Arc::new(Flaky {
+                failures,
+                transient,
+                sends: AtomicU32::new(0),
+            })
+        }
+    }
+
+    impl OtpSender for Flaky {
+        fn send(&self, _user: &str, _code: &str, _context: &SendContext) -> Result<()> {
+            if self.sends.fetch_add(1, Ordering::Relaxed) < self.failures {
+                bail!("gateway timeout");
+            }
+            Ok(())
+        }
+
+        fn is_transient(&self, _error: &anyhow::Error) -> bool {
+            self.transient
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let context = SendContext::new("login");
+        let sms = Flaky::new(2, true);
+        let email = Flaky::new(0, false);
+        let pipeline = DeliveryPipeline::new()
+            .channel(Channel::Sms, sms.clone())
+            .channel(Channel::Email, email.clone())
+            .with_retry(3, Duration::ZERO);
+        assert_eq!(pipeline.channels(), [Channel::Sms, Channel::Email]);
+
+        // transient failures retry on the same channel
+        let delivery = pipeline.deliver("sally", "123456", &context).unwrap();
+        assert_eq!(delivery.channel, Some(Channel::Sms));
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.state, DeliveryState::Sent);
+
+        // a permanent failure falls back at once
+        let sms = Flaky::new(1, false);
+        let pipeline = DeliveryPipeline::new()
+            .channel(Channel::Sms, sms.clone())
+            .channel(Channel::Email, email.clone())
+            .with_retry(3, Duration::ZERO);
+        let delivery = pipeline.deliver("sally", "123456", &context).unwrap();
+        assert_eq!(
+            (delivery.channel, delivery.attempts),
+            (Some(Channel::Email), 2)
+        );
+        assert_eq!(sms.sends.load(Ordering::Relaxed), 1);
+
+        let pipeline = DeliveryPipeline::new()
+            .channel(Channel::Sms, Flaky::new(5, true))
+            .with_retry(2, Duration::ZERO);
+        let err = pipeline.send("sally", "123456", &context).unwrap_err();
+        assert_eq!(err.to_string(), "sms: gateway timeout");
+        let mut skip = context.clone();
+        skip.skip.push(Channel::Sms);
+        assert!(pipeline.send("sally", "123456", &skip).is_err());
+        assert!(DeliveryPipeline::new()
+            .send("sally", "123456", &context)
+            .is_err());
+
+        let context = context
+            .with("to", "sally@example.com")
+            .with("phone", "+14155550123");
+        assert_eq!(context.recipient(Channel::Sms, "sally"), "+14155550123");
+        assert_eq!(
+            context.recipient(Channel::Email, "sally"),
+            "sally@example.com"
+        );
+    }
+}
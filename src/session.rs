@@ -1,69 +1,1636 @@
-use crate::db::{DataStore, SessionItem};
-use anyhow::Result;
-use log::debug;
+use crate::clock::{unix_millis, Clock, Instant};
+use crate::config::SessionConfig;
+use crate::db::{Change, DataStore, LimitReached, SessionItem, Validation};
+use crate::events::{EventKind, Events, Store};
+use crate::hash::{
+    base64url, base64url_decode, hmac_sha256, hmac_sha256_xor, random_bytes, random_hex,
+    random_string, sha256_hex, verify_hmac_sha256,
+};
+use crate::health::Health;
+use crate::logging;
+use crate::metrics;
+use crate::network::{IpBinding, Network};
+use crate::policy::{LoginPolicy, TtlPolicies};
+use crate::refresh::{Redeemed, RefreshReused, RefreshTokens, TokenPair, REFRESH_BYTES};
+use crate::stats::{Operation, Stats, StoreStats};
+use anyhow::{anyhow, bail, Result};
+use fastrand::Rng;
+use hashbrown::{HashMap, HashSet};
+use otp_session_core::{code, expiry};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a session stays elevated after the user re-authenticates
+pub const ELEVATION_WINDOW: u64 = 300;
+
+/// the user of a guest session until bind_user gives it a real one
+pub const GUEST: &str = "";
+
+/// the longest an impersonation session lasts, however often it is touched
+pub const IMPERSONATION_CAP: u64 = 900;
+
+/// the longest nanoid session code
+pub const NANOID_MAX_LENGTH: usize = 128;
+
+/// the fewest random bits a nanoid session code may carry, so codes stay too many to guess; the characters are drawn
+/// from the operating system's secure generator, so the bits can't be worked out from earlier codes
+pub const NANOID_MIN_BITS: u32 = 64;
+
+/// the item meta key holding the sha-256 of a split token's verifier
+pub const VERIFIER_META: &str = "verifier_sha256";
+
+/// the item meta key holding the network a session is bound to
+pub const NETWORK_META: &str = "network";
+
+/// the item meta key holding the sha-256 of the device fingerprint a session is bound to
+pub const FINGERPRINT_META: &str = "fingerprint_sha256";
+
+/// the item meta key holding the admin behind an impersonation session
+pub const IMPERSONATOR_META: &str = "impersonator";
+
+/// the item meta key holding the unix time an impersonation session ends
+pub const IMPERSONATION_ENDS_META: &str = "impersonation_ends";
+
+/// the item meta key holding a scoped session's scopes, sorted and separated by spaces
+pub const SCOPES_META: &str = "scopes";
+
+/// the random bytes in a split token's verifier
+pub const VERIFIER_BYTES: usize = 32;
+
+/// the random bytes in a split token's selector
+pub const SELECTOR_BYTES: usize = 16;
+
+/// the fewest bytes in a key that signs cookie tokens
+pub const COOKIE_KEY_MIN: usize = 32;
+
+// bytes in an hmac-sha256 tag, the mac that ends cookie and expiring tokens
+const DIGEST_LEN: usize = 32;
+
+// the first byte of a cookie token
+const COOKIE_TOKEN: u8 = 0;
+
+// the first byte of an expiring token, so a cookie token never passes for one
+const EXPIRING_TOKEN: u8 = 1;
+
+// the random bytes a token's user is encrypted with
+const USER_NONCE_LEN: usize = 16;
+
+// the key a token's user is encrypted with, kept apart from the cookie key that macs the token
+fn user_key(cookie_key: &[u8]) -> Vec<u8> {
+    hmac_sha256(cookie_key, b"otp-session-lib token user")
+}
+
+// append the session's code, after its length, then a random nonce and the user encrypted with it, so a token
+// doesn't give away its user, e.g. an email or phone number
+fn push_session(token: &mut Vec<u8>, cookie_key: &[u8], code: &str, user: &str) -> Result<()> {
+    let len = u8::try_from(code.len()).map_err(|_| anyhow!("session code too long for a token"))?;
+    token.push(len);
+    token.extend_from_slice(code.as_bytes());
+    let nonce = random_bytes(USER_NONCE_LEN);
+    token.extend_from_slice(&nonce);
+    token.extend_from_slice(&hmac_sha256_xor(
+        &user_key(cookie_key),
+        &nonce,
+        user.as_bytes(),
+    ));
+    Ok(())
+}
+
+// read the code and user written by push_session
+fn read_session(cookie_key: &[u8], bytes: &[u8]) -> Result<(String, String)> {
+    let (&len, rest) = bytes
+        .split_first()
+        .filter(|(&len, rest)| rest.len() >= len as usize + USER_NONCE_LEN)
+        .ok_or_else(|| anyhow!("malformed token"))?;
+    let (code, rest) = rest.split_at(len as usize);
+    let (nonce, user) = rest.split_at(USER_NONCE_LEN);
+    let user = hmac_sha256_xor(&user_key(cookie_key), nonce, user);
+    Ok((String::from_utf8(code.to_vec())?, String::from_utf8(user)?))
+}
+
+// whether the item is a split session, which only its whole token may read or touch
+fn is_split_item(item: &SessionItem) -> bool {
+    item.meta.contains_key(VERIFIER_META)
+}
+
+// a session's code and user
+type SessionKey = (String, String);
+
+// the key cookie tokens are signed with, kept out of debug output
+#[derive(Clone)]
+struct CookieKey(Vec<u8>);
+
+impl CookieKey {
+    fn new(key: &[u8]) -> Result<CookieKey> {
+        if key.len() < COOKIE_KEY_MIN {
+            bail!("cookie keys must be at least {} bytes", COOKIE_KEY_MIN);
+        }
+        Ok(CookieKey(key.to_vec()))
+    }
+
+    fn random() -> CookieKey {
+        CookieKey(random_bytes(COOKIE_KEY_MIN))
+    }
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CookieKey(..)")
+    }
+}
+
+/// the form of new session codes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdFormat {
+    /// two random numbers in hex, 20 to 22 characters
+    #[default]
+    Hex,
+    /// a random version 4 uuid
+    UuidV4,
+    /// a version 7 uuid, which starts with the creation time in milliseconds so ids sort by age
+    UuidV7,
+    /// a ulid, 26 characters that start with the creation time in milliseconds so ids sort by age as text
+    Ulid,
+    /// random characters from a url-safe alphabet, 21 by default, for codes embedded in links
+    Nanoid,
+}
+
+impl IdFormat {
+    /// return the name used in config files and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdFormat::Hex => "hex",
+            IdFormat::UuidV4 => "uuid_v4",
+            IdFormat::UuidV7 => "uuid_v7",
+            IdFormat::Ulid => "ulid",
+            IdFormat::Nanoid => "nanoid",
+        }
+    }
+
+    // the format as stored in an atomic
+    pub(crate) fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_u8(value: u8) -> IdFormat {
+        match value {
+            1 => IdFormat::UuidV4,
+            2 => IdFormat::UuidV7,
+            3 => IdFormat::Ulid,
+            4 => IdFormat::Nanoid,
+            _ => IdFormat::Hex,
+        }
+    }
+}
+
+impl FromStr for IdFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<IdFormat> {
+        match text {
+            "hex" => Ok(IdFormat::Hex),
+            "uuid_v4" => Ok(IdFormat::UuidV4),
+            "uuid_v7" => Ok(IdFormat::UuidV7),
+            "ulid" => Ok(IdFormat::Ulid),
+            "nanoid" => Ok(IdFormat::Nanoid),
+            _ => bail!(
+                "{} is not an id format; use hex, uuid_v4, uuid_v7, ulid or nanoid",
+                text
+            ),
+        }
+    }
+}
+
+/// reject a nanoid length and alphabet that would make codes unsafe in urls or easy to guess: the alphabet must be
+/// distinct letters, digits, `-`, `.`, `_` or `~`, and the codes must carry NANOID_MIN_BITS random bits
+pub fn validate_nanoid(length: usize, alphabet: &str) -> Result<()> {
+    let mut seen = [false; 128];
+    for c in alphabet.chars() {
+        if !(c.is_ascii_alphanumeric() || "-._~".contains(c)) {
+            bail!("{:?} is not url-safe; use letters, digits, -, ., _ or ~", c);
+        }
+        if seen[c as usize] {
+            bail!("{:?} appears twice in the nanoid alphabet", c);
+        }
+        seen[c as usize] = true;
+    }
+    if alphabet.len() < 2 {
+        bail!("the nanoid alphabet needs at least two characters");
+    }
+
+    let bits = length as f64 * (alphabet.len() as f64).log2();
+    if length > NANOID_MAX_LENGTH || bits < NANOID_MIN_BITS as f64 {
+        bail!(
+            "nanoid codes must carry at least {} random bits in at most {} characters; {} characters from {} carry {:.0}",
+            NANOID_MIN_BITS,
+            NANOID_MAX_LENGTH,
+            length,
+            alphabet.len(),
+            bits
+        );
+    }
+
+    Ok(())
+}
 
 #[derive(Debug, Clone)]
 pub struct Session {
-    keep_alive: u64,
+    keep_alive: Arc<AtomicU64>,
+    max_per_user: Arc<AtomicUsize>,
+    login_policy: Arc<AtomicU8>,
+    id_format: Arc<AtomicU8>,
+    // the length and alphabet of nanoid codes, together so a change never mixes the old and new
+    nanoid: Arc<RwLock<(usize, String)>>,
     db: DataStore,
+    events: Events,
+    policies: TtlPolicies,
+    refresh: RefreshTokens,
+    elevation: Arc<AtomicU64>,
+    // when each elevated session, by code and user, decays back to normal
+    elevated: Arc<RwLock<HashMap<SessionKey, u64>>>,
+    ip_binding: Arc<AtomicU8>,
+    reauth_after: Arc<AtomicU64>,
+    // when each session was created, touched or elevated
+    active: Arc<RwLock<HashMap<SessionKey, u64>>>,
+    cookie_key: Arc<RwLock<CookieKey>>,
+    stats: Stats,
+}
+
+/// what an expiring token says about its session. the times are signed with the cookie key, so an edge cache holding
+/// the key can reject expired tokens without asking the store, which still decides whether the session was revoked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiringToken {
+    pub code: String,
+    pub user: String,
+    /// unix time the token was issued
+    pub issued: u64,
+    /// unix time the session expires, as of when the token was issued
+    pub expires: u64,
+}
+
+impl ExpiringToken {
+    /// check the token's signature with the cookie key and return what it says
+    pub fn read(key: &[u8], token: &str) -> Result<ExpiringToken> {
+        let min = 1 + 16 + 1 + USER_NONCE_LEN + DIGEST_LEN;
+        let bytes = base64url_decode(token).unwrap_or_default();
+        if bytes.len() <= min || bytes[0] != EXPIRING_TOKEN {
+            bail!("malformed expiring token");
+        }
+        let (signed, mac) = bytes.split_at(bytes.len() - DIGEST_LEN);
+        if !verify_hmac_sha256(key, signed, mac) {
+            bail!("expiring token signature does not match");
+        }
+
+        let time = |at: usize| u64::from_be_bytes(signed[at..at + 8].try_into().unwrap());
+        let (code, user) = read_session(key, &signed[17..])?;
+        Ok(ExpiringToken {
+            code,
+            user,
+            issued: time(1),
+            expires: time(9),
+        })
+    }
+
+    /// return true if the token's session had expired at the unix time
+    pub fn has_expired_at(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires, now)
+    }
+}
+
+/// who is behind an impersonation session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Impersonation {
+    pub admin: String,
+    /// unix time the session ends, even if touched
+    pub ends: u64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// configures and builds a Session, e.g. `Session::builder().timeout(3600).build()`
+#[derive(Debug, Clone, Default)]
+pub struct SessionBuilder {
+    config: SessionConfig,
+    store: Option<DataStore>,
+    events: Option<Events>,
+    policies: Option<TtlPolicies>,
+    refresh: Option<RefreshTokens>,
+    elevation: Option<u64>,
+    clock: Option<Arc<dyn Clock>>,
+    cookie_key: Option<Vec<u8>>,
+}
+
+impl SessionBuilder {
+    /// start from these settings
+    pub fn config(mut self, config: SessionConfig) -> SessionBuilder {
+        self.config = config;
+        self
+    }
+
+    /// the session keep alive in seconds
+    pub fn timeout(mut self, timeout: u64) -> SessionBuilder {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// the most unexpired sessions a user may hold
+    pub fn max_per_user(mut self, max: usize) -> SessionBuilder {
+        self.config.max_per_user = Some(max);
+        self
+    }
+
+    /// what to do when a user with active sessions logs in again
+    pub fn login_policy(mut self, policy: LoginPolicy) -> SessionBuilder {
+        self.config.login_policy = policy;
+        self
+    }
+
+    /// what to do when a bound session is validated from another network
+    pub fn ip_binding(mut self, binding: IpBinding) -> SessionBuilder {
+        self.config.ip_binding = binding;
+        self
+    }
+
+    /// create session codes in this form, e.g. uuids for downstream systems with uuid columns
+    pub fn id_format(mut self, format: IdFormat) -> SessionBuilder {
+        self.config.id_format = format;
+        self
+    }
+
+    /// the length and url-safe alphabet of nanoid codes
+    pub fn nanoid(mut self, length: usize, alphabet: &str) -> SessionBuilder {
+        self.config.nanoid_length = length;
+        self.config.nanoid_alphabet = alphabet.to_string();
+        self
+    }
+
+    /// keep the sessions in this store instead of a new one, e.g. one restored from a snapshot
+    pub fn store(mut self, store: DataStore) -> SessionBuilder {
+        self.store = Some(store);
+        self
+    }
+
+    /// publish lifecycle events on this bus
+    pub fn events(mut self, events: Events) -> SessionBuilder {
+        self.events = Some(events);
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> SessionBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// give users in some classes a different keep alive
+    pub fn ttl_policies(mut self, policies: TtlPolicies) -> SessionBuilder {
+        self.policies = Some(policies);
+        self
+    }
+
+    /// issue token pairs with these ttls
+    pub fn refresh_tokens(mut self, refresh: RefreshTokens) -> SessionBuilder {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// sessions idle for this many seconds need re-authentication for sensitive operations
+    pub fn reauth_after(mut self, seconds: u64) -> SessionBuilder {
+        self.config.reauth_after = Some(seconds);
+        self
+    }
+
+    /// keep sessions elevated for this many seconds after re-authentication
+    pub fn elevation_window(mut self, window: u64) -> SessionBuilder {
+        self.elevation = Some(window);
+        self
+    }
+
+    /// sign cookie tokens with this key, at least COOKIE_KEY_MIN bytes, so they stay valid across restarts and
+    /// instances; by default each session store picks a random key
+    pub fn cookie_key(mut self, key: &[u8]) -> SessionBuilder {
+        self.cookie_key = Some(key.to_vec());
+        self
+    }
+
+    /// validate the settings and build the session
+    pub fn build(self) -> Result<Session> {
+        let config = self.config;
+        config.validate()?;
+        let cookie_key = match self.cookie_key {
+            Some(key) => CookieKey::new(&key)?,
+            None => CookieKey::random(),
+        };
+        let mut db = self.store.unwrap_or_else(DataStore::create);
+        if let Some(clock) = self.clock {
+            db = db.with_clock(clock);
+        }
+
+        Ok(Session {
+            keep_alive: Arc::new(AtomicU64::new(config.timeout)),
+            max_per_user: Arc::new(AtomicUsize::new(config.max_per_user.unwrap_or(0))),
+            login_policy: Arc::new(AtomicU8::new(config.login_policy.to_u8())),
+            id_format: Arc::new(AtomicU8::new(config.id_format.to_u8())),
+            nanoid: Arc::new(RwLock::new((config.nanoid_length, config.nanoid_alphabet))),
+            db,
+            events: self.events.unwrap_or_default(),
+            policies: self.policies.unwrap_or_default(),
+            refresh: self.refresh.unwrap_or_default(),
+            elevation: Arc::new(AtomicU64::new(self.elevation.unwrap_or(ELEVATION_WINDOW))),
+            elevated: Arc::new(RwLock::new(HashMap::new())),
+            ip_binding: Arc::new(AtomicU8::new(config.ip_binding.to_u8())),
+            reauth_after: Arc::new(AtomicU64::new(config.reauth_after.unwrap_or(0))),
+            active: Arc::new(RwLock::new(HashMap::new())),
+            cookie_key: Arc::new(RwLock::new(cookie_key)),
+            stats: Stats::new(metrics::SESSION),
+        })
+    }
 }
 
-impl Default for Session {
-    fn default() -> Self {
-        Self::new()
+// a random hex session code, shared with the other session stores
+pub(crate) fn generate_code() -> String {
+    code::session_code(&mut Rng::new())
+}
+
+// the random bytes of a uuid or ulid session code, from the secure generator so codes can't be worked out from
+// earlier ones
+fn random_id() -> [u8; 16] {
+    random_bytes(16).try_into().unwrap()
+}
+
+impl Session {
+    /// return a builder for a session with non-default settings
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    /// create a new session object
+    pub fn new() -> Session {
+        let db = DataStore::create();
+        let keep_alive = Arc::new(AtomicU64::new(crate::SESSION_TIMEOUT));
+
+        Session {
+            keep_alive,
+            max_per_user: Arc::new(AtomicUsize::new(0)),
+            login_policy: Arc::new(AtomicU8::new(LoginPolicy::Allow.to_u8())),
+            id_format: Arc::new(AtomicU8::new(IdFormat::Hex.to_u8())),
+            nanoid: Arc::new(RwLock::new((
+                code::NANOID_LENGTH,
+                code::NANOID_ALPHABET.to_string(),
+            ))),
+            db,
+            events: Events::new(),
+            policies: TtlPolicies::new(),
+            refresh: RefreshTokens::new(),
+            elevation: Arc::new(AtomicU64::new(ELEVATION_WINDOW)),
+            elevated: Arc::new(RwLock::new(HashMap::new())),
+            ip_binding: Arc::new(AtomicU8::new(IpBinding::Off.to_u8())),
+            reauth_after: Arc::new(AtomicU64::new(0)),
+            active: Arc::new(RwLock::new(HashMap::new())),
+            cookie_key: Arc::new(RwLock::new(CookieKey::random())),
+            stats: Stats::new(metrics::SESSION),
+        }
+    }
+
+    /// generate session id code in the id format; the time in a v7 uuid or ulid is the system clock's, whatever clock
+    /// the expirations use
+    pub fn generate_code(&self) -> String {
+        match self.id_format() {
+            IdFormat::Hex => generate_code(),
+            IdFormat::UuidV4 => code::uuid_v4_from(random_id()),
+            IdFormat::UuidV7 => code::uuid_v7_from(random_id(), unix_millis()),
+            IdFormat::Ulid => code::ulid_from(random_id(), unix_millis()),
+            IdFormat::Nanoid => {
+                let (length, alphabet) = &*self.nanoid.read().unwrap();
+                random_string(alphabet.as_bytes(), *length)
+            }
+        }
+    }
+
+    /// return the form of new session codes
+    pub fn id_format(&self) -> IdFormat {
+        IdFormat::from_u8(self.id_format.load(Ordering::Relaxed))
+    }
+
+    /// create session codes in this form from now on; existing sessions keep their codes
+    pub fn set_id_format(&self, format: IdFormat) {
+        self.id_format.store(format.to_u8(), Ordering::Relaxed);
+    }
+
+    /// return the length and alphabet of nanoid codes
+    pub fn nanoid(&self) -> (usize, String) {
+        self.nanoid.read().unwrap().clone()
+    }
+
+    /// set the length and url-safe alphabet of nanoid codes; existing sessions keep their codes
+    pub fn set_nanoid(&self, length: usize, alphabet: &str) -> Result<()> {
+        validate_nanoid(length, alphabet)?;
+        *self.nanoid.write().unwrap() = (length, alphabet.to_string());
+        Ok(())
+    }
+
+    /// return the most unexpired sessions a user may hold; None is unlimited
+    pub fn max_per_user(&self) -> Option<usize> {
+        match self.max_per_user.load(Ordering::Relaxed) {
+            0 => None,
+            max => Some(max),
+        }
+    }
+
+    /// limit the unexpired sessions a user may hold; creates beyond the limit fail
+    pub fn set_max_per_user(&self, max: Option<usize>) {
+        self.max_per_user.store(max.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// return what happens when a user with active sessions logs in again
+    pub fn login_policy(&self) -> LoginPolicy {
+        LoginPolicy::from_u8(self.login_policy.load(Ordering::Relaxed))
+    }
+
+    /// change what happens when a user with active sessions logs in again
+    pub fn set_login_policy(&self, policy: LoginPolicy) {
+        self.login_policy.store(policy.to_u8(), Ordering::Relaxed);
+    }
+
+    // the most sessions a login may leave the user with, and whether to end old ones to get there
+    fn login_limit(&self) -> Option<(usize, bool)> {
+        match self.login_policy() {
+            LoginPolicy::Allow => self.max_per_user().map(|max| (max, false)),
+            LoginPolicy::Deny => Some((1, false)),
+            LoginPolicy::KickOldest => Some((self.max_per_user().unwrap_or(1), true)),
+        }
+    }
+
+    /// create a user session and return the session code or error; the login policy decides what happens to the
+    /// user's other sessions
+    pub fn create_user_session(&mut self, user: &str) -> Result<String> {
+        let code = self.generate_code();
+        self.create_session(
+            &code,
+            user,
+            self.keep_alive_for(user),
+            self.login_limit(),
+            &[],
+        )
+    }
+
+    /// create a user session and return a split token, `selector.verifier`: the random selector indexes the store,
+    /// and only the sha-256 of the random verifier is kept, so neither the time a lookup takes nor a leaked store gives
+    /// away a usable token. only validate_split_token accepts it; the plain validate paths treat the selector as
+    /// NotFound
+    pub fn create_split_session(&mut self, user: &str) -> Result<String> {
+        let selector = random_hex(SELECTOR_BYTES);
+        let verifier = random_hex(VERIFIER_BYTES);
+        let hash = sha256_hex(verifier.as_bytes());
+        let meta = [(VERIFIER_META, hash.as_str())];
+        let keep_alive = self.keep_alive_for(user);
+        self.create_session(&selector, user, keep_alive, self.login_limit(), &meta)?;
+
+        Ok(format!("{}.{}", selector, verifier))
+    }
+
+    /// validate a split token for the user: the selector finds the session and the verifier's hash is compared in
+    /// constant time with the stored one. a wrong verifier, or a session that has expired, is NotFound
+    pub fn validate_split_token(&self, token: &str, user: &str) -> Validation {
+        let selector = token
+            .rsplit_once('.')
+            .map_or(token, |(selector, _)| selector);
+        match self.split_selector(token, user) {
+            Some(selector) => {
+                let _span = metrics::span("session.validate");
+                let start = Instant::now();
+                let result = self.db.validate(selector, user);
+                self.validated(selector, user, result, start)
+            }
+            None => self.validated(selector, user, Validation::NotFound, Instant::now()),
+        }
+    }
+
+    /// return the session item of a split token if the verifier matches and the session is still valid
+    pub fn get_split_token(&self, token: &str, user: &str) -> Option<SessionItem> {
+        self.db.get(self.split_selector(token, user)?, user)
+    }
+
+    /// extend the session of a split token like touch, if the verifier matches; return the updated item
+    pub fn touch_split_token(&mut self, token: &str, user: &str) -> Option<SessionItem> {
+        let selector = self.split_selector(token, user)?;
+        self.extend(selector, user)
+    }
+
+    // the selector of a split token, if its session is live and the verifier's hash matches the stored one
+    fn split_selector<'a>(&self, token: &'a str, user: &str) -> Option<&'a str> {
+        let (selector, verifier) = token.rsplit_once('.')?;
+        let hash = sha256_hex(verifier.as_bytes());
+        let genuine = self.db.get_ref(selector, user).is_some_and(|item| {
+            let stored = item.meta().get(VERIFIER_META);
+            stored.is_some_and(|stored| code::codes_match(stored, &hash))
+        });
+
+        genuine.then_some(selector)
+    }
+
+    /// create a user session bound to the network it was created from, an ip or a cidr block like 203.0.113.0/24.
+    /// the network is kept in the item's meta, so the binding is saved in snapshots and replicated
+    pub fn create_user_session_from(&mut self, user: &str, network: &str) -> Result<String> {
+        let network = network.parse::<Network>()?.to_string();
+        let code = self.generate_code();
+        let meta = [(NETWORK_META, network.as_str())];
+        let keep_alive = self.keep_alive_for(user);
+        self.create_session(&code, user, keep_alive, self.login_limit(), &meta)
+    }
+
+    // return a meta value of the live session
+    fn meta(&self, code: &str, user: &str, key: &str) -> Option<String> {
+        let item = self.db.get_ref(code, user)?;
+        item.meta().get(key).cloned()
+    }
+
+    /// return the network the session is bound to, if any
+    pub fn network(&self, code: &str, user: &str) -> Option<Network> {
+        self.meta(code, user, NETWORK_META)?.parse().ok()
+    }
+
+    /// return what happens when a bound session is validated from another network
+    pub fn ip_binding(&self) -> IpBinding {
+        IpBinding::from_u8(self.ip_binding.load(Ordering::Relaxed))
+    }
+
+    /// change the ip binding mode; applies to every bound session from now on
+    pub fn set_ip_binding(&self, binding: IpBinding) {
+        self.ip_binding.store(binding.to_u8(), Ordering::Relaxed);
+    }
+
+    /// validate this session for the user calling from the address; a session bound to another network is
+    /// WrongNetwork when the binding is strict, and only logged when it is warn
+    pub fn validate_from(&self, code: &str, user: &str, addr: IpAddr) -> Validation {
+        let result = self.validate(code, user);
+        let binding = self.ip_binding();
+        if !result.is_valid() || binding == IpBinding::Off {
+            return result;
+        }
+
+        let Some(network) = self.network(code, user) else {
+            return result;
+        };
+        if network.contains(addr) {
+            return result;
+        }
+
+        logging::event(
+            "session.wrong_network",
+            &[
+                ("user", user),
+                ("code", code),
+                ("addr", &addr.to_string()),
+                ("network", &network.to_string()),
+                ("binding", binding.as_str()),
+            ],
+        );
+        match binding {
+            IpBinding::Strict => Validation::WrongNetwork,
+            _ => result,
+        }
+    }
+
+    /// validate this session for the user calling from the address
+    pub fn is_valid_from(&self, code: &str, user: &str, addr: IpAddr) -> bool {
+        self.validate_from(code, user, addr).is_valid()
+    }
+
+    /// bind a valid session to the caller's device fingerprint, e.g. a hash of the user agent and platform; the
+    /// fingerprint's sha-256 is kept in the item's meta
+    pub fn bind_fingerprint(&mut self, code: &str, user: &str, fingerprint: &str) -> Result<()> {
+        let result = self.check(code, user);
+        if !result.is_valid() {
+            bail!("session not valid: {}", result.as_str());
+        }
+
+        let hash = sha256_hex(fingerprint.as_bytes());
+        if self
+            .db
+            .set_meta(code, user, FINGERPRINT_META, &hash)
+            .is_none()
+        {
+            bail!("session not valid: {}", self.check(code, user).as_str());
+        }
+        logging::event(
+            "session.bind_fingerprint",
+            &[("user", user), ("code", code)],
+        );
+
+        Ok(())
+    }
+
+    /// return true if the session is bound to a fingerprint
+    pub fn has_fingerprint(&self, code: &str, user: &str) -> bool {
+        self.meta(code, user, FINGERPRINT_META).is_some()
+    }
+
+    /// validate this session for the user on the device with this fingerprint; a bound session validated with
+    /// another fingerprint, or none, is WrongFingerprint so the app can force a new login
+    pub fn validate_with(&self, code: &str, user: &str, fingerprint: Option<&str>) -> Validation {
+        let result = self.validate(code, user);
+        if !result.is_valid() {
+            return result;
+        }
+
+        let Some(bound) = self.meta(code, user, FINGERPRINT_META) else {
+            return result;
+        };
+        if fingerprint.is_some_and(|fingerprint| {
+            code::codes_match(&sha256_hex(fingerprint.as_bytes()), &bound)
+        }) {
+            return result;
+        }
+
+        logging::event(
+            "session.wrong_fingerprint",
+            &[("user", user), ("code", code)],
+        );
+        Validation::WrongFingerprint
+    }
+
+    /// create a session with no user yet, e.g. for a shopper's cart; validate it with the GUEST user
+    pub fn create_guest_session(&mut self) -> Result<String> {
+        let code = self.generate_code();
+        self.create_session(&code, GUEST, self.keep_alive(), None, &[])
+    }
+
+    /// give a guest session its user after they log in, keeping the code so anything keyed by it survives; the
+    /// session gets the user's keep alive from now. the login policy applies as it does to a new login, and if it
+    /// refuses the session the guest keeps it. a code can only be bound once
+    pub fn bind_user(&mut self, code: &str, user: &str) -> Result<SessionItem> {
+        if user == GUEST {
+            bail!("can't bind a guest session to the guest user");
+        }
+
+        // consuming the guest item means only one caller can bind it
+        let guest = self.db.get(code, GUEST);
+        let result = self.db.consume(code, GUEST);
+        let Some(guest) = guest.filter(|_| result.is_valid()) else {
+            bail!("guest session not valid: {}", result.as_str());
+        };
+
+        let item = SessionItem::created_at(code, user, self.keep_alive_for(user), self.db.now());
+        if let Err(e) = self.put_limited(item.clone(), self.login_limit()) {
+            self.db.put(guest)?;
+            return Err(e);
+        }
+        self.forget(|(c, u)| c != code || u != GUEST);
+        self.record_activity(code, user);
+        logging::event("session.bind_user", &[("user", user), ("code", code)]);
+        self.events.emit(
+            EventKind::Put,
+            Store::Session,
+            Some(user),
+            Some(code),
+            self.db.now(),
+        );
+
+        Ok(item)
+    }
+
+    /// create a session for an admin to act as the target user, e.g. to reproduce a support ticket. it validates as
+    /// the target but records the admin, and lasts at most IMPERSONATION_CAP seconds. both are kept in the item's
+    /// meta, so the cap holds after a snapshot restore and on replicas
+    pub fn impersonate(&mut self, admin: &str, target: &str) -> Result<String> {
+        if admin == target {
+            bail!("{} can't impersonate themselves", admin);
+        }
+
+        let keep_alive = self.keep_alive_for(target).min(IMPERSONATION_CAP);
+        let ends = self.db.now().saturating_add(keep_alive).to_string();
+        let meta = [(IMPERSONATOR_META, admin), (IMPERSONATION_ENDS_META, &ends)];
+        // an impersonation never ends or is blocked by the target's own sessions
+        let code = self.generate_code();
+        self.create_session(&code, target, keep_alive, None, &meta)?;
+        logging::event(
+            "session.impersonate",
+            &[("admin", admin), ("user", target), ("code", &code)],
+        );
+        let kind = EventKind::Impersonated {
+            admin: admin.to_string(),
+        };
+        self.events.emit(
+            kind,
+            Store::Session,
+            Some(target),
+            Some(&code),
+            self.db.now(),
+        );
+
+        Ok(code)
+    }
+
+    /// return who is behind the session if it is an impersonation
+    pub fn impersonation(&self, code: &str, user: &str) -> Option<Impersonation> {
+        let item = self.db.get_ref(code, user)?;
+        let meta = item.meta();
+        Some(Impersonation {
+            admin: meta.get(IMPERSONATOR_META)?.clone(),
+            ends: meta.get(IMPERSONATION_ENDS_META)?.parse().ok()?,
+        })
+    }
+
+    /// create a user session that carries these scopes or roles, e.g. `["billing:read", "admin"]`, kept in the item's
+    /// meta. a scope can't be empty or contain whitespace
+    pub fn create_scoped_session(&mut self, user: &str, scopes: &[&str]) -> Result<String> {
+        if let Some(scope) = scopes
+            .iter()
+            .find(|scope| scope.is_empty() || scope.contains(char::is_whitespace))
+        {
+            bail!("invalid scope {:?}", scope);
+        }
+
+        let scopes: BTreeSet<&str> = scopes.iter().copied().collect();
+        let scopes = Vec::from_iter(scopes).join(" ");
+        let meta = [(SCOPES_META, scopes.as_str())];
+        let meta = if scopes.is_empty() {
+            &[][..]
+        } else {
+            &meta[..]
+        };
+        let code = self.generate_code();
+        let keep_alive = self.keep_alive_for(user);
+        self.create_session(&code, user, keep_alive, self.login_limit(), meta)
+    }
+
+    /// return the session's scopes, sorted; empty if it has none
+    pub fn scopes(&self, code: &str, user: &str) -> Vec<String> {
+        let mut scopes: Vec<String> = self
+            .meta(code, user, SCOPES_META)
+            .map(|scopes| scopes.split(' ').map(str::to_string).collect())
+            .unwrap_or_default();
+        scopes.sort();
+
+        scopes
+    }
+
+    /// return a single cookie value for a valid session: the code, the user encrypted with the cookie key and an hmac
+    /// of both, in unpadded base64url, so a web app needs one cookie and can't pair a code with the wrong user. the
+    /// code is readable by anyone holding the token, so only hand it to the session's own user
+    pub fn issue_cookie_token(&self, code: &str, user: &str) -> Result<String> {
+        self.token_item(code, user)?;
+        self.sign_token(vec![COOKIE_TOKEN], code, user)
+    }
+
+    /// return the session of a cookie token from issue_cookie_token; fails if the token was altered or signed with
+    /// another key, or its session is no longer valid. any store with the same cookie key can validate it
+    pub fn validate_cookie_token(&self, token: &str) -> Result<SessionItem> {
+        let bytes = base64url_decode(token).unwrap_or_default();
+        if bytes.len() <= 2 + USER_NONCE_LEN + DIGEST_LEN || bytes[0] != COOKIE_TOKEN {
+            bail!("malformed cookie token");
+        }
+        let (signed, mac) = bytes.split_at(bytes.len() - DIGEST_LEN);
+        let key = self.cookie_key.read().unwrap().0.clone();
+        if !verify_hmac_sha256(&key, signed, mac) {
+            bail!("cookie token signature does not match");
+        }
+
+        let (code, user) = read_session(&key, &signed[1..])?;
+        self.token_session(&code, &user)
+    }
+
+    /// return a token for a valid session that carries, signed, when it was issued and when the session expires, so
+    /// edge caches can reject expired tokens with ExpiringToken::read and the cookie key, without the store. the
+    /// expiry is the session's at issue, so reissue the token when the session is touched
+    pub fn issue_expiring_token(&self, code: &str, user: &str) -> Result<String> {
+        let item = self.token_item(code, user)?;
+        let mut token = vec![EXPIRING_TOKEN];
+        token.extend_from_slice(&self.db.now().to_be_bytes());
+        token.extend_from_slice(&item.expires.to_be_bytes());
+        self.sign_token(token, code, user)
+    }
+
+    /// return the session of a token from issue_expiring_token; an altered, foreign or expired token fails without
+    /// a lookup, and the store still rejects a session that was removed or locked
+    pub fn validate_expiring_token(&self, token: &str) -> Result<SessionItem> {
+        let read = ExpiringToken::read(&self.cookie_key.read().unwrap().0, token)?;
+        if read.has_expired_at(self.db.now()) {
+            bail!("session not valid: {}", Validation::Expired.as_str());
+        }
+
+        self.token_session(&read.code, &read.user)
+    }
+
+    // return the valid session a token is being issued for
+    fn token_item(&self, code: &str, user: &str) -> Result<SessionItem> {
+        let result = self.check(code, user);
+        match self.db.get(code, user).filter(|_| result.is_valid()) {
+            Some(item) => Ok(item),
+            None => bail!("session not valid: {}", result.as_str()),
+        }
+    }
+
+    // append the session and the hmac of the whole token under the cookie key, and encode it
+    fn sign_token(&self, mut token: Vec<u8>, code: &str, user: &str) -> Result<String> {
+        let key = self.cookie_key.read().unwrap();
+        push_session(&mut token, &key.0, code, user)?;
+        let mac = hmac_sha256(&key.0, &token);
+        token.extend_from_slice(&mac);
+        Ok(base64url(&token))
+    }
+
+    // return the session a signed token was issued for, if it is still valid
+    fn token_session(&self, code: &str, user: &str) -> Result<SessionItem> {
+        let result = self.validate(code, user);
+        match self.db.get(code, user).filter(|_| result.is_valid()) {
+            Some(item) => Ok(item),
+            None => bail!("session not valid: {}", result.as_str()),
+        }
+    }
+
+    /// sign new cookie and expiring tokens with this key, at least COOKIE_KEY_MIN bytes; tokens signed with the old
+    /// key stop validating
+    pub fn set_cookie_key(&self, key: &[u8]) -> Result<()> {
+        *self.cookie_key.write().unwrap() = CookieKey::new(key)?;
+        Ok(())
+    }
+
+    /// validate this session for the user and return true only if it also carries the scope
+    pub fn is_valid_for(&self, code: &str, user: &str, scope: &str) -> bool {
+        if !self.validate_sensitive(code, user).is_valid() {
+            return false;
+        }
+
+        let allowed = self
+            .meta(code, user, SCOPES_META)
+            .is_some_and(|scopes| scopes.split(' ').any(|s| s == scope));
+        logging::event(
+            "session.scope",
+            &[
+                ("user", user),
+                ("code", code),
+                ("scope", scope),
+                ("allowed", &allowed.to_string()),
+            ],
+        );
+
+        allowed
+    }
+
+    // create a session that lasts keep_alive seconds, within the limit of the user's sessions if given, ending the
+    // oldest to make room if the limit says so
+    fn create_session(
+        &mut self,
+        code: &str,
+        user: &str,
+        keep_alive: u64,
+        limit: Option<(usize, bool)>,
+        meta: &[(&str, &str)],
+    ) -> Result<String> {
+        let _span = metrics::span("session.create");
+        let start = Instant::now();
+        let ss = meta.iter().fold(
+            SessionItem::created_at(code, user, keep_alive, self.db.now()),
+            |item, (key, value)| item.with_meta(key, value),
+        );
+        self.put_limited(ss, limit)?;
+        self.record_activity(code, user);
+        logging::event("session.create", &[("user", user), ("code", code)]);
+        self.events.emit(
+            EventKind::Created,
+            Store::Session,
+            Some(user),
+            Some(code),
+            self.db.now(),
+        );
+        metrics::created(metrics::SESSION, self.db.dbsize());
+        self.stats.created();
+        self.stats.latency(Operation::Create, start.elapsed());
+
+        Ok(code.to_string())
+    }
+
+    // store the session, checking the user's other sessions against the limit and kicking any the policy evicts, all
+    // under one store lock
+    fn put_limited(&mut self, item: SessionItem, limit: Option<(usize, bool)>) -> Result<()> {
+        let user = item.user.clone();
+        let evicted = match limit {
+            Some((limit, evict)) => self.db.put_limited(item, limit, evict).map_err(|e| {
+                match e.downcast_ref::<LimitReached>() {
+                    Some(l) => anyhow!("{} already has {} sessions", l.user, l.held),
+                    None => e,
+                }
+            })?,
+            None => {
+                self.db.put(item)?;
+                Vec::new()
+            }
+        };
+        for old in evicted {
+            self.forget(|(c, u)| *c != old.code || *u != old.user);
+            logging::event("session.kick", &[("user", &user), ("code", &old.code)]);
+            self.events.emit(
+                EventKind::Removed,
+                Store::Session,
+                Some(&user),
+                Some(&old.code),
+                self.db.now(),
+            );
+            self.stats.removed(1);
+        }
+
+        Ok(())
+    }
+
+    /// create a short lived access session and a long lived refresh code for the user
+    pub fn create_token_pair(&mut self, user: &str) -> Result<TokenPair> {
+        self.issue_pair(user, None)
+    }
+
+    /// trade a refresh code for a new pair, ending the old access session. a code that was already traded revokes
+    /// its whole family and fails with a RefreshReused error, since someone else holds a copy
+    pub fn refresh(&mut self, refresh_code: &str) -> Result<TokenPair> {
+        match self.refresh.redeem(refresh_code, self.db.now()) {
+            Redeemed::Valid {
+                user,
+                family,
+                access,
+            } => {
+                self.remove(&access, &user);
+                self.issue_pair(&user, Some(family))
+            }
+            Redeemed::Expired => bail!("refresh code not valid: expired"),
+            Redeemed::NotFound => bail!("refresh code not valid: not_found"),
+            Redeemed::Reused { user, access } => {
+                let revoked = access
+                    .iter()
+                    .filter(|code| self.remove(code, &user).is_some())
+                    .count();
+                logging::event(
+                    "session.refresh_reused",
+                    &[("user", &user), ("revoked", &revoked.to_string())],
+                );
+                self.events.emit(
+                    EventKind::RefreshReused,
+                    Store::Session,
+                    Some(&user),
+                    None,
+                    self.db.now(),
+                );
+                Err(RefreshReused { user, revoked }.into())
+            }
+        }
+    }
+
+    // create an access session and its refresh code, in the family if given
+    fn issue_pair(&mut self, user: &str, family: Option<u64>) -> Result<TokenPair> {
+        let access_ttl = self.refresh.access_ttl();
+        // a new pair is a login; a refresh only replaces the access session it ends
+        let limit = match family {
+            None => self.login_limit(),
+            Some(_) => self.max_per_user().map(|max| (max, false)),
+        };
+        let access = self.generate_code();
+        self.create_session(&access, user, access_ttl, limit, &[])?;
+        // refresh codes stay hex whatever the id format, as only this store reads them
+        let refresh = random_hex(REFRESH_BYTES);
+        let now = self.db.now();
+        let refresh_expires = self.refresh.issue(&refresh, user, family, &access, now);
+
+        Ok(TokenPair {
+            user: user.to_string(),
+            access,
+            access_expires: now.saturating_add(access_ttl),
+            refresh,
+            refresh_expires,
+        })
+    }
+
+    /// return the refresh code registry; clones share it
+    pub fn refresh_tokens(&self) -> &RefreshTokens {
+        &self.refresh
+    }
+
+    /// store a session with a caller supplied code
+    pub fn put(&mut self, item: SessionItem) -> Result<()> {
+        logging::event("session.put", &[("user", &item.user), ("code", &item.code)]);
+        self.db.put(item.clone())?;
+        self.record_activity(&item.code, &item.user);
+        self.events.emit(
+            EventKind::Put,
+            Store::Session,
+            Some(&item.user),
+            Some(&item.code),
+            self.db.now(),
+        );
+
+        Ok(())
+    }
+
+    /// store the sessions under one lock, e.g. to import them from another store
+    pub fn put_many(&mut self, items: Vec<SessionItem>) -> Result<()> {
+        let count = items.len().to_string();
+        logging::event("session.put_many", &[("count", &count)]);
+        self.db.put_many(items.clone())?;
+        for item in items {
+            self.record_activity(&item.code, &item.user);
+            self.events.emit(
+                EventKind::Put,
+                Store::Session,
+                Some(&item.user),
+                Some(&item.code),
+                self.db.now(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// return the session item if it is still valid; a split session's selector alone is None, see get_split_token
+    pub fn get(&self, code: &str, user: &str) -> Option<SessionItem> {
+        self.db.get(code, user).filter(|item| !is_split_item(item))
+    }
+
+    /// return the items for the codes and users that are still valid, in the order asked; split sessions' selectors
+    /// are None
+    pub fn get_many(&self, keys: &[(&str, &str)]) -> Vec<Option<SessionItem>> {
+        let items = self.db.get_many(keys).into_iter();
+        items
+            .map(|item| item.filter(|item| !is_split_item(item)))
+            .collect()
+    }
+
+    /// extend a valid session to a full keep alive from now in one step, so concurrent touches can't race; return the
+    /// updated item. a split session's selector alone is None, see touch_split_token
+    pub fn touch(&mut self, code: &str, user: &str) -> Option<SessionItem> {
+        if self.is_split(code, user) {
+            return None;
+        }
+
+        self.extend(code, user)
+    }
+
+    // touch the session, split or not
+    fn extend(&mut self, code: &str, user: &str) -> Option<SessionItem> {
+        let mut ttl = self.keep_alive_for(user);
+        if let Some(impersonation) = self.impersonation(code, user) {
+            ttl = ttl.min(impersonation.ends.saturating_sub(self.db.now()));
+        }
+        let item = self.db.get_and_extend(code, user, ttl)?;
+        self.record_activity(code, user);
+        self.events.emit(
+            EventKind::Touched,
+            Store::Session,
+            Some(user),
+            Some(code),
+            self.db.now(),
+        );
+
+        Some(item)
+    }
+
+    /// return the default session keep alive in seconds
+    pub fn keep_alive(&self) -> u64 {
+        self.keep_alive.load(Ordering::Relaxed)
+    }
+
+    /// return the keep alive for the user's new sessions: their class policy if there is one, otherwise the default
+    pub fn keep_alive_for(&self, user: &str) -> u64 {
+        self.policies
+            .keep_alive(user)
+            .unwrap_or_else(|| self.keep_alive())
+    }
+
+    /// return the current unix time by the store's clock
+    pub fn now(&self) -> u64 {
+        self.db.now()
+    }
+
+    /// return the keep alive policies by user class; clones share them
+    pub fn ttl_policies(&self) -> &TtlPolicies {
+        &self.policies
+    }
+
+    /// set the keep alive for new sessions; existing sessions keep their expiration
+    pub fn set_keep_alive(&self, keep_alive: u64) {
+        self.keep_alive.store(keep_alive, Ordering::Relaxed);
     }
-}
 
-impl Session {
-    /// create a new session object
-    pub fn new() -> Session {
-        let db = DataStore::create();
-        let keep_alive = crate::SESSION_TIMEOUT;
+    /// validate this session for the given user
+    pub fn is_valid(&self, code: &str, user: &str) -> bool {
+        self.validate(code, user).is_valid()
+    }
 
-        Session { keep_alive, db }
+    /// validate this session for the given user and return why it is or is not valid
+    pub fn validate(&self, code: &str, user: &str) -> Validation {
+        let _span = metrics::span("session.validate");
+        let start = Instant::now();
+        let result = self.check(code, user);
+        self.validated(code, user, result, start)
     }
 
-    /// generate session id code
-    pub fn generate_code(&self) -> String {
-        let range = 1_000_000_000_000..10_000_000_000_000;
-        format!(
-            "{:x}{:x}",
-            fastrand::u64(range.clone()),
-            fastrand::u64(range)
-        )
+    /// validate this session and remove it if valid, so it can only be used once
+    pub fn consume(&mut self, code: &str, user: &str) -> Validation {
+        let _span = metrics::span("session.consume");
+        let start = Instant::now();
+        let item = self.db.get(code, user);
+        let result = match self.is_split(code, user) {
+            true => Validation::NotFound,
+            false => self.db.consume(code, user),
+        };
+        if let (Validation::Valid, Some(item)) = (result, item) {
+            self.stats
+                .ended(&item, self.keep_alive_for(user), self.db.now());
+            self.events.emit(
+                EventKind::Removed,
+                Store::Session,
+                Some(user),
+                Some(code),
+                self.db.now(),
+            );
+            metrics::removed(metrics::SESSION, self.db.dbsize());
+            self.stats.removed(1);
+        }
+        self.validated(code, user, result, start)
     }
 
-    /// create a user session and return the session code or error
-    pub fn create_user_session(&mut self, user: &str) -> Result<String> {
-        let code = self.generate_code();
-        debug!("user: {}, code: {}", user, &code);
+    // validate an ordinary session code: a split session's selector is NotFound without its verifier
+    fn check(&self, code: &str, user: &str) -> Validation {
+        let result = self.db.validate(code, user);
+        match result.is_valid() && self.is_split(code, user) {
+            true => Validation::NotFound,
+            false => result,
+        }
+    }
 
-        let ss = SessionItem::new(code.as_str(), user, self.keep_alive);
-        self.db.put(ss)?;
+    // whether the session was created by create_split_session
+    fn is_split(&self, code: &str, user: &str) -> bool {
+        let item = self.db.get_ref(code, user);
+        item.is_some_and(|item| item.meta().contains_key(VERIFIER_META))
+    }
 
-        Ok(code)
+    // log, emit and count a validation
+    fn validated(&self, code: &str, user: &str, result: Validation, start: Instant) -> Validation {
+        let valid = result.is_valid();
+        logging::event(
+            "session.validate",
+            &[("user", user), ("code", code), ("result", result.as_str())],
+        );
+        let kind = EventKind::Validated { ok: valid };
+        self.events
+            .emit(kind, Store::Session, Some(user), Some(code), self.db.now());
+        metrics::validated(metrics::SESSION, valid);
+        if !valid {
+            self.stats.failed();
+        }
+        self.stats.latency(Operation::Validate, start.elapsed());
+        result
     }
 
-    /// return true if the session is still valid
-    pub fn is_valid(&self, code: &str, user: &str) -> bool {
-        let resp = self.db.get(code, user);
-        resp.is_some()
+    /// return the seconds a session stays elevated
+    pub fn elevation_window(&self) -> u64 {
+        self.elevation.load(Ordering::Relaxed)
+    }
+
+    /// elevate a valid session for the elevation window, e.g. after the user re-enters an otp to change their payout
+    /// details; the elevation never outlasts the session. return when it decays
+    pub fn elevate(&self, code: &str, user: &str) -> Result<u64> {
+        self.elevate_for(code, user, self.elevation_window())
+    }
+
+    /// elevate a valid session for this many seconds; return when it decays
+    pub fn elevate_for(&self, code: &str, user: &str, window: u64) -> Result<u64> {
+        let result = self.check(code, user);
+        let Some(item) = self.db.get(code, user).filter(|_| result.is_valid()) else {
+            bail!("session not valid: {}", result.as_str());
+        };
+
+        let until = self.db.now().saturating_add(window).min(item.expires);
+        self.elevated
+            .write()
+            .unwrap()
+            .insert((code.to_string(), user.to_string()), until);
+        self.record_activity(code, user);
+        logging::event(
+            "session.elevate",
+            &[
+                ("user", user),
+                ("code", code),
+                ("until", &until.to_string()),
+            ],
+        );
+
+        Ok(until)
+    }
+
+    /// return when the session's elevation decays, if it is valid and still elevated
+    pub fn elevated_until(&self, code: &str, user: &str) -> Option<u64> {
+        let key = (code.to_string(), user.to_string());
+        let until = *self.elevated.read().unwrap().get(&key)?;
+        if until <= self.db.now() || !self.check(code, user).is_valid() {
+            return None;
+        }
+
+        Some(until)
+    }
+
+    /// return true if the session is valid and was elevated recently enough, to gate sensitive operations
+    pub fn is_elevated(&self, code: &str, user: &str) -> bool {
+        self.elevated_until(code, user).is_some()
+    }
+
+    /// end the session's elevation early, e.g. once the sensitive operation is done; return false if it was not
+    /// elevated
+    pub fn drop_elevation(&self, code: &str, user: &str) -> bool {
+        let key = (code.to_string(), user.to_string());
+        let until = self.elevated.write().unwrap().remove(&key);
+        until.is_some_and(|until| until > self.db.now())
+    }
+
+    /// return the seconds a session may be idle before sensitive operations need re-authentication; None never
+    /// downgrades
+    pub fn reauth_after(&self) -> Option<u64> {
+        match self.reauth_after.load(Ordering::Relaxed) {
+            0 => None,
+            seconds => Some(seconds),
+        }
+    }
+
+    /// change the re-auth window; applies to every session from now on
+    pub fn set_reauth_after(&self, seconds: Option<u64>) {
+        self.reauth_after
+            .store(seconds.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    // note that the session's user was active just now
+    fn record_activity(&self, code: &str, user: &str) {
+        let key = (code.to_string(), user.to_string());
+        self.active.write().unwrap().insert(key, self.db.now());
+    }
+
+    /// return when the session was last created, touched or elevated
+    pub fn last_active(&self, code: &str, user: &str) -> Option<u64> {
+        let key = (code.to_string(), user.to_string());
+        self.active.read().unwrap().get(&key).copied()
+    }
+
+    /// validate this session for a sensitive operation; a valid session idle for longer than the re-auth window is
+    /// NeedsReauth, which still allows reads. touching or elevating the session counts as activity. activity is only
+    /// known for sessions this store created, put, touched or elevated, so with a window set, a session that arrived
+    /// by replication or a restore, or outlived a restart, is NeedsReauth until it is touched here
+    pub fn validate_sensitive(&self, code: &str, user: &str) -> Validation {
+        let result = self.validate(code, user);
+        let Some(window) = self.reauth_after() else {
+            return result;
+        };
+        let now = self.db.now();
+        let active = self.last_active(code, user);
+        if !result.is_valid() || active.is_some_and(|active| now < active.saturating_add(window)) {
+            return result;
+        }
+
+        let idle = active.map_or("unknown".to_string(), |active| (now - active).to_string());
+        logging::event(
+            "session.needs_reauth",
+            &[("user", user), ("code", code), ("idle", &idle)],
+        );
+        Validation::NeedsReauth
+    }
+
+    /// return true if the session is valid but has been idle too long for sensitive operations
+    pub fn needs_reauth(&self, code: &str, user: &str) -> bool {
+        self.validate_sensitive(code, user) == Validation::NeedsReauth
+    }
+
+    /// lock the user so none of their sessions validate until unlocked; return false if already locked
+    pub fn lock_user(&self, user: &str) -> bool {
+        logging::event("session.lock_user", &[("user", user)]);
+        self.db.lock_user(user)
+    }
+
+    /// unlock the user; return false if they were not locked
+    pub fn unlock_user(&self, user: &str) -> bool {
+        logging::event("session.unlock_user", &[("user", user)]);
+        self.db.unlock_user(user)
     }
 
     /// remove the user session
     pub fn remove(&mut self, code: &str, user: &str) -> Option<String> {
-        debug!("remove user session: {}:{}", code, user);
+        let _span = metrics::span("session.remove");
+        let start = Instant::now();
+        let item = self.db.get(code, user);
         if self.db.remove(code, user) {
+            self.forget(|(c, u)| c != code || u != user);
+            if let Some(item) = item {
+                self.stats
+                    .ended(&item, self.keep_alive_for(user), self.db.now());
+            }
+            logging::event("session.remove", &[("user", user), ("code", code)]);
+            self.events.emit(
+                EventKind::Removed,
+                Store::Session,
+                Some(user),
+                Some(code),
+                self.db.now(),
+            );
+            metrics::removed(metrics::SESSION, self.db.dbsize());
+            self.stats.removed(1);
+            self.stats.latency(Operation::Remove, start.elapsed());
             Some(code.to_string())
         } else {
             None
         }
     }
 
+    /// remove the sessions for the codes and users under one lock, e.g. for a mass revocation; return the number
+    /// removed
+    pub fn remove_many(&mut self, keys: &[(&str, &str)]) -> usize {
+        let _span = metrics::span("session.remove_many");
+        let start = Instant::now();
+        let removed = self.db.remove_many(keys);
+        let gone: HashSet<SessionKey> = removed
+            .iter()
+            .map(|item| (item.code.clone(), item.user.clone()))
+            .collect();
+        self.forget(|key| !gone.contains(key));
+
+        let now = self.db.now();
+        for item in &removed {
+            self.stats.ended(item, self.keep_alive_for(&item.user), now);
+            self.events.emit(
+                EventKind::Removed,
+                Store::Session,
+                Some(&item.user),
+                Some(&item.code),
+                now,
+            );
+        }
+        let count = removed.len();
+        logging::event("session.remove_many", &[("count", &count.to_string())]);
+        metrics::removed(metrics::SESSION, self.db.dbsize());
+        self.stats.removed(count);
+        self.stats.latency(Operation::Remove, start.elapsed());
+        count
+    }
+
+    /// remove all of the user's sessions; return the number removed
+    pub fn remove_user(&mut self, user: &str) -> usize {
+        let count = self.db.remove_user(user);
+        self.forget(|(_, u)| u != user);
+        logging::event(
+            "session.remove_user",
+            &[("user", user), ("count", &count.to_string())],
+        );
+        let kind = EventKind::UserRemoved { count };
+        self.events
+            .emit(kind, Store::Session, Some(user), None, self.db.now());
+        self.stats.removed(count);
+        metrics::removed(metrics::SESSION, self.db.dbsize());
+        count
+    }
+
+    /// start clearing the store; return the token that confirms it, see DataStore::request_clear
+    pub fn request_clear(&self) -> String {
+        self.db.request_clear()
+    }
+
+    /// remove every session, confirmed by the token from request_clear; the clear is logged and emitted as an event
+    /// for the audit trail. return the number removed
+    pub fn clear(&mut self, token: &str) -> Result<usize> {
+        let count = self.db.clear(token)?;
+        self.forget(|_| false);
+        logging::event("session.clear", &[("count", &count.to_string())]);
+        let kind = EventKind::Cleared { count };
+        self.events
+            .emit(kind, Store::Session, None, None, self.db.now());
+        self.stats.removed(count);
+        metrics::removed(metrics::SESSION, 0);
+        Ok(count)
+    }
+
+    /// end all of the user's sessions at once by moving them to a new generation, without scanning the store; the old
+    /// sessions validate as revoked and are removed at the next purge. return the new generation
+    pub fn logout_everywhere(&mut self, user: &str) -> u64 {
+        let generation = self.db.bump_generation(user);
+        logging::event(
+            "session.logout_everywhere",
+            &[("user", user), ("generation", &generation.to_string())],
+        );
+        generation
+    }
+
+    /// return the user's session generation, bumped by each logout_everywhere
+    pub fn generation(&self, user: &str) -> u64 {
+        self.db.generation(user)
+    }
+
+    /// remove the expired sessions; return the number removed
+    pub fn purge_expired(&mut self) -> usize {
+        let _span = metrics::span("session.purge_expired");
+        let start = Instant::now();
+        let count = self.db.purge_expired();
+        self.refresh.purge_expired(self.db.now());
+        let now = self.db.now();
+        self.elevated
+            .write()
+            .unwrap()
+            .retain(|_, until| *until > now);
+        let db = self.db.clone();
+        self.forget(|(code, user)| db.get(code, user).is_some());
+        if count > 0 {
+            self.events.emit(
+                EventKind::Expired { count },
+                Store::Session,
+                None,
+                None,
+                self.db.now(),
+            );
+        }
+        self.stats.expired(count);
+        metrics::swept(metrics::SESSION, count, self.db.dbsize(), start.elapsed());
+        count
+    }
+
+    // drop the elevations and activity of the sessions not kept
+    fn forget<F: Fn(&SessionKey) -> bool>(&self, keep: F) {
+        self.elevated.write().unwrap().retain(|key, _| keep(key));
+        self.active.write().unwrap().retain(|key, _| keep(key));
+    }
+
+    /// return the active sessions, optionally filtered to a single user
+    pub fn list(&self, user: Option<&str>) -> Vec<SessionItem> {
+        match user {
+            Some(user) => self.db.scan_user(user),
+            None => self.db.list(),
+        }
+    }
+
+    /// return the totals since startup (or the last reset) and the lifetime and latency histograms; clones
+    /// share them
+    pub fn stats(&self) -> StoreStats {
+        self.stats.snapshot(self.db.dbsize())
+    }
+
+    /// zero the totals and clear the histograms
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// return the lifecycle event bus; clones share it
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// publish lifecycle events on this bus instead, e.g. to share one bus between otp and session
+    pub fn with_events(mut self, events: Events) -> Session {
+        self.events = events;
+        self
+    }
+
+    /// return a receiver for the session puts and removes, e.g. to replicate them to a peer
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<Change> {
+        self.db.subscribe()
+    }
+
+    /// apply a change from a peer; return true if the store changed
+    pub fn merge(&mut self, change: Change) -> bool {
+        self.db.merge(change)
+    }
+
+    /// return the changes that bring a peer up to date, as DataStore::replay does
+    pub fn replay(&self) -> Vec<Change> {
+        self.db.replay()
+    }
+
+    /// reject new sessions while true, e.g. while draining for shutdown
+    pub fn set_read_only(&self, read_only: bool) {
+        self.db.set_read_only(read_only);
+    }
+
+    /// return the health of the underlying store
+    pub fn health(&self) -> Health {
+        self.db.health()
+    }
+
     /// return the number of sessions currently in the database
     pub fn dbsize(&self) -> usize {
         self.db.dbsize()
     }
+
+    /// return the number of sessions that are still valid, leaving out expired and revoked ones waiting for a purge
+    pub fn dbsize_active(&self) -> usize {
+        self.db.dbsize_active()
+    }
+
+    /// return the number of valid sessions for users in the namespace, e.g. acme for acme:sally
+    pub fn dbsize_namespace(&self, namespace: &str) -> usize {
+        self.db.dbsize_namespace(namespace)
+    }
+
+    /// shrink the store to fit the sessions it holds, e.g. after purging a mass expiry
+    pub fn compact(&mut self) {
+        self.db.compact();
+    }
 }
 
 #[cfg(test)]
@@ -110,6 +1677,131 @@ mod tests {
         assert!(resp.is_none());
     }
 
+    #[test]
+    fn put_get() {
+        let mut session = create_session();
+        let code = session.generate_code();
+        let item = SessionItem::new(&code, "sally", session.keep_alive());
+        session.put(item).unwrap();
+
+        let item = session.get(&code, "sally").unwrap();
+        assert_eq!(item.code, code);
+        assert!(session.get(&code, "jack").is_none());
+    }
+
+    #[test]
+    fn batch() {
+        let mut session = create_session();
+        let items: Vec<SessionItem> = ["sally", "jack", "sammy"]
+            .iter()
+            .map(|user| SessionItem::new(&session.generate_code(), user, 60))
+            .collect();
+        session.put_many(items.clone()).unwrap();
+        let keys: Vec<(&str, &str)> = items
+            .iter()
+            .map(|item| (item.code.as_str(), item.user.as_str()))
+            .collect();
+        let found = session.get_many(&keys);
+        assert!(found.iter().all(|item| item.is_some()));
+        assert!(session.last_active(keys[0].0, "sally").is_some());
+
+        assert_eq!(session.remove_many(&keys[..2]), 2);
+        assert_eq!(session.remove_many(&keys[..2]), 0);
+        assert_eq!(
+            session.get_many(&keys),
+            [None, None, Some(items[2].clone())]
+        );
+        assert!(session.last_active(keys[0].0, "sally").is_none());
+        assert_eq!(session.stats().removed, 2);
+    }
+
+    #[test]
+    fn events() {
+        use crate::events::Event;
+        use std::sync::{Arc, Mutex};
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let mut session = create_session().with_events(Events::new());
+        session.events().subscribe(Arc::new(move |event: &Event| {
+            log.lock().unwrap().push(event.kind.clone());
+        }));
+
+        let code = session.create_user_session("sally").unwrap();
+        session.is_valid(&code, "jack");
+        session.remove(&code, "sally");
+
+        let expected = vec![
+            EventKind::Created,
+            EventKind::Validated { ok: false },
+            EventKind::Removed,
+        ];
+        assert_eq!(*seen.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn stats() {
+        let mut session = create_session();
+        let code = session.create_user_session("sally").unwrap();
+        session.is_valid(&code, "sally");
+        session.remove(&code, "sally");
+        session.remove(&code, "sally");
+
+        let stats = session.clone().stats();
+        assert_eq!(stats.create.count, 1);
+        assert_eq!(stats.validate.count, 1);
+        assert_eq!(stats.remove.count, 1);
+        assert_eq!(stats.lifetime.count, 1);
+        assert!(stats.lifetime.max < 10.0);
+        assert_eq!((stats.created, stats.removed, stats.active), (1, 1, 0));
+
+        session.is_valid(&code, "sally");
+        assert_eq!(session.stats().failed, 1);
+        session.reset_stats();
+        assert_eq!(session.stats().failed, 0);
+    }
+
+    #[test]
+    fn touch() {
+        let mut session = create_session();
+        let code = session.generate_code();
+        session.put(SessionItem::new(&code, "sally", 10)).unwrap();
+
+        let item = session.touch(&code, "sally").unwrap();
+        assert_eq!(session.get(&code, "sally"), Some(item));
+        assert!(
+            session.get(&code, "sally").unwrap().expires
+                > SessionItem::new(&code, "sally", 10).expires
+        );
+        assert!(session.touch(&code, "jack").is_none());
+    }
+
+    #[test]
+    fn remove_user() {
+        let mut session = create_session();
+        session.create_user_session("sally").unwrap();
+        session.create_user_session("sally").unwrap();
+        let code = session.create_user_session("jack").unwrap();
+
+        assert_eq!(session.remove_user("sally"), 2);
+        assert_eq!(session.dbsize(), 1);
+        assert!(session.is_valid(&code, "jack"));
+    }
+
+    #[test]
+    fn list() {
+        let mut session = create_session();
+        let code = session.create_user_session("sally").unwrap();
+        session.create_user_session("jack").unwrap();
+
+        assert_eq!(session.list(None).len(), 2);
+
+        let items = session.list(Some("sally"));
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].code, code);
+        assert!(session.list(Some("john")).is_empty());
+    }
+
     #[test]
     fn generate_code() {
         let session = create_session();
@@ -118,6 +1810,645 @@ mod tests {
         assert!(code.len() == 22);
     }
 
+    #[test]
+    fn id_format() {
+        let mut session = Session::builder()
+            .id_format(IdFormat::UuidV7)
+            .build()
+            .unwrap();
+        let code = session.create_user_session("sally").unwrap();
+        assert_eq!((code.len(), &code[14..15]), (36, "7"));
+        assert!(session.is_valid(&code, "sally"));
+
+        session.set_id_format(IdFormat::UuidV4);
+        assert_eq!(&session.generate_code()[14..15], "4");
+        assert_eq!("uuid_v7".parse::<IdFormat>().unwrap(), IdFormat::UuidV7);
+        session.set_id_format(IdFormat::Ulid);
+        let code = session.create_user_session("jack").unwrap();
+        assert!(code::decode_ulid(&code).is_some(), "{}", code);
+        assert!(session.is_valid(&code, "jack"));
+        assert!("uuid".parse::<IdFormat>().is_err());
+
+        session.set_id_format(IdFormat::Nanoid);
+        let code = session.create_user_session("jill").unwrap();
+        assert_eq!(code.len(), code::NANOID_LENGTH);
+        assert!(session.is_valid(&code, "jill"));
+        session
+            .set_nanoid(16, "0123456789abcdefghjkmnpqrstvwxyz")
+            .unwrap();
+        let code = session.generate_code();
+        assert_eq!(code.len(), 16);
+        assert!(!code.contains(|c: char| "ilou".contains(c)), "{}", code);
+        assert_eq!(session.nanoid().0, 16);
+    }
+
+    #[test]
+    fn nanoid_validation() {
+        assert!(validate_nanoid(code::NANOID_LENGTH, code::NANOID_ALPHABET).is_ok());
+        assert!(validate_nanoid(11, code::NANOID_ALPHABET).is_ok());
+        // 60 bits
+        assert!(validate_nanoid(10, code::NANOID_ALPHABET).is_err());
+        assert!(validate_nanoid(64, "01").is_ok());
+        assert!(validate_nanoid(NANOID_MAX_LENGTH + 1, "01").is_err());
+        assert!(validate_nanoid(64, "0").is_err());
+        assert!(validate_nanoid(32, "abca").is_err());
+        assert!(validate_nanoid(32, "ab/c").is_err());
+        assert!(validate_nanoid(32, "abcé").is_err());
+
+        let session = Session::new();
+        assert!(session.set_nanoid(4, code::NANOID_ALPHABET).is_err());
+        assert_eq!(session.nanoid().0, code::NANOID_LENGTH);
+    }
+
+    #[test]
+    fn builder() {
+        let events = Events::new();
+        let session = Session::builder()
+            .timeout(60)
+            .events(events.clone())
+            .build()
+            .unwrap();
+        assert_eq!(session.keep_alive(), 60);
+
+        events.subscribe(Arc::new(|_: &crate::events::Event| {}));
+        assert_eq!(session.events().len(), 1);
+    }
+
+    #[test]
+    fn ttl_policies() {
+        let policies = TtlPolicies::new();
+        policies.set("admin", 600).unwrap();
+        policies.set_classifier(|user| (user == "root").then(|| "admin".to_string()));
+        let mut session = Session::builder().ttl_policies(policies).build().unwrap();
+
+        assert_eq!(session.keep_alive_for("root"), 600);
+        assert_eq!(session.keep_alive_for("sally"), crate::SESSION_TIMEOUT);
+
+        let code = session.create_user_session("root").unwrap();
+        let expected = SessionItem::new(&code, "root", 600).expires;
+        assert!(session.get(&code, "root").unwrap().expires <= expected);
+    }
+
+    #[test]
+    fn clock() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .timeout(60)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let code = session.create_user_session("sally").unwrap();
+        assert_eq!(session.get(&code, "sally").unwrap().expires, 1_060);
+
+        clock.advance(std::time::Duration::from_secs(30));
+        session.touch(&code, "sally").unwrap();
+        clock.advance(std::time::Duration::from_secs(59));
+        assert!(session.is_valid(&code, "sally"));
+        clock.advance(std::time::Duration::from_secs(1));
+        assert!(!session.is_valid(&code, "sally"));
+        assert_eq!(session.purge_expired(), 1);
+    }
+
+    #[test]
+    fn token_pair() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .refresh_tokens(RefreshTokens::with_ttls(60, 3_600))
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let reused = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = reused.clone();
+        session
+            .events()
+            .subscribe(Arc::new(move |event: &crate::events::Event| {
+                if event.kind == EventKind::RefreshReused {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            }));
+
+        let pair = session.create_token_pair("sally").unwrap();
+        assert_eq!((pair.access_expires, pair.refresh_expires), (1_060, 4_600));
+        assert!(session.is_valid(&pair.access, "sally"));
+        assert_eq!(pair.refresh.len(), 2 * REFRESH_BYTES);
+
+        // the access session is short lived; the refresh code rotates both
+        clock.advance(std::time::Duration::from_secs(60));
+        assert!(!session.is_valid(&pair.access, "sally"));
+        let next = session.refresh(&pair.refresh).unwrap();
+        assert_ne!(next.refresh, pair.refresh);
+        assert!(session.is_valid(&next.access, "sally"));
+
+        // replaying the old refresh code revokes the family
+        let err = session.refresh(&pair.refresh).unwrap_err();
+        let err = err.downcast_ref::<RefreshReused>().unwrap();
+        assert_eq!((err.user.as_str(), err.revoked), ("sally", 1));
+        assert!(!session.is_valid(&next.access, "sally"));
+        assert!(session.refresh(&next.refresh).is_err());
+        assert_eq!(reused.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn elevate() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .timeout(600)
+            .elevation_window(120)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let code = session.create_user_session("sally").unwrap();
+        assert!(!session.is_elevated(&code, "sally"));
+        assert!(session.elevate("nope", "sally").is_err());
+
+        assert_eq!(session.elevate(&code, "sally").unwrap(), 1_120);
+        assert!(session.is_elevated(&code, "sally"));
+        assert!(!session.is_elevated(&code, "jack"));
+
+        // elevation decays on its own, and never outlasts the session
+        clock.set(1_120);
+        assert!(!session.is_elevated(&code, "sally"));
+        assert!(session.is_valid(&code, "sally"));
+        assert_eq!(session.elevate_for(&code, "sally", 3_600).unwrap(), 1_600);
+        session.lock_user("sally");
+        assert!(!session.is_elevated(&code, "sally"));
+        session.unlock_user("sally");
+        assert!(session.drop_elevation(&code, "sally"));
+        assert!(!session.is_elevated(&code, "sally"));
+    }
+
+    #[test]
+    fn scopes() {
+        let mut session = Session::new();
+        let code = session
+            .create_scoped_session("sally", &["payouts:write", "admin"])
+            .unwrap();
+        assert_eq!(
+            session.scopes(&code, "sally"),
+            vec!["admin", "payouts:write"]
+        );
+        assert!(session.is_valid_for(&code, "sally", "admin"));
+        assert!(!session.is_valid_for(&code, "sally", "billing:read"));
+        assert!(!session.is_valid_for(&code, "jack", "admin"));
+
+        let plain = session.create_user_session("sally").unwrap();
+        assert!(session.scopes(&plain, "sally").is_empty());
+        assert!(!session.is_valid_for(&plain, "sally", "admin"));
+
+        session.remove(&code, "sally");
+        assert!(!session.is_valid_for(&code, "sally", "admin"));
+        assert!(session.scopes(&code, "sally").is_empty());
+
+        for bad in ["", "billing read"] {
+            assert!(session.create_scoped_session("sally", &[bad]).is_err());
+        }
+    }
+
+    #[test]
+    fn bindings_live_in_meta() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let scoped = session.create_scoped_session("sally", &["admin"]).unwrap();
+        let bound = session
+            .create_user_session_from("sally", "10.0.0.0/8")
+            .unwrap();
+        session.bind_fingerprint(&bound, "sally", "laptop").unwrap();
+        let impersonation = session.impersonate("root", "jack").unwrap();
+
+        // a store restored from a snapshot, or a replica, has only the items
+        let mut restored = Session::builder()
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        restored.set_ip_binding(IpBinding::Strict);
+        for item in session.list(None) {
+            restored.put(item).unwrap();
+        }
+
+        assert!(restored.is_valid_for(&scoped, "sally", "admin"));
+        let outside = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            restored.validate_from(&bound, "sally", outside),
+            Validation::WrongNetwork
+        );
+        assert_eq!(
+            restored.validate_with(&bound, "sally", Some("phone")),
+            Validation::WrongFingerprint
+        );
+        assert_eq!(
+            restored
+                .impersonation(&impersonation, "jack")
+                .unwrap()
+                .admin,
+            "root"
+        );
+        clock.set(1_500);
+        assert_eq!(
+            restored.touch(&impersonation, "jack").unwrap().expires,
+            1_000 + IMPERSONATION_CAP
+        );
+    }
+
+    #[test]
+    fn ip_binding() {
+        let mut session = Session::new();
+        let code = session
+            .create_user_session_from("sally", "203.0.113.0/24")
+            .unwrap();
+        let home: IpAddr = "203.0.113.9".parse().unwrap();
+        let away: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(session.network(&code, "sally").unwrap().prefix(), 24);
+        assert!(session.create_user_session_from("sally", "home").is_err());
+
+        // off by default, so the network is recorded but not enforced
+        assert!(session.is_valid_from(&code, "sally", away));
+        session.set_ip_binding(IpBinding::Warn);
+        assert!(session.is_valid_from(&code, "sally", away));
+        session.set_ip_binding(IpBinding::Strict);
+        assert_eq!(
+            session.validate_from(&code, "sally", away),
+            Validation::WrongNetwork
+        );
+        assert!(session.is_valid_from(&code, "sally", home));
+
+        let unbound = session.create_user_session("sally").unwrap();
+        assert!(session.is_valid_from(&unbound, "sally", away));
+    }
+
+    #[test]
+    fn cookie_token() {
+        let key = [7; COOKIE_KEY_MIN];
+        let mut session = Session::builder().cookie_key(&key).build().unwrap();
+        let code = session.create_user_session("sally").unwrap();
+        assert!(session.issue_cookie_token("nope", "sally").is_err());
+        let token = session.issue_cookie_token(&code, "sally").unwrap();
+        assert!(!token.contains(|c: char| "+/=".contains(c)), "{}", token);
+        let bytes = base64url_decode(&token).unwrap();
+        assert!(!bytes.windows(5).any(|w| w == b"sally"), "{}", token);
+        let item = session.validate_cookie_token(&token).unwrap();
+        assert_eq!(
+            (item.code.as_str(), item.user.as_str()),
+            (code.as_str(), "sally")
+        );
+
+        // an altered token or one signed with another key is rejected
+        let mut altered = token.clone().into_bytes();
+        altered[0] = if altered[0] == b'A' { b'B' } else { b'A' };
+        let altered = String::from_utf8(altered).unwrap();
+        assert!(session.validate_cookie_token(&altered).is_err());
+        assert!(session.validate_cookie_token("abc").is_err());
+        let other = Session::builder().cookie_key(&[8; 40]).build().unwrap();
+        assert!(other.validate_cookie_token(&token).is_err());
+
+        // another store with the key and the session, e.g. after a restart or on a replica, takes the token
+        let mut restarted = Session::builder().cookie_key(&key).build().unwrap();
+        restarted.put(item.clone()).unwrap();
+        assert_eq!(restarted.validate_cookie_token(&token).unwrap(), item);
+        assert!(restarted.validate_expiring_token(&token).is_err());
+        assert!(Session::builder().cookie_key(b"short").build().is_err());
+
+        session.set_cookie_key(&[9; COOKIE_KEY_MIN]).unwrap();
+        assert!(session.validate_cookie_token(&token).is_err());
+        let token = session.issue_cookie_token(&code, "sally").unwrap();
+        assert!(session.validate_cookie_token(&token).is_ok());
+        assert!(session.set_cookie_key(b"short").is_err());
+
+        session.remove(&code, "sally");
+        let err = session.validate_cookie_token(&token).unwrap_err();
+        assert!(err.to_string().contains("revoked"), "{}", err);
+    }
+
+    #[test]
+    fn expiring_token() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let key = [7; COOKIE_KEY_MIN];
+        let mut session = Session::builder()
+            .timeout(60)
+            .cookie_key(&key)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let code = session.create_user_session("sally").unwrap();
+        let token = session.issue_expiring_token(&code, "sally").unwrap();
+        assert_eq!(
+            session.validate_expiring_token(&token).unwrap().user,
+            "sally"
+        );
+
+        // an edge with the key reads the times without the store
+        let read = ExpiringToken::read(&key, &token).unwrap();
+        assert_eq!(
+            (read.code.as_str(), read.issued, read.expires),
+            (code.as_str(), 1_000, 1_060)
+        );
+        assert_eq!(read.user, "sally");
+        assert!(!read.has_expired_at(1_059) && read.has_expired_at(1_060));
+        assert!(ExpiringToken::read(&[8; COOKIE_KEY_MIN], &token).is_err());
+
+        // cookie tokens and expiring tokens can't stand in for each other
+        let cookie = session.issue_cookie_token(&code, "sally").unwrap();
+        assert!(session.validate_expiring_token(&cookie).is_err());
+        assert!(session.validate_cookie_token(&token).is_err());
+
+        // the store stays authoritative for revocation
+        session.lock_user("sally");
+        assert!(session.validate_expiring_token(&token).is_err());
+        session.unlock_user("sally");
+        assert!(session.validate_expiring_token(&token).is_ok());
+
+        clock.advance(std::time::Duration::from_secs(60));
+        let err = session.validate_expiring_token(&token).unwrap_err();
+        assert!(err.to_string().contains("expired"), "{}", err);
+    }
+
+    #[test]
+    fn split_token() {
+        let mut session = create_session();
+        let token = session.create_split_session("sally").unwrap();
+        let (selector, verifier) = token.rsplit_once('.').unwrap();
+        assert_eq!(verifier.len(), 2 * VERIFIER_BYTES);
+        assert_eq!(
+            session.validate_split_token(&token, "sally"),
+            Validation::Valid
+        );
+
+        // the store keeps only the verifier's hash
+        let item = session.get_split_token(&token, "sally").unwrap();
+        assert_eq!(item.meta[VERIFIER_META], sha256_hex(verifier.as_bytes()));
+        assert!(!item.meta.values().any(|value| value == verifier));
+
+        let forged = format!("{}.{}", selector, "0".repeat(2 * VERIFIER_BYTES));
+        for (token, user) in [(&forged, "sally"), (&token, "jack")] {
+            assert_eq!(
+                session.validate_split_token(token, user),
+                Validation::NotFound
+            );
+        }
+        assert_eq!(
+            session.validate_split_token(selector, "sally"),
+            Validation::NotFound
+        );
+        let code = session.create_user_session("sally").unwrap();
+        assert_eq!(
+            session.validate_split_token(&code, "sally"),
+            Validation::NotFound
+        );
+
+        // the selector is not a session code, and the plain paths don't accept it alone
+        assert_ne!(selector, code);
+        assert_eq!(selector.len(), 2 * SELECTOR_BYTES);
+        assert!(!session.is_valid(selector, "sally"));
+        assert_eq!(session.validate(selector, "sally"), Validation::NotFound);
+        assert!(session.elevate(selector, "sally").is_err());
+        assert_eq!(session.consume(selector, "sally"), Validation::NotFound);
+        assert!(session.get(selector, "sally").is_none());
+        assert_eq!(session.get_many(&[(selector, "sally")]), vec![None]);
+        assert!(session.touch(selector, "sally").is_none());
+        assert!(session.get_split_token(&forged, "sally").is_none());
+        assert!(session.touch_split_token(&forged, "sally").is_none());
+        assert_eq!(
+            session.touch_split_token(&token, "sally").unwrap().code,
+            selector
+        );
+        assert_eq!(
+            session.validate_split_token(&token, "sally"),
+            Validation::Valid
+        );
+
+        session.lock_user("sally");
+        assert_eq!(
+            session.validate_split_token(&token, "sally"),
+            Validation::Locked
+        );
+        session.unlock_user("sally");
+        session.remove(selector, "sally");
+        assert_eq!(
+            session.validate_split_token(&token, "sally"),
+            Validation::NotFound
+        );
+    }
+
+    #[test]
+    fn fingerprint() {
+        let mut session = Session::new();
+        let code = session.create_user_session("sally").unwrap();
+        assert_eq!(
+            session.validate_with(&code, "sally", None),
+            Validation::Valid
+        );
+        assert!(session
+            .bind_fingerprint("nope", "sally", "firefox")
+            .is_err());
+
+        session
+            .bind_fingerprint(&code, "sally", "firefox/linux")
+            .unwrap();
+        assert!(session.has_fingerprint(&code, "sally"));
+        let valid = session.validate_with(&code, "sally", Some("firefox/linux"));
+        assert_eq!(valid, Validation::Valid);
+        let stolen = session.validate_with(&code, "sally", Some("curl/8.0"));
+        assert_eq!(stolen, Validation::WrongFingerprint);
+        assert_eq!(stolen.as_str(), "wrong_fingerprint");
+        assert_eq!(
+            session.validate_with(&code, "sally", None),
+            Validation::WrongFingerprint
+        );
+
+        session.remove(&code, "sally");
+        assert!(!session.has_fingerprint(&code, "sally"));
+        assert_eq!(
+            session.validate_with(&code, "sally", Some("firefox/linux")),
+            Validation::Revoked
+        );
+    }
+
+    #[test]
+    fn impersonate() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let admin = Arc::new(std::sync::Mutex::new(String::new()));
+        let seen = admin.clone();
+        session
+            .events()
+            .subscribe(Arc::new(move |event: &crate::events::Event| {
+                if let EventKind::Impersonated { admin } = &event.kind {
+                    *seen.lock().unwrap() = admin.clone();
+                }
+            }));
+
+        assert!(session.impersonate("root", "root").is_err());
+        let code = session.impersonate("root", "sally").unwrap();
+        assert!(session.is_valid(&code, "sally"));
+        assert_eq!(*admin.lock().unwrap(), "root");
+        let impersonation = session.impersonation(&code, "sally").unwrap();
+        assert_eq!(impersonation.admin, "root");
+        assert_eq!(impersonation.ends, 1_000 + IMPERSONATION_CAP);
+
+        // touching can't stretch it past the cap
+        clock.set(1_500);
+        assert_eq!(
+            session.touch(&code, "sally").unwrap().expires,
+            1_000 + IMPERSONATION_CAP
+        );
+        clock.set(1_000 + IMPERSONATION_CAP);
+        assert!(!session.is_valid(&code, "sally"));
+        session.purge_expired();
+        assert!(session.impersonation(&code, "sally").is_none());
+
+        let own = session.create_user_session("sally").unwrap();
+        assert!(session.impersonation(&own, "sally").is_none());
+    }
+
+    #[test]
+    fn guest() {
+        let mut session = Session::new();
+        let code = session.create_guest_session().unwrap();
+        assert!(session.is_valid(&code, GUEST));
+        assert!(!session.is_valid(&code, "sally"));
+        assert!(session.bind_user(&code, GUEST).is_err());
+
+        let item = session.bind_user(&code, "sally").unwrap();
+        assert_eq!(item.code, code);
+        assert!(session.is_valid(&code, "sally"));
+        assert!(!session.is_valid(&code, GUEST));
+        let err = session.bind_user(&code, "jack").unwrap_err();
+        assert_eq!(err.to_string(), "guest session not valid: consumed");
+        assert!(session.bind_user("nope", "jack").is_err());
+
+        // binding is a login, so the policy applies; a refused guest keeps their session
+        session.set_login_policy(LoginPolicy::Deny);
+        let code = session.create_guest_session().unwrap();
+        let err = session.bind_user(&code, "sally").unwrap_err();
+        assert_eq!(err.to_string(), "sally already has 1 sessions");
+        assert!(session.is_valid(&code, GUEST));
+
+        session.set_login_policy(LoginPolicy::KickOldest);
+        session.bind_user(&code, "sally").unwrap();
+        assert!(session.is_valid(&code, "sally"));
+        assert!(!session.is_valid(&item.code, "sally"));
+        assert_eq!(session.list(Some("sally")).len(), 1);
+    }
+
+    #[test]
+    fn login_policy() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .login_policy(LoginPolicy::Deny)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let first = session.create_user_session("sally").unwrap();
+        let err = session.create_user_session("sally").unwrap_err();
+        assert_eq!(err.to_string(), "sally already has 1 sessions");
+        session.create_user_session("jack").unwrap();
+
+        // kicking keeps the newest max_per_user sessions
+        session.set_login_policy(LoginPolicy::KickOldest);
+        session.set_max_per_user(Some(2));
+        clock.set(1_010);
+        let second = session.create_user_session("sally").unwrap();
+        clock.set(1_020);
+        let third = session.create_user_session("sally").unwrap();
+        assert_eq!(session.validate(&first, "sally"), Validation::Revoked);
+        assert!(session.is_valid(&second, "sally") && session.is_valid(&third, "sally"));
+        assert_eq!(session.list(Some("sally")).len(), 2);
+
+        session.set_max_per_user(None);
+        session.create_user_session("sally").unwrap();
+        assert_eq!(session.list(Some("sally")).len(), 1);
+        assert_eq!(session.list(Some("jack")).len(), 1);
+    }
+
+    #[test]
+    fn reauth() {
+        let clock = crate::clock::MockClock::at(1_000);
+        let mut session = Session::builder()
+            .reauth_after(600)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        assert_eq!(session.reauth_after(), Some(600));
+        let code = session
+            .create_scoped_session("sally", &["billing"])
+            .unwrap();
+        assert_eq!(session.last_active(&code, "sally"), Some(1_000));
+        assert_eq!(
+            session.validate_sensitive(&code, "sally"),
+            Validation::Valid
+        );
+
+        // idle sessions still read, but scoped checks fail until the user is active again
+        clock.set(1_600);
+        let result = session.validate_sensitive(&code, "sally");
+        assert_eq!(result, Validation::NeedsReauth);
+        assert!(result.allows_read() && !result.is_valid());
+        assert!(session.is_valid(&code, "sally"));
+        assert!(!session.is_valid_for(&code, "sally", "billing"));
+        session.elevate(&code, "sally").unwrap();
+        assert!(session.is_valid_for(&code, "sally", "billing"));
+
+        clock.set(2_300);
+        assert!(session.needs_reauth(&code, "sally"));
+        session.touch(&code, "sally").unwrap();
+        assert!(!session.needs_reauth(&code, "sally"));
+
+        // a replicated session has no activity here, so it must re-authenticate until touched
+        let item = SessionItem::created_at("abc123", "jack", 3_600, 2_300);
+        let generation = 0;
+        assert!(session.merge(Change::Put { item, generation }));
+        assert!(session.is_valid("abc123", "jack"));
+        assert!(session.needs_reauth("abc123", "jack"));
+        session.touch("abc123", "jack").unwrap();
+        assert!(!session.needs_reauth("abc123", "jack"));
+        session.set_reauth_after(None);
+        clock.set(9_000);
+        assert_eq!(
+            session.validate_sensitive(&code, "sally"),
+            Validation::Valid
+        );
+        assert_eq!(
+            session.validate_sensitive("nope", "sally"),
+            Validation::NotFound
+        );
+    }
+
+    #[test]
+    fn logout_everywhere() {
+        let mut session = create_session();
+        let first = session.create_user_session("sally").unwrap();
+        let second = session.create_user_session("sally").unwrap();
+        let other = session.create_user_session("jack").unwrap();
+        assert_eq!(session.generation("sally"), 0);
+
+        assert_eq!(session.logout_everywhere("sally"), 1);
+        assert_eq!(session.validate(&first, "sally"), Validation::Revoked);
+        assert!(!session.is_valid(&second, "sally"));
+        assert!(session.list(Some("sally")).is_empty());
+        assert!(session.is_valid(&other, "jack"));
+
+        // new sessions belong to the new generation
+        let third = session.create_user_session("sally").unwrap();
+        assert!(session.is_valid(&third, "sally"));
+        assert_eq!(session.purge_expired(), 0);
+        assert_eq!(session.list(None).len(), 2);
+        assert_eq!(session.validate(&second, "sally"), Validation::Revoked);
+    }
+
+    #[test]
+    fn max_per_user() {
+        let mut session = Session::builder().max_per_user(1).build().unwrap();
+        assert_eq!(session.max_per_user(), Some(1));
+        session.create_user_session("sally").unwrap();
+        assert!(session.create_user_session("sally").is_err());
+        assert!(Session::builder().max_per_user(0).build().is_err());
+    }
+
     #[test]
     fn create() {
         let session = create_session();
@@ -0,0 +1,406 @@
+/// sms OtpSenders: twilio, or any http gateway that takes a json post. numbers must be E.164, e.g. +14155550123
+use crate::clock::{Clock, SystemClock};
+use crate::hash::base64;
+use crate::sender::{Delivery, DeliveryState, OtpSender, SendContext};
+use crate::template::{Channel, Template, Templates};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the twilio rest api base
+pub const TWILIO_API: &str = "https://api.twilio.com/2010-04-01";
+
+/// the timeout for each send
+pub const SMS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// why an sms was not sent; the OtpSender error downcasts to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmsError {
+    /// the recipient is not an E.164 number, or the provider says it is not valid
+    InvalidNumber(String),
+    /// the provider rejected the credentials
+    Unauthorized,
+    /// too many messages; try again later
+    RateLimited,
+    /// the recipient opted out, e.g. replied STOP
+    Blocked,
+    /// the number can't receive sms, e.g. a landline or an unsupported region
+    Undeliverable,
+    /// the provider could not be reached or failed
+    Unavailable(String),
+    /// any other provider error
+    Rejected {
+        status: u16,
+        code: Option<u64>,
+        message: String,
+    },
+}
+
+impl fmt::Display for SmsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmsError::InvalidNumber(number) => write!(f, "{} is not a valid phone number", number),
+            SmsError::Unauthorized => write!(f, "sms provider rejected the credentials"),
+            SmsError::RateLimited => write!(f, "sms provider rate limited the request"),
+            SmsError::Blocked => write!(f, "recipient has opted out of sms"),
+            SmsError::Undeliverable => write!(f, "number can't receive sms"),
+            SmsError::Unavailable(reason) => write!(f, "sms provider unavailable: {}", reason),
+            SmsError::Rejected {
+                status, message, ..
+            } => write!(
+                f,
+                "sms provider rejected the message ({}): {}",
+                status, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SmsError {}
+
+/// return true if the number is E.164: a plus, then 7 to 15 digits not starting with zero
+pub fn is_e164(number: &str) -> bool {
+    let Some(digits) = number.strip_prefix('+') else {
+        return false;
+    };
+    (7..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+// map a twilio error response to an SmsError; see https://www.twilio.com/docs/api/errors
+fn twilio_error(status: u16, code: Option<u64>, message: &str, to: &str) -> SmsError {
+    match (status, code) {
+        (_, Some(21211 | 21614)) => SmsError::InvalidNumber(to.to_string()),
+        (_, Some(21610)) => SmsError::Blocked,
+        (_, Some(21408 | 21612)) => SmsError::Undeliverable,
+        (401, _) | (_, Some(20003)) => SmsError::Unauthorized,
+        (429, _) | (_, Some(20429)) => SmsError::RateLimited,
+        (500.., _) => SmsError::Unavailable(format!("status {}", status)),
+        _ => SmsError::Rejected {
+            status,
+            code,
+            message: message.to_string(),
+        },
+    }
+}
+
+/// map a twilio message status, e.g. from a status callback's MessageStatus, to a delivery state
+pub fn twilio_status(status: &str) -> Option<DeliveryState> {
+    match status {
+        "accepted" | "scheduled" | "queued" | "sending" => Some(DeliveryState::Queued),
+        "sent" => Some(DeliveryState::Sent),
+        "delivered" | "read" => Some(DeliveryState::Delivered),
+        "undelivered" | "failed" | "canceled" => Some(DeliveryState::Failed),
+        _ => None,
+    }
+}
+
+// map a gateway status to an SmsError; gateways only give us the status
+fn gateway_error(status: u16, body: &str, to: &str) -> SmsError {
+    match status {
+        400 | 422 => SmsError::InvalidNumber(to.to_string()),
+        401 | 403 => SmsError::Unauthorized,
+        429 => SmsError::RateLimited,
+        500.. => SmsError::Unavailable(format!("status {}", status)),
+        _ => SmsError::Rejected {
+            status,
+            code: None,
+            message: body.to_string(),
+        },
+    }
+}
+
+#[derive(Clone)]
+enum Provider {
+    Twilio {
+        account_sid: String,
+        auth_token: String,
+        /// a sending number, or a messaging service sid starting with MG
+        from: String,
+    },
+    Gateway {
+        url: String,
+        token: Option<String>,
+    },
+}
+
+/// sends otps by sms. the recipient is the context's `phone` or `to` field if set, otherwise the user
+#[derive(Clone)]
+pub struct SmsSender {
+    provider: Provider,
+    agent: ureq::Agent,
+    status_callback: Option<String>,
+    templates: Templates,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for SmsSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let provider = match &self.provider {
+            Provider::Twilio { account_sid, .. } => format!("twilio {}", account_sid),
+            Provider::Gateway { url, .. } => format!("gateway {}", url),
+        };
+        f.debug_struct("SmsSender")
+            .field("provider", &provider)
+            .finish()
+    }
+}
+
+impl SmsSender {
+    /// send through twilio from a number or a messaging service sid
+    pub fn twilio(account_sid: &str, auth_token: &str, from: &str) -> SmsSender {
+        SmsSender::with_provider(Provider::Twilio {
+            account_sid: account_sid.to_string(),
+            auth_token: auth_token.to_string(),
+            from: from.to_string(),
+        })
+    }
+
+    /// send through a gateway that accepts a json post of `{"to", "body"}`, with a bearer token if given
+    pub fn gateway(url: &str, token: Option<&str>) -> SmsSender {
+        SmsSender::with_provider(Provider::Gateway {
+            url: url.to_string(),
+            token: token.map(|token| token.to_string()),
+        })
+    }
+
+    fn with_provider(provider: Provider) -> SmsSender {
+        SmsSender {
+            provider,
+            agent: ureq::AgentBuilder::new().timeout(SMS_TIMEOUT).build(),
+            status_callback: None,
+            templates: Templates::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// render messages from these templates, e.g. ones shared with the email sender
+    pub fn with_templates(mut self, templates: Templates) -> SmsSender {
+        self.templates = templates;
+        self
+    }
+
+    /// use this message instead of the built-in one
+    pub fn with_template(mut self, template: &str) -> SmsSender {
+        self.templates = self.templates.with(Channel::Sms, Template::body(template));
+        self
+    }
+
+    /// ask twilio to post status changes to this url; pass its MessageSid and MessageStatus, via twilio_status, to
+    /// Otp::update_delivery
+    pub fn with_status_callback(mut self, url: &str) -> SmsSender {
+        self.status_callback = Some(url.to_string());
+        self
+    }
+
+    /// use this clock for the minutes left in the message instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> SmsSender {
+        self.clock = clock;
+        self
+    }
+
+    /// return the message that would be sent for the code
+    pub fn render(&self, user: &str, code: &str, context: &SendContext) -> String {
+        let (_, body) = self
+            .templates
+            .render(Channel::Sms, user, code, context, self.clock.now());
+        body
+    }
+
+    // post to twilio's messages resource as a form; return the message sid
+    fn send_twilio(
+        &self,
+        account_sid: &str,
+        auth_token: &str,
+        from: &str,
+        to: &str,
+        body: &str,
+    ) -> Result<Option<String>, SmsError> {
+        let url = format!("{}/Accounts/{}/Messages.json", TWILIO_API, account_sid);
+        let auth = format!(
+            "Basic {}",
+            base64(format!("{}:{}", account_sid, auth_token).as_bytes())
+        );
+        let from_key = if from.starts_with("MG") {
+            "MessagingServiceSid"
+        } else {
+            "From"
+        };
+        let mut form = vec![("To", to), (from_key, from), ("Body", body)];
+        if let Some(callback) = &self.status_callback {
+            form.push(("StatusCallback", callback.as_str()));
+        }
+        let result = self
+            .agent
+            .post(&url)
+            .set("Authorization", &auth)
+            .send_form(&form);
+
+        match result {
+            Ok(response) => Ok(message_id(response, "sid")),
+            Err(ureq::Error::Status(status, response)) => {
+                let text = response.into_string().unwrap_or_default();
+                let error: Value = serde_json::from_str(&text).unwrap_or_default();
+                let message = error["message"].as_str().unwrap_or(&text);
+                Err(twilio_error(status, error["code"].as_u64(), message, to))
+            }
+            Err(e) => Err(SmsError::Unavailable(e.to_string())),
+        }
+    }
+
+    // post the message to the gateway as json; return the message id if the gateway replies with one
+    fn send_gateway(
+        &self,
+        url: &str,
+        token: Option<&str>,
+        to: &str,
+        body: &str,
+    ) -> Result<Option<String>, SmsError> {
+        let mut request = self.agent.post(url).set("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        let payload = json!({ "to": to, "body": body }).to_string();
+
+        match request.send_string(&payload) {
+            Ok(response) => Ok(message_id(response, "id")),
+            Err(ureq::Error::Status(status, response)) => {
+                let text = response.into_string().unwrap_or_default();
+                Err(gateway_error(status, &text, to))
+            }
+            Err(e) => Err(SmsError::Unavailable(e.to_string())),
+        }
+    }
+}
+
+// read the message id from a provider's json reply
+fn message_id(response: ureq::Response, key: &str) -> Option<String> {
+    let text = response.into_string().ok()?;
+    let reply: Value = serde_json::from_str(&text).ok()?;
+    reply[key].as_str().map(|id| id.to_string())
+}
+
+impl OtpSender for SmsSender {
+    fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()> {
+        self.deliver(user, code, context).map(|_| ())
+    }
+
+    fn deliver(&self, user: &str, code: &str, context: &SendContext) -> Result<Delivery> {
+        let to = context.recipient(Channel::Sms, user);
+        if !is_e164(to) {
+            return Err(SmsError::InvalidNumber(to.to_string()).into());
+        }
+
+        let body = self.render(user, code, context);
+        let message_id = match &self.provider {
+            Provider::Twilio {
+                account_sid,
+                auth_token,
+                from,
+            } => self.send_twilio(account_sid, auth_token, from, to, &body)?,
+            Provider::Gateway { url, token } => {
+                self.send_gateway(url, token.as_deref(), to, &body)?
+            }
+        };
+
+        Ok(Delivery {
+            channel: Some(Channel::Sms),
+            attempts: 1,
+            state: DeliveryState::Sent,
+            message_id,
+            ..Default::default()
+        })
+    }
+
+    fn channel(&self) -> Option<Channel> {
+        Some(Channel::Sms)
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<SmsError>(),
+            Some(SmsError::RateLimited | SmsError::Unavailable(_))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn e164() {
+        assert!(is_e164("+14155550123"));
+        assert!(is_e164("+442071838750"));
+        assert!(!is_e164("14155550123"));
+        assert!(!is_e164("+0155550123"));
+        assert!(!is_e164("+1 415 555 0123"));
+        assert!(!is_e164("+1234"));
+        assert!(!is_e164("+1234567890123456"));
+
+        let sender = SmsSender::gateway("http://localhost:9000/sms", None);
+        let context = SendContext::new("login").with("to", "555-0123");
+        let err = sender.send("sally", "123456", &context).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SmsError>(),
+            Some(&SmsError::InvalidNumber("555-0123".to_string()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let to = "+14155550123";
+        assert_eq!(
+            twilio_error(400, Some(21211), "invalid To", to),
+            SmsError::InvalidNumber(to.to_string())
+        );
+        assert_eq!(twilio_error(400, Some(21610), "", to), SmsError::Blocked);
+        assert_eq!(
+            twilio_error(401, Some(20003), "", to),
+            SmsError::Unauthorized
+        );
+        assert_eq!(twilio_error(429, None, "", to), SmsError::RateLimited);
+        assert!(matches!(
+            twilio_error(503, None, "", to),
+            SmsError::Unavailable(_)
+        ));
+        let err = twilio_error(400, Some(21602), "Message body is required", to);
+        assert_eq!(
+            err.to_string(),
+            "sms provider rejected the message (400): Message body is required"
+        );
+        assert_eq!(gateway_error(403, "", to), SmsError::Unauthorized);
+        assert_eq!(twilio_status("sending"), Some(DeliveryState::Queued));
+        assert_eq!(twilio_status("delivered"), Some(DeliveryState::Delivered));
+        assert_eq!(twilio_status("undelivered"), Some(DeliveryState::Failed));
+        assert_eq!(twilio_status("lost"), None);
+        assert_eq!(
+            gateway_error(422, "", to),
+            SmsError::InvalidNumber(to.to_string())
+        );
+    }
+
+    #[test]
+    fn render() {
+        let sender = SmsSender::twilio("AC123", "secret", "+15005550006")
+            .with_clock(Arc::new(MockClock::at(1_000)));
+        let mut context = SendContext::new("login");
+        context.expires = 1_300;
+        assert_eq!(
+            sender.render("sally", "123456", &context),
+            "Your code is 123456. It expires in 5 minutes."
+        );
+        let sender = sender.with_template("{app}: {code}");
+        let context = context.with("app", "Acme");
+        assert_eq!(sender.render("sally", "123456", &context), "Acme: 123456");
+        let sender = sender.with_templates(Templates::new("Initech"));
+        assert!(sender
+            .render("sally", "123456", &context)
+            .starts_with("Your code"));
+        assert!(!format!("{:?}", sender).contains("secret"));
+    }
+}
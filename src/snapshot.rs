@@ -0,0 +1,477 @@
+/// point in time snapshots of the otp and session stores, persisted as json (or bincode with the bincode feature)
+use crate::clock::unix_now;
+use crate::config::Config;
+use crate::db::SessionItem;
+use crate::events::Store;
+use crate::otp::Otp;
+use crate::session::Session;
+use anyhow::{anyhow, bail, Result};
+use hashbrown::HashMap;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// the current snapshot file format version
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// the snapshot encodings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    /// compact binary, requires the bincode feature
+    Bincode,
+}
+
+impl Format {
+    /// return the format name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Bincode => "bincode",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "bincode" => Ok(Format::Bincode),
+            _ => Err(anyhow!("unknown snapshot format: {}", s)),
+        }
+    }
+}
+
+/// how an import treats items already in the stores
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// keep the existing items; an imported item only replaces one with the same code and user that expires sooner
+    #[default]
+    Merge,
+    /// remove every existing item first
+    Overwrite,
+}
+
+impl Strategy {
+    /// return the strategy name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strategy::Merge => "merge",
+            Strategy::Overwrite => "overwrite",
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "merge" => Ok(Strategy::Merge),
+            "overwrite" => Ok(Strategy::Overwrite),
+            _ => Err(anyhow!("unknown import strategy: {}", s)),
+        }
+    }
+}
+
+/// why a prune removed an item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneReason {
+    Expired,
+    /// not expired, but older than the prune's maximum age
+    Stale,
+}
+
+impl PruneReason {
+    /// return the reason name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PruneReason::Expired => "expired",
+            PruneReason::Stale => "stale",
+        }
+    }
+}
+
+/// an item removed by a prune
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pruned {
+    pub store: Store,
+    pub reason: PruneReason,
+    pub item: SessionItem,
+}
+
+// split off the items to prune; an item's age is estimated as the timeout less the time it has left
+fn prune_items(
+    items: &mut Vec<SessionItem>,
+    store: Store,
+    timeout: u64,
+    max_age: Option<u64>,
+    now: u64,
+) -> Vec<Pruned> {
+    let mut pruned = Vec::new();
+    items.retain(|item| {
+        let reason = if item.has_expired_at(now) {
+            PruneReason::Expired
+        } else {
+            let age = timeout.saturating_sub(item.expires - now);
+            match max_age {
+                Some(max_age) if age >= max_age => PruneReason::Stale,
+                _ => return true,
+            }
+        };
+
+        let item = item.clone();
+        pruned.push(Pruned {
+            store,
+            reason,
+            item,
+        });
+        false
+    });
+
+    pruned
+}
+
+// put the items, skipping expired ones and, when merging, any the store already has with a later expiry
+fn import_items<F>(items: &[SessionItem], existing: Vec<SessionItem>, mut put: F) -> Result<usize>
+where
+    F: FnMut(SessionItem) -> Result<()>,
+{
+    let existing: HashMap<(String, String), u64> = existing
+        .into_iter()
+        .map(|item| ((item.code, item.user), item.expires))
+        .collect();
+
+    let mut count = 0;
+    for item in items.iter().filter(|item| !item.has_expired()) {
+        let key = (item.code.clone(), item.user.clone());
+        if existing
+            .get(&key)
+            .map_or(true, |expires| *expires < item.expires)
+        {
+            put(item.clone())?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+// a version 1 item, before items carried meta
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+struct ItemV1 {
+    code: String,
+    user: String,
+    expires: u64,
+}
+
+// a version 1 snapshot, to read older bincode files
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+struct SnapshotV1 {
+    version: u32,
+    created: u64,
+    otp: Vec<ItemV1>,
+    session: Vec<ItemV1>,
+}
+
+#[cfg(feature = "bincode")]
+impl From<SnapshotV1> for Snapshot {
+    fn from(old: SnapshotV1) -> Snapshot {
+        let items = |items: Vec<ItemV1>| {
+            items
+                .into_iter()
+                .map(|item| SessionItem {
+                    code: item.code,
+                    user: item.user,
+                    expires: item.expires,
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        Snapshot {
+            version: old.version,
+            created: old.created,
+            otp: items(old.otp),
+            session: items(old.session),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created: u64,
+    pub otp: Vec<SessionItem>,
+    pub session: Vec<SessionItem>,
+}
+
+impl Snapshot {
+    /// capture the active items from both stores
+    pub fn capture(otp: &Otp, session: &Session) -> Snapshot {
+        let created = unix_now();
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            created,
+            otp: otp.list(None),
+            session: session.list(None),
+        }
+    }
+
+    /// write the snapshot to path; the file is replaced atomically
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            self.write(&mut writer, Format::Json)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+
+        fs::rename(&tmp, path)?;
+        info!(
+            "saved snapshot to {:?}: {} otp, {} session",
+            path,
+            self.otp.len(),
+            self.session.len()
+        );
+
+        Ok(())
+    }
+
+    /// read a snapshot from path
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot> {
+        Snapshot::read(BufReader::new(File::open(path)?), Format::Json)
+    }
+
+    /// encode the snapshot to the writer
+    pub fn write<W: Write>(&self, writer: W, format: Format) -> Result<()> {
+        match format {
+            Format::Json => serde_json::to_writer(writer, self)?,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => bincode::serialize_into(writer, self)?,
+            #[cfg(not(feature = "bincode"))]
+            Format::Bincode => bail!("bincode snapshots require the bincode feature"),
+        }
+
+        Ok(())
+    }
+
+    /// decode a snapshot from the reader
+    pub fn read<R: Read>(reader: R, format: Format) -> Result<Snapshot> {
+        let snapshot: Snapshot = match format {
+            Format::Json => serde_json::from_reader(reader)?,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => {
+                let mut bytes = Vec::new();
+                let mut reader = reader;
+                reader.read_to_end(&mut bytes)?;
+                // bincode can't skip missing fields, so older versions, written first as a little endian u32, are
+                // decoded with their own layout
+                match bytes.get(..4) {
+                    Some([1, 0, 0, 0]) => {
+                        bincode::deserialize_from::<_, SnapshotV1>(bytes.as_slice())?.into()
+                    }
+                    _ => bincode::deserialize_from(bytes.as_slice())?,
+                }
+            }
+            #[cfg(not(feature = "bincode"))]
+            Format::Bincode => bail!("bincode snapshots require the bincode feature"),
+        };
+        if snapshot.version > SNAPSHOT_VERSION {
+            bail!("unsupported snapshot version: {}", snapshot.version);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// put the items that have not expired into the stores; return the number restored
+    pub fn restore(&self, otp: &mut Otp, session: &mut Session) -> Result<usize> {
+        let mut count = 0;
+        for item in self.otp.iter().filter(|item| !item.has_expired()) {
+            otp.put(item.clone())?;
+            count += 1;
+        }
+
+        for item in self.session.iter().filter(|item| !item.has_expired()) {
+            session.put(item.clone())?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// remove the items that have expired by now and, with a max_age in seconds, those older than it, estimating an
+    /// item's age from the configured timeouts; return the removed items
+    pub fn prune(&mut self, now: u64, max_age: Option<u64>, config: &Config) -> Vec<Pruned> {
+        let mut pruned = prune_items(&mut self.otp, Store::Otp, config.otp.timeout, max_age, now);
+        pruned.extend(prune_items(
+            &mut self.session,
+            Store::Session,
+            config.session.timeout,
+            max_age,
+            now,
+        ));
+
+        pruned
+    }
+
+    /// put the items that have not expired into the stores using the strategy; return the number imported
+    pub fn import(
+        &self,
+        otp: &mut Otp,
+        session: &mut Session,
+        strategy: Strategy,
+    ) -> Result<usize> {
+        if strategy == Strategy::Overwrite {
+            for item in otp.list(None) {
+                otp.remove(&item.code, &item.user);
+            }
+            for item in session.list(None) {
+                session.remove(&item.code, &item.user);
+            }
+            otp.purge_expired();
+            session.purge_expired();
+        }
+
+        let count = import_items(&self.otp, otp.list(None), |item| otp.put(item))?;
+        let count =
+            count + import_items(&self.session, session.list(None), |item| session.put(item))?;
+        info!(
+            "imported {} items from a snapshot ({})",
+            count,
+            strategy.as_str()
+        );
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_restore() {
+        let mut otp = Otp::new();
+        let mut session = Session::new();
+        let code = otp.create_user_otp("sally").unwrap();
+        session.create_user_session("sally").unwrap();
+        session.create_user_session("jack").unwrap();
+
+        let mut snapshot = Snapshot::capture(&otp, &session);
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        assert_eq!(snapshot.otp.len(), 1);
+        assert_eq!(snapshot.session.len(), 2);
+
+        // expired items are skipped
+        snapshot.session[1].expires = 0;
+
+        let mut otp = Otp::new();
+        let mut session = Session::new();
+        assert_eq!(snapshot.restore(&mut otp, &mut session).unwrap(), 2);
+        assert!(otp.is_valid(&code, "sally"));
+        assert_eq!(session.dbsize(), 1);
+    }
+
+    #[test]
+    fn import() {
+        let mut otp = Otp::new();
+        let mut session = Session::new();
+        let kept = session.create_user_session("sally").unwrap();
+        let mut snapshot = Snapshot::capture(&otp, &session);
+        snapshot.session[0].expires -= 10;
+        snapshot
+            .session
+            .push(SessionItem::new("abc123", "jack", 600));
+        otp.create_user_otp("jack").unwrap();
+
+        // the existing session expires later so only jack's is added
+        let count = snapshot
+            .import(&mut otp, &mut session, Strategy::Merge)
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!((otp.dbsize(), session.dbsize()), (1, 2));
+        assert!(session.is_valid(&kept, "sally"));
+
+        session.create_user_session("bob").unwrap();
+        let count = snapshot
+            .import(&mut otp, &mut session, Strategy::Overwrite)
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!((otp.dbsize(), session.dbsize()), (0, 2));
+        assert_eq!(
+            "overwrite".parse::<Strategy>().unwrap(),
+            Strategy::Overwrite
+        );
+        assert!("replace".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn prune() {
+        let config = Config::default();
+        let mut snapshot = Snapshot {
+            otp: vec![SessionItem::created_at("100000", "sally", 300, 1_000)],
+            session: vec![
+                SessionItem::created_at("abc", "sally", config.session.timeout, 1_000),
+                SessionItem::created_at("def", "jack", config.session.timeout, 5_000),
+            ],
+            ..Default::default()
+        };
+
+        // at 6_000 the otp has expired, sally's session is 5_000 seconds old and jack's 1_000
+        let mut copy = snapshot.clone();
+        let pruned = copy.prune(6_000, None, &config);
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(
+            (pruned[0].store, pruned[0].reason),
+            (Store::Otp, PruneReason::Expired)
+        );
+
+        let pruned = snapshot.prune(6_000, Some(3_600), &config);
+        assert_eq!(pruned.len(), 2);
+        assert_eq!(pruned[1].reason, PruneReason::Stale);
+        assert_eq!(pruned[1].item.code, "abc");
+        assert_eq!(snapshot.session.len(), 1);
+    }
+
+    #[test]
+    fn save_load() {
+        let mut session = Session::new();
+        let code = session.create_user_session("sally").unwrap();
+        let snapshot = Snapshot::capture(&Otp::new(), &session);
+
+        let path = std::env::temp_dir().join(format!("otp-session-{}.json", fastrand::u64(..)));
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.session.len(), 1);
+        assert_eq!(loaded.session[0].code, code);
+    }
+}
@@ -0,0 +1,426 @@
+/// in-process histograms of item lifetimes and operation latency, reported by `stats()` and mirrored to the
+/// metrics exporters
+use crate::db::SessionItem;
+use crate::metrics;
+use crate::otp::Otp;
+use crate::session::Session;
+use hashbrown::HashMap;
+use otp_session_core::expiry;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// latency bucket upper bounds in seconds
+pub const LATENCY_BOUNDS: &[f64] = &[
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1,
+];
+
+/// lifetime bucket upper bounds in seconds
+pub const LIFETIME_BOUNDS: &[f64] = &[
+    10.0, 30.0, 60.0, 300.0, 900.0, 1_800.0, 3_600.0, 7_200.0, 14_400.0, 28_800.0, 86_400.0,
+];
+
+/// the timed store operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Create,
+    Validate,
+    Remove,
+}
+
+impl Operation {
+    /// return the operation name used as the metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Validate => "validate",
+            Operation::Remove => "remove",
+        }
+    }
+}
+
+/// a point in time copy of a histogram
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    /// bucket upper bounds; values above the last bound are counted in a final overflow bucket
+    pub bounds: Vec<f64>,
+    /// observations per bucket, one more than the bounds
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum: f64,
+    pub max: f64,
+}
+
+impl HistogramSnapshot {
+    /// return the mean observation, or zero if there are none
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+
+    /// return the upper bound of the bucket holding the q quantile (0.0..=1.0); the overflow bucket
+    /// reports the largest observation
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self.bounds.get(i).copied().unwrap_or(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// return the snapshot as a json object
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"count":{},"sum":{},"mean":{},"p50":{},"p90":{},"p99":{},"max":{}}}"#,
+            self.count,
+            self.sum,
+            self.mean(),
+            self.quantile(0.5),
+            self.quantile(0.9),
+            self.quantile(0.99),
+            self.max
+        )
+    }
+}
+
+/// a fixed bucket histogram; clones share the same counts
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    data: Arc<Mutex<HistogramSnapshot>>,
+}
+
+impl Histogram {
+    /// create an empty histogram with these ascending bucket bounds
+    pub fn new(bounds: &[f64]) -> Histogram {
+        let data = HistogramSnapshot {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            ..Default::default()
+        };
+
+        Histogram {
+            data: Arc::new(Mutex::new(data)),
+        }
+    }
+
+    /// record an observation
+    pub fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        let bucket = data.bounds.partition_point(|bound| *bound < value);
+        data.counts[bucket] += 1;
+        data.count += 1;
+        data.sum += value;
+        if value > data.max {
+            data.max = value;
+        }
+    }
+
+    /// return a copy of the current counts
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// clear the counts
+    pub fn reset(&self) {
+        let mut data = self.data.lock().unwrap();
+        *data = HistogramSnapshot {
+            bounds: data.bounds.clone(),
+            counts: vec![0; data.counts.len()],
+            ..Default::default()
+        };
+    }
+}
+
+/// totals since startup or the last reset, and the lifetime and latency histograms for one store, as returned by
+/// `stats()`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub created: u64,
+    /// items currently in the store
+    pub active: usize,
+    /// items removed by a sweep after they expired
+    pub expired: u64,
+    /// items removed by remove or remove_user
+    pub removed: u64,
+    /// validations that failed
+    pub failed: u64,
+    /// seconds from creation (or the last touch) to removal
+    pub lifetime: HistogramSnapshot,
+    pub create: HistogramSnapshot,
+    pub validate: HistogramSnapshot,
+    pub remove: HistogramSnapshot,
+}
+
+impl StoreStats {
+    /// return the stats as a json object
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"created":{},"active":{},"expired":{},"removed":{},"failed":{},"lifetime":{},"create":{},"validate":{},"remove":{}}}"#,
+            self.created,
+            self.active,
+            self.expired,
+            self.removed,
+            self.failed,
+            self.lifetime.to_json(),
+            self.create.to_json(),
+            self.validate.to_json(),
+            self.remove.to_json()
+        )
+    }
+
+    /// return the fraction of validations that failed, or zero if there were none
+    pub fn failure_rate(&self) -> f64 {
+        if self.validate.count == 0 {
+            0.0
+        } else {
+            self.failed as f64 / self.validate.count as f64
+        }
+    }
+}
+
+/// the number of active items a user holds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserCount {
+    pub user: String,
+    pub count: usize,
+}
+
+/// return the users holding the most items, busiest first (ties by name), at most limit of them
+pub fn top_users(items: &[SessionItem], limit: usize) -> Vec<UserCount> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for item in items {
+        *counts.entry(item.user.as_str()).or_default() += 1;
+    }
+
+    let mut users: Vec<UserCount> = counts
+        .into_iter()
+        .map(|(user, count)| UserCount {
+            user: user.to_string(),
+            count,
+        })
+        .collect();
+    users.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.user.cmp(&b.user)));
+    users.truncate(limit);
+    users
+}
+
+/// both stores' stats and the users with the most active sessions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub otp: StoreStats,
+    pub session: StoreStats,
+    pub top_users: Vec<UserCount>,
+}
+
+impl StatsReport {
+    /// gather the stats and the top limit session users
+    pub fn collect(otp: &Otp, session: &Session, limit: usize) -> StatsReport {
+        StatsReport {
+            otp: otp.stats(),
+            session: session.stats(),
+            top_users: top_users(&session.list(None), limit),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    created: AtomicU64,
+    expired: AtomicU64,
+    removed: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// the counters and histograms for one store; clones share them
+#[derive(Debug, Clone)]
+pub(crate) struct Stats {
+    store: &'static str,
+    counters: Arc<Counters>,
+    lifetime: Histogram,
+    create: Histogram,
+    validate: Histogram,
+    remove: Histogram,
+}
+
+impl Stats {
+    /// create empty histograms labeled with the store name
+    pub(crate) fn new(store: &'static str) -> Stats {
+        Stats {
+            store,
+            counters: Arc::new(Counters::default()),
+            lifetime: Histogram::new(LIFETIME_BOUNDS),
+            create: Histogram::new(LATENCY_BOUNDS),
+            validate: Histogram::new(LATENCY_BOUNDS),
+            remove: Histogram::new(LATENCY_BOUNDS),
+        }
+    }
+
+    /// count a created item
+    pub(crate) fn created(&self) {
+        self.counters.created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count removed items
+    pub(crate) fn removed(&self, count: usize) {
+        self.counters
+            .removed
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// count items swept after they expired
+    pub(crate) fn expired(&self, count: usize) {
+        self.counters
+            .expired
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// count a failed validation
+    pub(crate) fn failed(&self) {
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// record the time an operation took
+    pub(crate) fn latency(&self, op: Operation, elapsed: Duration) {
+        let histogram = match op {
+            Operation::Create => &self.create,
+            Operation::Validate => &self.validate,
+            Operation::Remove => &self.remove,
+        };
+        histogram.observe(elapsed.as_secs_f64());
+        metrics::latency(self.store, op.as_str(), elapsed);
+    }
+
+    /// record how long an item lived before it was removed
+    pub(crate) fn lifetime(&self, seconds: u64) {
+        self.lifetime.observe(seconds as f64);
+        metrics::lifetime(self.store, seconds);
+    }
+
+    /// record the lifetime of an item being removed, estimated from the time it had left of its keep alive
+    pub(crate) fn ended(&self, item: &SessionItem, keep_alive: u64, now: u64) {
+        let remaining = expiry::remaining(item.expires, now);
+        self.lifetime(keep_alive.saturating_sub(remaining));
+    }
+
+    /// return a snapshot of the counters and histograms; active is the current store size
+    pub(crate) fn snapshot(&self, active: usize) -> StoreStats {
+        let counters = &self.counters;
+        StoreStats {
+            created: counters.created.load(Ordering::Relaxed),
+            active,
+            expired: counters.expired.load(Ordering::Relaxed),
+            removed: counters.removed.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+            lifetime: self.lifetime.snapshot(),
+            create: self.create.snapshot(),
+            validate: self.validate.snapshot(),
+            remove: self.remove.snapshot(),
+        }
+    }
+
+    /// zero the counters and clear the histograms
+    pub(crate) fn reset(&self) {
+        let counters = &self.counters;
+        for counter in [
+            &counters.created,
+            &counters.expired,
+            &counters.removed,
+            &counters.failed,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for histogram in [&self.lifetime, &self.create, &self.validate, &self.remove] {
+            histogram.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new(&[1.0, 10.0, 100.0]);
+        for value in [0.5, 2.0, 3.0, 10.0, 500.0] {
+            histogram.observe(value);
+        }
+
+        let snapshot = histogram.clone().snapshot();
+        assert_eq!(snapshot.counts, vec![1, 3, 0, 1]);
+        assert_eq!(snapshot.count, 5);
+        assert_eq!(snapshot.mean(), 103.1);
+        assert_eq!(snapshot.quantile(0.5), 10.0);
+        assert_eq!(snapshot.quantile(0.0), 1.0);
+        assert_eq!(snapshot.quantile(1.0), 500.0);
+        assert_eq!(Histogram::new(&[1.0]).snapshot().quantile(0.5), 0.0);
+    }
+
+    #[test]
+    fn top() {
+        let items: Vec<SessionItem> = [("a", "jack"), ("b", "sally"), ("c", "jack"), ("d", "bob")]
+            .iter()
+            .map(|(code, user)| SessionItem::new(code, user, 60))
+            .collect();
+
+        let users = top_users(&items, 2);
+        assert_eq!(users.len(), 2);
+        assert_eq!((users[0].user.as_str(), users[0].count), ("jack", 2));
+        assert_eq!((users[1].user.as_str(), users[1].count), ("bob", 1));
+    }
+
+    #[test]
+    fn stats() {
+        let stats = Stats::new(metrics::SESSION);
+        let shared = stats.clone();
+        shared.latency(Operation::Validate, Duration::from_micros(20));
+        shared.ended(
+            &SessionItem::created_at("abc123", "sally", 180, 1_000),
+            300,
+            1_000,
+        );
+        shared.created();
+        shared.removed(2);
+        shared.failed();
+
+        let snapshot = stats.snapshot(4);
+        assert_eq!(
+            (snapshot.created, snapshot.removed, snapshot.failed),
+            (1, 2, 1)
+        );
+        assert_eq!(snapshot.active, 4);
+        assert_eq!(snapshot.validate.count, 1);
+        assert_eq!(snapshot.validate.quantile(0.5), 0.000_05);
+        assert_eq!(snapshot.failure_rate(), 1.0);
+        assert_eq!(snapshot.lifetime.quantile(0.5), 300.0);
+        assert_eq!(snapshot.create.count, 0);
+        assert!(snapshot.to_json().starts_with(
+            r#"{"created":1,"active":4,"expired":0,"removed":2,"failed":1,"lifetime":{"count":1,"#
+        ));
+
+        stats.reset();
+        assert_eq!(
+            stats.snapshot(0),
+            StoreStats {
+                lifetime: Histogram::new(LIFETIME_BOUNDS).snapshot(),
+                create: Histogram::new(LATENCY_BOUNDS).snapshot(),
+                validate: Histogram::new(LATENCY_BOUNDS).snapshot(),
+                remove: Histogram::new(LATENCY_BOUNDS).snapshot(),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,235 @@
+/// a statsd/dogstatsd sink for the store metrics, sent over udp
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use std::net::UdpSocket;
+use std::sync::Arc;
+
+/// where and how to send the metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsdConfig {
+    /// statsd agent address, e.g. 127.0.0.1:8125
+    pub addr: String,
+    /// prepended to every metric name with a dot
+    pub prefix: Option<String>,
+    /// tags added to every metric
+    pub tags: Vec<(String, String)>,
+    /// send dogstatsd tags and histograms; plain statsd folds the labels into the metric name
+    pub dogstatsd: bool,
+}
+
+impl StatsdConfig {
+    /// create a plain statsd config for the agent at addr
+    pub fn new(addr: &str) -> StatsdConfig {
+        StatsdConfig {
+            addr: addr.to_string(),
+            prefix: None,
+            tags: Vec::new(),
+            dogstatsd: false,
+        }
+    }
+
+    /// prefix every metric name
+    pub fn with_prefix(mut self, prefix: &str) -> StatsdConfig {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// add a tag to every metric
+    pub fn with_tag(mut self, key: &str, value: &str) -> StatsdConfig {
+        self.tags.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// use the dogstatsd format
+    pub fn with_dogstatsd(mut self) -> StatsdConfig {
+        self.dogstatsd = true;
+        self
+    }
+
+    // the metric name and tag suffix for a key
+    fn format(&self, key: &Key) -> (String, String) {
+        let mut name = match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, key.name()),
+            None => key.name().to_string(),
+        };
+
+        let labels = key.labels().map(|l| (l.key(), l.value()));
+        let tags = self.tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        if !self.dogstatsd {
+            for (_, value) in labels {
+                name = format!("{}.{}", name, value);
+            }
+            return (name, String::new());
+        }
+
+        let tags: Vec<String> = tags
+            .chain(labels)
+            .map(|(k, v)| format!("{}:{}", k, v))
+            .collect();
+        let suffix = if tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", tags.join(","))
+        };
+
+        (name, suffix)
+    }
+}
+
+#[derive(Debug)]
+struct Metric {
+    socket: Arc<UdpSocket>,
+    name: String,
+    tags: String,
+    dogstatsd: bool,
+}
+
+impl Metric {
+    fn send(&self, value: &str, kind: &str) {
+        let line = format!("{}:{}|{}{}", self.name, value, kind, self.tags);
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("statsd send failed: {}", e);
+        }
+    }
+}
+
+impl CounterFn for Metric {
+    fn increment(&self, value: u64) {
+        self.send(&value.to_string(), "c");
+    }
+
+    fn absolute(&self, _value: u64) {}
+}
+
+impl GaugeFn for Metric {
+    fn increment(&self, value: f64) {
+        self.send(&format!("+{}", value), "g");
+    }
+
+    fn decrement(&self, value: f64) {
+        self.send(&format!("-{}", value), "g");
+    }
+
+    fn set(&self, value: f64) {
+        self.send(&value.to_string(), "g");
+    }
+}
+
+impl HistogramFn for Metric {
+    // plain statsd has no histogram type, so send the value as a timer in milliseconds
+    fn record(&self, value: f64) {
+        if self.dogstatsd {
+            self.send(&value.to_string(), "h");
+        } else {
+            self.send(&(value * 1000.0).to_string(), "ms");
+        }
+    }
+}
+
+/// a `metrics` recorder that sends each update to a statsd agent
+#[derive(Debug)]
+pub struct StatsdRecorder {
+    config: StatsdConfig,
+    socket: Arc<UdpSocket>,
+}
+
+impl StatsdRecorder {
+    /// bind a local udp socket for the agent in the config
+    pub fn new(config: StatsdConfig) -> Result<StatsdRecorder> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(&config.addr)?;
+
+        Ok(StatsdRecorder {
+            config,
+            socket: Arc::new(socket),
+        })
+    }
+
+    fn metric(&self, key: &Key) -> Arc<Metric> {
+        let (name, tags) = self.config.format(key);
+        Arc::new(Metric {
+            socket: Arc::clone(&self.socket),
+            name,
+            tags,
+            dogstatsd: self.config.dogstatsd,
+        })
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.metric(key))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.metric(key))
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(self.metric(key))
+    }
+}
+
+/// install the statsd recorder as the global `metrics` recorder
+pub fn install(config: StatsdConfig) -> Result<()> {
+    let addr = config.addr.clone();
+    metrics::set_global_recorder(StatsdRecorder::new(config)?)
+        .map_err(|_| anyhow!("a metrics recorder is already installed"))?;
+    info!("sending statsd metrics to {}", addr);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::Label;
+
+    fn create_key() -> Key {
+        let labels = vec![Label::new("store", "otp"), Label::new("result", "ok")];
+        Key::from_parts(crate::metrics::VALIDATIONS, labels)
+    }
+
+    #[test]
+    fn format() {
+        let config = StatsdConfig::new("127.0.0.1:8125").with_prefix("auth");
+        let (name, tags) = config.format(&create_key());
+        assert_eq!(name, "auth.otp_session_validations_total.otp.ok");
+        assert_eq!(tags, "");
+
+        let config = config.with_dogstatsd().with_tag("env", "prod");
+        let (name, tags) = config.format(&create_key());
+        assert_eq!(name, "auth.otp_session_validations_total");
+        assert_eq!(tags, "|#env:prod,store:otp,result:ok");
+    }
+
+    #[test]
+    fn send() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = agent.local_addr().unwrap().to_string();
+        let config = StatsdConfig::new(&addr).with_dogstatsd();
+        let recorder = StatsdRecorder::new(config).unwrap();
+
+        let metadata = Metadata::new("test", metrics::Level::INFO, None);
+        let counter = recorder.register_counter(&create_key(), &metadata);
+        counter.increment(2);
+
+        let mut buf = [0; 256];
+        let n = agent.recv(&mut buf).unwrap();
+        let line = std::str::from_utf8(&buf[..n]).unwrap();
+        assert_eq!(
+            line,
+            "otp_session_validations_total:2|c|#store:otp,result:ok"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+/// the common session store interface for embedded and remote stores
+use crate::db::SessionItem;
+use crate::session::Session;
+use anyhow::Result;
+
+/// session operations shared by the embedded Session and remote clients
+pub trait SessionStore {
+    /// create a user session and return the session code
+    fn create_user_session(&mut self, user: &str) -> Result<String>;
+
+    /// return true if the session is still valid
+    fn is_valid(&self, code: &str, user: &str) -> Result<bool>;
+
+    /// remove the user session; return the code if it was removed
+    fn remove(&mut self, code: &str, user: &str) -> Result<Option<String>>;
+
+    /// return the active sessions, optionally filtered to a single user
+    fn list(&self, user: Option<&str>) -> Result<Vec<SessionItem>>;
+
+    /// remove the sessions for the codes and users, e.g. for a mass revocation; return the number removed.
+    /// stores override it to do them all at once
+    fn remove_many(&mut self, keys: &[(&str, &str)]) -> Result<usize> {
+        let mut count = 0;
+        for (code, user) in keys {
+            if self.remove(code, user)?.is_some() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// return the number of sessions in the store
+    fn dbsize(&self) -> Result<usize>;
+}
+
+impl SessionStore for Session {
+    fn create_user_session(&mut self, user: &str) -> Result<String> {
+        Session::create_user_session(self, user)
+    }
+
+    fn is_valid(&self, code: &str, user: &str) -> Result<bool> {
+        Ok(Session::is_valid(self, code, user))
+    }
+
+    fn remove(&mut self, code: &str, user: &str) -> Result<Option<String>> {
+        Ok(Session::remove(self, code, user))
+    }
+
+    fn list(&self, user: Option<&str>) -> Result<Vec<SessionItem>> {
+        Ok(Session::list(self, user))
+    }
+
+    fn remove_many(&mut self, keys: &[(&str, &str)]) -> Result<usize> {
+        Ok(Session::remove_many(self, keys))
+    }
+
+    fn dbsize(&self) -> Result<usize> {
+        Ok(Session::dbsize(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise<S: SessionStore>(store: &mut S) {
+        let user = "sally";
+        let code = store.create_user_session(user).unwrap();
+        assert!(store.is_valid(&code, user).unwrap());
+        assert_eq!(store.list(Some(user)).unwrap().len(), 1);
+        assert_eq!(store.dbsize().unwrap(), 1);
+
+        assert_eq!(store.remove(&code, user).unwrap(), Some(code.clone()));
+        assert!(!store.is_valid(&code, user).unwrap());
+        assert!(store.remove(&code, user).unwrap().is_none());
+
+        let first = store.create_user_session(user).unwrap();
+        let second = store.create_user_session("jack").unwrap();
+        let keys = [
+            (first.as_str(), user),
+            (second.as_str(), "jack"),
+            ("nope", user),
+        ];
+        assert_eq!(store.remove_many(&keys).unwrap(), 2);
+        assert_eq!(store.dbsize().unwrap(), 0);
+    }
+
+    #[test]
+    fn session_store() {
+        let mut session = Session::new();
+        exercise(&mut session);
+    }
+}
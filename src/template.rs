@@ -0,0 +1,337 @@
+/// message templates for otp delivery: a minimal `{placeholder}` engine and per-channel, per-purpose and per-locale
+/// templates so apps can change the wording without replacing the senders
+use crate::sender::SendContext;
+use hashbrown::HashMap;
+use otp_session_core::expiry;
+
+/// how a message is delivered; each channel has its own templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Email,
+    Sms,
+    /// a phone call that reads the message aloud
+    Voice,
+}
+
+impl Channel {
+    /// return the name used in logs, e.g. email
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Email => "email",
+            Channel::Sms => "sms",
+            Channel::Voice => "voice",
+        }
+    }
+}
+
+/// a message subject and body; sms and voice ignore the subject
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    pub subject: String,
+    pub body: String,
+}
+
+impl Template {
+    /// create a template from its subject and body
+    pub fn new(subject: &str, body: &str) -> Template {
+        Template {
+            subject: subject.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    /// create a template with only a body, e.g. for sms
+    pub fn body(body: &str) -> Template {
+        Template::new("", body)
+    }
+
+    /// return the built-in template for the channel
+    pub fn default_for(channel: Channel) -> Template {
+        match channel {
+            Channel::Email => Template::new(
+                "Your code is {code}",
+                concat!(
+                    "Your one-time code is {code}. It expires in {minutes} minutes.\n\n",
+                    "If you didn't ask for it, you can ignore this email.\n"
+                ),
+            ),
+            Channel::Sms => Template::body("Your code is {code}. It expires in {minutes} minutes."),
+            Channel::Voice => {
+                Template::body("Your code is {digits}. Again, your code is {digits}.")
+            }
+        }
+    }
+}
+
+/// the locale unlocalized and built-in templates are written in
+pub const DEFAULT_LOCALE: &str = "en";
+
+// a template's channel, locale and purpose; a None locale is unlocalized and a None purpose is the default
+type TemplateKey = (Channel, Option<String>, Option<String>);
+
+/// the templates for each channel, locale and purpose, and the app name for `{app}`; the built-in templates don't
+/// use it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Templates {
+    app: String,
+    default_locale: String,
+    templates: HashMap<TemplateKey, Template>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates::new("")
+    }
+}
+
+impl Templates {
+    /// create the built-in templates for the app, e.g. "Acme"
+    pub fn new(app: &str) -> Templates {
+        Templates {
+            app: app.to_string(),
+            default_locale: DEFAULT_LOCALE.to_string(),
+            templates: HashMap::new(),
+        }
+    }
+
+    /// the last locale to try before the unlocalized templates, e.g. en; a message without a locale starts here
+    pub fn with_default_locale(mut self, locale: &str) -> Templates {
+        self.default_locale = normalize(locale);
+        self
+    }
+
+    /// return the app name
+    pub fn app(&self) -> &str {
+        &self.app
+    }
+
+    /// use this template for the channel's messages without a template of their own
+    pub fn with(mut self, channel: Channel, template: Template) -> Templates {
+        self.templates.insert((channel, None, None), template);
+        self
+    }
+
+    /// use this template for the channel's messages for the purpose, e.g. verify_email
+    pub fn with_purpose(
+        mut self,
+        channel: Channel,
+        purpose: &str,
+        template: Template,
+    ) -> Templates {
+        self.templates
+            .insert((channel, None, Some(purpose.to_string())), template);
+        self
+    }
+
+    /// use this template for the channel's messages in the locale, e.g. fr
+    pub fn with_locale(mut self, channel: Channel, locale: &str, template: Template) -> Templates {
+        self.templates
+            .insert((channel, Some(normalize(locale)), None), template);
+        self
+    }
+
+    /// use this template for the channel's messages for the purpose in the locale
+    pub fn with_locale_purpose(
+        mut self,
+        channel: Channel,
+        locale: &str,
+        purpose: &str,
+        template: Template,
+    ) -> Templates {
+        let key = (channel, Some(normalize(locale)), Some(purpose.to_string()));
+        self.templates.insert(key, template);
+        self
+    }
+
+    /// return the locales to try for the locale, most specific first, e.g. fr-ca, fr, then the default locale
+    pub fn fallbacks(&self, locale: Option<&str>) -> Vec<String> {
+        let mut chain = Vec::new();
+        if let Some(locale) = locale {
+            let mut locale = normalize(locale);
+            loop {
+                chain.push(locale.clone());
+                match locale.rfind('-') {
+                    Some(end) => locale.truncate(end),
+                    None => break,
+                }
+            }
+        }
+        if !chain.contains(&self.default_locale) {
+            chain.push(self.default_locale.clone());
+        }
+
+        chain
+    }
+
+    /// return the template for the channel, locale and purpose. each locale in the fallback chain is tried for the
+    /// purpose's template, then the channel's, before the unlocalized ones and finally the built-in one
+    pub fn get(&self, channel: Channel, locale: Option<&str>, purpose: &str) -> Template {
+        let locales = self.fallbacks(locale).into_iter().map(Some).chain([None]);
+        for locale in locales {
+            for purpose in [Some(purpose.to_string()), None] {
+                if let Some(template) = self.templates.get(&(channel, locale.clone(), purpose)) {
+                    return template.clone();
+                }
+            }
+        }
+
+        Template::default_for(channel)
+    }
+
+    /// return the subject and body for the code: `{code}`, `{digits}` (the code spelled out for speech), `{user}`,
+    /// `{purpose}`, `{minutes}` left before it expires, the context's fields by key and `{app}` are filled in
+    pub fn render(
+        &self,
+        channel: Channel,
+        user: &str,
+        code: &str,
+        context: &SendContext,
+        now: u64,
+    ) -> (String, String) {
+        // round up, so a code with 30 seconds left reads as 1 minute
+        let minutes = ((expiry::remaining(context.expires, now) + 59) / 60).to_string();
+        let digits = digits(code);
+        let mut values = vec![
+            ("code", code),
+            ("digits", digits.as_str()),
+            ("user", user),
+            ("purpose", context.purpose.as_str()),
+            ("minutes", minutes.as_str()),
+        ];
+        for (key, value) in &context.fields {
+            values.push((key, value));
+        }
+        values.push(("app", &self.app));
+
+        let template = self.get(channel, context.locale.as_deref(), &context.purpose);
+        (
+            render(&template.subject, &values),
+            render(&template.body, &values),
+        )
+    }
+}
+
+// locale tags compare case insensitively and may use _ for -, e.g. fr_CA is fr-ca
+fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// spell the code out one character at a time, e.g. `1, 2, 3`, so text to speech reads digits rather than a number
+pub fn digits(code: &str) -> String {
+    let chars: Vec<String> = code.chars().map(|c| c.to_string()).collect();
+    chars.join(", ")
+}
+
+/// fill in the `{name}` placeholders in one pass, so values are never expanded again; `{{` and `}}` are literal
+/// braces and unknown names are left as they are
+pub fn render(text: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['{', '}']) {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+
+        let name = tail[1..].find('}').map(|end| &tail[1..end + 1]);
+        match name.and_then(|name| values.iter().find(|(key, _)| *key == name)) {
+            Some((key, value)) => {
+                out.push_str(value);
+                rest = &tail[key.len() + 2..];
+            }
+            None => {
+                out.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        let values = [("code", "123456"), ("user", "{code}")];
+        assert_eq!(render("{user} got {code}", &values), "{code} got 123456");
+        assert_eq!(render("{{code}} is {code}", &values), "{code} is 123456");
+        assert_eq!(render("{nope} {code", &values), "{nope} {code");
+        assert_eq!(render("", &values), "");
+        assert_eq!(digits("4821"), "4, 8, 2, 1");
+        assert_eq!(digits(""), "");
+    }
+
+    #[test]
+    fn lookup() {
+        let templates = Templates::new("Acme")
+            .with(Channel::Sms, Template::body("{app}: {code}"))
+            .with_purpose(
+                Channel::Email,
+                "verify_email",
+                Template::new("Verify", "{code}"),
+            );
+        assert_eq!(
+            templates.get(Channel::Sms, None, "login").body,
+            "{app}: {code}"
+        );
+        assert_eq!(
+            templates.get(Channel::Email, None, "verify_email").subject,
+            "Verify"
+        );
+        assert_eq!(
+            templates.get(Channel::Email, None, "login"),
+            Template::default_for(Channel::Email)
+        );
+        assert_eq!(templates.app(), "Acme");
+
+        let mut context = SendContext::new("login").with("code", "000000");
+        context.expires = 1_030;
+        let (_, body) = templates.render(Channel::Sms, "sally", "123456", &context, 1_000);
+        assert_eq!(body, "Acme: 123456");
+        let (subject, body) = templates.render(Channel::Email, "sally", "123456", &context, 1_000);
+        assert_eq!(subject, "Your code is 123456");
+        assert!(body.contains("expires in 1 minutes"));
+    }
+
+    #[test]
+    fn locales() {
+        let templates = Templates::new("Acme")
+            .with_locale(Channel::Sms, "fr", Template::body("Votre code est {code}"))
+            .with_locale(Channel::Sms, "fr-CA", Template::body("Ton code est {code}"))
+            .with_locale_purpose(
+                Channel::Sms,
+                "fr",
+                "verify_phone",
+                Template::body("Vérifiez {code}"),
+            )
+            .with_locale(Channel::Sms, "en", Template::body("Code {code}"))
+            .with_default_locale("en");
+        assert_eq!(templates.fallbacks(Some("fr_CA")), ["fr-ca", "fr", "en"]);
+        assert_eq!(templates.fallbacks(None), ["en"]);
+
+        let body =
+            |locale: Option<&str>, purpose: &str| templates.get(Channel::Sms, locale, purpose).body;
+        assert_eq!(body(Some("fr-CA"), "login"), "Ton code est {code}");
+        assert_eq!(body(Some("fr-BE"), "login"), "Votre code est {code}");
+        // the language beats a purpose template in another language
+        assert_eq!(body(Some("fr-CA"), "verify_phone"), "Ton code est {code}");
+        assert_eq!(body(Some("fr"), "verify_phone"), "Vérifiez {code}");
+        assert_eq!(body(Some("de"), "login"), "Code {code}");
+        assert_eq!(body(None, "login"), "Code {code}");
+        assert_eq!(
+            templates.get(Channel::Email, Some("fr"), "login"),
+            Template::default_for(Channel::Email)
+        );
+
+        let context = SendContext::new("login").with_locale("fr-CA");
+        let (_, body) = templates.render(Channel::Sms, "sally", "123456", &context, 0);
+        assert_eq!(body, "Ton code est 123456");
+    }
+}
@@ -0,0 +1,166 @@
+/// rustls server configuration for the tcp servers, with optional client certificate (mtls) verification, and the
+/// client side for replication peers
+use anyhow::{anyhow, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// pem file locations for a tls listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// server certificate chain
+    pub cert: PathBuf,
+    /// server private key
+    pub key: PathBuf,
+    /// when set, clients must present a certificate signed by one of these roots
+    pub client_ca: Option<PathBuf>,
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found in {:?}", path));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("no private key found in {:?}", path))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+impl TlsConfig {
+    /// create the config for a server certificate and key
+    pub fn new<P: AsRef<Path>>(cert: P, key: P) -> TlsConfig {
+        TlsConfig {
+            cert: cert.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+            client_ca: None,
+        }
+    }
+
+    /// require client certificates signed by the roots in this pem file
+    pub fn with_client_ca<P: AsRef<Path>>(mut self, client_ca: P) -> TlsConfig {
+        self.client_ca = Some(client_ca.as_ref().to_path_buf());
+        self
+    }
+
+    /// load the pem files and build the rustls server config
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let certs = load_certs(&self.cert)?;
+        let key = load_key(&self.key)?;
+        let builder = ServerConfig::builder();
+
+        let config = match &self.client_ca {
+            Some(path) => {
+                let verifier =
+                    WebPkiClientVerifier::builder(Arc::new(load_roots(path)?)).build()?;
+                builder
+                    .with_client_cert_verifier(verifier)
+                    .with_single_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+        };
+
+        Ok(Arc::new(config))
+    }
+
+    /// build the rustls client config for connecting to peers that run the same config: trust the client_ca roots
+    /// and present this certificate, so both ends are verified
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let path = self
+            .client_ca
+            .as_ref()
+            .ok_or_else(|| anyhow!("connecting to peers needs a client_ca to verify them"))?;
+        let config = ClientConfig::builder()
+            .with_root_certificates(load_roots(path)?)
+            .with_client_auth_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+/// complete the server handshake on an accepted connection
+pub fn accept(
+    config: &Arc<ServerConfig>,
+    mut stream: TcpStream,
+) -> Result<StreamOwned<ServerConnection, TcpStream>> {
+    let mut conn = ServerConnection::new(Arc::clone(config))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+
+    Ok(StreamOwned::new(conn, stream))
+}
+
+/// complete the client handshake with the server at addr, a host:port whose host the server's certificate must name
+pub fn connect(
+    config: &Arc<ClientConfig>,
+    addr: &str,
+    mut stream: TcpStream,
+) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|_| anyhow!("invalid server name {}", host))?;
+    let mut conn = ClientConnection::new(Arc::clone(config), name)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+
+    Ok(StreamOwned::new(conn, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let tls = TlsConfig::new("server.pem", "server.key").with_client_ca("ca.pem");
+        assert_eq!(tls.cert, PathBuf::from("server.pem"));
+        assert_eq!(tls.key, PathBuf::from("server.key"));
+        assert_eq!(tls.client_ca, Some(PathBuf::from("ca.pem")));
+    }
+
+    #[test]
+    fn missing_files() {
+        let tls = TlsConfig::new("/nonexistent/server.pem", "/nonexistent/server.key");
+        assert!(tls.server_config().is_err());
+        assert!(tls.client_config().is_err());
+        assert!(tls
+            .with_client_ca("/nonexistent/ca.pem")
+            .client_config()
+            .is_err());
+    }
+
+    #[test]
+    fn empty_cert_file() {
+        let path = std::env::temp_dir().join(format!("otp-session-{}.pem", fastrand::u64(..)));
+        std::fs::write(&path, "").unwrap();
+        let tls = TlsConfig::new(&path, &path);
+        let resp = tls.server_config();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(resp.is_err());
+    }
+}
@@ -0,0 +1,110 @@
+/// short-lived tokens derived from sessions, so api gateways can check a session without calling the store
+use crate::session::Session;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "jwt")]
+pub mod jwt;
+#[cfg(feature = "paseto")]
+pub mod paseto;
+
+/// the default seconds a session-derived token is good for
+pub const TOKEN_TTL: u64 = 300;
+
+/// the claims carried by session-derived tokens; the session code is readable by anyone holding the token, so only
+/// hand tokens to the session's own user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionClaims {
+    /// the user
+    pub sub: String,
+    /// the session code
+    pub sid: String,
+    /// unix time the token was issued
+    pub iat: u64,
+    /// unix time the token expires; never later than the session
+    pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+}
+
+impl SessionClaims {
+    /// create the claims for a valid session, good for ttl seconds but no longer than the session itself
+    pub fn for_session(
+        session: &Session,
+        code: &str,
+        user: &str,
+        ttl: u64,
+        issuer: Option<&str>,
+    ) -> Result<SessionClaims> {
+        let Some(item) = session.get(code, user) else {
+            bail!(
+                "session not valid: {}",
+                session.validate(code, user).as_str()
+            );
+        };
+
+        let now = session.now();
+        Ok(SessionClaims {
+            sub: item.user,
+            sid: item.code,
+            iat: now,
+            exp: now.saturating_add(ttl).min(item.expires),
+            iss: issuer.map(|iss| iss.to_string()),
+        })
+    }
+
+    /// fail if the token has expired at the unix time
+    pub fn check_expiry(&self, now: u64) -> Result<()> {
+        if self.exp <= now {
+            bail!("token expired");
+        }
+
+        Ok(())
+    }
+
+    /// fail unless the session the token was issued for is still valid, e.g. it has not been revoked
+    pub fn check_session(&self, session: &Session) -> Result<()> {
+        self.check_expiry(session.now())?;
+        let result = session.validate(&self.sid, &self.sub);
+        if !result.is_valid() {
+            bail!("session not valid: {}", result.as_str());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn claims() {
+        let clock = MockClock::at(1_000);
+        let mut session = Session::builder()
+            .timeout(120)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let code = session.create_user_session("sally").unwrap();
+
+        let claims =
+            SessionClaims::for_session(&session, &code, "sally", 60, Some("acme")).unwrap();
+        assert_eq!((claims.iat, claims.exp), (1_000, 1_060));
+        assert_eq!(claims.iss.as_deref(), Some("acme"));
+        assert!(claims.check_session(&session).is_ok());
+
+        // capped at the session's own expiry
+        let claims = SessionClaims::for_session(&session, &code, "sally", TOKEN_TTL, None).unwrap();
+        assert_eq!(claims.exp, 1_120);
+
+        clock.set(1_060);
+        assert!(claims.check_expiry(1_059).is_ok());
+        session.remove(&code, "sally");
+        let err = claims.check_session(&session).unwrap_err();
+        assert_eq!(err.to_string(), "session not valid: revoked");
+        assert!(SessionClaims::for_session(&session, &code, "sally", 60, None).is_err());
+    }
+}
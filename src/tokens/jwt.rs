@@ -0,0 +1,183 @@
+/// json web tokens (HS256 or RS256) minted from sessions, carrying the session code and user as claims
+use crate::clock::unix_now;
+use crate::session::Session;
+use crate::tokens::{SessionClaims, TOKEN_TTL};
+use anyhow::Result;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use std::fmt;
+
+/// signs jwts for valid sessions
+pub struct JwtIssuer {
+    key: EncodingKey,
+    algorithm: Algorithm,
+    ttl: u64,
+    issuer: Option<String>,
+}
+
+impl fmt::Debug for JwtIssuer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the signing key
+        f.debug_struct("JwtIssuer")
+            .field("algorithm", &self.algorithm)
+            .field("ttl", &self.ttl)
+            .field("issuer", &self.issuer)
+            .finish()
+    }
+}
+
+impl JwtIssuer {
+    /// sign with a shared hmac secret; verifiers need the same secret
+    pub fn hs256(secret: &[u8]) -> JwtIssuer {
+        JwtIssuer::new(EncodingKey::from_secret(secret), Algorithm::HS256)
+    }
+
+    /// sign with a pem encoded rsa private key; verifiers only need the public key
+    pub fn rs256(private_pem: &[u8]) -> Result<JwtIssuer> {
+        let key = EncodingKey::from_rsa_pem(private_pem)?;
+        Ok(JwtIssuer::new(key, Algorithm::RS256))
+    }
+
+    fn new(key: EncodingKey, algorithm: Algorithm) -> JwtIssuer {
+        JwtIssuer {
+            key,
+            algorithm,
+            ttl: TOKEN_TTL,
+            issuer: None,
+        }
+    }
+
+    /// tokens are good for this many seconds, or until the session expires if that is sooner
+    pub fn with_ttl(mut self, ttl: u64) -> JwtIssuer {
+        self.ttl = ttl;
+        self
+    }
+
+    /// set the iss claim; verifiers with an issuer reject tokens without it
+    pub fn with_issuer(mut self, issuer: &str) -> JwtIssuer {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// return a signed token for the session; fails unless the session is valid
+    pub fn issue(&self, session: &Session, code: &str, user: &str) -> Result<String> {
+        let claims =
+            SessionClaims::for_session(session, code, user, self.ttl, self.issuer.as_deref())?;
+        self.sign(&claims)
+    }
+
+    /// sign the claims as they are
+    pub fn sign(&self, claims: &SessionClaims) -> Result<String> {
+        let token = jsonwebtoken::encode(&Header::new(self.algorithm), claims, &self.key)?;
+        Ok(token)
+    }
+}
+
+/// checks jwt signatures and expiry, and optionally the session behind the token
+#[derive(Clone)]
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: jsonwebtoken::Validation,
+}
+
+impl fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtVerifier")
+            .field("algorithms", &self.validation.algorithms)
+            .finish()
+    }
+}
+
+impl JwtVerifier {
+    /// verify HS256 tokens signed with the shared secret
+    pub fn hs256(secret: &[u8]) -> JwtVerifier {
+        JwtVerifier::new(DecodingKey::from_secret(secret), Algorithm::HS256)
+    }
+
+    /// verify RS256 tokens with the pem encoded rsa public key
+    pub fn rs256(public_pem: &[u8]) -> Result<JwtVerifier> {
+        let key = DecodingKey::from_rsa_pem(public_pem)?;
+        Ok(JwtVerifier::new(key, Algorithm::RS256))
+    }
+
+    fn new(key: DecodingKey, algorithm: Algorithm) -> JwtVerifier {
+        // only the one algorithm is accepted, and expiry is checked against the caller's clock
+        let mut validation = jsonwebtoken::Validation::new(algorithm);
+        validation.validate_exp = false;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+
+        JwtVerifier { key, validation }
+    }
+
+    /// reject tokens whose iss claim is not this issuer
+    pub fn with_issuer(mut self, issuer: &str) -> JwtVerifier {
+        self.validation.set_issuer(&[issuer]);
+        self
+    }
+
+    /// check the signature and expiry without the store and return the claims, e.g. at a gateway; a revoked
+    /// session's tokens stay valid until they expire
+    pub fn verify(&self, token: &str) -> Result<SessionClaims> {
+        self.verify_at(token, unix_now())
+    }
+
+    /// check the signature and expiry at the unix time and return the claims
+    pub fn verify_at(&self, token: &str, now: u64) -> Result<SessionClaims> {
+        let claims = self.decode(token)?;
+        claims.check_expiry(now)?;
+        Ok(claims)
+    }
+
+    /// check the signature and expiry, then that the session is still valid in the store
+    pub fn verify_session(&self, token: &str, session: &Session) -> Result<SessionClaims> {
+        let claims = self.decode(token)?;
+        claims.check_session(session)?;
+        Ok(claims)
+    }
+
+    fn decode(&self, token: &str) -> Result<SessionClaims> {
+        let data = jsonwebtoken::decode::<SessionClaims>(token, &self.key, &self.validation)?;
+        Ok(data.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn issue_verify() {
+        let clock = MockClock::new();
+        let mut session = Session::builder()
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let code = session.create_user_session("sally").unwrap();
+
+        let issuer = JwtIssuer::hs256(b"secret").with_ttl(60).with_issuer("acme");
+        let token = issuer.issue(&session, &code, "sally").unwrap();
+        assert_eq!(token.split('.').count(), 3);
+
+        let verifier = JwtVerifier::hs256(b"secret").with_issuer("acme");
+        let claims = verifier.verify(&token).unwrap();
+        assert_eq!(
+            (claims.sub.as_str(), claims.sid.as_str()),
+            ("sally", code.as_str())
+        );
+        assert!(verifier.verify_session(&token, &session).is_ok());
+        assert!(verifier.verify_at(&token, claims.exp).is_err());
+
+        assert!(JwtVerifier::hs256(b"other").verify(&token).is_err());
+        assert!(JwtVerifier::hs256(b"secret")
+            .with_issuer("other")
+            .verify(&token)
+            .is_err());
+
+        // stateless checks still pass after a revoke; the session check does not
+        session.remove(&code, "sally");
+        assert!(verifier.verify(&token).is_ok());
+        assert!(verifier.verify_session(&token, &session).is_err());
+        assert!(issuer.issue(&session, &code, "sally").is_err());
+    }
+}
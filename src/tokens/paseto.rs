@@ -0,0 +1,318 @@
+/// paseto v4 tokens minted from sessions, local (encrypted) or public (signed), with the same claims as the jwts
+use crate::session::Session;
+use crate::tokens::{SessionClaims, TOKEN_TTL};
+use anyhow::{anyhow, bail, Result};
+use pasetors::claims::{Claims, ClaimsValidationRules};
+use pasetors::keys::{AsymmetricPublicKey, AsymmetricSecretKey, SymmetricKey};
+use pasetors::token::UntrustedToken;
+use pasetors::version4::V4;
+use pasetors::{local, public, Local, Public};
+use std::fmt;
+
+enum IssuerKey {
+    Local(SymmetricKey<V4>),
+    Public(AsymmetricSecretKey<V4>),
+}
+
+enum VerifierKey {
+    Local(SymmetricKey<V4>),
+    Public(AsymmetricPublicKey<V4>),
+}
+
+// the purpose shown in debug output
+fn purpose(local: bool) -> &'static str {
+    if local {
+        "v4.local"
+    } else {
+        "v4.public"
+    }
+}
+
+/// encrypts or signs paseto v4 tokens for valid sessions
+pub struct PasetoIssuer {
+    key: IssuerKey,
+    ttl: u64,
+    issuer: Option<String>,
+}
+
+impl fmt::Debug for PasetoIssuer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key
+        f.debug_struct("PasetoIssuer")
+            .field("purpose", &purpose(matches!(self.key, IssuerKey::Local(_))))
+            .field("ttl", &self.ttl)
+            .field("issuer", &self.issuer)
+            .finish()
+    }
+}
+
+impl PasetoIssuer {
+    /// encrypt v4.local tokens with a 32 byte shared key; the claims are hidden from the token holder
+    pub fn local(key: &[u8]) -> Result<PasetoIssuer> {
+        let key = SymmetricKey::<V4>::from(key)?;
+        Ok(PasetoIssuer::new(IssuerKey::Local(key)))
+    }
+
+    /// sign v4.public tokens with a 64 byte ed25519 secret key; verifiers only need the public key
+    pub fn public(secret_key: &[u8]) -> Result<PasetoIssuer> {
+        let key = AsymmetricSecretKey::<V4>::from(secret_key)?;
+        Ok(PasetoIssuer::new(IssuerKey::Public(key)))
+    }
+
+    fn new(key: IssuerKey) -> PasetoIssuer {
+        PasetoIssuer {
+            key,
+            ttl: TOKEN_TTL,
+            issuer: None,
+        }
+    }
+
+    /// tokens are good for this many seconds, or until the session expires if that is sooner
+    pub fn with_ttl(mut self, ttl: u64) -> PasetoIssuer {
+        self.ttl = ttl;
+        self
+    }
+
+    /// set the iss claim; verifiers with an issuer reject tokens without it
+    pub fn with_issuer(mut self, issuer: &str) -> PasetoIssuer {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// return a token for the session; fails unless the session is valid
+    pub fn issue(&self, session: &Session, code: &str, user: &str) -> Result<String> {
+        let claims =
+            SessionClaims::for_session(session, code, user, self.ttl, self.issuer.as_deref())?;
+        self.sign(&claims)
+    }
+
+    /// encrypt or sign the claims as they are
+    pub fn sign(&self, claims: &SessionClaims) -> Result<String> {
+        let claims = to_paseto(claims)?;
+        let token = match &self.key {
+            IssuerKey::Local(key) => local::encrypt(key, &claims, None, None)?,
+            IssuerKey::Public(key) => public::sign(key, &claims, None, None)?,
+        };
+
+        Ok(token)
+    }
+}
+
+/// decrypts or checks paseto v4 tokens and their expiry, and optionally the session behind the token
+pub struct PasetoVerifier {
+    key: VerifierKey,
+    issuer: Option<String>,
+}
+
+impl fmt::Debug for PasetoVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasetoVerifier")
+            .field(
+                "purpose",
+                &purpose(matches!(self.key, VerifierKey::Local(_))),
+            )
+            .field("issuer", &self.issuer)
+            .finish()
+    }
+}
+
+impl PasetoVerifier {
+    /// decrypt v4.local tokens with the 32 byte shared key
+    pub fn local(key: &[u8]) -> Result<PasetoVerifier> {
+        let key = SymmetricKey::<V4>::from(key)?;
+        Ok(PasetoVerifier::new(VerifierKey::Local(key)))
+    }
+
+    /// check v4.public tokens with the 32 byte ed25519 public key
+    pub fn public(public_key: &[u8]) -> Result<PasetoVerifier> {
+        let key = AsymmetricPublicKey::<V4>::from(public_key)?;
+        Ok(PasetoVerifier::new(VerifierKey::Public(key)))
+    }
+
+    fn new(key: VerifierKey) -> PasetoVerifier {
+        PasetoVerifier { key, issuer: None }
+    }
+
+    /// reject tokens whose iss claim is not this issuer
+    pub fn with_issuer(mut self, issuer: &str) -> PasetoVerifier {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// check the token and its expiry without the store and return the claims, e.g. at a gateway; a revoked
+    /// session's tokens stay valid until they expire
+    pub fn verify(&self, token: &str) -> Result<SessionClaims> {
+        self.decode(token)
+    }
+
+    /// check the token and its expiry, then that the session is still valid in the store
+    pub fn verify_session(&self, token: &str, session: &Session) -> Result<SessionClaims> {
+        let claims = self.decode(token)?;
+        claims.check_session(session)?;
+        Ok(claims)
+    }
+
+    fn decode(&self, token: &str) -> Result<SessionClaims> {
+        // the rules check exp and nbf against the system clock
+        let mut rules = ClaimsValidationRules::new();
+        if let Some(issuer) = &self.issuer {
+            rules.validate_issuer_with(issuer);
+        }
+
+        let trusted = match &self.key {
+            VerifierKey::Local(key) => {
+                let untrusted = UntrustedToken::<Local, V4>::try_from(token)?;
+                local::decrypt(key, &untrusted, &rules, None, None)?
+            }
+            VerifierKey::Public(key) => {
+                let untrusted = UntrustedToken::<Public, V4>::try_from(token)?;
+                public::verify(key, &untrusted, &rules, None, None)?
+            }
+        };
+
+        let claims = trusted
+            .payload_claims()
+            .ok_or_else(|| anyhow!("token has no claims"))?;
+        from_paseto(claims)
+    }
+}
+
+// map the claims to paseto's registered claims, which carry times as rfc 3339 strings
+fn to_paseto(claims: &SessionClaims) -> Result<Claims> {
+    let mut paseto = Claims::new()?;
+    paseto.subject(&claims.sub)?;
+    paseto.add_additional("sid", claims.sid.as_str())?;
+    paseto.issued_at(&rfc3339(claims.iat))?;
+    paseto.not_before(&rfc3339(claims.iat))?;
+    paseto.expiration(&rfc3339(claims.exp))?;
+    if let Some(iss) = &claims.iss {
+        paseto.issuer(iss)?;
+    }
+
+    Ok(paseto)
+}
+
+fn from_paseto(claims: &Claims) -> Result<SessionClaims> {
+    let text = |name: &str| {
+        claims
+            .get_claim(name)
+            .and_then(|value| value.as_str())
+            .ok_or_else(|| anyhow!("token is missing the {} claim", name))
+    };
+
+    Ok(SessionClaims {
+        sub: text("sub")?.to_string(),
+        sid: text("sid")?.to_string(),
+        iat: parse_rfc3339(text("iat")?)?,
+        exp: parse_rfc3339(text("exp")?)?,
+        iss: text("iss").ok().map(|iss| iss.to_string()),
+    })
+}
+
+// days since the unix epoch for the civil date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// the civil date for days since the unix epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// format the unix time as utc rfc 3339, e.g. 2023-11-14T22:13:20Z
+fn rfc3339(time: u64) -> String {
+    let (year, month, day) = civil_from_days((time / 86_400) as i64);
+    let seconds = time % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// parse a utc rfc 3339 time as written by rfc3339; other offsets are rejected
+fn parse_rfc3339(text: &str) -> Result<u64> {
+    let (datetime, zone) = text.split_at(text.len().min(19));
+    if zone != "Z" && zone != "+00:00" {
+        bail!("unsupported time: {}", text);
+    }
+
+    let fields: Vec<i64> = datetime
+        .split(['-', 'T', ':'])
+        .map(|field| field.parse::<i64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| anyhow!("invalid time: {}", text))?;
+    let [year, month, day, hour, minute, second] = fields[..] else {
+        bail!("invalid time: {}", text);
+    };
+
+    let time = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    u64::try_from(time).map_err(|_| anyhow!("time before the epoch: {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        for time in [0, 951_782_400, 1_700_000_000, 4_102_444_799] {
+            assert_eq!(parse_rfc3339(&rfc3339(time)).unwrap(), time);
+        }
+
+        assert_eq!(
+            parse_rfc3339("2023-11-14T22:13:20+00:00").unwrap(),
+            1_700_000_000
+        );
+        assert!(parse_rfc3339("2023-11-14T22:13:20+01:00").is_err());
+        assert!(parse_rfc3339("2023-11-14").is_err());
+    }
+
+    #[test]
+    fn issue_verify() {
+        let mut session = Session::new();
+        let code = session.create_user_session("sally").unwrap();
+
+        let issuer = PasetoIssuer::local(&[7; 32]).unwrap().with_issuer("acme");
+        let token = issuer.issue(&session, &code, "sally").unwrap();
+        assert!(token.starts_with("v4.local."));
+
+        let verifier = PasetoVerifier::local(&[7; 32]).unwrap().with_issuer("acme");
+        let claims = verifier.verify(&token).unwrap();
+        assert_eq!(
+            (claims.sub.as_str(), claims.sid.as_str()),
+            ("sally", code.as_str())
+        );
+        assert_eq!(claims.iss.as_deref(), Some("acme"));
+        assert!(verifier.verify_session(&token, &session).is_ok());
+        assert!(PasetoVerifier::local(&[8; 32])
+            .unwrap()
+            .verify(&token)
+            .is_err());
+
+        session.remove(&code, "sally");
+        assert!(verifier.verify(&token).is_ok());
+        assert!(verifier.verify_session(&token, &session).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+/// time based one time passwords (rfc 6238) for enrolling authenticator apps, from the no_std core
+pub use otp_session_core::totp::{
+    base32_decode, base32_encode, generate_secret, generate_secret_with, hotp, Totp, SECRET_BYTES,
+    TOTP_DIGITS, TOTP_PERIOD,
+};
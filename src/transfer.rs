@@ -0,0 +1,233 @@
+/// cross-device session transfer: a logged in device offers a short code, and the device it is typed into gets a
+/// fresh session for the same user, e.g. logging in a tv from a phone
+use crate::clock::{Clock, SystemClock};
+use crate::db::Validation;
+use crate::device::{normalize, user_code};
+use crate::hash::sha256_hex;
+use crate::logging;
+use crate::session::Session;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a transfer code is good for
+pub const TRANSFER_TTL: u64 = 120;
+
+/// the default number of unknown codes accept takes before every outstanding offer is burned, so a guesser can't
+/// work through the code space while offers are open
+pub const MAX_FAILED_ACCEPTS: u32 = 20;
+
+/// an offered transfer, shown on the device handing over its session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferCode {
+    /// the code to type into the other device, e.g. WDJB-MJHT; case and the dash are ignored
+    pub code: String,
+    pub user: String,
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    user: String,
+    // the offering session, which must still be valid when the code is accepted
+    session: String,
+    expires: u64,
+    used: bool,
+}
+
+#[derive(Debug, Default)]
+struct Offers {
+    // keyed by the sha-256 of the normalized code
+    codes: HashMap<String, Entry>,
+    // unknown codes tried since the offers were last burned or all ended
+    failures: u32,
+}
+
+/// the offered transfer codes; clones share them
+#[derive(Debug, Clone)]
+pub struct SessionTransfers {
+    offers: Arc<RwLock<Offers>>,
+    ttl: u64,
+    max_failures: u32,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for SessionTransfers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the store key for a code as typed
+fn key(code: &str) -> String {
+    sha256_hex(normalize(code).as_bytes())
+}
+
+impl SessionTransfers {
+    /// create the store with the default ttl
+    pub fn new() -> SessionTransfers {
+        SessionTransfers {
+            offers: Arc::new(RwLock::new(Offers::default())),
+            ttl: TRANSFER_TTL,
+            max_failures: MAX_FAILED_ACCEPTS,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// burn every outstanding offer once accept has been given this many unknown codes
+    pub fn with_max_failures(mut self, max: u32) -> SessionTransfers {
+        self.max_failures = max;
+        self
+    }
+
+    /// codes are good for this many seconds
+    pub fn with_ttl(mut self, ttl: u64) -> SessionTransfers {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> SessionTransfers {
+        self.clock = clock;
+        self
+    }
+
+    /// offer a transfer from the user's valid session
+    pub fn offer(&self, session: &Session, code: &str, user: &str) -> Result<TransferCode> {
+        let result = session.validate(code, user);
+        if !result.is_valid() {
+            bail!("session not valid: {}", result.as_str());
+        }
+
+        let mut offers = self.offers.write().unwrap();
+        let codes = &mut offers.codes;
+        let mut transfer = user_code();
+        while codes.contains_key(&key(&transfer)) {
+            transfer = user_code();
+        }
+        let expires = self.clock.now().saturating_add(self.ttl);
+        let entry = Entry {
+            user: user.to_string(),
+            session: code.to_string(),
+            expires,
+            used: false,
+        };
+        codes.insert(key(&transfer), entry);
+        drop(offers);
+        logging::event("transfer.offer", &[("user", user), ("code", code)]);
+
+        Ok(TransferCode {
+            code: transfer,
+            user: user.to_string(),
+            expires,
+        })
+    }
+
+    /// use up the transfer code and create a fresh session for its user on this device; return the new session code
+    /// and the user. fails if the offering session has ended since. an unknown code counts against max_failures, and
+    /// reaching it burns every outstanding offer
+    pub fn accept(&self, transfer: &str, session: &mut Session) -> Result<(String, String)> {
+        let now = self.clock.now();
+        let mut offers = self.offers.write().unwrap();
+        let entry = match offers.codes.get_mut(&key(transfer)) {
+            None => {
+                offers.failures += 1;
+                if offers.failures >= self.max_failures {
+                    let burned = offers.codes.len();
+                    *offers = Offers::default();
+                    logging::event("transfer.burn", &[("count", &burned.to_string())]);
+                }
+                bail!("transfer code not valid: {}", Validation::NotFound.as_str())
+            }
+            Some(entry) if entry.used => {
+                bail!("transfer code not valid: {}", Validation::Consumed.as_str())
+            }
+            Some(entry) if entry.expires <= now => {
+                bail!("transfer code not valid: {}", Validation::Expired.as_str())
+            }
+            Some(entry) => {
+                entry.used = true;
+                entry.clone()
+            }
+        };
+        drop(offers);
+
+        let result = session.validate(&entry.session, &entry.user);
+        if !result.is_valid() {
+            bail!("offering session not valid: {}", result.as_str());
+        }
+        let code = session.create_user_session(&entry.user)?;
+        logging::event("transfer.accept", &[("user", &entry.user), ("code", &code)]);
+
+        Ok((code, entry.user))
+    }
+
+    /// forget the expired codes, and the failed accepts once no offer is left to guess; return the number removed
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut offers = self.offers.write().unwrap();
+        let before = offers.codes.len();
+        offers.codes.retain(|_, entry| entry.expires > now);
+        if offers.codes.is_empty() {
+            offers.failures = 0;
+        }
+        before - offers.codes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn offer_accept() {
+        let clock = MockClock::at(1_000);
+        let transfers = SessionTransfers::new()
+            .with_ttl(60)
+            .with_clock(Arc::new(clock.clone()));
+        let mut session = Session::new();
+        let phone = session.create_user_session("sally").unwrap();
+        assert!(transfers.offer(&session, "nope", "sally").is_err());
+
+        let offer = transfers.offer(&session, &phone, "sally").unwrap();
+        assert_eq!(offer.expires, 1_060);
+        let typed = offer.code.replace('-', " ").to_lowercase();
+        let (tv, user) = transfers.accept(&typed, &mut session).unwrap();
+        assert_eq!(user, "sally");
+        assert_ne!(tv, phone);
+        assert!(session.is_valid(&tv, "sally"));
+        assert!(session.is_valid(&phone, "sally"));
+        let err = transfers.accept(&offer.code, &mut session).unwrap_err();
+        assert_eq!(err.to_string(), "transfer code not valid: consumed");
+
+        // logging out the phone cancels its offers
+        let offer = transfers.offer(&session, &phone, "sally").unwrap();
+        session.remove(&phone, "sally");
+        assert!(transfers.accept(&offer.code, &mut session).is_err());
+
+        let offer = transfers.offer(&session, &tv, "sally").unwrap();
+        clock.set(1_060);
+        assert!(transfers.accept(&offer.code, &mut session).is_err());
+        assert_eq!(transfers.purge_expired(), 3);
+    }
+
+    #[test]
+    fn failed_accepts() {
+        let transfers = SessionTransfers::new().with_max_failures(2);
+        let mut session = Session::new();
+        let phone = session.create_user_session("sally").unwrap();
+        let offer = transfers.offer(&session, &phone, "sally").unwrap();
+
+        // a wrong code is rejected and counts, and reaching the cap burns the open offers
+        let err = transfers.accept("BBBB-BBBB", &mut session).unwrap_err();
+        assert_eq!(err.to_string(), "transfer code not valid: not_found");
+        assert!(transfers.accept("CCCC-CCCC", &mut session).is_err());
+        let err = transfers.accept(&offer.code, &mut session).unwrap_err();
+        assert_eq!(err.to_string(), "transfer code not valid: not_found");
+
+        // the count started over with the burn, so a new offer is taken
+        let offer = transfers.offer(&session, &phone, "sally").unwrap();
+        assert!(transfers.accept(&offer.code, &mut session).is_ok());
+    }
+}
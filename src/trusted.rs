@@ -0,0 +1,241 @@
+/// trusted device tokens: after an otp is validated on a device it can be remembered, and later logins there skip the
+/// otp until the token expires or is revoked
+use crate::clock::{Clock, SystemClock};
+use crate::db::Validation;
+use crate::hash::{random_hex, sha256_hex};
+use crate::logging;
+use crate::otp::Otp;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a device stays trusted, 30 days
+pub const TRUSTED_DEVICE_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// the number of random bytes in a trusted device token
+pub const DEVICE_TOKEN_BYTES: usize = 32;
+
+/// the number of random bytes in a trusted device's public id
+pub const DEVICE_ID_BYTES: usize = 6;
+
+/// a trusted device's details; the token is only returned by trust
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedDevice {
+    /// the public id, safe to log and show in a device list
+    pub id: String,
+    pub user: String,
+    /// what the user sees in the list, e.g. "Firefox on Linux"
+    pub label: String,
+    /// unix time the device was trusted
+    pub created: u64,
+    pub expires: u64,
+    /// unix time the device last skipped an otp
+    pub last_used: Option<u64>,
+}
+
+/// the trusted devices, keyed by the token's sha-256; clones share them
+#[derive(Debug, Clone)]
+pub struct TrustedDevices {
+    devices: Arc<RwLock<HashMap<String, TrustedDevice>>>,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for TrustedDevices {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrustedDevices {
+    /// create the store with the default ttl
+    pub fn new() -> TrustedDevices {
+        TrustedDevices {
+            devices: Arc::new(RwLock::new(HashMap::new())),
+            ttl: TRUSTED_DEVICE_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// devices stay trusted for this many seconds, e.g. 7 days
+    pub fn with_ttl(mut self, ttl: u64) -> TrustedDevices {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> TrustedDevices {
+        self.clock = clock;
+        self
+    }
+
+    /// return the seconds a device stays trusted
+    pub fn ttl(&self) -> u64 {
+        self.ttl
+    }
+
+    /// consume the user's otp and, if it was valid, trust the device; return the token to keep on the device, e.g. in
+    /// a cookie, which can't be shown again
+    pub fn trust(
+        &self,
+        otp: &mut Otp,
+        code: &str,
+        user: &str,
+        label: &str,
+    ) -> Result<(String, TrustedDevice)> {
+        let result = otp.consume(code, user);
+        if !result.is_valid() {
+            bail!("otp not valid: {}", result.as_str());
+        }
+
+        let token = random_hex(DEVICE_TOKEN_BYTES);
+        let now = self.clock.now();
+        let device = TrustedDevice {
+            id: random_hex(DEVICE_ID_BYTES),
+            user: user.to_string(),
+            label: label.to_string(),
+            created: now,
+            expires: now.saturating_add(self.ttl),
+            last_used: None,
+        };
+        self.devices
+            .write()
+            .unwrap()
+            .insert(sha256_hex(token.as_bytes()), device.clone());
+        logging::event("trusted.create", &[("user", user), ("id", &device.id)]);
+
+        Ok((token, device))
+    }
+
+    /// return why the token is or is not a trusted device for the user, recording the use if it is
+    pub fn validate(&self, token: &str, user: &str) -> Validation {
+        let now = self.clock.now();
+        let mut devices = self.devices.write().unwrap();
+        let result = match devices.get_mut(&sha256_hex(token.as_bytes())) {
+            Some(device) if device.user != user => Validation::NotFound,
+            Some(device) if device.expires <= now => Validation::Expired,
+            Some(device) => {
+                device.last_used = Some(now);
+                Validation::Valid
+            }
+            None => Validation::NotFound,
+        };
+        drop(devices);
+
+        logging::event(
+            "trusted.validate",
+            &[("user", user), ("result", result.as_str())],
+        );
+        result
+    }
+
+    /// return true if the user can skip the otp on the device holding this token
+    pub fn is_trusted(&self, token: &str, user: &str) -> bool {
+        self.validate(token, user).is_valid()
+    }
+
+    /// return the user's unexpired devices, oldest first
+    pub fn list(&self, user: &str) -> Vec<TrustedDevice> {
+        let now = self.clock.now();
+        let mut devices: Vec<TrustedDevice> = self
+            .devices
+            .read()
+            .unwrap()
+            .values()
+            .filter(|device| device.user == user && device.expires > now)
+            .cloned()
+            .collect();
+        devices.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+
+        devices
+    }
+
+    /// stop trusting the user's device with this id; return false if there was no such device
+    pub fn revoke(&self, user: &str, id: &str) -> bool {
+        let mut devices = self.devices.write().unwrap();
+        let before = devices.len();
+        devices.retain(|_, device| !(device.user == user && device.id == id));
+        let revoked = devices.len() < before;
+        drop(devices);
+
+        if revoked {
+            logging::event("trusted.revoke", &[("user", user), ("id", id)]);
+        }
+        revoked
+    }
+
+    /// stop trusting all of the user's devices, e.g. after a password change; return the number revoked
+    pub fn revoke_user(&self, user: &str) -> usize {
+        let mut devices = self.devices.write().unwrap();
+        let before = devices.len();
+        devices.retain(|_, device| device.user != user);
+        let revoked = before - devices.len();
+        drop(devices);
+
+        if revoked > 0 {
+            logging::event("trusted.revoke", &[("user", user)]);
+        }
+        revoked
+    }
+
+    /// forget the expired devices; return the number removed
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut devices = self.devices.write().unwrap();
+        let before = devices.len();
+        devices.retain(|_, device| device.expires > now);
+        before - devices.len()
+    }
+
+    /// return the number of devices remembered, including expired ones not yet purged
+    pub fn len(&self) -> usize {
+        self.devices.read().unwrap().len()
+    }
+
+    /// return true if no devices are remembered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn trust_list_revoke() {
+        let clock = MockClock::at(1_000);
+        let trusted = TrustedDevices::new()
+            .with_ttl(600)
+            .with_clock(Arc::new(clock.clone()));
+        let mut otp = Otp::new();
+        assert!(trusted
+            .trust(&mut otp, "123456", "sally", "laptop")
+            .is_err());
+
+        let code = otp.create_user_otp("sally").unwrap();
+        let (token, device) = trusted.trust(&mut otp, &code, "sally", "laptop").unwrap();
+        assert_eq!((device.expires, device.last_used), (1_600, None));
+        assert!(!otp.is_valid(&code, "sally"));
+        assert!(trusted.trust(&mut otp, &code, "sally", "phone").is_err());
+
+        clock.set(1_100);
+        assert!(trusted.is_trusted(&token, "sally"));
+        assert!(!trusted.is_trusted(&token, "jack"));
+        assert_eq!(trusted.list("sally")[0].last_used, Some(1_100));
+
+        let code = otp.create_user_otp("sally").unwrap();
+        let (phone, _) = trusted.trust(&mut otp, &code, "sally", "phone").unwrap();
+        assert_eq!(trusted.list("sally").len(), 2);
+        assert!(!trusted.revoke("jack", &device.id));
+        assert!(trusted.revoke("sally", &device.id));
+        assert_eq!(trusted.validate(&token, "sally"), Validation::NotFound);
+
+        clock.set(1_700);
+        assert_eq!(trusted.validate(&phone, "sally"), Validation::Expired);
+        assert!(trusted.list("sally").is_empty());
+        assert_eq!(trusted.purge_expired(), 1);
+        assert_eq!(trusted.revoke_user("sally"), 0);
+    }
+}
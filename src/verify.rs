@@ -0,0 +1,226 @@
+/// email verification tokens with a multi-day ttl, and a record of when each address was confirmed
+use crate::clock::{Clock, SystemClock};
+use crate::db::Validation;
+use crate::hash::{random_hex, sha256_hex};
+use crate::logging;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a verification token is good for, 3 days
+pub const VERIFICATION_TTL: u64 = 3 * 24 * 60 * 60;
+
+/// the number of random bytes in a verification token
+pub const VERIFICATION_TOKEN_BYTES: usize = 24;
+
+/// a confirmed address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub user: String,
+    /// the address, lower cased
+    pub email: String,
+    /// unix time the token was confirmed
+    pub verified_at: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+    user: String,
+    email: String,
+    expires: u64,
+    used: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // keyed by the token's sha-256
+    pending: HashMap<String, Pending>,
+    // keyed by user and address
+    verified: HashMap<(String, String), u64>,
+}
+
+/// issued verification tokens and confirmed addresses; clones share them
+#[derive(Debug, Clone)]
+pub struct VerificationTokens {
+    state: Arc<RwLock<State>>,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for VerificationTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// addresses compare ignoring case and surrounding space
+fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+impl VerificationTokens {
+    /// create the store with the default ttl
+    pub fn new() -> VerificationTokens {
+        VerificationTokens {
+            state: Arc::new(RwLock::new(State::default())),
+            ttl: VERIFICATION_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// tokens are good for this many seconds, e.g. 7 days for slow inboxes
+    pub fn with_ttl(mut self, ttl: u64) -> VerificationTokens {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> VerificationTokens {
+        self.clock = clock;
+        self
+    }
+
+    /// issue a token to send to the user's address; return the token and its expiry. earlier tokens stay good, so a
+    /// resent email doesn't break the first one
+    pub fn issue(&self, user: &str, email: &str) -> (String, u64) {
+        let token = random_hex(VERIFICATION_TOKEN_BYTES);
+        let expires = self.clock.now().saturating_add(self.ttl);
+        let pending = Pending {
+            user: user.to_string(),
+            email: normalize(email),
+            expires,
+            used: false,
+        };
+        self.state
+            .write()
+            .unwrap()
+            .pending
+            .insert(sha256_hex(token.as_bytes()), pending);
+        logging::event("verify.issue", &[("user", user), ("code", &token)]);
+
+        (token, expires)
+    }
+
+    /// return why the token is or is not valid, without confirming it
+    pub fn validate(&self, token: &str) -> Validation {
+        let state = self.state.read().unwrap();
+        match state.pending.get(&sha256_hex(token.as_bytes())) {
+            None => Validation::NotFound,
+            Some(pending) if pending.used => Validation::Consumed,
+            Some(pending) if pending.expires <= self.clock.now() => Validation::Expired,
+            Some(_) => Validation::Valid,
+        }
+    }
+
+    /// confirm the token, recording the address as verified now; each token confirms once
+    pub fn confirm(&self, token: &str) -> Result<Verification> {
+        let now = self.clock.now();
+        let mut state = self.state.write().unwrap();
+        let pending = match state.pending.get_mut(&sha256_hex(token.as_bytes())) {
+            None => bail!("verification token not valid: not_found"),
+            Some(pending) if pending.used => bail!("verification token not valid: consumed"),
+            Some(pending) if pending.expires <= now => {
+                bail!("verification token not valid: expired")
+            }
+            Some(pending) => {
+                pending.used = true;
+                pending.clone()
+            }
+        };
+
+        state
+            .verified
+            .insert((pending.user.clone(), pending.email.clone()), now);
+        drop(state);
+        logging::event(
+            "verify.confirm",
+            &[("user", &pending.user), ("code", token)],
+        );
+
+        Ok(Verification {
+            user: pending.user,
+            email: pending.email,
+            verified_at: now,
+        })
+    }
+
+    /// return when the user's address was confirmed, if it was
+    pub fn verified_at(&self, user: &str, email: &str) -> Option<u64> {
+        let key = (user.to_string(), normalize(email));
+        self.state.read().unwrap().verified.get(&key).copied()
+    }
+
+    /// return true if the user's address was confirmed
+    pub fn is_verified(&self, user: &str, email: &str) -> bool {
+        self.verified_at(user, email).is_some()
+    }
+
+    /// return the user's confirmed addresses
+    pub fn verified(&self, user: &str) -> Vec<Verification> {
+        let state = self.state.read().unwrap();
+        let mut verified: Vec<Verification> = state
+            .verified
+            .iter()
+            .filter(|((owner, _), _)| owner == user)
+            .map(|((user, email), at)| Verification {
+                user: user.clone(),
+                email: email.clone(),
+                verified_at: *at,
+            })
+            .collect();
+        verified.sort_by(|a, b| a.email.cmp(&b.email));
+
+        verified
+    }
+
+    /// forget the record of an address, e.g. when the user removes it; return false if it was not verified
+    pub fn unverify(&self, user: &str, email: &str) -> bool {
+        let key = (user.to_string(), normalize(email));
+        self.state.write().unwrap().verified.remove(&key).is_some()
+    }
+
+    /// forget the expired tokens; confirmed addresses are kept. return the number removed
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut state = self.state.write().unwrap();
+        let before = state.pending.len();
+        state.pending.retain(|_, pending| pending.expires > now);
+        before - state.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn confirm() {
+        let clock = MockClock::at(1_000);
+        let tokens = VerificationTokens::new().with_clock(Arc::new(clock.clone()));
+        let (token, expires) = tokens.issue("sally", "Sally@Example.com");
+        assert_eq!(expires, 1_000 + VERIFICATION_TTL);
+        assert!(!tokens.is_verified("sally", "sally@example.com"));
+
+        clock.set(90_000);
+        let verification = tokens.confirm(&token).unwrap();
+        assert_eq!(verification.email, "sally@example.com");
+        assert_eq!(verification.verified_at, 90_000);
+        assert_eq!(
+            tokens.verified_at("sally", "SALLY@example.com"),
+            Some(90_000)
+        );
+        assert_eq!(tokens.verified("sally"), vec![verification]);
+        assert_eq!(tokens.validate(&token), Validation::Consumed);
+        assert!(tokens.confirm(&token).is_err());
+
+        let (token, _) = tokens.issue("sally", "sally@work.example");
+        clock.set(expires + 90_000);
+        let err = tokens.confirm(&token).unwrap_err();
+        assert_eq!(err.to_string(), "verification token not valid: expired");
+        assert_eq!(tokens.purge_expired(), 2);
+        assert!(tokens.is_verified("sally", "sally@example.com"));
+        assert!(tokens.unverify("sally", "sally@example.com"));
+        assert!(tokens.verified("sally").is_empty());
+    }
+}
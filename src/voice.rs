@@ -0,0 +1,141 @@
+/// a voice OtpSender that reads the code aloud over a phone call, for accessibility and for users without sms. it
+/// renders the speech and leaves the call to any text to speech provider behind the VoiceCaller trait
+use crate::clock::{Clock, SystemClock};
+use crate::sender::{Delivery, DeliveryState, OtpSender, SendContext};
+use crate::template::{Channel, Template, Templates};
+use anyhow::Result;
+use std::fmt;
+use std::sync::Arc;
+
+/// places a call that speaks the text, e.g. through twilio's `<Say>` or a cloud text to speech api
+pub trait VoiceCaller: fmt::Debug + Send + Sync {
+    /// call the number and speak the text in the locale if the provider supports it; return the provider's call id,
+    /// if it has one, to match its status callbacks
+    fn call(&self, to: &str, speech: &str, locale: Option<&str>) -> Result<Option<String>>;
+
+    /// return true if the error from call is worth retrying, e.g. a timeout or a busy line
+    fn is_transient(&self, _error: &anyhow::Error) -> bool {
+        false
+    }
+}
+
+/// sends otps by voice call, reading the digits one at a time, twice. the recipient is the context's `phone` or `to`
+/// field if set, otherwise the user
+#[derive(Debug, Clone)]
+pub struct VoiceSender {
+    caller: Arc<dyn VoiceCaller>,
+    templates: Templates,
+    clock: Arc<dyn Clock>,
+}
+
+impl VoiceSender {
+    /// create a sender that places calls with the caller
+    pub fn new(caller: Arc<dyn VoiceCaller>) -> VoiceSender {
+        VoiceSender {
+            caller,
+            templates: Templates::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// render speech from these templates, e.g. ones shared with the sms sender
+    pub fn with_templates(mut self, templates: Templates) -> VoiceSender {
+        self.templates = templates;
+        self
+    }
+
+    /// speak this text instead of the built-in one; use `{digits}` for the code spelled out
+    pub fn with_template(mut self, template: &str) -> VoiceSender {
+        self.templates = self
+            .templates
+            .with(Channel::Voice, Template::body(template));
+        self
+    }
+
+    /// use this clock for the minutes left in the speech instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> VoiceSender {
+        self.clock = clock;
+        self
+    }
+
+    /// return the text that would be spoken for the code
+    pub fn render(&self, user: &str, code: &str, context: &SendContext) -> String {
+        let (_, body) =
+            self.templates
+                .render(Channel::Voice, user, code, context, self.clock.now());
+        body
+    }
+}
+
+impl OtpSender for VoiceSender {
+    fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()> {
+        self.deliver(user, code, context).map(|_| ())
+    }
+
+    fn deliver(&self, user: &str, code: &str, context: &SendContext) -> Result<Delivery> {
+        let to = context.recipient(Channel::Voice, user);
+        let speech = self.render(user, code, context);
+        let message_id = self.caller.call(to, &speech, context.locale.as_deref())?;
+
+        Ok(Delivery {
+            channel: Some(Channel::Voice),
+            attempts: 1,
+            state: DeliveryState::Sent,
+            message_id,
+            ..Default::default()
+        })
+    }
+
+    fn channel(&self) -> Option<Channel> {
+        Some(Channel::Voice)
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        self.caller.is_transient(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Mutex;
+
+    // records each call instead of placing it
+    #[derive(Debug, Default)]
+    struct Recorder {
+        calls: Mutex<Vec<(String, String, Option<String>)>>,
+    }
+
+    impl VoiceCaller for Recorder {
+        fn call(&self, to: &str, speech: &str, locale: Option<&str>) -> Result<Option<String>> {
+            let call = (to.to_string(), speech.to_string(), locale.map(String::from));
+            self.calls.lock().unwrap().push(call);
+            Ok(Some("CA123".to_string()))
+        }
+    }
+
+    #[test]
+    fn deliver() {
+        let recorder = Arc::new(Recorder::default());
+        let sender = VoiceSender::new(recorder.clone()).with_clock(Arc::new(MockClock::at(0)));
+        let context = SendContext::new("login")
+            .with("phone", "+14155550123")
+            .with_locale("en-US");
+        let delivery = sender.deliver("sally", "4821", &context).unwrap();
+        assert_eq!(delivery.channel, Some(Channel::Voice));
+        assert_eq!(delivery.message_id.as_deref(), Some("CA123"));
+
+        let (to, speech, locale) = recorder.calls.lock().unwrap().pop().unwrap();
+        assert_eq!(to, "+14155550123");
+        assert_eq!(
+            speech,
+            "Your code is 4, 8, 2, 1. Again, your code is 4, 8, 2, 1."
+        );
+        assert_eq!(locale.as_deref(), Some("en-US"));
+
+        let sender = sender.with_template("{app} code: {digits}");
+        let context = context.with("app", "Acme");
+        assert_eq!(sender.render("sally", "12", &context), "Acme code: 1, 2");
+    }
+}
@@ -0,0 +1,282 @@
+/// short lived webauthn challenges, bound to the user, origin and ceremony they were issued for and used once
+use crate::clock::{Clock, SystemClock};
+use crate::db::Validation;
+use crate::hash::{base64url, random_bytes, sha256_hex};
+use crate::logging;
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// the default seconds a challenge is good for, the webauthn recommended ceremony timeout
+pub const CHALLENGE_TTL: u64 = 300;
+
+/// the number of random bytes in a challenge; webauthn asks for at least 16
+pub const CHALLENGE_BYTES: usize = 32;
+
+/// the webauthn ceremony a challenge is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ceremony {
+    /// navigator.credentials.create
+    Registration,
+    /// navigator.credentials.get
+    Authentication,
+}
+
+impl Ceremony {
+    /// return the name used in logs, e.g. registration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Ceremony::Registration => "registration",
+            Ceremony::Authentication => "authentication",
+        }
+    }
+}
+
+/// an issued challenge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// the random bytes to pass to the browser
+    pub bytes: Vec<u8>,
+    /// the user, or None for a usernameless (discoverable credential) login
+    pub user: Option<String>,
+    /// the origin the ceremony must come from, e.g. https://example.com
+    pub origin: String,
+    pub ceremony: Ceremony,
+    pub expires: u64,
+}
+
+impl Challenge {
+    /// return the challenge as unpadded base64url, the form it takes in the client data json
+    pub fn encoded(&self) -> String {
+        base64url(&self.bytes)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    challenge: Challenge,
+    used: bool,
+}
+
+/// the issued challenges, keyed by the sha-256 of their encoded form; clones share them
+#[derive(Debug, Clone)]
+pub struct Challenges {
+    challenges: Arc<RwLock<HashMap<String, Entry>>>,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Challenges {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Challenges {
+    /// create the store with the default ttl
+    pub fn new() -> Challenges {
+        Challenges {
+            challenges: Arc::new(RwLock::new(HashMap::new())),
+            ttl: CHALLENGE_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// challenges are good for this many seconds; match the timeout passed to the browser
+    pub fn with_ttl(mut self, ttl: u64) -> Challenges {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Challenges {
+        self.clock = clock;
+        self
+    }
+
+    /// create a challenge for the ceremony from the origin, for the user if known
+    pub fn create(&self, user: Option<&str>, origin: &str, ceremony: Ceremony) -> Challenge {
+        let challenge = Challenge {
+            bytes: random_bytes(CHALLENGE_BYTES),
+            user: user.map(|user| user.to_string()),
+            origin: origin.to_string(),
+            ceremony,
+            expires: self.clock.now().saturating_add(self.ttl),
+        };
+        let entry = Entry {
+            challenge: challenge.clone(),
+            used: false,
+        };
+        let encoded = challenge.encoded();
+        self.challenges
+            .write()
+            .unwrap()
+            .insert(sha256_hex(encoded.as_bytes()), entry);
+        logging::event(
+            "webauthn.challenge",
+            &[
+                ("user", user.unwrap_or("")),
+                ("ceremony", ceremony.as_str()),
+                ("code", &encoded),
+            ],
+        );
+
+        challenge
+    }
+
+    /// return why the encoded challenge is or is not valid, without using it up
+    pub fn validate(&self, encoded: &str) -> Validation {
+        let key = sha256_hex(encoded.as_bytes());
+        match self.challenges.read().unwrap().get(&key) {
+            None => Validation::NotFound,
+            Some(entry) if entry.used => Validation::Consumed,
+            Some(entry) if entry.challenge.expires <= self.clock.now() => Validation::Expired,
+            Some(_) => Validation::Valid,
+        }
+    }
+
+    /// fetch and use up the challenge from the client data json, checking it was issued for this user, origin and
+    /// ceremony; a mismatch uses it up too, so each challenge gets one try
+    pub fn consume(
+        &self,
+        encoded: &str,
+        user: Option<&str>,
+        origin: &str,
+        ceremony: Ceremony,
+    ) -> Result<Challenge> {
+        let key = sha256_hex(encoded.as_bytes());
+        let now = self.clock.now();
+        let mut challenges = self.challenges.write().unwrap();
+        let Some(entry) = challenges.get_mut(&key) else {
+            bail!("challenge not valid: {}", Validation::NotFound.as_str());
+        };
+
+        if entry.used {
+            bail!("challenge not valid: {}", Validation::Consumed.as_str());
+        }
+        if entry.challenge.expires <= now {
+            bail!("challenge not valid: {}", Validation::Expired.as_str());
+        }
+
+        entry.used = true;
+        let challenge = entry.challenge.clone();
+        drop(challenges);
+        logging::event(
+            "webauthn.consume",
+            &[("user", user.unwrap_or("")), ("code", encoded)],
+        );
+
+        if challenge.ceremony != ceremony {
+            bail!(
+                "challenge not valid: issued for {}",
+                challenge.ceremony.as_str()
+            );
+        }
+        if challenge.origin != origin {
+            bail!("challenge not valid: origin does not match");
+        }
+        // a usernameless challenge learns its user from the credential, so any user may use it
+        if challenge.user.is_some() && challenge.user.as_deref() != user {
+            bail!("challenge not valid: user does not match");
+        }
+
+        Ok(challenge)
+    }
+
+    /// forget the expired challenges; return the number removed
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut challenges = self.challenges.write().unwrap();
+        let before = challenges.len();
+        challenges.retain(|_, entry| entry.challenge.expires > now);
+        before - challenges.len()
+    }
+
+    /// return the number of challenges remembered, including used ones that have not expired
+    pub fn len(&self) -> usize {
+        self.challenges.read().unwrap().len()
+    }
+
+    /// return true if no challenges are remembered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const ORIGIN: &str = "https://example.com";
+
+    #[test]
+    fn encoding() {
+        assert_eq!(base64url(b""), "");
+        assert_eq!(base64url(b"f"), "Zg");
+        assert_eq!(base64url(b"fo"), "Zm8");
+        assert_eq!(base64url(b"foo"), "Zm9v");
+        assert_eq!(base64url(&[0xfb, 0xff]), "-_8");
+    }
+
+    #[test]
+    fn consume_once() {
+        let clock = MockClock::at(1_000);
+        let challenges = Challenges::new()
+            .with_ttl(60)
+            .with_clock(Arc::new(clock.clone()));
+        let challenge = challenges.create(Some("sally"), ORIGIN, Ceremony::Registration);
+        assert_eq!(challenge.bytes.len(), CHALLENGE_BYTES);
+        assert_eq!(challenge.expires, 1_060);
+        let encoded = challenge.encoded();
+        assert_eq!(challenges.validate(&encoded), Validation::Valid);
+
+        let used = challenges
+            .consume(&encoded, Some("sally"), ORIGIN, Ceremony::Registration)
+            .unwrap();
+        assert_eq!(used, challenge);
+        let err = challenges
+            .consume(&encoded, Some("sally"), ORIGIN, Ceremony::Registration)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "challenge not valid: consumed");
+
+        // any mismatch burns the challenge
+        let encoded = challenges
+            .create(Some("sally"), ORIGIN, Ceremony::Authentication)
+            .encoded();
+        let err = challenges
+            .consume(
+                &encoded,
+                Some("sally"),
+                "https://evil.example",
+                Ceremony::Authentication,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "challenge not valid: origin does not match"
+        );
+        assert_eq!(challenges.validate(&encoded), Validation::Consumed);
+
+        let encoded = challenges
+            .create(None, ORIGIN, Ceremony::Authentication)
+            .encoded();
+        assert!(challenges
+            .consume(&encoded, Some("jack"), ORIGIN, Ceremony::Registration)
+            .is_err());
+        let encoded = challenges
+            .create(None, ORIGIN, Ceremony::Authentication)
+            .encoded();
+        assert!(challenges
+            .consume(&encoded, Some("jack"), ORIGIN, Ceremony::Authentication)
+            .is_ok());
+
+        let encoded = challenges
+            .create(Some("jack"), ORIGIN, Ceremony::Registration)
+            .encoded();
+        clock.set(1_060);
+        assert_eq!(challenges.validate(&encoded), Validation::Expired);
+        assert_eq!(challenges.purge_expired(), 5);
+        assert!(challenges.is_empty());
+    }
+}
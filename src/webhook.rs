@@ -0,0 +1,437 @@
+/// outbound webhooks: POST hmac-signed json payloads for lifecycle events, retrying transient failures, and an
+/// OtpSender that hands codes to an organization's own notification service the same way
+use crate::clock::unix_now;
+use crate::events::{Event, EventKind, EventSubscriber};
+use crate::hash::hmac_sha256_hex;
+use crate::logging::{escape, redact};
+use crate::sender::{OtpSender, SendContext};
+use crate::template::Channel;
+use anyhow::{anyhow, bail, Result};
+use log::{error, warn};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// the request header carrying `sha256=<hex hmac of the body>`
+pub const SIGNATURE_HEADER: &str = "X-Otp-Session-Signature";
+
+/// attempts per delivery, including the first
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// delay before the first retry; doubled for each later retry
+pub const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// timeout for each delivery attempt
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// an endpoint and the events it receives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    /// key for the payload signature
+    pub secret: String,
+    /// event names such as session.created or otp.user_removed; empty means every event
+    pub events: Vec<String>,
+}
+
+impl Webhook {
+    /// create a webhook that receives every event
+    pub fn new(url: &str, secret: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            events: Vec::new(),
+        }
+    }
+
+    /// only deliver these events
+    pub fn with_events(mut self, events: &[&str]) -> Webhook {
+        self.events = events.iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    /// return true if the webhook receives this event
+    pub fn matches(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.contains(&event.name())
+    }
+}
+
+/// return the json payload for the event; the code is redacted
+pub fn payload(event: &Event) -> String {
+    let mut json = format!(
+        r#"{{"event":"{}","store":"{}","time":{}"#,
+        event.name(),
+        event.store,
+        event.time
+    );
+    if let Some(user) = &event.user {
+        json.push_str(&format!(r#","user":"{}""#, escape(user)));
+    }
+    if let Some(code) = &event.code {
+        json.push_str(&format!(r#","code":"{}""#, redact(code)));
+    }
+    if let EventKind::UserRemoved { count }
+    | EventKind::Expired { count }
+    | EventKind::Cleared { count } = event.kind
+    {
+        json.push_str(&format!(r#","count":{}"#, count));
+    }
+    json.push('}');
+
+    json
+}
+
+/// return the signature header value for the body
+pub fn sign(secret: &str, body: &str) -> String {
+    format!(
+        "sha256={}",
+        hmac_sha256_hex(secret.as_bytes(), body.as_bytes())
+    )
+}
+
+// sends a request and returns the http status; errors are transport failures
+trait Transport: Send + Sync + 'static {
+    fn post(&self, url: &str, headers: &[(&str, &str)], body: &str) -> Result<u16>;
+}
+
+struct HttpTransport {
+    agent: ureq::Agent,
+}
+
+impl Transport for HttpTransport {
+    fn post(&self, url: &str, headers: &[(&str, &str)], body: &str) -> Result<u16> {
+        let mut request = self.agent.post(url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+
+        match request.send_string(body) {
+            Ok(response) => Ok(response.status()),
+            Err(ureq::Error::Status(status, _)) => Ok(status),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+}
+
+// post the body, retrying transport errors, 429 and 5xx with exponential backoff
+fn deliver(transport: &impl Transport, hook: &Webhook, body: &str, delay: Duration) -> Result<()> {
+    let signature = sign(&hook.secret, body);
+    let headers = [
+        ("Content-Type", "application/json"),
+        (SIGNATURE_HEADER, signature.as_str()),
+    ];
+
+    let mut delay = delay;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match transport.post(&hook.url, &headers, body) {
+            Ok(status) if (200..300).contains(&status) => return Ok(()),
+            Ok(status) if status != 429 && status < 500 => {
+                bail!("{} rejected the webhook with status {}", hook.url, status)
+            }
+            Ok(status) => warn!(
+                "webhook {} attempt {}: status {}",
+                hook.url, attempt, status
+            ),
+            Err(e) => warn!("webhook {} attempt {}: {}", hook.url, attempt, e),
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+
+    bail!("{} failed after {} attempts", hook.url, MAX_ATTEMPTS)
+}
+
+/// queues events and delivers them to the matching webhooks from a background thread;
+/// subscribe it to an event bus with `events.subscribe(dispatcher)`
+#[derive(Debug)]
+pub struct WebhookDispatcher {
+    tx: Mutex<Sender<Event>>,
+}
+
+impl WebhookDispatcher {
+    /// start delivering to these webhooks
+    pub fn start(hooks: Vec<Webhook>) -> Result<Arc<WebhookDispatcher>> {
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        WebhookDispatcher::start_with(hooks, HttpTransport { agent }, RETRY_DELAY)
+    }
+
+    fn start_with<T: Transport>(
+        hooks: Vec<Webhook>,
+        transport: T,
+        delay: Duration,
+    ) -> Result<Arc<WebhookDispatcher>> {
+        let (tx, rx): (Sender<Event>, Receiver<Event>) = mpsc::channel();
+        thread::Builder::new()
+            .name("webhooks".to_string())
+            .spawn(move || {
+                for event in rx {
+                    let body = payload(&event);
+                    for hook in hooks.iter().filter(|hook| hook.matches(&event)) {
+                        if let Err(e) = deliver(&transport, hook, &body, delay) {
+                            error!("webhook {} dropped: {}", event.name(), e);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Arc::new(WebhookDispatcher { tx: Mutex::new(tx) }))
+    }
+}
+
+impl EventSubscriber for WebhookDispatcher {
+    fn on_event(&self, event: &Event) {
+        let _ = self.tx.lock().unwrap().send(event.clone());
+    }
+}
+
+/// why a webhook sender's post failed; the OtpSender error downcasts to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebhookError {
+    /// the endpoint answered with a status outside 2xx
+    Status(u16),
+    /// the endpoint could not be reached
+    Unreachable(String),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookError::Status(status) => {
+                write!(f, "webhook rejected the otp with status {}", status)
+            }
+            WebhookError::Unreachable(reason) => write!(f, "webhook unreachable: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+/// return the json payload for an otp delivery: the user, the live code, the context and the unix time it was sent,
+/// so receivers can reject stale replays
+pub fn otp_payload(user: &str, code: &str, context: &SendContext, time: u64) -> String {
+    let mut json = format!(
+        r#"{{"user":"{}","code":"{}","purpose":"{}","expires":{},"time":{}"#,
+        escape(user),
+        escape(code),
+        escape(&context.purpose),
+        context.expires,
+        time
+    );
+    if let Some(locale) = &context.locale {
+        json.push_str(&format!(r#","locale":"{}""#, escape(locale)));
+    }
+    let fields: Vec<String> = context
+        .fields
+        .iter()
+        .map(|(key, value)| format!(r#""{}":"{}""#, escape(key), escape(value)))
+        .collect();
+    json.push_str(&format!(r#","fields":{{{}}}}}"#, fields.join(",")));
+
+    json
+}
+
+/// sends otps by posting a signed json payload to a notification service, which delivers them however it likes.
+/// the body is signed like event webhooks, in the SIGNATURE_HEADER; pipelines retry 429, 5xx and unreachable
+/// endpoints
+#[derive(Clone)]
+pub struct WebhookSender {
+    url: String,
+    secret: String,
+    channel: Option<Channel>,
+    transport: Arc<dyn Transport>,
+}
+
+impl fmt::Debug for WebhookSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookSender")
+            .field("url", &self.url)
+            .field("channel", &self.channel)
+            .finish()
+    }
+}
+
+impl WebhookSender {
+    /// post otps to the url, signed with the secret
+    pub fn new(url: &str, secret: &str) -> WebhookSender {
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        WebhookSender::with_transport(url, secret, Arc::new(HttpTransport { agent }))
+    }
+
+    fn with_transport(url: &str, secret: &str, transport: Arc<dyn Transport>) -> WebhookSender {
+        WebhookSender {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            channel: None,
+            transport,
+        }
+    }
+
+    /// say which channel the service delivers on, so pipelines and resend throttling can tell it apart
+    pub fn with_channel(mut self, channel: Channel) -> WebhookSender {
+        self.channel = Some(channel);
+        self
+    }
+}
+
+impl OtpSender for WebhookSender {
+    fn send(&self, user: &str, code: &str, context: &SendContext) -> Result<()> {
+        let body = otp_payload(user, code, context, unix_now());
+        let signature = sign(&self.secret, &body);
+        let headers = [
+            ("Content-Type", "application/json"),
+            (SIGNATURE_HEADER, signature.as_str()),
+        ];
+
+        match self.transport.post(&self.url, &headers, &body) {
+            Ok(status) if (200..300).contains(&status) => Ok(()),
+            Ok(status) => Err(WebhookError::Status(status).into()),
+            Err(e) => Err(WebhookError::Unreachable(e.to_string()).into()),
+        }
+    }
+
+    fn channel(&self) -> Option<Channel> {
+        self.channel
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        match error.downcast_ref::<WebhookError>() {
+            Some(WebhookError::Status(status)) => *status == 429 || *status >= 500,
+            Some(WebhookError::Unreachable(_)) => true,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Store;
+
+    // fails with the queued statuses, then succeeds; records each request
+    #[derive(Clone, Default)]
+    struct MockTransport {
+        statuses: Arc<Mutex<Vec<u16>>>,
+        requests: Arc<Mutex<Vec<(String, String, String)>>>,
+    }
+
+    impl Transport for MockTransport {
+        fn post(&self, url: &str, headers: &[(&str, &str)], body: &str) -> Result<u16> {
+            let signature = headers[1].1.to_string();
+            let request = (url.to_string(), signature, body.to_string());
+            self.requests.lock().unwrap().push(request);
+
+            Ok(self.statuses.lock().unwrap().pop().unwrap_or(200))
+        }
+    }
+
+    fn create_event() -> Event {
+        Event::new(
+            EventKind::Created,
+            Store::Session,
+            Some("sally"),
+            Some("abc123"),
+        )
+    }
+
+    #[test]
+    fn payload_redacts_code() {
+        let event = create_event();
+        let body = payload(&event);
+        assert!(body.starts_with(r#"{"event":"session.created","store":"session""#));
+        assert!(body.contains(r#""user":"sally""#));
+        assert!(!body.contains("abc123"));
+
+        let event = Event::new(
+            EventKind::UserRemoved { count: 3 },
+            Store::Otp,
+            Some("jack"),
+            None,
+        );
+        assert!(payload(&event).ends_with(r#""user":"jack","count":3}"#));
+    }
+
+    #[test]
+    fn matches() {
+        let event = create_event();
+        assert!(Webhook::new("http://localhost/hook", "secret").matches(&event));
+
+        let hook = Webhook::new("http://localhost/hook", "secret").with_events(&["otp.created"]);
+        assert!(!hook.matches(&event));
+    }
+
+    #[test]
+    fn retries() {
+        let hook = Webhook::new("http://localhost/hook", "secret");
+        let transport = MockTransport::default();
+        transport.statuses.lock().unwrap().extend([503, 500]);
+
+        let body = payload(&create_event());
+        deliver(&transport, &hook, &body, Duration::ZERO).unwrap();
+
+        let requests = transport.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].1, sign("secret", &body));
+
+        // client errors are not retried
+        transport.statuses.lock().unwrap().push(404);
+        assert!(deliver(&transport, &hook, &body, Duration::ZERO).is_err());
+        assert_eq!(transport.requests.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn otp_sender() {
+        let context = SendContext::new("login")
+            .with("ip", "203.0.113.7")
+            .with_locale("fr");
+        let body = otp_payload("sa\"lly", "123456", &context, 1_000);
+        assert_eq!(
+            body,
+            r#"{"user":"sa\"lly","code":"123456","purpose":"login","expires":0,"time":1000,"locale":"fr","fields":{"ip":"203.0.113.7"}}"#
+        );
+
+        let transport = MockTransport::default();
+        let url = "http://localhost/notify";
+        let sender = WebhookSender::with_transport(url, "secret", Arc::new(transport.clone()))
+            .with_channel(Channel::Sms);
+        sender.send("sally", "123456", &context).unwrap();
+        let (_, signature, body) = transport.requests.lock().unwrap().pop().unwrap();
+        assert_eq!(signature, sign("secret", &body));
+        assert!(body.contains(r#""code":"123456""#));
+        assert_eq!(sender.channel(), Some(Channel::Sms));
+
+        transport.statuses.lock().unwrap().extend([400, 503]);
+        let err = sender.send("sally", "123456", &context).unwrap_err();
+        assert!(sender.is_transient(&err));
+        let err = sender.send("sally", "123456", &context).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&WebhookError::Status(400)));
+        assert!(!sender.is_transient(&err));
+        assert!(!format!("{:?}", sender).contains("secret"));
+    }
+
+    #[test]
+    fn dispatch() {
+        let transport = MockTransport::default();
+        let hooks = vec![
+            Webhook::new("http://localhost/all", "secret"),
+            Webhook::new("http://localhost/otp", "secret").with_events(&["otp.created"]),
+        ];
+        let dispatcher =
+            WebhookDispatcher::start_with(hooks, transport.clone(), Duration::ZERO).unwrap();
+        dispatcher.on_event(&create_event());
+
+        let start = std::time::Instant::now();
+        while transport.requests.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "http://localhost/all");
+    }
+}
@@ -0,0 +1,296 @@
+/// a session store over the browser's web storage, localStorage or sessionStorage, so a wasm frontend can keep short
+/// lived challenge state behind the same SessionStore interface as the embedded and remote stores
+use crate::clock::{Clock, SystemClock};
+use crate::db::SessionItem;
+use crate::session::generate_code;
+use crate::store::SessionStore;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// the default prefix of the store's storage keys, so they stay clear of the app's own entries
+pub const KEY_PREFIX: &str = "otp-session:";
+
+/// the default seconds a challenge is good for
+pub const CHALLENGE_TTL: u64 = 300;
+
+/// string keys and values that outlive the store, like web storage; implemented for web_sys::Storage
+pub trait StorageArea {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+
+    fn set(&self, key: &str, value: &str) -> Result<()>;
+
+    fn remove(&self, key: &str) -> Result<()>;
+
+    /// return every key in the area, the store's and any others
+    fn keys(&self) -> Result<Vec<String>>;
+}
+
+// web storage reports failures, e.g. a full quota or storage disabled by the user, as javascript values
+fn js_error<E: std::fmt::Debug>(e: E) -> anyhow::Error {
+    anyhow!("web storage: {:?}", e)
+}
+
+impl StorageArea for web_sys::Storage {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_item(key).map_err(js_error)
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.set_item(key, value).map_err(js_error)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        self.remove_item(key).map_err(js_error)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let length = self.length().map_err(js_error)?;
+        let mut keys = Vec::with_capacity(length as usize);
+        for index in 0..length {
+            if let Some(key) = self.key(index).map_err(js_error)? {
+                keys.push(key);
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// sessions kept in a storage area as one entry per session, keyed by prefix, user and code and holding the unix time
+/// it expires. expired entries are removed as they are found
+#[derive(Debug, Clone)]
+pub struct WebStore<S> {
+    area: S,
+    prefix: String,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl WebStore<web_sys::Storage> {
+    /// create a store over the window's localStorage, kept until the app removes it
+    pub fn local() -> Result<WebStore<web_sys::Storage>> {
+        let window = web_sys::window().ok_or_else(|| anyhow!("web storage needs a window"))?;
+        let area = window.local_storage().map_err(js_error)?;
+        let area = area.ok_or_else(|| anyhow!("localStorage is not available"))?;
+        Ok(WebStore::new(area))
+    }
+
+    /// create a store over the window's sessionStorage, cleared when the tab closes
+    pub fn session() -> Result<WebStore<web_sys::Storage>> {
+        let window = web_sys::window().ok_or_else(|| anyhow!("web storage needs a window"))?;
+        let area = window.session_storage().map_err(js_error)?;
+        let area = area.ok_or_else(|| anyhow!("sessionStorage is not available"))?;
+        Ok(WebStore::new(area))
+    }
+}
+
+impl<S: StorageArea> WebStore<S> {
+    /// create a store over the area with the default prefix and ttl
+    pub fn new(area: S) -> WebStore<S> {
+        WebStore {
+            area,
+            prefix: KEY_PREFIX.to_string(),
+            ttl: CHALLENGE_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// prefix the store's keys with this instead, e.g. to keep two stores in one area apart
+    pub fn with_prefix(mut self, prefix: &str) -> WebStore<S> {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// sessions are good for this many seconds
+    pub fn with_ttl(mut self, ttl: u64) -> WebStore<S> {
+        self.ttl = ttl;
+        self
+    }
+
+    /// use this clock for expirations instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> WebStore<S> {
+        self.clock = clock;
+        self
+    }
+
+    /// return the seconds a session is good for
+    pub fn ttl(&self) -> u64 {
+        self.ttl
+    }
+
+    /// remove the expired sessions; return the number removed
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let now = self.clock.now();
+        let mut count = 0;
+        for (key, item) in self.entries()? {
+            if item.expires <= now {
+                self.area.remove(&key)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn key(&self, code: &str, user: &str) -> String {
+        format!("{}{}:{}", self.prefix, user, code)
+    }
+
+    // the store's entries and their items; other keys and unreadable values are skipped. codes have no colon, so
+    // the user is everything before the last one
+    fn entries(&self) -> Result<Vec<(String, SessionItem)>> {
+        let mut entries = Vec::new();
+        for key in self.area.keys()? {
+            let Some((user, code)) = key
+                .strip_prefix(&self.prefix)
+                .and_then(|rest| rest.rsplit_once(':'))
+            else {
+                continue;
+            };
+            let Some(expires) = self.area.get(&key)?.and_then(|value| value.parse().ok()) else {
+                continue;
+            };
+            let item = SessionItem {
+                code: code.to_string(),
+                user: user.to_string(),
+                expires,
+                meta: BTreeMap::new(),
+            };
+            entries.push((key, item));
+        }
+
+        Ok(entries)
+    }
+
+    // the unexpired items, removing the expired ones found along the way
+    fn live(&self) -> Result<Vec<SessionItem>> {
+        let now = self.clock.now();
+        let mut items = Vec::new();
+        for (key, item) in self.entries()? {
+            if item.expires > now {
+                items.push(item);
+            } else {
+                self.area.remove(&key)?;
+            }
+        }
+
+        Ok(items)
+    }
+}
+
+impl<S: StorageArea> SessionStore for WebStore<S> {
+    fn create_user_session(&mut self, user: &str) -> Result<String> {
+        let code = generate_code();
+        let expires = self.clock.now().saturating_add(self.ttl);
+        self.area
+            .set(&self.key(&code, user), &expires.to_string())?;
+
+        Ok(code)
+    }
+
+    fn is_valid(&self, code: &str, user: &str) -> Result<bool> {
+        let key = self.key(code, user);
+        let Some(expires) = self.area.get(&key)? else {
+            return Ok(false);
+        };
+        if expires
+            .parse::<u64>()
+            .map_or(true, |expires| expires <= self.clock.now())
+        {
+            self.area.remove(&key)?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    fn remove(&mut self, code: &str, user: &str) -> Result<Option<String>> {
+        let valid = self.is_valid(code, user)?;
+        self.area.remove(&self.key(code, user))?;
+
+        Ok(valid.then(|| code.to_string()))
+    }
+
+    fn list(&self, user: Option<&str>) -> Result<Vec<SessionItem>> {
+        let mut items = self.live()?;
+        if let Some(user) = user {
+            items.retain(|item| item.user == user);
+        }
+
+        Ok(items)
+    }
+
+    fn dbsize(&self) -> Result<usize> {
+        Ok(self.live()?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    // an in memory area standing in for web storage
+    #[derive(Debug, Default)]
+    struct MemoryArea(RefCell<BTreeMap<String, String>>);
+
+    impl StorageArea for MemoryArea {
+        fn get(&self, key: &str) -> Result<Option<String>> {
+            Ok(self.0.borrow().get(key).cloned())
+        }
+
+        fn set(&self, key: &str, value: &str) -> Result<()> {
+            self.0
+                .borrow_mut()
+                .insert(key.to_string(), value.to_string());
+            Ok(())
+        }
+
+        fn remove(&self, key: &str) -> Result<()> {
+            self.0.borrow_mut().remove(key);
+            Ok(())
+        }
+
+        fn keys(&self) -> Result<Vec<String>> {
+            Ok(self.0.borrow().keys().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn web_store() {
+        let clock = MockClock::at(1_000);
+        let area = MemoryArea::default();
+        area.set("theme", "dark").unwrap();
+        let mut store = WebStore::new(area)
+            .with_ttl(60)
+            .with_clock(Arc::new(clock.clone()));
+
+        let code = store.create_user_session("acme:sally").unwrap();
+        store.create_user_session("jack").unwrap();
+        assert!(store.is_valid(&code, "acme:sally").unwrap());
+        assert!(!store.is_valid(&code, "jack").unwrap());
+        let items = store.list(Some("acme:sally")).unwrap();
+        assert_eq!(
+            (items[0].code.as_str(), items[0].expires),
+            (code.as_str(), 1_060)
+        );
+        assert_eq!(store.dbsize().unwrap(), 2);
+
+        assert_eq!(
+            store.remove(&code, "acme:sally").unwrap(),
+            Some(code.clone())
+        );
+        assert!(store.remove(&code, "acme:sally").unwrap().is_none());
+
+        // expired sessions stop validating and are removed, leaving the app's own entries alone
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(store.dbsize().unwrap(), 0);
+        store.create_user_session("jack").unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert_eq!(store.area.keys().unwrap(), ["theme"]);
+    }
+}